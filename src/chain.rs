@@ -0,0 +1,388 @@
+//! Combining multiple rate limiters, such as per-IP and per-user limits, into one layer.
+//!
+//! Stacking multiple [`RateLimitLayer`]s as separate tower layers works, but each layer rejects on
+//! its own, so clients only learn about the first limit they hit. A [`ChainLayer`] instead applies
+//! every limit to the request, and rejects it if any were exceeded with a single [`ChainRejection`]
+//! listing each exceeded limit, and a `Retry-After` of the longest wait among them.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use axum::{routing::get, Router};
+//! use axum_gcra::{chain::ChainLayer, gcra::Quota, real_ip::RealIp, RateLimitLayer};
+//!
+//! # type UserId = ();
+//! let per_ip = RateLimitLayer::<RealIp>::builder().with_default_quota(Quota::per_second(10)).build();
+//! let per_user = RateLimitLayer::<UserId>::builder().with_default_quota(Quota::per_second(2)).build();
+//!
+//! let app = Router::<()>::new()
+//!     .route("/", get(|| async { "Hello, World!" }))
+//!     .route_layer(ChainLayer::new().with_limit("ip", per_ip).with_limit("user", per_user));
+//! ```
+
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    fmt,
+    future::Future,
+    hash::BuildHasher,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
+use http::{request::Parts, Request};
+use tower::{Layer, Service};
+
+use crate::{
+    gcra::{Capacity, HeaderStyle, NotUntil, Quota},
+    store::Store,
+    Error, Key, RateLimitLayer, RouteWithKey,
+};
+
+type LimitFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<(Capacity, Quota)>, Outcome>> + Send + 'a>>;
+
+/// Reason a single limit did not allow the request.
+enum Outcome {
+    /// The limit was exceeded, and other limits should still be applied.
+    Exceeded(NotUntil),
+
+    /// The request was rejected outright, such as by key extraction, skipping any other limits.
+    Rejected(Response),
+}
+
+/// Object-safe wrapper around a [`RateLimitLayer`] with any key and store.
+trait Limit: Send + Sync + 'static {
+    fn limit<'a>(&'a self, parts: &'a mut Parts) -> LimitFuture<'a>;
+
+    /// Returns the style of the headers for allowed requests, if enabled.
+    fn headers(&self) -> Option<HeaderStyle>;
+}
+
+impl<K, H, S> Limit for RateLimitLayer<K, H, S>
+where
+    K: Key + FromRequestParts<()>,
+    K::Rejection: IntoResponse,
+    H: BuildHasher + Send + Sync + 'static,
+    S: Store<RouteWithKey<K>>,
+{
+    fn limit<'a>(&'a self, parts: &'a mut Parts) -> LimitFuture<'a> {
+        Box::pin(async move {
+            match RateLimitLayer::limit::<Infallible>(self, parts, self.now()).await {
+                Ok(capacity) => Ok(capacity),
+                Err(Error::RateLimit(e)) => Err(Outcome::Exceeded(e)),
+                Err(e) => Err(Outcome::Rejected(e.into_response())),
+            }
+        })
+    }
+
+    fn headers(&self) -> Option<HeaderStyle> {
+        self.builder.headers.then_some(self.builder.header_style)
+    }
+}
+
+/// Rejection returned by a [`ChainLayer`] when one or more of its limits were exceeded.
+///
+/// Responds as the [`NotUntil`] with the longest wait, so `Retry-After` is the earliest time
+/// at which every exceeded limit would allow the request.
+#[derive(Debug, Clone)]
+pub struct ChainRejection {
+    exceeded: Vec<(Cow<'static, str>, NotUntil)>,
+}
+
+impl ChainRejection {
+    /// Returns the name and denial of each exceeded limit, in the order the limits were added.
+    pub fn exceeded(&self) -> &[(Cow<'static, str>, NotUntil)] {
+        &self.exceeded
+    }
+
+    /// Returns the denial with the longest wait among the exceeded limits.
+    pub fn longest(&self) -> NotUntil {
+        let longest = self.exceeded.iter().max_by_key(|(_, e)| e.error());
+        longest.expect("a rejection is only created with at least one exceeded limit").1
+    }
+
+    /// Returns `true` if the limit with the given name was exceeded.
+    pub fn is_exceeded(&self, name: &str) -> bool {
+        self.exceeded.iter().any(|(n, _)| n == name)
+    }
+}
+
+impl fmt::Display for ChainRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = self.exceeded.iter().map(|(n, _)| &**n);
+
+        write!(f, "rate limit exceeded for {}", names.next().unwrap_or_default())?;
+        names.try_for_each(|n| write!(f, ", {n}"))?;
+        write!(
+            f,
+            ", retry in {:.3} seconds",
+            self.longest().as_duration().as_secs_f32()
+        )
+    }
+}
+
+impl std::error::Error for ChainRejection {}
+
+impl IntoResponse for ChainRejection {
+    #[inline]
+    fn into_response(self) -> Response {
+        self.longest().into_response()
+    }
+}
+
+type RejectionHandler = dyn Fn(ChainRejection) -> Response + Send + Sync;
+
+/// Layer applying several [`RateLimitLayer`]s to each request, rejecting it if any are exceeded.
+///
+/// Each limit is applied in the order it was added, using its own key, quotas and store. A request
+/// that exceeds one limit is still counted against the others, so the rejection can report every
+/// exceeded limit, but key rejections and store failures short-circuit immediately.
+///
+/// If any limit has [rate limit headers](crate::RateLimitLayerBuilder::with_rate_limit_headers)
+/// enabled, the headers of the limit with the least remaining capacity are added to the response.
+#[derive(Clone, Default)]
+pub struct ChainLayer {
+    limits: Vec<(Cow<'static, str>, Arc<dyn Limit>)>,
+    on_rejected: Option<Arc<RejectionHandler>>,
+}
+
+impl ChainLayer {
+    /// Constructs a new layer without any limits.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a named limit to the chain, as built by [`RateLimitLayerBuilder::build`].
+    ///
+    /// [`RateLimitLayerBuilder::build`]: crate::RateLimitLayerBuilder::build
+    pub fn add_limit<K, H, S>(&mut self, name: impl Into<Cow<'static, str>>, limit: RateLimitLayer<K, H, S>)
+    where
+        K: Key + FromRequestParts<()>,
+        K::Rejection: IntoResponse,
+        H: BuildHasher + Send + Sync + 'static,
+        S: Store<RouteWithKey<K>>,
+    {
+        self.limits.push((name.into(), Arc::new(limit)));
+    }
+
+    /// Add a named limit to the chain. See [`ChainLayer::add_limit`].
+    #[must_use]
+    pub fn with_limit<K, H, S>(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        limit: RateLimitLayer<K, H, S>,
+    ) -> Self
+    where
+        K: Key + FromRequestParts<()>,
+        K::Rejection: IntoResponse,
+        H: BuildHasher + Send + Sync + 'static,
+        S: Store<RouteWithKey<K>>,
+    {
+        self.add_limit(name, limit);
+        self
+    }
+
+    /// Set the response returned when any limits are exceeded,
+    /// instead of the default `429 Too Many Requests`.
+    ///
+    /// The headers of the [longest](ChainRejection::longest) denial are added to the response,
+    /// along with `Cache-Control: no-store` unless the callback sets its own `Cache-Control` header.
+    #[must_use]
+    pub fn on_rejected<F>(mut self, cb: F) -> Self
+    where
+        F: Fn(ChainRejection) -> Response + Send + Sync + 'static,
+    {
+        self.on_rejected = Some(Arc::new(cb));
+        self
+    }
+}
+
+impl<I> Layer<I> for ChainLayer {
+    type Service = ChainService<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        ChainService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service applying the limits of a [`ChainLayer`].
+#[derive(Clone)]
+pub struct ChainService<I> {
+    inner: I,
+    layer: ChainLayer,
+}
+
+impl ChainLayer {
+    /// Apply every limit to the request, returning the most restrictive capacity with headers enabled.
+    async fn limit(&self, parts: &mut Parts) -> Result<Option<(Capacity, Quota, HeaderStyle)>, Response> {
+        let mut exceeded = Vec::new();
+        let mut allowed: Option<(Capacity, Quota, HeaderStyle)> = None;
+
+        for (name, limit) in &self.limits {
+            match limit.limit(parts).await {
+                Ok(Some((c, quota))) => {
+                    if let Some(style) = limit.headers() {
+                        #[allow(clippy::unnecessary_map_or)] // `is_none_or` would require Rust 1.82
+                        if allowed.map_or(true, |(prev, ..)| c.remaining < prev.remaining) {
+                            allowed = Some((c, quota, style));
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(Outcome::Exceeded(e)) => exceeded.push((name.clone(), e)),
+                Err(Outcome::Rejected(res)) => return Err(res),
+            }
+        }
+
+        if exceeded.is_empty() {
+            return Ok(allowed);
+        }
+
+        let rejection = ChainRejection { exceeded };
+
+        Err(match self.on_rejected {
+            Some(ref cb) => {
+                let longest = rejection.longest();
+                let mut res = cb(rejection);
+                longest.insert_headers(res.headers_mut());
+                crate::gcra::insert_no_store(res.headers_mut());
+                res
+            }
+            None => rejection.into_response(),
+        })
+    }
+}
+
+impl<B, I> Service<Request<B>> for ChainService<I>
+where
+    B: Send + 'static,
+    I: Service<Request<B>, Response = Response, Future: Send> + Clone + Send + 'static,
+{
+    type Response = Response;
+    type Error = I::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, I::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // the ready service is used for this request, and a clone is left in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        let (mut parts, body) = req.into_parts();
+
+        Box::pin(async move {
+            let allowed = match layer.limit(&mut parts).await {
+                Ok(allowed) => allowed,
+                Err(res) => return Ok(res),
+            };
+
+            let mut res = inner.call(Request::from_parts(parts, body)).await?;
+
+            if let Some((capacity, quota, style)) = allowed {
+                capacity.insert_headers_with(res.headers_mut(), quota, style);
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, routing::get, Router};
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    use crate::key::{Extract, KeyExtractor};
+
+    /// Rate limit by the `x-ip` header, standing in for the client address.
+    struct Ip;
+
+    /// Rate limit by the `x-user` header.
+    struct User;
+
+    impl KeyExtractor for Ip {
+        type Key = String;
+
+        fn extract(parts: &Parts) -> Option<String> {
+            Some(parts.headers.get("x-ip")?.to_str().ok()?.to_owned())
+        }
+    }
+
+    impl KeyExtractor for User {
+        type Key = String;
+
+        fn extract(parts: &Parts) -> Option<String> {
+            Some(parts.headers.get("x-user")?.to_str().ok()?.to_owned())
+        }
+    }
+
+    /// Two requests per minute for each address, and one per hour for each user,
+    /// responding with the names of the exceeded limits.
+    fn app() -> Router {
+        let per_ip = RateLimitLayer::<Extract<Ip>>::builder()
+            .with_default_quota(Quota::per_minute(2).with_burst(2))
+            .build();
+        let per_user = RateLimitLayer::<Extract<User>>::builder().with_default_quota(Quota::per_hour(1)).build();
+
+        let chain =
+            ChainLayer::new().with_limit("ip", per_ip).with_limit("user", per_user).on_rejected(|rejection| {
+                let names: Vec<_> = rejection.exceeded().iter().map(|(name, _)| &**name).collect();
+                (StatusCode::TOO_MANY_REQUESTS, names.join(", ")).into_response()
+            });
+
+        Router::new().route("/", get(|| async { "ok" })).route_layer(chain)
+    }
+
+    /// Status, body and `Retry-After` of a request from the address as the user.
+    async fn send(app: &Router, ip: &str, user: Option<&str>) -> (u16, String, Option<String>) {
+        let mut req = Request::get("/").header("x-ip", ip);
+        if let Some(user) = user {
+            req = req.header("x-user", user);
+        }
+
+        let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        let status = res.status().as_u16();
+        let retry_after = res.headers().get("retry-after").map(|v| v.to_str().unwrap().to_owned());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap(), retry_after)
+    }
+
+    #[tokio::test]
+    async fn either_limit_rejects() {
+        let app = app();
+        let rejected = |body: &str, retry_after: &str| (429, body.to_owned(), Some(retry_after.to_owned()));
+
+        assert_eq!(send(&app, "a", Some("alice")).await, (200, "ok".to_owned(), None));
+
+        // passes the IP limit, but not the user limit
+        assert_eq!(send(&app, "a", Some("alice")).await, rejected("user", "3600"));
+
+        // passes the user limit, but not the IP limit, which still counted the last request
+        assert_eq!(send(&app, "a", Some("bob")).await, rejected("ip", "30"));
+
+        // both, waiting for the longest
+        assert_eq!(send(&app, "a", Some("alice")).await, rejected("ip, user", "3600"));
+
+        // and key rejections short-circuit, though earlier limits have already counted the request
+        assert_eq!(send(&app, "b", None).await.0, 400);
+        assert_eq!(send(&app, "b", Some("carol")).await.0, 200);
+        assert_eq!(send(&app, "b", Some("dave")).await, rejected("ip", "30"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Per-key concurrency limiting, capping the number of in-flight requests rather than their rate.
+//!
+//! Rate limits alone do not protect slow endpoints, since a client within its rate can still
+//! accumulate many requests in progress at once. The [`ConcurrencyLimitLayer`] holds a permit
+//! for each request until the inner service has produced a response, and rejects requests
+//! for keys that already hold the maximum number of permits.
+//!
+//! Keys are extracted in the same way as for the [`RateLimitLayer`](crate::RateLimitLayer),
+//! but do not include the route, so a client's requests to every route behind the layer share
+//! a single limit. Use a tuple with the [`RouteKeyExtractor`](crate::key::RouteKeyExtractor)
+//! to limit each route separately.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use axum::{routing::get, Router};
+//! use axum_gcra::{concurrency::ConcurrencyLimitLayer, real_ip::RealIp};
+//!
+//! let app = Router::<()>::new()
+//!     .route("/report", get(|| async { "slow report" }))
+//!     // at most 2 reports in progress per client
+//!     .route_layer(ConcurrencyLimitLayer::<RealIp>::new(2));
+//! ```
+
+use std::{
+    future::Future,
+    hash::BuildHasher,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
+use http::{Request, StatusCode};
+use scc::HashMap;
+use tower::{Layer, Service};
+
+use crate::{Key, RandomState};
+
+struct Shared<K, H: BuildHasher> {
+    max: usize,
+    in_flight: HashMap<K, usize, H>,
+}
+
+/// Layer limiting the number of concurrent in-flight requests for each key.
+///
+/// Requests over the limit are rejected with `429 Too Many Requests` by default, with `Cache-Control: no-store`
+/// so the rejection is not cached. The permit is released once the inner service returns a response,
+/// so streaming response bodies are not counted.
+pub struct ConcurrencyLimitLayer<K: Key, H: BuildHasher = RandomState> {
+    shared: Arc<Shared<K, H>>,
+    status: StatusCode,
+}
+
+impl<K: Key, H: BuildHasher> Clone for ConcurrencyLimitLayer<K, H> {
+    fn clone(&self) -> Self {
+        ConcurrencyLimitLayer {
+            shared: self.shared.clone(),
+            status: self.status,
+        }
+    }
+}
+
+impl<K: Key> ConcurrencyLimitLayer<K> {
+    /// Constructs a new layer allowing at most `max` in-flight requests per key.
+    ///
+    /// A maximum of zero is treated as one.
+    #[must_use]
+    pub fn new(max: usize) -> Self {
+        Self::with_hasher(max, RandomState::default())
+    }
+}
+
+impl<K: Key, H: BuildHasher> ConcurrencyLimitLayer<K, H> {
+    /// Constructs a new layer allowing at most `max` in-flight requests per key, with the given hasher.
+    ///
+    /// A maximum of zero is treated as one.
+    #[must_use]
+    pub fn with_hasher(max: usize, hasher: H) -> Self {
+        ConcurrencyLimitLayer {
+            shared: Arc::new(Shared {
+                max: max.max(1),
+                in_flight: HashMap::with_hasher(hasher),
+            }),
+            status: StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Set the status code of the response for rejected requests, such as `503 Service Unavailable`.
+    #[must_use]
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Returns the number of in-flight requests for the given key.
+    pub fn in_flight(&self, key: &K) -> usize {
+        self.shared.in_flight.read(key, |_, count| *count).unwrap_or(0)
+    }
+}
+
+impl<K: Key, H: BuildHasher, I> Layer<I> for ConcurrencyLimitLayer<K, H> {
+    type Service = ConcurrencyLimitService<I, K, H>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        ConcurrencyLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service limiting the number of concurrent in-flight requests for each key.
+pub struct ConcurrencyLimitService<I, K: Key, H: BuildHasher = RandomState> {
+    inner: I,
+    layer: ConcurrencyLimitLayer<K, H>,
+}
+
+impl<I: Clone, K: Key, H: BuildHasher> Clone for ConcurrencyLimitService<I, K, H> {
+    fn clone(&self) -> Self {
+        ConcurrencyLimitService {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+/// Permit for a single in-flight request, released on drop.
+struct Permit<K: Key, H: BuildHasher> {
+    key: K,
+    shared: Arc<Shared<K, H>>,
+}
+
+impl<K: Key, H: BuildHasher> Drop for Permit<K, H> {
+    fn drop(&mut self) {
+        self.shared.in_flight.remove_if(&self.key, |count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+impl<K: Key + Clone, H: BuildHasher> Shared<K, H> {
+    /// Acquire a permit for the key, or `None` if the key is at the limit.
+    async fn acquire(self: Arc<Self>, key: K) -> Option<Permit<K, H>> {
+        let mut entry = self.in_flight.entry_async(key.clone()).await.or_insert(0);
+
+        if *entry.get() >= self.max {
+            return None;
+        }
+
+        *entry.get_mut() += 1;
+        drop(entry);
+
+        Some(Permit { key, shared: self })
+    }
+}
+
+impl<B, I, K, H> Service<Request<B>> for ConcurrencyLimitService<I, K, H>
+where
+    B: Send + 'static,
+    I: Service<Request<B>, Response = Response, Future: Send> + Clone + Send + 'static,
+    K: Key + Clone + FromRequestParts<()>,
+    H: BuildHasher + Send + Sync + 'static,
+{
+    type Response = Response;
+    type Error = I::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, I::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // the ready service is used for this request, and a clone is left in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let shared = self.layer.shared.clone();
+        let status = self.layer.status;
+
+        let (mut parts, body) = req.into_parts();
+        parts.extensions.insert(crate::extensions::LayerApplied);
+
+        Box::pin(async move {
+            let key = match crate::get_user_key::<K>(&mut parts).await {
+                Ok(key) => key,
+                Err(rejection) => return Ok(rejection.into_response()),
+            };
+
+            let Some(permit) = shared.acquire(key).await else {
+                let mut res = status.into_response();
+                crate::gcra::insert_no_store(res.headers_mut());
+                return Ok(res);
+            };
+
+            let res = inner.call(Request::from_parts(parts, body)).await;
+            drop(permit);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, routing::get, Router};
+    use http::request::Parts;
+    use tokio::sync::Semaphore;
+    use tower::ServiceExt;
+
+    use crate::key::{Extract, KeyExtractor};
+
+    /// Limit by the `x-client` header.
+    struct Client;
+
+    impl KeyExtractor for Client {
+        type Key = String;
+
+        fn extract(parts: &Parts) -> Option<String> {
+            Some(parts.headers.get("x-client")?.to_str().ok()?.to_owned())
+        }
+    }
+
+    fn send(app: &Router, path: &str, client: &str) -> impl Future<Output = u16> {
+        let req = Request::get(path).header("x-client", client).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req);
+
+        async move { res.await.unwrap().status().as_u16() }
+    }
+
+    #[tokio::test]
+    async fn rejects_over_limit_until_one_completes() {
+        let layer = ConcurrencyLimitLayer::<Extract<Client>>::new(2);
+        let in_flight =
+            |layer: &ConcurrencyLimitLayer<_>, client: &str| layer.in_flight(&Extract(client.to_owned()));
+
+        // slow requests wait for a permit of the gate before responding
+        let gate = Arc::new(Semaphore::new(0));
+        let app = Router::new()
+            .route(
+                "/slow",
+                get({
+                    let gate = gate.clone();
+                    move || async move { gate.acquire().await.unwrap().forget() }
+                }),
+            )
+            .route("/fast", get(|| async {}))
+            .route_layer(layer.clone());
+
+        let slow = [
+            tokio::spawn(send(&app, "/slow", "a")),
+            tokio::spawn(send(&app, "/slow", "a")),
+        ];
+        while in_flight(&layer, "a") < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // the next request for the same key is rejected, on any route
+        assert_eq!(send(&app, "/slow", "a").await, 429);
+        assert_eq!(send(&app, "/fast", "a").await, 429);
+        assert_eq!(send(&app, "/fast", "b").await, 200);
+        assert_eq!(in_flight(&layer, "a"), 2);
+
+        // until one of them completes
+        gate.add_permits(1);
+        while in_flight(&layer, "a") > 1 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(send(&app, "/fast", "a").await, 200);
+
+        gate.add_permits(1);
+        for status in slow {
+            assert_eq!(status.await.unwrap(), 200);
+        }
+        assert_eq!(in_flight(&layer, "a"), 0);
+    }
+
+    #[tokio::test]
+    async fn rejection_status() {
+        let layer = ConcurrencyLimitLayer::<Extract<Client>>::new(0).with_status(StatusCode::SERVICE_UNAVAILABLE);
+
+        let gate = Arc::new(Semaphore::new(0));
+        let app = Router::new()
+            .route(
+                "/slow",
+                get({
+                    let gate = gate.clone();
+                    move || async move { gate.acquire().await.unwrap().forget() }
+                }),
+            )
+            .route_layer(layer.clone());
+
+        // a maximum of zero still allows one request
+        let slow = tokio::spawn(send(&app, "/slow", "a"));
+        while layer.in_flight(&Extract("a".to_owned())) < 1 {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(send(&app, "/slow", "a").await, 503);
+
+        gate.add_permits(1);
+        assert_eq!(slow.await.unwrap(), 200);
+
+        // and requests without a key are rejected by the extractor
+        let res = app.oneshot(Request::get("/slow").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -6,6 +6,7 @@ use std::{
     error::Error,
     fmt,
     hash::{BuildHasher, Hash},
+    net::IpAddr,
     num::NonZeroU64,
     sync::atomic::{AtomicU64, Ordering},
     time::{Duration, Instant},
@@ -20,6 +21,8 @@ pub struct RateLimiter<K: Eq + Hash, H: BuildHasher = std::collections::hash_map
     start: Instant,
     gc_interval: u64,
     last_gc: AtomicU64,
+    max_entries: usize,
+    cold_start: ColdStart,
     limits: HashMap<K, Gcra, H>,
 }
 
@@ -31,14 +34,145 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
             start: Instant::now(),
             gc_interval,
             last_gc: AtomicU64::new(1),
+            max_entries: usize::MAX,
+            cold_start: ColdStart::Full,
             limits: HashMap::with_hasher(hasher),
         }
     }
 
+    /// Sets the maximum number of entries in the rate limiter table.
+    ///
+    /// When a new key would exceed this limit, expired entries are removed first,
+    /// and if the table is still full, the oldest idle entries are evicted until the table
+    /// is at 7/8ths of the limit, to amortize the cost of finding them.
+    ///
+    /// Evicting an entry resets the rate limit for that key, so this should be set well
+    /// above the expected number of active keys. The default is unlimited.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.set_max_entries(max_entries);
+        self
+    }
+
+    /// Sets the garbage collection interval, in number of requests.
+    pub(crate) fn set_gc_interval(&mut self, gc_interval: u64) {
+        self.gc_interval = gc_interval;
+    }
+
+    pub(crate) fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries.max(1);
+    }
+
+    /// Sets how much capacity keys start with when first seen. The default is [`ColdStart::Full`].
+    ///
+    /// Keys that were garbage collected or evicted are unseen again, and will start over the same way.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::{Duration, Instant};
+    /// use axum_gcra::gcra::{ColdStart, Quota, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::<&str>::default().with_cold_start(ColdStart::Empty);
+    /// let quota = Quota::per_second(10).with_burst(5);
+    /// let now = Instant::now();
+    ///
+    /// // a new key must wait one emission interval for its first request
+    /// assert_eq!(limiter.req_sync("new", quota, now).unwrap_err().as_duration(), Duration::from_millis(100));
+    /// assert!(limiter.req_sync("new", quota, now + Duration::from_millis(100)).is_ok());
+    /// ```
+    #[must_use]
+    pub fn with_cold_start(mut self, cold_start: ColdStart) -> Self {
+        self.set_cold_start(cold_start);
+        self
+    }
+
+    pub(crate) fn set_cold_start(&mut self, cold_start: ColdStart) {
+        self.cold_start = cold_start;
+    }
+
+    /// Inserts an empty GCRA for an unseen key when [cold starting empty](ColdStart::Empty),
+    /// so the request is then handled as for an existing key.
+    #[inline]
+    fn cold<'h>(&self, entry: Entry<'h, K, Gcra, H>, quota: Quota, now: u64) -> Entry<'h, K, Gcra, H> {
+        match entry {
+            Entry::Vacant(gcra) if self.cold_start == ColdStart::Empty => {
+                Entry::Occupied(gcra.insert_entry(Gcra::empty(quota, now)))
+            }
+            entry => entry,
+        }
+    }
+
+    /// Returns the number of entries in the rate limiter table.
+    pub fn len(&self) -> usize {
+        self.limits.len()
+    }
+
+    /// Returns `true` if the rate limiter table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    // `is_multiple_of` would require Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
     fn should_gc(&self) -> bool {
         self.gc_interval != u64::MAX && 0 == self.last_gc.fetch_add(1, Ordering::Relaxed) % self.gc_interval
     }
 
+    /// Returns a predicate evicting the entries with the oldest theoretical arrival times, if the table is full,
+    /// until it is at 7/8ths of the limit.
+    fn eviction(&self, mut tats: Vec<u64>) -> Option<impl FnMut(&K, &mut Gcra) -> bool> {
+        let target = self.max_entries - self.max_entries / 8;
+        let evict = tats.len().checked_sub(target)?;
+        let (older, &mut cutoff, _) = tats.select_nth_unstable(evict.checked_sub(1)?);
+
+        // entries tied with the cutoff are only evicted as needed, rather than all of them
+        let mut ties = evict - older.iter().filter(|&&tat| tat < cutoff).count();
+
+        Some(
+            move |_: &K, v: &mut Gcra| match (*AtomicU64::get_mut(&mut v.0)).cmp(&cutoff) {
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Equal if ties > 0 => {
+                    ties -= 1;
+                    false
+                }
+                _ => true,
+            },
+        )
+    }
+
+    /// Garbage collection and eviction for the slow path, before a new entry may be inserted.
+    async fn collect(&self, now: u64) {
+        if self.should_gc() || self.limits.len() >= self.max_entries {
+            self.limits.retain_async(move |_, v| *AtomicU64::get_mut(&mut v.0) >= now).await;
+        }
+
+        if self.limits.len() >= self.max_entries {
+            let mut tats = Vec::with_capacity(self.limits.len());
+            self.limits.scan_async(|_, v| tats.push(v.0.load(Ordering::Relaxed))).await;
+
+            if let Some(evict) = self.eviction(tats) {
+                self.limits.retain_async(evict).await;
+            }
+        }
+    }
+
+    /// Synchronous version of [`RateLimiter::collect`].
+    fn collect_sync(&self, now: u64) {
+        if self.should_gc() || self.limits.len() >= self.max_entries {
+            self.limits.retain(move |_, v| *AtomicU64::get_mut(&mut v.0) >= now);
+        }
+
+        if self.limits.len() >= self.max_entries {
+            let mut tats = Vec::with_capacity(self.limits.len());
+            self.limits.scan(|_, v| tats.push(v.0.load(Ordering::Relaxed)));
+
+            if let Some(evict) = self.eviction(tats) {
+                self.limits.retain(evict);
+            }
+        }
+    }
+
     #[inline]
     fn relative(&self, ts: Instant) -> u64 {
         ts.saturating_duration_since(self.start).as_nanos() as u64
@@ -60,17 +194,29 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
 
     /// Perform a request, returning an error if the request is too soon.
     pub async fn req(&self, key: K, quota: Quota, now: Instant) -> Result<(), RateLimitError> {
+        self.req_n(key, quota, 1, now).await
+    }
+
+    /// Synchonous version of [`RateLimiter::req`].
+    pub fn req_sync(&self, key: K, quota: Quota, now: Instant) -> Result<(), RateLimitError> {
+        self.req_n_sync(key, quota, 1, now)
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the request is too soon.
+    ///
+    /// This advances the theoretical arrival time by `cost` emission intervals at once, so a request
+    /// costing the full burst size is only allowed when the bucket is full. Requests costing more than
+    /// the burst size will always be rejected. A cost of zero is treated as one.
+    pub async fn req_n(&self, key: K, quota: Quota, cost: u64, now: Instant) -> Result<(), RateLimitError> {
         let now = self.relative(now);
 
-        let Some(res) = self.limits.read_async(&key, |_, gcra| gcra.req(quota, now)).await else {
-            if self.should_gc() {
-                self.limits.retain_async(move |_, v| *AtomicU64::get_mut(&mut v.0) >= now).await;
-            }
+        let Some(res) = self.limits.read_async(&key, |_, gcra| gcra.req_n(quota, cost, now)).await else {
+            self.collect(now).await;
 
-            return match self.limits.entry_async(key).await {
-                Entry::Occupied(gcra) => gcra.get().req(quota, now),
+            return match self.cold(self.limits.entry_async(key).await, quota, now) {
+                Entry::Occupied(gcra) => gcra.get().req_n(quota, cost, now),
                 Entry::Vacant(gcra) => {
-                    gcra.insert_entry(Gcra::first(quota, now));
+                    gcra.insert_entry(Gcra::first_n(quota, cost, now)?);
                     Ok(())
                 }
             };
@@ -79,19 +225,99 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
         res
     }
 
-    /// Synchonous version of [`RateLimiter::req`].
-    pub fn req_sync(&self, key: K, quota: Quota, now: Instant) -> Result<(), RateLimitError> {
-        let now = self.relative(now);
+    /// Perform a batch of `n` requests at once, for batched operations charged per sub-operation,
+    /// returning the remaining capacity for the key on success.
+    ///
+    /// Either all `n` cells are consumed or none are, as with [`RateLimiter::req_n`], so a rejected batch
+    /// never partially charges the key. On rejection, the [`BatchError`] reports how many of the `n`
+    /// requests would have fit, so the batch can be trimmed or retried later.
+    pub async fn check_batch(&self, key: K, quota: Quota, n: u64, now: Instant) -> Result<Capacity, BatchError> {
+        self.req_peek_key(key, quota, n, now, |_| {})
+            .await
+            .map_err(|error| BatchError::new(error, quota, n))
+    }
+
+    /// Waits until a request is allowed for the key, then performs it, returning the remaining capacity.
+    ///
+    /// Instead of rejecting requests that are too soon, this [sleeps](crate::clock::Clock::sleep) on the given
+    /// clock for the computed wait and tries again, which paces a stream of calls to the rate of the quota,
+    /// such as for background workers making outbound requests.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use axum_gcra::{clock::{Clock, FakeClock}, gcra::{Quota, RateLimiter}};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let limiter = RateLimiter::<&str>::new(8192, Default::default());
+    /// let quota = Quota::per_second(10);
+    /// let clock = FakeClock::new();
+    ///
+    /// for _ in 0..10 {
+    ///     limiter.until_ready("jobs", quota, &clock).await;
+    /// }
+    ///
+    /// // the fake clock advances itself instead of sleeping
+    /// assert!(clock.elapsed() >= Duration::from_millis(900));
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn until_ready(&self, key: K, quota: Quota, clock: &(impl crate::Clock + ?Sized)) -> Capacity
+    where
+        K: Clone,
+    {
+        // a single cell always fits within the burst
+        match self.until_n_ready(key, quota, 1, clock).await {
+            Ok(capacity) => capacity,
+            Err(_) => unreachable!("burst is at least one cell"),
+        }
+    }
+
+    /// Waits until a request costing `n` cells is allowed for the key, then performs it,
+    /// returning the remaining capacity. See [`RateLimiter::until_ready`].
+    ///
+    /// Returns an error immediately if `n` is greater than the burst size of the quota,
+    /// as such a request would never be allowed. A cost of zero is treated as one.
+    #[cfg(feature = "tokio")]
+    pub async fn until_n_ready(
+        &self,
+        key: K,
+        quota: Quota,
+        n: u64,
+        clock: &(impl crate::Clock + ?Sized),
+    ) -> Result<Capacity, InsufficientCapacity>
+    where
+        K: Clone,
+    {
+        let requested = n.max(1);
 
-        let Some(res) = self.limits.read(&key, |_, gcra| gcra.req(quota, now)) else {
-            if self.should_gc() {
-                self.limits.retain(move |_, v| *AtomicU64::get_mut(&mut v.0) >= now);
+        if quota.t != 0 && requested > quota.burst() {
+            return Err(InsufficientCapacity {
+                requested,
+                burst: quota.burst(),
+            });
+        }
+
+        loop {
+            match self.req_peek_key(key.clone(), quota, requested, clock.now(), |_| {}).await {
+                Ok(capacity) => return Ok(capacity),
+                Err(e) => clock.sleep(e.as_duration()).await,
             }
+        }
+    }
+
+    /// Synchonous version of [`RateLimiter::req_n`].
+    pub fn req_n_sync(&self, key: K, quota: Quota, cost: u64, now: Instant) -> Result<(), RateLimitError> {
+        let now = self.relative(now);
+
+        let Some(res) = self.limits.read(&key, |_, gcra| gcra.req_n(quota, cost, now)) else {
+            self.collect_sync(now);
 
-            return match self.limits.entry(key) {
-                Entry::Occupied(gcra) => gcra.get().req(quota, now),
+            return match self.cold(self.limits.entry(key), quota, now) {
+                Entry::Occupied(gcra) => gcra.get().req_n(quota, cost, now),
                 Entry::Vacant(gcra) => {
-                    gcra.insert_entry(Gcra::first(quota, now));
+                    gcra.insert_entry(Gcra::first_n(quota, cost, now)?);
                     Ok(())
                 }
             };
@@ -100,14 +326,16 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
         res
     }
 
-    /// Variant of [`RateLimiter::req`] that allows for a peek at the key after it's been inserted.
+    /// Variant of [`RateLimiter::req`] that allows for a peek at the key after it's been inserted,
+    /// and returns the remaining capacity for the key on success.
     pub(crate) async fn req_peek_key<F>(
         &self,
         key: K,
         quota: Quota,
+        cost: u64,
         now: Instant,
         peek: F,
-    ) -> Result<(), RateLimitError>
+    ) -> Result<Capacity, RateLimitError>
     where
         F: FnOnce(&K),
     {
@@ -117,10 +345,10 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
         let read = self
             .limits
             .read_async(&key, |_, gcra| {
-                gcra.req(quota, now)?;
+                let tat = gcra.req_tat(quota, cost, now)?;
                 let peek = unsafe { peek.take().unwrap_unchecked() }; // SAFETY: peek is Some
                 peek(&key);
-                Ok(())
+                Ok(Capacity::new(tat, now, quota))
             })
             .await;
 
@@ -130,20 +358,18 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
             let peek = unsafe { peek.unwrap_unchecked() };
 
             // since we hit the slow path, perform garbage collection
-            if self.should_gc() {
-                self.limits.retain_async(move |_, v| *AtomicU64::get_mut(&mut v.0) >= now).await;
-            }
+            self.collect(now).await;
 
-            return match self.limits.entry_async(key).await {
+            return match self.cold(self.limits.entry_async(key).await, quota, now) {
                 Entry::Occupied(gcra) => {
-                    gcra.get().req(quota, now)?;
+                    let tat = gcra.get().req_tat(quota, cost, now)?;
                     peek(gcra.key());
-                    Ok(())
+                    Ok(Capacity::new(tat, now, quota))
                 }
                 Entry::Vacant(gcra) => {
-                    let gcra = gcra.insert_entry(Gcra::first(quota, now));
+                    let gcra = gcra.insert_entry(Gcra::first_n(quota, cost, now)?);
                     peek(gcra.key());
-                    Ok(())
+                    Ok(Capacity::new(gcra.get().0.load(Ordering::Relaxed), now, quota))
                 }
             };
         };
@@ -151,6 +377,44 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
         res
     }
 
+    /// Checks whether a request would be allowed, without recording it.
+    ///
+    /// On success, returns the capacity that would remain after making the request.
+    /// Unlike [`RateLimiter::req`], this never inserts the key or modifies its state,
+    /// so repeated checks will never deplete the bucket.
+    pub async fn check<Q>(&self, key: &Q, quota: Quota, now: Instant) -> Result<Capacity, RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let now = self.relative(now);
+
+        let tat = match self.limits.read_async(key, |_, gcra| gcra.check(quota, now)).await {
+            Some(res) => res?,
+            None if self.cold_start == ColdStart::Empty => Gcra::empty(quota, now).check(quota, now)?,
+            None => Gcra::first(quota, now).0.into_inner(),
+        };
+
+        Ok(Capacity::new(tat, now, quota))
+    }
+
+    /// Synchronous version of [`RateLimiter::check`].
+    pub fn check_sync<Q>(&self, key: &Q, quota: Quota, now: Instant) -> Result<Capacity, RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let now = self.relative(now);
+
+        let tat = match self.limits.read(key, |_, gcra| gcra.check(quota, now)) {
+            Some(res) => res?,
+            None if self.cold_start == ColdStart::Empty => Gcra::empty(quota, now).check(quota, now)?,
+            None => Gcra::first(quota, now).0.into_inner(),
+        };
+
+        Ok(Capacity::new(tat, now, quota))
+    }
+
     /// Penalizes the given key by the given amount of time,
     /// returning `true` if the key was found.
     ///
@@ -158,18 +422,13 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
     /// Best to use after [`RateLimiter::req`] has been called receently.
     ///
     /// NOTE: Furthermore, it uses a 64-bit integer to store the GCRA value,
-    /// so it may overflow if used with too large a value. Keep it modest.
+    /// so penalties are saturated at the roughly 584 years representable in nanoseconds.
     pub async fn penalize<Q>(&self, key: &Q, penalty: Duration) -> bool
     where
         K: Borrow<Q>,
         Q: Eq + Hash + ?Sized,
     {
-        self.limits
-            .read_async(key, |_, grca| {
-                grca.0.fetch_add(penalty.as_nanos() as u64, Ordering::Relaxed)
-            })
-            .await
-            .is_some()
+        self.limits.read_async(key, |_, grca| grca.penalize(penalty)).await.is_some()
     }
 
     /// Synchronous version of [`RateLimiter::penalize`].
@@ -178,14 +437,14 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
         K: Borrow<Q>,
         Q: Eq + Hash + ?Sized,
     {
-        self.limits
-            .read(key, |_, grca| {
-                grca.0.fetch_add(penalty.as_nanos() as u64, Ordering::Relaxed)
-            })
-            .is_some()
+        self.limits.read(key, |_, grca| grca.penalize(penalty)).is_some()
     }
 
     /// Resets the rate limit for the given key, returning `true` if the key was found.
+    ///
+    /// The entry is removed, so the next request for the key starts again with a full burst,
+    /// such as to lift a throttle administratively. A request racing with the reset is either
+    /// counted before the entry is removed, or against a fresh entry afterwards.
     pub async fn reset<Q>(&self, key: &Q) -> bool
     where
         K: Borrow<Q>,
@@ -202,138 +461,1580 @@ impl<K: Eq + Hash, H: BuildHasher> RateLimiter<K, H> {
     {
         self.limits.remove(key).is_some()
     }
-}
 
-impl<K: Eq + Hash, H: BuildHasher> Default for RateLimiter<K, H>
-where
-    H: Default,
-{
-    fn default() -> Self {
-        // default to 8192 unique requests before garbage collection
-        RateLimiter::new(8192, H::default())
+    /// Resets the rate limit for every key, as by [`RateLimiter::reset`].
+    pub async fn reset_all(&self) {
+        self.limits.clear_async().await;
     }
-}
-
-/// An error that occurs when a rate limit is exceeded,
-/// with the amount of time until the next request can be made.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-#[repr(transparent)]
-pub struct RateLimitError(pub NonZeroU64);
 
-impl fmt::Display for RateLimitError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "rate limit exceeded, retry in {:.3} seconds",
-            self.as_duration().as_secs_f32()
-        )
+    /// Synchronous version of [`RateLimiter::reset_all`].
+    pub fn reset_all_sync(&self) {
+        self.limits.clear();
     }
-}
 
-impl Error for RateLimitError {}
+    /// Returns the theoretical arrival time of the given key, if it exists,
+    /// which can later be imported with [`RateLimiter::set_state`].
+    pub async fn state<Q>(&self, key: &Q) -> Option<Instant>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let tat = self.limits.read_async(key, |_, gcra| gcra.0.load(Ordering::Acquire)).await?;
 
-use axum::http::StatusCode;
-use axum::response::{IntoResponse, Response};
+        Some(self.start + Duration::from_nanos(tat))
+    }
 
-impl IntoResponse for RateLimitError {
-    fn into_response(self) -> Response {
-        use http::{HeaderName, HeaderValue};
+    /// Synchronous version of [`RateLimiter::state`].
+    pub fn state_sync<Q>(&self, key: &Q) -> Option<Instant>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let tat = self.limits.read(key, |_, gcra| gcra.0.load(Ordering::Acquire))?;
 
-        // reuse as_duration value
-        let reset = self.as_duration();
+        Some(self.start + Duration::from_nanos(tat))
+    }
 
-        let mut res = Response::new(From::from(format!(
-            "rate limit exceeded, retry in {:.3} seconds",
-            reset.as_secs_f32()
-        )));
+    /// Sets the theoretical arrival time of the given key, inserting it if needed, such as to seed
+    /// the state of a client from another node so they don't receive a fresh burst after a deploy.
+    ///
+    /// The theoretical arrival time is the time at which the bucket would be full again plus one
+    /// emission interval, so a state of `now + emission_interval * (burst + 1)` or later has no remaining
+    /// capacity, and a state of `now + emission_interval` or earlier is equivalent to a new key.
+    ///
+    /// The time must come from the same clock used for requests, typically [`Instant::now`]. Since `Instant`s
+    /// are only meaningful within a single process, state from another node should be transferred as the
+    /// remaining delay and added to the current time. Times before this rate limiter was created are clamped.
+    ///
+    /// Seeding is specific to this in-memory store, and is not part of the [`Store`](crate::store::Store) trait,
+    /// so the other stores cannot be seeded. Within a [`RateLimitLayer`](crate::RateLimitLayer), this store
+    /// is reachable through [`RateLimitLayer::store`](crate::RateLimitLayer::store), keyed by
+    /// [`RouteWithKey`](crate::RouteWithKey) as returned by
+    /// [`RateLimiter::route_key`](crate::extensions::RateLimiter::route_key).
+    pub async fn set_state(&self, key: K, tat: Instant) {
+        let tat = self.relative(tat);
 
-        *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        match self.limits.entry_async(key).await {
+            Entry::Occupied(gcra) => gcra.get().0.store(tat, Ordering::Release),
+            Entry::Vacant(gcra) => _ = gcra.insert_entry(Gcra(AtomicU64::new(tat))),
+        }
+    }
 
-        let reset = reset.as_secs().max(1);
+    /// Synchronous version of [`RateLimiter::set_state`].
+    pub fn set_state_sync(&self, key: K, tat: Instant) {
+        let tat = self.relative(tat);
 
-        // optimize for common values
-        let value = match reset {
-            1 => HeaderValue::from_static("1"),
-            2 => HeaderValue::from_static("2"),
-            _ => {
-                #[cfg(feature = "itoa")]
-                let value = {
-                    let mut buffer = itoa::Buffer::new();
-                    HeaderValue::from_str(buffer.format(reset)).unwrap()
-                };
+        match self.limits.entry(key) {
+            Entry::Occupied(gcra) => gcra.get().0.store(tat, Ordering::Release),
+            Entry::Vacant(gcra) => _ = gcra.insert_entry(Gcra(AtomicU64::new(tat))),
+        }
+    }
 
-                #[cfg(not(feature = "itoa"))]
-                let value = HeaderValue::from_str(&reset.to_string()).unwrap();
+    /// Returns the state of the given key relative to the given time, if it exists and has not fully recovered,
+    /// such as to replicate individual hot keys to another store.
+    ///
+    /// The [`State`] holds the nanoseconds from `now` until the theoretical arrival time of the key, so it can be
+    /// applied by [`RateLimiter::put_state`] in another process, relative to that process's own clock. A key that
+    /// has fully recovered is equivalent to a missing key, and returns `None`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::{Duration, Instant};
+    /// use axum_gcra::gcra::{Quota, RateLimiter};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let (primary, replica) = (RateLimiter::<&str>::default(), RateLimiter::<&str>::default());
+    /// let quota = Quota::per_second(1);
+    /// let now = Instant::now();
+    ///
+    /// primary.req("client", quota, now).await.unwrap();
+    ///
+    /// let state = primary.get_state("client", now).await.unwrap();
+    /// replica.put_state("client", state, now).await;
+    ///
+    /// // the replica throttles the client as the primary would
+    /// assert!(replica.req("client", quota, now).await.is_err());
+    /// assert!(replica.req("client", quota, now + Duration::from_secs(1)).await.is_ok());
+    /// # }
+    /// ```
+    pub async fn get_state<Q>(&self, key: &Q, now: Instant) -> Option<State>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let now = self.relative(now);
 
-                value
-            }
-        };
+        State::new(
+            self.limits.read_async(key, |_, gcra| gcra.0.load(Ordering::Acquire)).await?,
+            now,
+        )
+    }
 
-        let headers = res.headers_mut();
+    /// Synchronous version of [`RateLimiter::get_state`].
+    pub fn get_state_sync<Q>(&self, key: &Q, now: Instant) -> Option<State>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let now = self.relative(now);
 
-        headers.insert(HeaderName::from_static("ratelimit-reset"), value.clone());
-        headers.insert(HeaderName::from_static("x-ratelimit-reset"), value.clone());
-        headers.insert(HeaderName::from_static("retry-after"), value.clone());
-        headers.insert(
-            HeaderName::from_static("ratelimit-remaining"),
-            HeaderValue::from_static("0"),
-        );
+        State::new(self.limits.read(key, |_, gcra| gcra.0.load(Ordering::Acquire))?, now)
+    }
 
-        res
+    /// Returns when the given key regains its next cell and its full burst under the given quota,
+    /// as by [`State::reset_at`]. Keys that are not tracked have fully recovered, so both are `now`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::{Duration, Instant};
+    /// use axum_gcra::gcra::{Quota, RateLimiter};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let limiter = RateLimiter::<&str>::default();
+    /// let quota = Quota::per_second(10).with_burst(10);
+    /// let now = Instant::now();
+    ///
+    /// // half of the burst is used
+    /// for _ in 0..5 {
+    ///     limiter.req("client", quota, now).await.unwrap();
+    /// }
+    ///
+    /// let reset = limiter.reset_at("client", quota, now).await;
+    /// assert_eq!(reset.next, now + Duration::from_millis(100));
+    /// assert_eq!(reset.full, now + Duration::from_millis(500));
+    /// # }
+    /// ```
+    pub async fn reset_at<Q>(&self, key: &Q, quota: Quota, now: Instant) -> ResetAt
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.get_state(key, now).await {
+            Some(state) => state.reset_at(quota, now),
+            None => ResetAt { next: now, full: now },
+        }
     }
-}
 
-impl RateLimitError {
-    /// Returns the amount of time until the next request can be made as a `Duration`.
-    #[inline]
-    #[must_use]
-    pub const fn as_duration(self) -> Duration {
-        Duration::from_nanos(self.0.get())
+    /// Synchronous version of [`RateLimiter::reset_at`].
+    pub fn reset_at_sync<Q>(&self, key: &Q, quota: Quota, now: Instant) -> ResetAt
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.get_state_sync(key, now) {
+            Some(state) => state.reset_at(quota, now),
+            None => ResetAt { next: now, full: now },
+        }
     }
-}
 
-/// A rate limit quota, which defines the number of requests that can be made
-/// within a given time frame and with a given burst size.
-#[derive(Debug, Clone, Copy)]
-pub struct Quota {
-    /// Burst size/cells, in nanoseconds `(t * burst)`
-    tau: u64,
+    /// Sets the state of the given key relative to the given time, as returned by [`RateLimiter::get_state`],
+    /// inserting the key if needed and replacing any existing state.
+    ///
+    /// The theoretical arrival time becomes `now + state.delay`, so any time spent transferring the state
+    /// only makes the key stricter for that long. See [`RateLimiter::set_state`] for absolute times.
+    pub async fn put_state(&self, key: K, state: State, now: Instant) {
+        let tat = self.relative(now).saturating_add(state.delay);
 
-    /// Cell emission interval, in nanoseconds
-    t: u64,
-}
+        match self.limits.entry_async(key).await {
+            Entry::Occupied(gcra) => gcra.get().0.store(tat, Ordering::Release),
+            Entry::Vacant(gcra) => _ = gcra.insert_entry(Gcra(AtomicU64::new(tat))),
+        }
+    }
 
-impl Default for Quota {
-    /// Returns a default quota of 1000 requests per second.
-    fn default() -> Quota {
-        Quota::simple(Duration::from_millis(1))
+    /// Synchronous version of [`RateLimiter::put_state`].
+    pub fn put_state_sync(&self, key: K, state: State, now: Instant) {
+        let tat = self.relative(now).saturating_add(state.delay);
+
+        match self.limits.entry(key) {
+            Entry::Occupied(gcra) => gcra.get().0.store(tat, Ordering::Release),
+            Entry::Vacant(gcra) => _ = gcra.insert_entry(Gcra(AtomicU64::new(tat))),
+        }
     }
-}
 
-impl Quota {
-    /// Constructs a new quota with the given number of burst requests and
-    /// an `emission_interval` parameter, which is the amount of time it takes
-    /// for the limit to release.
-    ///
-    /// For example, 100reqs/second would have an emission_interval of 10ms
+    /// Takes a snapshot of every key that has not fully recovered at the given time,
+    /// which can be serialized (with the `serde` feature) and later loaded with [`RateLimiter::restore`].
     ///
-    /// Burst requests ignore the individual emission interval in favor of
-    /// delivering all at once or in quick succession, up until the provided limit.
-    #[rustfmt::skip] #[must_use]
-    pub const fn new(emission_interval: Duration, burst: NonZeroU64) -> Quota {
-        let t = emission_interval.as_nanos() as u64;
-        Quota { t, tau: t * burst.get() }
+    /// Each [`State`] is relative to `now`, so the snapshot can be restored by another process
+    /// or after a restart. Keys changed while the snapshot is being taken may or may not be included.
+    pub async fn snapshot(&self, now: Instant) -> Vec<(K, State)>
+    where
+        K: Clone,
+    {
+        let now = self.relative(now);
+        let mut snapshot = Vec::new();
+
+        self.limits
+            .scan_async(|key, gcra| {
+                if let Some(state) = State::new(gcra.0.load(Ordering::Acquire), now) {
+                    snapshot.push((key.clone(), state));
+                }
+            })
+            .await;
+
+        snapshot
+    }
+
+    /// Synchronous version of [`RateLimiter::snapshot`].
+    pub fn snapshot_sync(&self, now: Instant) -> Vec<(K, State)>
+    where
+        K: Clone,
+    {
+        let now = self.relative(now);
+        let mut snapshot = Vec::new();
+
+        self.limits.scan(|key, gcra| {
+            if let Some(state) = State::new(gcra.0.load(Ordering::Acquire), now) {
+                snapshot.push((key.clone(), state));
+            }
+        });
+
+        snapshot
+    }
+
+    /// Restores a snapshot taken by [`RateLimiter::snapshot`], relative to the given time,
+    /// replacing the state of any existing keys in the snapshot.
+    pub async fn restore(&self, snapshot: impl IntoIterator<Item = (K, State)>, now: Instant) {
+        let now = self.relative(now);
+
+        for (key, state) in snapshot {
+            let tat = now.saturating_add(state.delay);
+
+            match self.limits.entry_async(key).await {
+                Entry::Occupied(gcra) => gcra.get().0.store(tat, Ordering::Release),
+                Entry::Vacant(gcra) => _ = gcra.insert_entry(Gcra(AtomicU64::new(tat))),
+            }
+        }
+    }
+
+    /// Synchronous version of [`RateLimiter::restore`].
+    pub fn restore_sync(&self, snapshot: impl IntoIterator<Item = (K, State)>, now: Instant) {
+        let now = self.relative(now);
+
+        for (key, state) in snapshot {
+            let tat = now.saturating_add(state.delay);
+
+            match self.limits.entry(key) {
+                Entry::Occupied(gcra) => gcra.get().0.store(tat, Ordering::Release),
+                Entry::Vacant(gcra) => _ = gcra.insert_entry(Gcra(AtomicU64::new(tat))),
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher> Default for RateLimiter<K, H>
+where
+    H: Default,
+{
+    fn default() -> Self {
+        // default to 8192 unique requests before garbage collection
+        RateLimiter::new(8192, H::default())
+    }
+}
+
+/// A rate limiter for a single global limit, without any keys.
+///
+/// Where a [`RateLimiter`] hashes each key and looks it up in the table, this holds a single [`Gcra`]
+/// state, so each request is a single atomic compare-and-swap. There is nothing to garbage collect,
+/// and since requests never wait on the table, there are no asynchronous variants.
+///
+/// This is used by the [`GlobalRateLimitLayer`](crate::global::GlobalRateLimitLayer) for coarse limits
+/// on a whole service, but can also be used directly.
+#[derive(Debug)]
+pub struct GlobalRateLimiter {
+    start: Instant,
+    gcra: Gcra,
+}
+
+impl Default for GlobalRateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GlobalRateLimiter {
+    /// Constructs a new global rate limiter with full capacity.
+    #[must_use]
+    pub fn new() -> Self {
+        GlobalRateLimiter {
+            start: Instant::now(),
+            gcra: Gcra(AtomicU64::new(0)),
+        }
+    }
+
+    #[inline]
+    fn relative(&self, ts: Instant) -> u64 {
+        ts.saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    /// Perform a request, returning an error if the request is too soon,
+    /// or the remaining capacity on success.
+    #[inline]
+    pub fn req(&self, quota: Quota, now: Instant) -> Result<Capacity, RateLimitError> {
+        self.req_n(quota, 1, now)
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the request is too soon,
+    /// or the remaining capacity on success.
+    ///
+    /// See [`RateLimiter::req_n`] for more information.
+    pub fn req_n(&self, quota: Quota, cost: u64, now: Instant) -> Result<Capacity, RateLimitError> {
+        let now = self.relative(now);
+        let mut prev = self.gcra.0.load(Ordering::Acquire);
+
+        loop {
+            let next = Gcra::decide(Self::effective(prev, quota, now), now, quota, cost)?;
+
+            match self.gcra.0.compare_exchange_weak(prev, next, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return Ok(Capacity::new(next, now, quota)),
+                Err(next_prev) => prev = next_prev,
+            }
+        }
+    }
+
+    /// Checks whether a request would be allowed, without recording it.
+    ///
+    /// On success, returns the capacity that would remain after making the request.
+    pub fn check(&self, quota: Quota, now: Instant) -> Result<Capacity, RateLimitError> {
+        let now = self.relative(now);
+        let prev = Self::effective(self.gcra.0.load(Ordering::Acquire), quota, now);
+
+        Gcra::decide(prev, now, quota, 1).map(|tat| Capacity::new(tat, now, quota))
+    }
+
+    /// An unused or reset limiter behaves as a new key in a [`RateLimiter`], as by [`Gcra::first`].
+    #[inline]
+    fn effective(prev: u64, quota: Quota, now: u64) -> u64 {
+        match prev {
+            0 => now.saturating_add(quota.t),
+            prev => prev,
+        }
+    }
+
+    /// Penalizes the limiter by the given amount of time. See [`RateLimiter::penalize`] for more information.
+    pub fn penalize(&self, penalty: Duration) {
+        self.gcra.penalize(penalty);
+    }
+
+    /// Resets the limiter to full capacity.
+    pub fn reset(&self) {
+        self.gcra.0.store(0, Ordering::Release);
+    }
+}
+
+/// How much capacity a key starts with when first seen by a [`RateLimiter`],
+/// as set by [`RateLimiter::with_cold_start`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColdStart {
+    /// Start with the full burst, so a new key may immediately make up to `burst` requests.
+    #[default]
+    Full,
+
+    /// Start without any capacity, so a new key must wait one emission interval for its first request,
+    /// and earn the rest of its burst over time.
+    ///
+    /// This blunts distributed attacks from many fresh keys, such as rotating IP addresses,
+    /// at the cost of delaying legitimate first requests, so it suits keys that persist
+    /// across many requests. Rejected first requests are still recorded,
+    /// so retrying immediately does not help.
+    Empty,
+}
+
+/// Serializable state of a single key, as taken by [`RateLimiter::snapshot`] or [`RateLimiter::get_state`].
+///
+/// The state is stored relative to the time of the snapshot, since [`Instant`]s cannot be serialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct State {
+    /// Nanoseconds from the time of the snapshot until the theoretical arrival time of the key.
+    pub delay: u64,
+}
+
+impl State {
+    /// Returns the state of the theoretical arrival time `tat` at `now`, if it has not fully recovered.
+    #[inline]
+    fn new(tat: u64, now: u64) -> Option<State> {
+        tat.checked_sub(now).filter(|&delay| delay > 0).map(|delay| State { delay })
+    }
+
+    /// Returns the delay until the theoretical arrival time as a `Duration`.
+    #[inline]
+    #[must_use]
+    pub const fn as_duration(&self) -> Duration {
+        Duration::from_nanos(self.delay)
+    }
+
+    /// Returns when the key regains its next cell and its full burst under the given quota,
+    /// where `now` is the time the state is relative to.
+    ///
+    /// The full reset is the same as the `reset` of the [`Capacity`] after a request, and of the
+    /// `RateLimit-Reset` header. If the key is currently limited, the next cell is when a request
+    /// would be allowed again.
+    #[must_use]
+    pub fn reset_at(&self, quota: Quota, now: Instant) -> ResetAt {
+        let Quota { tau, t } = quota;
+
+        // as in `Capacity::new`, the burst is restored one emission interval before the arrival time
+        let full = self.delay.saturating_sub(t);
+
+        let next = if t == 0 || full == 0 {
+            0
+        } else if self.delay > tau {
+            // limited until the arrival time is within the burst again
+            self.delay - tau
+        } else {
+            match full % t {
+                0 => t,
+                partial => partial,
+            }
+        };
+
+        ResetAt {
+            next: now + Duration::from_nanos(next),
+            full: now + Duration::from_nanos(full),
+        }
+    }
+}
+
+/// When a key regains capacity, as returned by [`State::reset_at`] and [`RateLimiter::reset_at`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResetAt {
+    /// When the next cell becomes available, so one more request could be made than before,
+    /// or the first if the key is limited. This is the current time if the key has fully recovered.
+    pub next: Instant,
+
+    /// When the full burst capacity is restored.
+    pub full: Instant,
+}
+
+/// An error that occurs when a rate limit is exceeded,
+/// with the amount of time until the next request can be made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct RateLimitError(pub NonZeroU64);
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate limit exceeded, retry in {:.3} seconds",
+            self.as_duration().as_secs_f32()
+        )
+    }
+}
+
+impl Error for RateLimitError {}
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+/// Formats an integer header value, optimized for common values.
+pub(crate) fn int_header_value(value: u64) -> http::HeaderValue {
+    use http::HeaderValue;
+
+    match value {
+        0 => HeaderValue::from_static("0"),
+        1 => HeaderValue::from_static("1"),
+        2 => HeaderValue::from_static("2"),
+        _ => {
+            #[cfg(feature = "itoa")]
+            let value = {
+                let mut buffer = itoa::Buffer::new();
+                HeaderValue::from_str(buffer.format(value)).unwrap()
+            };
+
+            #[cfg(not(feature = "itoa"))]
+            let value = HeaderValue::from_str(&value.to_string()).unwrap();
+
+            value
+        }
+    }
+}
+
+impl IntoResponse for RateLimitError {
+    /// Returns a `429 Too Many Requests` response with the `Retry-After`, `RateLimit-Reset`
+    /// and `RateLimit-Remaining` headers set, where the reset is the number of seconds
+    /// until the next request can be made, rounded up.
+    ///
+    /// `X-RateLimit-Reset` is also set to the absolute Unix timestamp (in seconds) of the reset,
+    /// and `Cache-Control: no-store` so that intermediaries do not keep serving the rejection
+    /// after the limit has reset.
+    fn into_response(self) -> Response {
+        let mut res = self.response();
+        self.insert_headers(res.headers_mut());
+        res
+    }
+}
+
+/// Inserts `Cache-Control: no-store` unless the response already has a `Cache-Control` header.
+pub(crate) fn insert_no_store(headers: &mut http::HeaderMap) {
+    headers.entry(http::header::CACHE_CONTROL).or_insert(http::HeaderValue::from_static("no-store"));
+}
+
+impl RateLimitError {
+    /// Returns the amount of time until the next request can be made as a `Duration`.
+    #[inline]
+    #[must_use]
+    pub const fn as_duration(self) -> Duration {
+        Duration::from_nanos(self.0.get())
+    }
+
+    /// Builds the default response, without any headers but `Cache-Control`.
+    fn response(self) -> Response {
+        let mut res = Response::new(From::from(format!(
+            "rate limit exceeded, retry in {:.3} seconds",
+            self.as_duration().as_secs_f32()
+        )));
+
+        *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+
+        insert_no_store(res.headers_mut());
+
+        res
+    }
+
+    /// Inserts the `Retry-After`, `RateLimit-Reset`, `RateLimit-Remaining` and `X-RateLimit-Reset` headers,
+    /// as used by the default response.
+    ///
+    /// Useful for building a custom response,
+    /// see [`RateLimitLayerBuilder::on_rejected`](crate::RateLimitLayerBuilder::on_rejected).
+    pub fn insert_headers(&self, headers: &mut http::HeaderMap) {
+        self.insert_headers_with(headers, RetryAfter::Ceil);
+    }
+
+    fn insert_headers_with(&self, headers: &mut http::HeaderMap, rounding: RetryAfter) {
+        let secs = self.insert_retry_after(headers, rounding);
+        self.insert_legacy_headers(headers, secs);
+    }
+
+    /// Inserts the `RateLimit-Reset`, `RateLimit-Remaining` and `X-RateLimit-Reset` headers,
+    /// given the rounded wait in seconds.
+    fn insert_legacy_headers(&self, headers: &mut http::HeaderMap, secs: u64) {
+        use http::{HeaderName, HeaderValue};
+
+        if let Some(reset_at) = unix_reset_at(self.as_duration()) {
+            headers.insert(HeaderName::from_static("x-ratelimit-reset"), int_header_value(reset_at));
+        }
+
+        headers.insert(HeaderName::from_static("ratelimit-reset"), int_header_value(secs));
+        headers.insert(
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderValue::from_static("0"),
+        );
+    }
+
+    /// Inserts the `Retry-After` header, and `X-RateLimit-Reset-After` for [`RetryAfter::ExactMs`],
+    /// returning the rounded wait in seconds.
+    fn insert_retry_after(&self, headers: &mut http::HeaderMap, rounding: RetryAfter) -> u64 {
+        use http::HeaderName;
+
+        let secs = self.retry_after_secs(rounding);
+
+        if rounding == RetryAfter::ExactMs {
+            // round up, as retrying before the reset will fail
+            let ms = self.as_duration().as_nanos().div_ceil(1_000_000) as u64;
+            headers.insert(HeaderName::from_static("x-ratelimit-reset-after"), int_header_value(ms));
+        }
+
+        headers.insert(HeaderName::from_static("retry-after"), int_header_value(secs));
+
+        secs
+    }
+
+    /// Returns the wait in whole seconds for `Retry-After`, which is always at least 1.
+    fn retry_after_secs(&self, rounding: RetryAfter) -> u64 {
+        let reset = self.as_duration();
+
+        let secs = match rounding {
+            RetryAfter::Round => (reset + Duration::from_millis(500)).as_secs(),
+            RetryAfter::Ceil | RetryAfter::ExactMs => ceil_secs(reset),
+        };
+
+        secs.max(1)
+    }
+
+    /// Builds the [`RejectionBody::ProblemJson`] response, without any headers but `Content-Type`
+    /// and `Cache-Control`.
+    fn problem_response(self, rounding: RetryAfter) -> Response {
+        let body = format!(
+            concat!(
+                r#"{{"type":"about:blank","title":"Too Many Requests","status":429,"#,
+                r#""detail":"{}","retryAfter":{}}}"#,
+            ),
+            self,
+            self.retry_after_secs(rounding),
+        );
+
+        let mut res = Response::new(From::from(body));
+
+        *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+
+        res.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/problem+json"),
+        );
+
+        insert_no_store(res.headers_mut());
+
+        res
+    }
+}
+
+/// Which rate limit headers describe the limit in responses, as set by
+/// [`RateLimitLayerBuilder::with_header_style`](crate::RateLimitLayerBuilder::with_header_style).
+///
+/// `Retry-After` is inserted into denials regardless.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{num::NonZeroU64, time::{Duration, Instant}};
+/// use axum_gcra::gcra::{HeaderStyle, NotUntil, Quota, RateLimitError};
+///
+/// let wait = RateLimitError(NonZeroU64::new(Duration::from_millis(4500).as_nanos() as u64).unwrap());
+/// let denied = NotUntil::new(wait, Quota::per_minute(100).with_burst(100), Instant::now())
+///     .with_header_style(HeaderStyle::Structured);
+///
+/// let mut headers = http::HeaderMap::new();
+/// denied.insert_headers(&mut headers);
+///
+/// assert_eq!(headers["ratelimit"], "limit=100, remaining=0, reset=5");
+/// assert_eq!(headers["ratelimit-policy"], "100;w=60");
+/// assert_eq!(headers["retry-after"], "5");
+/// assert!(!headers.contains_key("x-ratelimit-reset"));
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeaderStyle {
+    /// The separate `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers of earlier drafts
+    /// of the IETF specification, along with the de facto `X-RateLimit-Limit`, `X-RateLimit-Remaining`
+    /// and `X-RateLimit-Reset` headers.
+    #[default]
+    Legacy,
+
+    /// The structured `RateLimit` and `RateLimit-Policy` headers of [draft-ietf-httpapi-ratelimit-headers],
+    /// such as `RateLimit: limit=100, remaining=73, reset=50` and `RateLimit-Policy: 100;w=60`.
+    ///
+    /// As with the legacy headers, the limit is the burst size of the quota, and the reset is the time
+    /// in seconds until the full burst capacity is restored, or until the next request is allowed for denials.
+    /// The policy window is the time for the full burst to be restored, rounded up to whole seconds.
+    /// The policy is omitted for [unlimited](Quota::unlimited) quotas.
+    ///
+    /// [draft-ietf-httpapi-ratelimit-headers]: https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/
+    Structured,
+
+    /// Both the [legacy](HeaderStyle::Legacy) and [structured](HeaderStyle::Structured) headers,
+    /// such as while clients migrate.
+    Both,
+}
+
+/// Body of denial responses, as set by
+/// [`RateLimitLayerBuilder::with_rejection_body`](crate::RateLimitLayerBuilder::with_rejection_body).
+///
+/// Both respond with `429 Too Many Requests` and the same headers.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{num::NonZeroU64, time::{Duration, Instant}};
+/// use axum::response::IntoResponse;
+/// use axum_gcra::gcra::{NotUntil, Quota, RateLimitError, RejectionBody};
+///
+/// # #[tokio::main(flavor = "current_thread")] async fn main() {
+/// let wait = RateLimitError(NonZeroU64::new(Duration::from_millis(1500).as_nanos() as u64).unwrap());
+/// let res = NotUntil::new(wait, Quota::per_second(1), Instant::now())
+///     .with_body(RejectionBody::ProblemJson)
+///     .into_response();
+///
+/// assert_eq!(res.status(), 429);
+/// assert_eq!(res.headers()["content-type"], "application/problem+json");
+/// assert_eq!(res.headers()["retry-after"], "2");
+///
+/// let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+/// assert_eq!(
+///     body,
+///     r#"{"type":"about:blank","title":"Too Many Requests","status":429,"#.to_owned()
+///         + r#""detail":"rate limit exceeded, retry in 1.500 seconds","retryAfter":2}"#
+/// );
+/// # }
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectionBody {
+    /// A plain text message with the wait, such as `rate limit exceeded, retry in 1.500 seconds`.
+    #[default]
+    Text,
+
+    /// An [RFC 9457] `application/problem+json` document with the `type`, `title`, `status` and `detail`
+    /// members, and a `retryAfter` extension member with the same wait in seconds as `Retry-After`.
+    ///
+    /// [RFC 9457]: https://www.rfc-editor.org/rfc/rfc9457
+    ProblemJson,
+}
+
+impl HeaderStyle {
+    #[inline]
+    const fn legacy(self) -> bool {
+        matches!(self, HeaderStyle::Legacy | HeaderStyle::Both)
+    }
+
+    #[inline]
+    const fn structured(self) -> bool {
+        matches!(self, HeaderStyle::Structured | HeaderStyle::Both)
+    }
+}
+
+/// Inserts the structured `RateLimit` and `RateLimit-Policy` headers, with the reset in whole seconds.
+fn insert_structured_headers(headers: &mut http::HeaderMap, quota: Quota, remaining: u64, reset: u64) {
+    use http::{HeaderName, HeaderValue};
+
+    let limit = quota.burst();
+
+    // integers only contain visible ASCII, so these never fail
+    if let Ok(value) = HeaderValue::try_from(format!("limit={limit}, remaining={remaining}, reset={reset}")) {
+        headers.insert(HeaderName::from_static("ratelimit"), value);
+    }
+
+    if quota.t != 0 {
+        let window = ceil_secs(Duration::from_nanos(quota.tau)).max(1);
+
+        if let Ok(value) = HeaderValue::try_from(format!("{limit};w={window}")) {
+            headers.insert(HeaderName::from_static("ratelimit-policy"), value);
+        }
+    }
+}
+
+/// How the wait is rounded to whole seconds for the `Retry-After` and `RateLimit-Reset` headers of a denial,
+/// as set by [`RateLimitLayerBuilder::with_retry_after`](crate::RateLimitLayerBuilder::with_retry_after).
+///
+/// Both headers are always at least 1 second, as `0` would invite an immediate retry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetryAfter {
+    /// Round up to whole seconds, so a retry made at the indicated time is never early.
+    ///
+    /// Clients may wait up to a second longer than necessary.
+    #[default]
+    Ceil,
+
+    /// Round to the nearest second, so clients wait at most half a second too long,
+    /// but a retry may be up to half a second early and be denied again.
+    Round,
+
+    /// Round up to whole seconds as with [`RetryAfter::Ceil`], but also insert the
+    /// `X-RateLimit-Reset-After` header with the exact wait in milliseconds, rounded up.
+    ExactMs,
+}
+
+/// A denied request, with a snapshot of the rate limit that denied it.
+///
+/// This is the rate limiting error passed to the [error handler](crate::RateLimitLayerBuilder::handle_error)
+/// of the layer, and responds the same as the underlying [`RateLimitError`],
+/// with its headers rounded as set by [`NotUntil::with_retry_after`].
+#[derive(Debug, Clone, Copy)]
+pub struct NotUntil {
+    error: RateLimitError,
+    quota: Quota,
+    at: Instant,
+    rounding: RetryAfter,
+    style: HeaderStyle,
+    body: RejectionBody,
+    client: Option<IpAddr>,
+}
+
+impl NotUntil {
+    /// Constructs a new denial from the error, the quota in effect and the time of the request.
+    #[must_use]
+    pub const fn new(error: RateLimitError, quota: Quota, at: Instant) -> NotUntil {
+        NotUntil {
+            error,
+            quota,
+            at,
+            rounding: RetryAfter::Ceil,
+            style: HeaderStyle::Legacy,
+            body: RejectionBody::Text,
+            client: None,
+        }
+    }
+
+    /// Set how the wait is rounded in the headers of the denial. The default is [`RetryAfter::Ceil`].
+    #[inline]
+    #[must_use]
+    pub const fn with_retry_after(mut self, rounding: RetryAfter) -> NotUntil {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Set which headers describe the limit in the denial. The default is [`HeaderStyle::Legacy`].
+    #[inline]
+    #[must_use]
+    pub const fn with_header_style(mut self, style: HeaderStyle) -> NotUntil {
+        self.style = style;
+        self
+    }
+
+    /// Set the body of the denial response. The default is [`RejectionBody::Text`].
+    #[inline]
+    #[must_use]
+    pub const fn with_body(mut self, body: RejectionBody) -> NotUntil {
+        self.body = body;
+        self
+    }
+
+    /// Set the client address to echo in the `x-ratelimit-client` header of the denial, such as for users
+    /// to report exactly which address was throttled. The default is `None`, omitting the header.
+    ///
+    /// See [`RateLimitLayerBuilder::with_client_ip_header`](crate::RateLimitLayerBuilder::with_client_ip_header).
+    #[inline]
+    #[must_use]
+    pub const fn with_client_ip(mut self, client: Option<IpAddr>) -> NotUntil {
+        self.client = client;
+        self
+    }
+
+    /// Returns the client address echoed in the headers of the denial, if set.
+    #[inline]
+    #[must_use]
+    pub const fn client_ip(&self) -> Option<IpAddr> {
+        self.client
+    }
+
+    /// Returns the underlying [`RateLimitError`].
+    #[inline]
+    #[must_use]
+    pub const fn error(&self) -> RateLimitError {
+        self.error
+    }
+
+    /// Returns the amount of time until the next request can be made as a `Duration`.
+    #[inline]
+    #[must_use]
+    pub const fn as_duration(&self) -> Duration {
+        self.error.as_duration()
+    }
+
+    /// Returns the quota that was in effect for the request.
+    #[inline]
+    #[must_use]
+    pub const fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Returns the time of the request that was denied.
+    #[inline]
+    #[must_use]
+    pub const fn denied_at(&self) -> Instant {
+        self.at
+    }
+
+    /// Returns the earliest time the request could be made again.
+    #[inline]
+    #[must_use]
+    pub fn earliest_possible(&self) -> Instant {
+        self.at + self.as_duration()
+    }
+
+    /// Returns how many cells over the limit the request was,
+    /// i.e. how many cells must be replenished before it would be allowed.
+    ///
+    /// This is always at least 1.
+    #[must_use]
+    pub fn exceeded_by(&self) -> u64 {
+        match self.quota.t {
+            0 => 1,
+            t => self.error.0.get().div_ceil(t),
+        }
+    }
+
+    /// See [`RateLimitError::insert_headers`], with the wait rounded as set by [`NotUntil::with_retry_after`],
+    /// the headers chosen by [`NotUntil::with_header_style`], and the `x-ratelimit-client` header
+    /// if [set](NotUntil::with_client_ip).
+    pub fn insert_headers(&self, headers: &mut http::HeaderMap) {
+        let secs = self.error.insert_retry_after(headers, self.rounding);
+
+        if self.style.legacy() {
+            self.error.insert_legacy_headers(headers, secs);
+        }
+
+        if self.style.structured() {
+            insert_structured_headers(headers, self.quota, 0, secs);
+        }
+
+        self.insert_client(headers);
+    }
+
+    fn insert_client(&self, headers: &mut http::HeaderMap) {
+        if let Some(client) = self.client {
+            // addresses only contain visible ASCII, so this never fails
+            if let Ok(value) = http::HeaderValue::try_from(client.to_string()) {
+                headers.insert(http::HeaderName::from_static("x-ratelimit-client"), value);
+            }
+        }
+    }
+}
+
+impl From<NotUntil> for RateLimitError {
+    #[inline]
+    fn from(denied: NotUntil) -> Self {
+        denied.error
+    }
+}
+
+impl fmt::Display for NotUntil {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl Error for NotUntil {}
+
+impl IntoResponse for NotUntil {
+    #[inline]
+    fn into_response(self) -> Response {
+        let mut res = match self.body {
+            RejectionBody::Text => self.error.response(),
+            RejectionBody::ProblemJson => self.error.problem_response(self.rounding),
+        };
+
+        self.insert_headers(res.headers_mut());
+        res
+    }
+}
+
+/// Error returned when a batch of requests does not fit within the remaining capacity,
+/// as by [`RateLimiter::check_batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchError {
+    /// The error for the batch as a whole, with the time until all of it would be allowed.
+    pub error: RateLimitError,
+
+    /// The number of requests in the batch.
+    pub requested: u64,
+
+    /// The number of requests that would have been allowed immediately.
+    pub available: u64,
+}
+
+impl BatchError {
+    fn new(error: RateLimitError, quota: Quota, n: u64) -> BatchError {
+        let requested = n.max(1);
+
+        // each cell over the limit delays the batch by one emission interval
+        let exceeded = match quota.t {
+            0 => 1,
+            t => error.0.get().div_ceil(t),
+        };
+
+        BatchError {
+            error,
+            requested,
+            available: requested.saturating_sub(exceeded),
+        }
+    }
+
+    /// Returns how many requests in the batch were over the limit.
+    #[inline]
+    #[must_use]
+    pub const fn shortfall(&self) -> u64 {
+        self.requested - self.available
+    }
+}
+
+impl From<BatchError> for RateLimitError {
+    #[inline]
+    fn from(error: BatchError) -> Self {
+        error.error
+    }
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "batch of {} exceeds rate limit by {}, ",
+            self.requested,
+            self.shortfall()
+        )?;
+        write!(f, "retry in {:.3} seconds", self.error.as_duration().as_secs_f32())
+    }
+}
+
+impl Error for BatchError {}
+
+impl IntoResponse for BatchError {
+    #[inline]
+    fn into_response(self) -> Response {
+        self.error.into_response()
+    }
+}
+
+/// Error returned when a request costs more cells than the burst size of its quota,
+/// so it could never be allowed, as by [`RateLimiter::until_n_ready`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsufficientCapacity {
+    /// The number of cells requested.
+    pub requested: u64,
+
+    /// The burst size of the quota.
+    pub burst: u64,
+}
+
+impl fmt::Display for InsufficientCapacity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request of {} cells exceeds burst size of {}",
+            self.requested, self.burst
+        )
+    }
+}
+
+impl Error for InsufficientCapacity {}
+
+/// Remaining capacity for a key after a successful request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    /// The burst size of the quota, i.e. the maximum number of requests that can be made at once.
+    pub limit: u64,
+
+    /// The number of requests that could be made immediately after this one.
+    pub remaining: u64,
+
+    /// The amount of time until the full burst capacity is restored.
+    pub reset: Duration,
+}
+
+impl Capacity {
+    /// Derives the capacity from the theoretical arrival time `tat` relative to `now`.
+    pub(crate) fn new(tat: u64, now: u64, quota: Quota) -> Capacity {
+        let Quota { tau, t } = quota;
+
+        // a request is allowed while `tat - now <= tau`, and each one advances `tat` by `t`
+        let delay = tat.saturating_sub(now);
+
+        Capacity {
+            limit: quota.burst(),
+            remaining: match tau.checked_sub(delay) {
+                Some(_) if t == 0 => u64::MAX,
+                Some(slack) => slack / t + 1,
+                None => 0,
+            },
+            reset: Duration::from_nanos(delay.saturating_sub(t)),
+        }
+    }
+
+    /// Inserts the `RateLimit-*` and `X-RateLimit-*` headers describing this capacity.
+    ///
+    /// As with [`RateLimitError`], `RateLimit-Reset` is relative and `X-RateLimit-Reset` is an absolute Unix timestamp.
+    pub(crate) fn insert_headers(&self, headers: &mut http::HeaderMap) {
+        self.insert_legacy_headers(headers);
+    }
+
+    /// Inserts the headers of the given style describing this capacity under the given quota.
+    pub(crate) fn insert_headers_with(&self, headers: &mut http::HeaderMap, quota: Quota, style: HeaderStyle) {
+        if style.legacy() {
+            self.insert_legacy_headers(headers);
+        }
+
+        if style.structured() {
+            insert_structured_headers(headers, quota, self.remaining, ceil_secs(self.reset));
+        }
+    }
+
+    fn insert_legacy_headers(&self, headers: &mut http::HeaderMap) {
+        use http::HeaderName;
+
+        let limit = int_header_value(self.limit);
+        let remaining = int_header_value(self.remaining);
+
+        headers.insert(HeaderName::from_static("ratelimit-limit"), limit.clone());
+        headers.insert(HeaderName::from_static("ratelimit-remaining"), remaining.clone());
+        headers.insert(
+            HeaderName::from_static("ratelimit-reset"),
+            int_header_value(ceil_secs(self.reset)),
+        );
+        headers.insert(HeaderName::from_static("x-ratelimit-limit"), limit);
+        headers.insert(HeaderName::from_static("x-ratelimit-remaining"), remaining);
+
+        if let Some(reset_at) = unix_reset_at(self.reset) {
+            headers.insert(HeaderName::from_static("x-ratelimit-reset"), int_header_value(reset_at));
+        }
+    }
+}
+
+/// Longest emission interval or burst window of a [`Quota`], in nanoseconds, roughly 146 years.
+///
+/// Theoretical arrival times saturate at `u64::MAX`, so a burst window must leave room below that
+/// for requests to ever be denied.
+const MAX_QUOTA_NANOS: u64 = u64::MAX / 4;
+
+/// Nanoseconds in the duration, capped at [`MAX_QUOTA_NANOS`].
+#[inline]
+const fn quota_nanos(d: Duration) -> u64 {
+    let nanos = d.as_nanos();
+
+    match nanos > MAX_QUOTA_NANOS as u128 {
+        true => MAX_QUOTA_NANOS,
+        false => nanos as u64,
+    }
+}
+
+/// Burst window `t * burst`, capped at [`MAX_QUOTA_NANOS`].
+#[inline]
+const fn burst_window(t: u64, burst: u64) -> u64 {
+    match t.checked_mul(burst) {
+        Some(tau) if tau <= MAX_QUOTA_NANOS => tau,
+        _ => MAX_QUOTA_NANOS,
+    }
+}
+
+/// Nanoseconds in the duration, saturated at `u64::MAX`.
+#[inline]
+const fn saturating_nanos(d: Duration) -> u64 {
+    let nanos = d.as_nanos();
+
+    match nanos > u64::MAX as u128 {
+        true => u64::MAX,
+        false => nanos as u64,
+    }
+}
+
+/// Whole seconds in the duration, rounded up.
+#[inline]
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + (d.subsec_nanos() > 0) as u64
+}
+
+/// Absolute Unix timestamp in seconds after the given delay, rounded up.
+fn unix_reset_at(reset: Duration) -> Option<u64> {
+    use std::time::SystemTime;
+
+    let reset_at = SystemTime::now() + reset;
+    reset_at.duration_since(SystemTime::UNIX_EPOCH).ok().map(ceil_secs)
+}
+
+/// A rate limit quota, which defines the number of requests that can be made
+/// within a given time frame and with a given burst size.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    /// Burst size/cells, in nanoseconds `(t * burst)`
+    tau: u64,
+
+    /// Cell emission interval, in nanoseconds
+    t: u64,
+}
+
+impl Default for Quota {
+    /// Returns a default quota of 1000 requests per second.
+    fn default() -> Quota {
+        Quota::simple(Duration::from_millis(1))
+    }
+}
+
+impl Quota {
+    /// Constructs a new quota with the given number of burst requests and
+    /// an `emission_interval` parameter, which is the amount of time it takes
+    /// for the limit to release.
+    ///
+    /// For example, 100reqs/second would have an emission_interval of 10ms
+    ///
+    /// Burst requests ignore the individual emission interval in favor of
+    /// delivering all at once or in quick succession, up until the provided limit.
+    ///
+    /// The emission interval and `emission_interval * burst` are capped at roughly 146 years,
+    /// so extreme values limit requests as strictly as possible rather than wrapping around
+    /// or allowing everything. Use [`Quota::try_new`] to reject such values instead.
+    ///
+    /// ```rust
+    /// use std::{num::NonZeroU64, time::{Duration, Instant}};
+    /// use axum_gcra::gcra::{Quota, RateLimiter};
+    ///
+    /// let quota = Quota::new(Duration::MAX, NonZeroU64::MAX);
+    /// assert_eq!(quota.emission_interval(), Duration::from_nanos(u64::MAX / 4));
+    /// assert_eq!(quota.burst(), 1);
+    ///
+    /// let limiter = RateLimiter::<()>::default();
+    /// let now = Instant::now();
+    ///
+    /// assert!(limiter.req_sync((), quota, now).is_ok());
+    /// assert!(limiter.req_sync((), quota, now + Duration::from_secs(3600)).is_err());
+    /// ```
+    #[must_use]
+    pub const fn new(emission_interval: Duration, burst: NonZeroU64) -> Quota {
+        let t = quota_nanos(emission_interval);
+        Quota {
+            t,
+            tau: burst_window(t, burst.get()),
+        }
     }
 
     /// Constructs a new quota with the given emission interval, but with a burst size of 1.
     ///
-    /// See [`Quota::new`] for more information.
+    /// See [`Quota::new`] for more information.
+    #[must_use]
+    pub const fn simple(emission_interval: Duration) -> Quota {
+        Self::new(emission_interval, NonZeroU64::MIN)
+    }
+
+    /// Constructs a quota that never limits requests, such as to exempt some requests from a resolver.
+    #[must_use]
+    pub const fn unlimited() -> Quota {
+        Quota { t: 0, tau: 0 }
+    }
+
+    /// Constructs a quota allowing `count` requests evenly spread across `period`, with a burst size of 1.
+    ///
+    /// The emission interval is `period / count`, so `Quota::per(Duration::from_secs(60), 30)`
+    /// allows one request every 2 seconds. Use [`Quota::with_burst`] to allow requests in quick succession.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero, or greater than the number of nanoseconds in the period.
+    #[must_use]
+    pub const fn per(period: Duration, count: u64) -> Quota {
+        assert!(count > 0, "quota request count must be non-zero");
+
+        // an emission interval of zero would never limit requests, as with `Quota::unlimited`
+        let t = quota_nanos(period) / count;
+        assert!(t > 0, "quota emission interval must be at least one nanosecond");
+
+        Self::simple(Duration::from_nanos(t))
+    }
+
+    /// Constructs a quota allowing `count` requests evenly spread across `period` with the given burst size,
+    /// as by `Quota::per(period, count).with_burst(burst)`, but returning an error for invalid parameters
+    /// instead of panicking or silently misbehaving.
+    ///
+    /// This is intended for quotas from configuration files or other runtime input,
+    /// while the infallible constructors remain more convenient for constants.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use axum_gcra::gcra::{Quota, QuotaError};
+    ///
+    /// let quota = Quota::try_new(Duration::from_secs(60), 30, 5).unwrap();
+    /// assert_eq!(quota.emission_interval(), Duration::from_secs(2));
+    /// assert_eq!(quota.burst(), 5);
+    ///
+    /// assert_eq!(Quota::try_new(Duration::from_secs(1), 0, 1).unwrap_err(), QuotaError::ZeroCount);
+    /// assert_eq!(Quota::try_new(Duration::from_nanos(10), 100, 1).unwrap_err(), QuotaError::IntervalTooShort);
+    /// assert_eq!(Quota::try_new(Duration::from_secs(1), 1, 0).unwrap_err(), QuotaError::ZeroBurst);
+    ///
+    /// // longer than `u64::MAX` nanoseconds, or an interval of more than 146 years
+    /// assert_eq!(Quota::try_new(Duration::MAX, 1_000_000, 1).unwrap_err(), QuotaError::PeriodTooLong);
+    /// assert_eq!(Quota::try_new(Duration::from_nanos(u64::MAX), 1, 1).unwrap_err(), QuotaError::PeriodTooLong);
+    ///
+    /// // 10 hours per request, for a burst window of over 146 years
+    /// let hourly = Duration::from_secs(60 * 60);
+    /// assert_eq!(Quota::try_new(hourly * 10, 1, 200_000).unwrap_err(), QuotaError::BurstTooLarge);
+    /// assert_eq!(Quota::try_new(hourly, 1, u64::MAX).unwrap_err(), QuotaError::BurstTooLarge);
+    /// assert!(Quota::try_new(hourly * 10, 1, 100_000).is_ok());
+    /// ```
+    pub const fn try_new(period: Duration, count: u64, burst: u64) -> Result<Quota, QuotaError> {
+        if count == 0 {
+            return Err(QuotaError::ZeroCount);
+        }
+
+        if burst == 0 {
+            return Err(QuotaError::ZeroBurst);
+        }
+
+        let period = period.as_nanos();
+
+        if period > u64::MAX as u128 {
+            return Err(QuotaError::PeriodTooLong);
+        }
+
+        // the emission interval is stored in whole nanoseconds
+        let t = period as u64 / count;
+
+        if t == 0 {
+            return Err(QuotaError::IntervalTooShort);
+        }
+
+        if t > MAX_QUOTA_NANOS {
+            return Err(QuotaError::PeriodTooLong);
+        }
+
+        match t.checked_mul(burst) {
+            Some(tau) if tau <= MAX_QUOTA_NANOS => Ok(Quota { t, tau }),
+            _ => Err(QuotaError::BurstTooLarge),
+        }
+    }
+
+    /// Implementation of the [`quota!`](crate::quota) macro, panicking at compile time for invalid quotas.
+    ///
+    /// ```rust,compile_fail
+    /// const QUOTA: axum_gcra::gcra::Quota = axum_gcra::gcra::Quota::__checked(std::time::Duration::ZERO, 1, 1);
+    /// ```
+    #[doc(hidden)]
+    pub const fn __checked(period: Duration, count: u64, burst: u64) -> Quota {
+        match Quota::try_new(period, count, burst) {
+            Ok(quota) => quota,
+            Err(e) => panic!("{}", e.as_str()),
+        }
+    }
+
+    /// Constructs a quota allowing `count` requests per second. See [`Quota::per`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero, or greater than the number of nanoseconds in the period.
+    #[must_use]
+    pub const fn per_second(count: u64) -> Quota {
+        Self::per(Duration::from_secs(1), count)
+    }
+
+    /// Constructs a quota allowing `count` requests per minute. See [`Quota::per`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero, or greater than the number of nanoseconds in the period.
     #[must_use]
-    pub const fn simple(emission_interval: Duration) -> Quota {
-        Self::new(emission_interval, NonZeroU64::MIN)
+    pub const fn per_minute(count: u64) -> Quota {
+        Self::per(Duration::from_secs(60), count)
+    }
+
+    /// Constructs a quota allowing `count` requests per hour. See [`Quota::per`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero, or greater than the number of nanoseconds in the period.
+    #[must_use]
+    pub const fn per_hour(count: u64) -> Quota {
+        Self::per(Duration::from_secs(60 * 60), count)
+    }
+
+    /// Sets the burst size, which is the number of requests that can be made in quick succession
+    /// before being limited to the sustained rate, without changing the emission interval.
+    ///
+    /// Internally, the GCRA delay variation tolerance is `emission_interval * burst`,
+    /// so `Quota::per_second(10).with_burst(20)` allows 20 requests at once,
+    /// then one request every 100ms thereafter until the burst capacity is regained.
+    ///
+    /// As with [`Quota::new`], `emission_interval * burst` is capped at roughly 146 years,
+    /// so the burst size may be smaller than requested for extremely long emission intervals.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is zero.
+    #[must_use]
+    pub const fn with_burst(self, burst: u64) -> Quota {
+        assert!(burst > 0, "quota burst size must be non-zero");
+
+        Quota {
+            t: self.t,
+            tau: burst_window(self.t, burst),
+        }
+    }
+
+    /// Returns the emission interval, which is the amount of time between sustained requests.
+    #[inline]
+    #[must_use]
+    pub const fn emission_interval(&self) -> Duration {
+        Duration::from_nanos(self.t)
+    }
+
+    /// Returns the burst size, which is the number of requests that can be made in quick succession.
+    #[inline]
+    #[must_use]
+    pub const fn burst(&self) -> u64 {
+        match self.t {
+            0 => 1,
+            t => self.tau / t,
+        }
+    }
+}
+
+/// Error returned by [`Quota::try_new`] for invalid quota parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaError {
+    /// The request count was zero, so no requests would ever be allowed.
+    ZeroCount,
+
+    /// The burst size was zero, so no requests would ever be allowed.
+    ZeroBurst,
+
+    /// The period was longer than the roughly 584 years representable in nanoseconds,
+    /// or the emission interval, `period / count`, was longer than roughly 146 years.
+    PeriodTooLong,
+
+    /// The emission interval, `period / count`, was shorter than one nanosecond.
+    IntervalTooShort,
+
+    /// The burst size times the emission interval was longer than roughly 146 years,
+    /// leaving no room to track requests against it.
+    BurstTooLarge,
+}
+
+impl QuotaError {
+    const fn as_str(&self) -> &'static str {
+        match self {
+            QuotaError::ZeroCount => "quota request count must be non-zero",
+            QuotaError::ZeroBurst => "quota burst size must be non-zero",
+            QuotaError::PeriodTooLong => "quota period is too long",
+            QuotaError::IntervalTooShort => "quota emission interval is shorter than one nanosecond",
+            QuotaError::BurstTooLarge => "quota burst size is too large for the emission interval",
+        }
+    }
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error for QuotaError {}
+
+/// A set of additional [`Quota`]s enforced together with the quota of each request, such as a sustained
+/// hourly limit alongside a short burst limit, as used by the [`MultiQuotaStore`](crate::store::MultiQuotaStore).
+///
+/// A request is only allowed if it fits within every quota, and is then counted against all of them.
+#[derive(Debug, Clone, Default)]
+pub struct MultiQuota {
+    quotas: Vec<Quota>,
+}
+
+impl MultiQuota {
+    /// Constructs a new set without any additional quotas.
+    #[must_use]
+    pub const fn new() -> Self {
+        MultiQuota { quotas: Vec::new() }
+    }
+
+    /// Add a quota to the set.
+    pub fn add_quota(&mut self, quota: Quota) {
+        self.quotas.push(quota);
+    }
+
+    /// Add a quota to the set. See [`MultiQuota::add_quota`].
+    #[must_use]
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.add_quota(quota);
+        self
+    }
+
+    /// Returns the quotas in the set, in the order they were added.
+    #[inline]
+    pub fn quotas(&self) -> &[Quota] {
+        &self.quotas
+    }
+
+    /// Returns the request quota followed by the quotas in the set.
+    #[inline]
+    pub(crate) fn with_primary(&self, primary: Quota) -> impl Iterator<Item = Quota> + '_ {
+        std::iter::once(primary).chain(self.quotas.iter().copied())
+    }
+
+    /// Performs a request costing `cost` cells against the theoretical arrival times of every quota,
+    /// as by [`MultiQuota::with_primary`], updating them only if the request is allowed by all of them.
+    ///
+    /// Returns the longest wait among the denying quotas on failure, or the capacity of the quota
+    /// with the least remaining on success.
+    pub(crate) fn decide(
+        &self,
+        primary: Quota,
+        tats: &mut [u64],
+        cost: u64,
+        now: u64,
+    ) -> Result<Capacity, RateLimitError> {
+        let mut error: Option<RateLimitError> = None;
+
+        for (quota, prev) in self.with_primary(primary).zip(tats.iter()) {
+            if let Err(e) = Gcra::decide(*prev, now, quota, cost) {
+                error = Some(error.map_or(e, |prev| prev.max(e)));
+            }
+        }
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        let mut capacity: Option<Capacity> = None;
+
+        for (quota, tat) in self.with_primary(primary).zip(tats.iter_mut()) {
+            let decision = Gcra::decide(*tat, now, quota, cost);
+            *tat = decision.expect("every quota was just checked with the same arguments");
+
+            let c = Capacity::new(*tat, now, quota);
+
+            #[allow(clippy::unnecessary_map_or)] // `is_none_or` would require Rust 1.82
+            if capacity.map_or(true, |prev| c.remaining < prev.remaining) {
+                capacity = Some(c);
+            }
+        }
+
+        Ok(capacity.expect("there is always at least the primary quota"))
+    }
+}
+
+impl FromIterator<Quota> for MultiQuota {
+    fn from_iter<T: IntoIterator<Item = Quota>>(iter: T) -> Self {
+        MultiQuota {
+            quotas: iter.into_iter().collect(),
+        }
     }
 }
 
+/// Constructs a [`Quota`](crate::gcra::Quota) checked at compile time, such as `quota!(10 / minute)`
+/// or `quota!(10 / minute, burst = 20)`.
+///
+/// The count is followed by a unit of `second`, `minute` or `hour`. The burst size defaults to one,
+/// as with [`Quota::per`](crate::gcra::Quota::per). The count and burst may be any constant expressions,
+/// with the count in parentheses if it is more than a single literal or identifier.
+///
+/// Invalid quotas, as rejected by [`Quota::try_new`](crate::gcra::Quota::try_new), fail to compile,
+/// and the result can be used in `const` items.
+///
+/// # Example
+///
+/// ```rust
+/// use axum_gcra::{gcra::Quota, quota};
+///
+/// const LOGIN: Quota = quota!(5 / minute, burst = 10);
+///
+/// assert_eq!(LOGIN.burst(), 10);
+/// assert_eq!(quota!(10 / second).emission_interval(), Quota::per_second(10).emission_interval());
+/// ```
+///
+/// A zero count is rejected:
+///
+/// ```rust,compile_fail
+/// let quota = axum_gcra::quota!(0 / second);
+/// ```
+///
+/// As are a zero burst, an emission interval shorter than one nanosecond, and an oversized burst:
+///
+/// ```rust,compile_fail
+/// let quota = axum_gcra::quota!(10 / second, burst = 0);
+/// ```
+///
+/// ```rust,compile_fail
+/// let quota = axum_gcra::quota!(2_000_000_000 / second);
+/// ```
+///
+/// ```rust,compile_fail
+/// let quota = axum_gcra::quota!(1 / hour, burst = u64::MAX);
+/// ```
+#[macro_export]
+macro_rules! quota {
+    ($count:tt / $unit:ident $(, burst = $burst:expr)? $(,)?) => {
+        const {
+            #[allow(unused_parens)]
+            let count: u64 = $count;
+            let burst: u64 = $crate::quota!(@burst $($burst)?);
+
+            $crate::gcra::Quota::__checked($crate::quota!(@period $unit), count, burst)
+        }
+    };
+    (@period second) => { ::core::time::Duration::from_secs(1) };
+    (@period minute) => { ::core::time::Duration::from_secs(60) };
+    (@period hour) => { ::core::time::Duration::from_secs(60 * 60) };
+    (@burst) => { 1 };
+    (@burst $burst:expr) => { $burst };
+}
+
 /// Generic Cell Rate Algorithm (GCRA) implementation.
 ///
 /// Uses a single atomic value to store the next time a request can be made.
@@ -349,33 +2050,541 @@ impl Gcra {
     #[must_use]
     pub const fn first(Quota { t, .. }: Quota, now: u64) -> Gcra {
         // Equivalent to `Gcra(now + t).req()` to calculate the first request
-        Gcra(AtomicU64::new(now + t + t))
+        Gcra(AtomicU64::new(now.saturating_add(t).saturating_add(t)))
+    }
+
+    /// Constructs a new GCRA without any capacity at the given time, so the first request
+    /// is only allowed one emission interval later, as for [`ColdStart::Empty`].
+    #[inline]
+    #[must_use]
+    pub const fn empty(Quota { tau, t }: Quota, now: u64) -> Gcra {
+        // the next request is allowed once `now >= tat - tau`
+        Gcra(AtomicU64::new(now.saturating_add(t).saturating_add(tau)))
+    }
+
+    /// Constructs a new GCRA for the first request at the given time, costing `cost` cells,
+    /// returning an error if the cost exceeds the burst size.
+    #[inline]
+    fn first_n(quota: Quota, cost: u64, now: u64) -> Result<Gcra, RateLimitError> {
+        if cost <= 1 {
+            return Ok(Gcra::first(quota, now));
+        }
+
+        Self::decide(now.saturating_add(quota.t), now, quota, cost).map(|tat| Gcra(AtomicU64::new(tat)))
+    }
+
+    /// Delays the theoretical arrival time by the penalty, saturating rather than wrapping around.
+    fn penalize(&self, penalty: Duration) {
+        let penalty = saturating_nanos(penalty);
+
+        // the closure always returns `Some`, so this never fails
+        _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tat| {
+            Some(tat.saturating_add(penalty))
+        });
     }
 
     /// Core GCRA logic. Returns the next time a request can be made, either as an error or a success.
-    fn decide(prev: u64, now: u64, Quota { tau, t }: Quota) -> Result<u64, RateLimitError> {
-        // burst's act as an offset to allow more through at the start
-        let next = prev.saturating_sub(tau);
+    pub(crate) fn decide(prev: u64, now: u64, Quota { tau, t }: Quota, cost: u64) -> Result<u64, RateLimitError> {
+        let cost = cost.max(1);
+
+        // burst's act as an offset to allow more through at the start,
+        // while each additional cell must also fit within it
+        let next = prev.saturating_add(t.saturating_mul(cost - 1)).saturating_sub(tau);
 
+        // a `now` earlier than a previous request, such as from a clock stepping backwards,
+        // only makes this stricter, and the new TAT never moves back past `prev`
         if now < next {
             // SAFETY: next > now, so next - now is non-zero by definition
             Err(RateLimitError(unsafe { NonZeroU64::new_unchecked(next - now) }))
         } else {
-            Ok(now.max(prev) + t)
+            Ok(now.max(prev).saturating_add(t.saturating_mul(cost)))
         }
     }
 
     /// Perform a request, returning an error if the request is too soon.
     pub fn req(&self, quota: Quota, now: u64) -> Result<(), RateLimitError> {
+        self.req_n(quota, 1, now)
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the request is too soon.
+    pub fn req_n(&self, quota: Quota, cost: u64, now: u64) -> Result<(), RateLimitError> {
+        self.req_tat(quota, cost, now).map(|_| ())
+    }
+
+    /// Check if a request would be allowed without performing it,
+    /// returning the theoretical arrival time it would result in on success.
+    ///
+    /// This reads the state once, so it is consistent with any concurrent requests.
+    pub fn check(&self, quota: Quota, now: u64) -> Result<u64, RateLimitError> {
+        Self::decide(self.0.load(Ordering::Acquire), now, quota, 1)
+    }
+
+    /// Perform a request, returning the new theoretical arrival time on success.
+    fn req_tat(&self, quota: Quota, cost: u64, now: u64) -> Result<u64, RateLimitError> {
         let mut prev = self.0.load(Ordering::Acquire);
 
         loop {
-            let next = Self::decide(prev, now, quota)?;
+            let next = Self::decide(prev, now, quota, cost)?;
 
             match self.0.compare_exchange_weak(prev, next, Ordering::Release, Ordering::Relaxed) {
-                Ok(_) => return Ok(()),
+                Ok(_) => return Ok(next),
                 Err(next_prev) => prev = next_prev,
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_shortest_interval() {
+        assert_eq!(
+            Quota::per_second(1_000_000_000).emission_interval(),
+            Duration::from_nanos(1)
+        );
+        assert_eq!(
+            Quota::per(Duration::from_nanos(10), 10).emission_interval(),
+            Duration::from_nanos(1)
+        );
+    }
+
+    #[test]
+    #[should_panic = "quota emission interval must be at least one nanosecond"]
+    fn per_interval_too_short() {
+        _ = Quota::per_second(2_000_000_000);
+    }
+
+    #[test]
+    #[should_panic = "quota emission interval must be at least one nanosecond"]
+    fn per_zero_period() {
+        _ = Quota::per(Duration::ZERO, 1);
+    }
+
+    #[test]
+    fn multi_quota_decide() {
+        let primary = Quota::per_second(10).with_burst(5);
+        let hourly = MultiQuota::new().with_quota(Quota::per_hour(3).with_burst(3));
+
+        let now = 1_000_000_000_000;
+        let mut tats = [0; 2];
+
+        // the capacity is that of the quota with the least remaining, from a fully recovered state
+        for remaining in [3, 2, 1, 0] {
+            let capacity = hourly.decide(primary, &mut tats, 1, now).unwrap();
+            assert_eq!((capacity.limit, capacity.remaining), (3, remaining));
+        }
+
+        // denied by the hourly quota alone, without counting against the primary quota
+        let before = tats;
+        let e = hourly.decide(primary, &mut tats, 1, now).unwrap_err();
+        assert_eq!(tats, before);
+        assert!(e.as_duration() > Duration::from_secs(60 * 19));
+
+        // the longest wait of all the denying quotas
+        let e = hourly.decide(primary, &mut tats, 10, now).unwrap_err();
+        assert!(e.as_duration() > Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn quota_macro() {
+        const QUOTA: Quota = crate::quota!(5 / minute, burst = 10);
+        const COUNT: u64 = 3;
+
+        let eq = |a: Quota, b: Quota| a.t == b.t && a.tau == b.tau;
+
+        assert!(eq(QUOTA, Quota::try_new(Duration::from_secs(60), 5, 10).unwrap()));
+        assert!(eq(crate::quota!(COUNT / hour), Quota::per_hour(3)));
+        assert!(eq(
+            crate::quota!((COUNT * 2) / second, burst = COUNT + 1),
+            Quota::per_second(6).with_burst(4)
+        ));
+    }
+
+    #[test]
+    #[should_panic = "quota burst size must be non-zero"]
+    fn checked_zero_burst() {
+        // the same check as in `quota!`, but evaluated at runtime so the panic can be observed
+        _ = Quota::__checked(Duration::from_secs(1), 1, 0);
+    }
+
+    fn new_limiter(gc_interval: u64) -> RateLimiter<u32> {
+        RateLimiter::new(gc_interval, Default::default())
+    }
+
+    #[test]
+    fn gc_removes_recovered_keys() {
+        let limiter = new_limiter(1000);
+        let quota = Quota::per_second(10);
+        let now = Instant::now();
+
+        for key in 0..999 {
+            assert!(limiter.req_sync(key, quota, now).is_ok());
+        }
+
+        assert_eq!(limiter.len(), 999);
+
+        // every key has recovered by then, so the 1000th request collects all of them before inserting
+        assert!(limiter.req_sync(u32::MAX, quota, now + Duration::from_secs(1)).is_ok());
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn max_entries_evicts_oldest() {
+        let limiter = new_limiter(u64::MAX).with_max_entries(100);
+        let quota = Quota::per_hour(1);
+        let now = Instant::now();
+
+        for key in 0..100 {
+            assert!(limiter.req_sync(key, quota, now + Duration::from_millis(key as u64)).is_ok());
+        }
+
+        assert_eq!(limiter.len(), 100);
+        assert!(limiter.req_sync(100, quota, now + Duration::from_millis(100)).is_ok());
+
+        // down to 7/8ths, evicting the 12 oldest, then inserting the new key
+        assert_eq!(limiter.len(), 89);
+
+        for key in 0..=100 {
+            assert_eq!(limiter.state_sync(&key).is_some(), key >= 12, "{key}");
+        }
+    }
+
+    #[test]
+    fn max_entries_evicts_only_needed_ties() {
+        let limiter = new_limiter(u64::MAX).with_max_entries(100);
+        let quota = Quota::per_hour(1);
+        let now = Instant::now();
+
+        // every key has exactly the same theoretical arrival time
+        for key in 0..100 {
+            assert!(limiter.req_sync(key, quota, now).is_ok());
+        }
+
+        assert!(limiter.req_sync(100, quota, now).is_ok());
+        assert_eq!(limiter.len(), 89);
+
+        // with a few older keys, those go first and ties make up the rest
+        let limiter = new_limiter(u64::MAX).with_max_entries(100);
+
+        for key in 0..100 {
+            let at = if key < 5 { now } else { now + Duration::from_secs(1) };
+            assert!(limiter.req_sync(key, quota, at).is_ok());
+        }
+
+        assert!(limiter.req_sync(100, quota, now + Duration::from_secs(1)).is_ok());
+        assert_eq!(limiter.len(), 89);
+        assert!((0..5).all(|key| limiter.state_sync(&key).is_none()));
+    }
+
+    #[tokio::test]
+    async fn max_entries_evicts_only_needed_ties_async() {
+        let limiter = new_limiter(u64::MAX).with_max_entries(64);
+        let quota = Quota::per_hour(1);
+        let now = Instant::now();
+
+        for key in 0..64 {
+            assert!(limiter.req(key, quota, now).await.is_ok());
+        }
+
+        assert!(limiter.req(64, quota, now).await.is_ok());
+        assert_eq!(limiter.len(), 57);
+    }
+
+    #[test]
+    fn check_does_not_consume() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_hour(1).with_burst(3);
+        let now = Instant::now();
+
+        // unseen keys are not inserted
+        assert_eq!(limiter.check_sync(&1, quota, now).unwrap().remaining, 2);
+        assert!(limiter.is_empty());
+
+        assert!(limiter.req_sync(1, quota, now).is_ok());
+
+        for _ in 0..100 {
+            assert_eq!(limiter.check_sync(&1, quota, now).unwrap().remaining, 1);
+        }
+
+        assert!(limiter.req_sync(1, quota, now).is_ok());
+        assert!(limiter.req_sync(1, quota, now).is_ok());
+        assert!(limiter.check_sync(&1, quota, now).is_err());
+        assert!(limiter.req_sync(1, quota, now).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn check_does_not_consume_concurrently() {
+        let quota = Quota::per_hour(1).with_burst(50);
+        let now = Instant::now();
+
+        // as many requests as a single task is allowed
+        let expected = {
+            let limiter = new_limiter(u64::MAX);
+            (0..100).filter(|_| limiter.req_sync(1, quota, now).is_ok()).count()
+        };
+
+        let limiter = std::sync::Arc::new(new_limiter(u64::MAX));
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for task in 0..8 {
+            let limiter = limiter.clone();
+
+            tasks.spawn(async move {
+                let mut allowed = 0;
+
+                for _ in 0..100 {
+                    if task % 2 == 0 {
+                        _ = limiter.check(&1, quota, now).await;
+                    } else if limiter.req(1, quota, now).await.is_ok() {
+                        allowed += 1;
+                    }
+
+                    tokio::task::yield_now().await;
+                }
+
+                allowed
+            });
+        }
+
+        let allowed: usize = tasks.join_all().await.into_iter().sum();
+        assert_eq!(allowed, expected);
+    }
+
+    #[test]
+    fn backwards_clock_grants_nothing_extra() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_second(1).with_burst(2);
+        let now = Instant::now() + Duration::from_secs(10);
+
+        while limiter.req_sync(1, quota, now).is_ok() {}
+        let wait = limiter.req_sync(1, quota, now).unwrap_err().as_duration();
+
+        // an earlier time only lengthens the wait, even before the limiter was created
+        for back in [1, 1_000, 1_000_000_000, 20_000_000_000] {
+            let e = limiter.req_sync(1, quota, now - Duration::from_nanos(back)).unwrap_err();
+            assert!(e.as_duration() >= wait);
+        }
+
+        assert!(limiter.req_sync(1, quota, now + wait).is_ok());
+        assert!(limiter.req_sync(1, quota, now + wait).is_err());
+
+        // stepping back after a request only allows fewer, and never moves its TAT back
+        let quota = Quota::per_second(1).with_burst(5);
+        let second: u64 = 1_000_000_000;
+
+        let allowed = |back: u64| {
+            let gcra = Gcra::first(quota, 5 * second);
+            let mut tat = gcra.0.load(Ordering::Relaxed);
+            let mut n = 0;
+
+            while gcra.req(quota, (5 - back) * second).is_ok() {
+                assert_eq!(gcra.0.load(Ordering::Relaxed), tat + quota.t);
+                tat += quota.t;
+                n += 1;
+            }
+
+            n
+        };
+
+        assert_eq!([0, 2, 5].map(allowed), [4, 2, 0]);
+    }
+
+    #[test]
+    fn seeded_state_throttles() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_second(1).with_burst(5);
+        let now = Instant::now();
+
+        // no capacity left, so even the first request from this limiter is denied
+        limiter.set_state_sync(1, now + Duration::from_secs(6));
+        let e = limiter.req_sync(1, quota, now).unwrap_err();
+        assert_eq!(e.as_duration(), Duration::from_secs(1));
+
+        // a single request left
+        limiter.set_state_sync(2, now + Duration::from_secs(5));
+        assert!(limiter.req_sync(2, quota, now).is_ok());
+        assert!(limiter.req_sync(2, quota, now).is_err());
+
+        // and the state round trips, as when moving it between limiters
+        let other = new_limiter(u64::MAX);
+        other.set_state_sync(2, limiter.state_sync(&2).unwrap());
+        assert!(other.req_sync(2, quota, now).is_err());
+        assert_eq!(other.state_sync(&2), limiter.state_sync(&2));
+        assert_eq!(other.state_sync(&3), None);
+    }
+
+    #[tokio::test]
+    async fn seeded_state_throttles_async() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_second(1).with_burst(5);
+        let now = Instant::now();
+
+        limiter.set_state(1, now + Duration::from_secs(6)).await;
+        assert!(limiter.req(1, quota, now).await.is_err());
+        assert_eq!(limiter.state(&1).await, Some(now + Duration::from_secs(6)));
+    }
+
+    #[tokio::test]
+    async fn batch_is_all_or_nothing() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_second(1).with_burst(5);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_batch(1, quota, 3, now).await.unwrap().remaining, 2);
+        let state = limiter.state(&1).await;
+
+        // a batch exceeding the remaining capacity is rejected without charging the key
+        let error = limiter.check_batch(1, quota, 3, now).await.unwrap_err();
+        assert_eq!((error.requested, error.available, error.shortfall()), (3, 2, 1));
+        assert_eq!(error.error.as_duration(), Duration::from_secs(1));
+        assert_eq!(limiter.state(&1).await, state);
+
+        // so the batch that fits still does, exhausting the key
+        assert_eq!(limiter.check_batch(1, quota, 2, now).await.unwrap().remaining, 0);
+
+        let error = limiter.check_batch(1, quota, 6, now).await.unwrap_err();
+        assert_eq!((error.requested, error.available, error.shortfall()), (6, 0, 6));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_second(1).with_burst(2);
+        let now = Instant::now();
+
+        // one key exhausted, and another with a request left
+        limiter.req_sync(1, quota, now).unwrap();
+        limiter.req_sync(1, quota, now).unwrap();
+        limiter.req_sync(2, quota, now).unwrap();
+
+        let mut snapshot = limiter.snapshot_sync(now);
+        snapshot.sort_by_key(|(key, _)| *key);
+
+        let keys: Vec<_> = snapshot.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, [1, 2]);
+        assert_eq!(
+            snapshot[0].1.as_duration() - snapshot[1].1.as_duration(),
+            Duration::from_secs(1)
+        );
+
+        #[cfg(feature = "serde")]
+        let snapshot: Vec<(u32, State)> = {
+            let json = serde_json::to_string(&snapshot).unwrap();
+            serde_json::from_str(&json).unwrap()
+        };
+
+        // restored relative to another time by another limiter, as after a restart
+        let restored = new_limiter(u64::MAX);
+        let later = now + Duration::from_secs(3600);
+        restored.restore_sync(snapshot, later);
+
+        assert!(restored.req_sync(1, quota, later).is_err());
+        assert!(restored.req_sync(2, quota, later).is_ok());
+        assert!(restored.req_sync(2, quota, later).is_err());
+        assert!(restored.req_sync(3, quota, later).is_ok());
+
+        // and recovering as it would have
+        assert!(restored.req_sync(1, quota, later + Duration::from_millis(1001)).is_ok());
+
+        // only keys that have not recovered are included
+        assert!(limiter.snapshot_sync(now + Duration::from_secs(3)).is_empty());
+    }
+
+    #[test]
+    fn retry_after_rounding() {
+        let headers = |ms: u64, rounding| {
+            let error = RateLimitError(NonZeroU64::new(ms * 1_000_000).unwrap());
+            let res = NotUntil::new(error, Quota::per_second(1), Instant::now()).with_retry_after(rounding);
+            let res = res.into_response();
+
+            let header = |name| res.headers().get(name).map(|v| v.to_str().unwrap().to_owned());
+            (header("retry-after").unwrap(), header("x-ratelimit-reset-after"))
+        };
+
+        // a 200ms wait still asks for at least a second, but can be exact with the extra header
+        assert_eq!(headers(200, RetryAfter::Ceil), ("1".to_owned(), None));
+        assert_eq!(headers(200, RetryAfter::Round), ("1".to_owned(), None));
+        assert_eq!(
+            headers(200, RetryAfter::ExactMs),
+            ("1".to_owned(), Some("200".to_owned()))
+        );
+
+        assert_eq!(headers(1400, RetryAfter::Ceil).0, "2");
+        assert_eq!(headers(1400, RetryAfter::Round).0, "1");
+        assert_eq!(headers(1600, RetryAfter::Round).0, "2");
+        assert_eq!(
+            headers(1400, RetryAfter::ExactMs),
+            ("2".to_owned(), Some("1400".to_owned()))
+        );
+
+        // and sub-millisecond waits are rounded up
+        let error = RateLimitError(NonZeroU64::new(200_000_001).unwrap());
+        let res = NotUntil::new(error, Quota::per_second(1), Instant::now()).with_retry_after(RetryAfter::ExactMs);
+        assert_eq!(res.into_response().headers()["x-ratelimit-reset-after"], "201");
+    }
+
+    #[tokio::test]
+    async fn reset_grants_a_fresh_burst() {
+        let limiter = new_limiter(u64::MAX);
+        let quota = Quota::per_hour(2).with_burst(2);
+        let now = Instant::now();
+
+        for key in [1, 2] {
+            for _ in 0..2 {
+                limiter.req(key, quota, now).await.unwrap();
+            }
+            assert!(limiter.req(key, quota, now).await.is_err());
+        }
+
+        // only the reset key is allowed again, immediately and for its full burst
+        assert!(limiter.reset(&1).await);
+        assert!(!limiter.reset(&3).await);
+        assert!(limiter.req(1, quota, now).await.is_ok());
+        assert!(limiter.req(1, quota, now).await.is_ok());
+        assert!(limiter.req(1, quota, now).await.is_err());
+        assert!(limiter.req(2, quota, now).await.is_err());
+
+        limiter.reset_all().await;
+        assert!(limiter.is_empty());
+        assert!(limiter.req(1, quota, now).await.is_ok());
+        assert!(limiter.req(2, quota, now).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reset_races_requests() {
+        let limiter = std::sync::Arc::new(new_limiter(u64::MAX));
+        let quota = Quota::per_hour(10).with_burst(10);
+        let now = Instant::now();
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    let mut allowed = 0;
+                    for _ in 0..1000 {
+                        allowed += limiter.req(1, quota, now).await.is_ok() as u64;
+                        tokio::task::yield_now().await;
+                    }
+                    allowed
+                })
+            })
+            .collect();
+
+        let mut resets = 0;
+        while !tasks.iter().all(|task| task.is_finished()) {
+            resets += limiter.reset(&1).await as u64;
+            tokio::task::yield_now().await;
+        }
+
+        let mut allowed = 0;
+        for task in tasks {
+            allowed += task.await.unwrap();
+        }
+
+        // every request was counted against exactly one entry, each allowing at most a full burst
+        assert!(allowed <= 10 * (resets + 1), "{allowed} allowed with {resets} resets");
+        assert!(allowed >= 10);
+    }
+}
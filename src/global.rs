@@ -0,0 +1,292 @@
+//! Coarse rate limiting of a whole service with a single global limit.
+//!
+//! A [`RateLimitLayer`](crate::RateLimitLayer) keyed by `()` already limits all requests together, but still
+//! hashes each request's route into its table. The [`GlobalRateLimitLayer`] skips the table entirely,
+//! checking every request against a single [`GlobalRateLimiter`], which is cheaper under high load.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use axum::{routing::get, Router};
+//! use axum_gcra::{gcra::Quota, global::GlobalRateLimitLayer};
+//!
+//! let app = Router::<()>::new()
+//!     .route("/", get(|| async { "Hello, World!" }))
+//!     .layer(GlobalRateLimitLayer::new(Quota::per_second(10_000).with_burst(1_000)));
+//! ```
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+};
+use http::Request;
+use tower::{Layer, Service};
+
+use crate::{
+    clock::{Clock, MonotonicClock},
+    gcra::{Capacity, GlobalRateLimiter, Quota},
+    NotUntil,
+};
+
+/// Layer limiting all requests to a service with a single [`Quota`].
+///
+/// Rejected requests are answered directly with `429 Too Many Requests` and the usual `Retry-After`
+/// headers, as by [`NotUntil`], so no error handler is needed. Any response body type that can be
+/// converted from an axum [`Body`] is supported. Clones of the layer share the same limiter.
+#[derive(Clone)]
+pub struct GlobalRateLimitLayer {
+    limiter: Arc<GlobalRateLimiter>,
+    quota: Quota,
+    clock: Arc<dyn Clock>,
+    headers: bool,
+}
+
+impl GlobalRateLimitLayer {
+    /// Constructs a new layer limiting all requests with the given quota.
+    #[must_use]
+    pub fn new(quota: Quota) -> Self {
+        Self::with_shared_limiter(quota, Arc::default())
+    }
+
+    /// Constructs a new layer using a [`GlobalRateLimiter`] shared with other layers or used directly elsewhere.
+    #[must_use]
+    pub fn with_shared_limiter(quota: Quota, limiter: Arc<GlobalRateLimiter>) -> Self {
+        GlobalRateLimitLayer {
+            limiter,
+            quota,
+            clock: Arc::new(MonotonicClock),
+            headers: false,
+        }
+    }
+
+    /// Set the [`Clock`] used to get the time of each request, instead of [`Instant::now`](std::time::Instant::now).
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Set whether to insert rate limit headers into successful responses.
+    ///
+    /// See [`RateLimitLayerBuilder::with_rate_limit_headers`](crate::RateLimitLayerBuilder::with_rate_limit_headers)
+    /// for more information. The default is `false`.
+    #[must_use]
+    pub fn with_rate_limit_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Get the underlying [`GlobalRateLimiter`] of the layer.
+    #[inline]
+    pub fn limiter(&self) -> &Arc<GlobalRateLimiter> {
+        &self.limiter
+    }
+}
+
+impl<I> Layer<I> for GlobalRateLimitLayer {
+    type Service = GlobalRateLimitService<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        GlobalRateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service limiting all requests with the [`Quota`] of a [`GlobalRateLimitLayer`].
+#[derive(Clone)]
+pub struct GlobalRateLimitService<I> {
+    inner: I,
+    layer: GlobalRateLimitLayer,
+}
+
+impl<B, I, ResB> Service<Request<B>> for GlobalRateLimitService<I>
+where
+    I: Service<Request<B>, Response = Response<ResB>>,
+    ResB: From<Body>,
+{
+    type Response = Response<ResB>;
+    type Error = I::Error;
+    type Future = GlobalRateLimitedResponse<I::Future, ResB>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        req.extensions_mut().insert(crate::extensions::LayerApplied);
+
+        let GlobalRateLimitLayer {
+            ref limiter,
+            quota,
+            ref clock,
+            headers,
+        } = self.layer;
+
+        let now = clock.now();
+
+        match limiter.req(quota, now) {
+            Ok(capacity) => GlobalRateLimitedResponse::Allowed {
+                f: self.inner.call(req),
+                capacity: headers.then_some(capacity),
+            },
+            Err(e) => GlobalRateLimitedResponse::Limited {
+                res: Some(NotUntil::new(e, quota, now).into_response().map(ResB::from)),
+            },
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    #[doc(hidden)]
+    #[project = GlobalRateLimitedResponseProj]
+    pub enum GlobalRateLimitedResponse<F, ResB = Body> {
+        Allowed {
+            #[pin] f: F,
+            capacity: Option<Capacity>,
+        },
+        Limited {
+            res: Option<Response<ResB>>,
+        },
+    }
+}
+
+impl<F, E, ResB> Future for GlobalRateLimitedResponse<F, ResB>
+where
+    F: Future<Output = Result<Response<ResB>, E>>,
+{
+    type Output = Result<Response<ResB>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            GlobalRateLimitedResponseProj::Allowed { f, capacity } => {
+                let mut res = ready!(f.poll(cx))?;
+
+                if let Some(capacity) = capacity.take() {
+                    capacity.insert_headers(res.headers_mut());
+                }
+
+                Poll::Ready(Ok(res))
+            }
+            GlobalRateLimitedResponseProj::Limited { res } => {
+                Poll::Ready(Ok(res.take().expect("response is Some until polled")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{convert::Infallible, time::Instant};
+
+    use http::StatusCode;
+    use tower::{service_fn, ServiceExt};
+
+    /// Response body that isn't an axum [`Body`], recording whether it came from one.
+    #[derive(Debug, Default)]
+    struct TestBody {
+        converted: bool,
+    }
+
+    impl From<Body> for TestBody {
+        fn from(_: Body) -> Self {
+            TestBody { converted: true }
+        }
+    }
+
+    fn service(
+        layer: &GlobalRateLimitLayer,
+    ) -> impl Service<Request<()>, Response = Response<TestBody>, Error = Infallible, Future: Send> + Clone {
+        layer.layer(service_fn(|_: Request<()>| async {
+            Ok(Response::new(TestBody::default()))
+        }))
+    }
+
+    #[tokio::test]
+    async fn generic_response_body() {
+        let layer = GlobalRateLimitLayer::new(Quota::per_hour(1).with_burst(2)).with_rate_limit_headers(true);
+
+        for allowed in [true, true, false] {
+            let res = service(&layer).oneshot(Request::new(())).await.unwrap();
+
+            assert_eq!(res.status() == StatusCode::OK, allowed);
+            assert_eq!(res.body().converted, !allowed);
+            assert!(res.headers().contains_key(if allowed { "x-ratelimit-limit" } else { "retry-after" }));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_share_the_burst() {
+        let layer = GlobalRateLimitLayer::new(Quota::per_hour(1).with_burst(100));
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for _ in 0..8 {
+            let service = service(&layer);
+
+            tasks.spawn(async move {
+                let mut allowed = 0;
+                for _ in 0..50 {
+                    let res = service.clone().oneshot(Request::new(())).await.unwrap();
+                    allowed += u64::from(res.status() == StatusCode::OK);
+                }
+                allowed
+            });
+        }
+
+        let allowed: u64 = tasks.join_all().await.into_iter().sum();
+        assert_eq!(allowed, 100);
+    }
+
+    /// Rough throughput comparison with a `RateLimitLayer` keyed by `()`.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture global_throughput`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "benchmark"]
+    async fn global_throughput() {
+        const TASKS: usize = 8;
+        const REQUESTS: usize = 100_000;
+
+        async fn run<S>(service: S) -> std::time::Duration
+        where
+            S: Service<Request<()>, Future: Send> + Clone + Send + 'static,
+        {
+            let mut tasks = tokio::task::JoinSet::new();
+            let start = Instant::now();
+
+            for _ in 0..TASKS {
+                let service = service.clone();
+                tasks.spawn(async move {
+                    for _ in 0..REQUESTS {
+                        let _ = service.clone().oneshot(Request::new(())).await;
+                    }
+                });
+            }
+
+            tasks.join_all().await;
+            start.elapsed()
+        }
+
+        let quota = Quota::per_second(1_000_000).with_burst(1_000);
+        let inner = service_fn(|_: Request<()>| async { Ok::<_, Infallible>(Response::new(Body::empty())) });
+
+        let global = run(GlobalRateLimitLayer::new(quota).layer(inner)).await;
+        let keyed = run(crate::RateLimitLayer::<()>::new(quota).layer(inner)).await;
+
+        let per_request = |d: std::time::Duration| d / (TASKS * REQUESTS) as u32;
+        println!(
+            "global: {:?}/request, keyed: {:?}/request",
+            per_request(global),
+            per_request(keyed)
+        );
+    }
+}
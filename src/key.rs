@@ -0,0 +1,665 @@
+//! Stateless key extractors, for keys that aren't themselves extractable from the request.
+//!
+//! Keys used by the [`RateLimitLayer`](crate::RateLimitLayer) must implement [`FromRequestParts`].
+//! Rather than implementing it for every key type, a [`KeyExtractor`] can be implemented
+//! once and used as the key with the [`Extract`] wrapper.
+//! Keys that require I/O to resolve can use an [`AsyncKeyExtractor`] with the [`ExtractAsync`] wrapper.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use axum::{routing::get, Router};
+//! use axum_gcra::{key::{Extract, KeyExtractor}, RateLimitLayer};
+//! use http::request::Parts;
+//!
+//! /// Rate limit by API key.
+//! struct ApiKey;
+//!
+//! impl KeyExtractor for ApiKey {
+//!     type Key = String;
+//!
+//!     fn extract(parts: &Parts) -> Option<String> {
+//!         let value = parts.headers.get("authorization")?.to_str().ok()?;
+//!         Some(value.strip_prefix("Bearer ")?.to_owned())
+//!     }
+//! }
+//!
+//! let app = Router::<()>::new()
+//!     .route("/", get(|| async { "Hello, World!" }))
+//!     .route_layer(RateLimitLayer::<Extract<ApiKey>>::builder().default_handle_error());
+//! ```
+//!
+//! # Composite keys
+//!
+//! Tuples of up to four key extractors are also key extractors, combining their keys into a tuple,
+//! and rejecting the request if any of them fail. For example, `Extract<(PeerIpKeyExtractor, ApiKey)>`
+//! limits each API key separately from each IP address it is used from.
+//!
+//! The combined key is hashed as a whole, with the cost of hashing and cloning each part,
+//! so keys that allocate, such as `String`, will allocate for each request as usual.
+//!
+//! Note that the route and method of the request are always part of the rate limiting key,
+//! so the same IP address on two different routes already uses two independent buckets.
+//! Only routes using the [global fallback](crate::RateLimitLayerBuilder::with_global_fallback)
+//! share a bucket, which can be split again with the [`RouteKeyExtractor`].
+
+use std::{
+    fmt,
+    future::{self, Future},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{FromRequestParts, MatchedPath},
+    response::{IntoResponse, Response},
+};
+use http::{request::Parts, StatusCode};
+
+use crate::Key;
+
+/// Stateless extractor for rate limiting keys.
+///
+/// Implementations are used through the [`Extract`] wrapper, which is the actual key type.
+pub trait KeyExtractor: Send + Sync + 'static {
+    /// The key type extracted from the request.
+    type Key: Key;
+
+    /// Extract the key from the request parts, or `None` to reject the request with [`MissingKey`].
+    fn extract(parts: &Parts) -> Option<Self::Key>;
+}
+
+/// Rate limiting key extracted by the [`KeyExtractor`] `E`.
+#[repr(transparent)]
+pub struct Extract<E: KeyExtractor>(pub E::Key);
+
+/// Implements the key wrapper traits, delegating to the inner key.
+macro_rules! impl_key_wrapper {
+    ($wrapper:ident, $extractor:ident) => {
+        impl<E: $extractor> $wrapper<E> {
+            /// Returns the inner key.
+            #[inline]
+            pub fn into_inner(self) -> E::Key {
+                self.0
+            }
+        }
+
+        impl<E: $extractor> std::ops::Deref for $wrapper<E> {
+            type Target = E::Key;
+
+            #[inline]
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl<E: $extractor> Clone for $wrapper<E>
+        where
+            E::Key: Clone,
+        {
+            fn clone(&self) -> Self {
+                $wrapper(self.0.clone())
+            }
+        }
+
+        impl<E: $extractor> fmt::Debug for $wrapper<E>
+        where
+            E::Key: fmt::Debug,
+        {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&self.0, f)
+            }
+        }
+
+        impl<E: $extractor> PartialEq for $wrapper<E> {
+            #[inline]
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+
+        impl<E: $extractor> Eq for $wrapper<E> {}
+
+        impl<E: $extractor> Hash for $wrapper<E> {
+            #[inline]
+            fn hash<Hs: Hasher>(&self, state: &mut Hs) {
+                self.0.hash(state);
+            }
+        }
+    };
+}
+
+impl_key_wrapper!(Extract, KeyExtractor);
+
+/// Asynchronous extractor for rate limiting keys, for keys that require I/O to resolve,
+/// such as mapping a session cookie to an account through a cache or database.
+///
+/// Implementations are used through the [`ExtractAsync`] wrapper, which is the actual key type.
+/// The key is resolved before the rate limit is checked, so slow extractors delay every request;
+/// prefer a [`KeyExtractor`] when the key is available from the request itself.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{key::{AsyncKeyExtractor, ExtractAsync}, RateLimitLayer};
+/// use http::{request::Parts, StatusCode};
+///
+/// /// Rate limit by account, looked up from the session.
+/// struct Account;
+///
+/// # async fn lookup_account(session: &str) -> Option<u64> { None }
+/// impl AsyncKeyExtractor for Account {
+///     type Key = u64;
+///     type Rejection = StatusCode;
+///
+///     async fn extract(parts: &mut Parts) -> Result<u64, StatusCode> {
+///         let session = parts.headers.get("x-session").and_then(|v| v.to_str().ok());
+///
+///         match session {
+///             Some(session) => lookup_account(session).await.ok_or(StatusCode::UNAUTHORIZED),
+///             None => Err(StatusCode::UNAUTHORIZED),
+///         }
+///     }
+/// }
+///
+/// let app = Router::<()>::new()
+///     .route("/", get(|| async { "Hello, World!" }))
+///     .route_layer(RateLimitLayer::<ExtractAsync<Account>>::builder().default_handle_error());
+/// ```
+pub trait AsyncKeyExtractor: Send + Sync + 'static {
+    /// The key type extracted from the request.
+    type Key: Key;
+
+    /// The rejection returned when no key can be extracted, which is passed to the rate limiter's
+    /// [error handler](crate::RateLimitLayerBuilder::handle_error) to short-circuit the request.
+    type Rejection: IntoResponse;
+
+    /// Extract the key from the request parts.
+    fn extract(parts: &mut Parts) -> impl Future<Output = Result<Self::Key, Self::Rejection>> + Send;
+}
+
+/// Rate limiting key extracted by the [`AsyncKeyExtractor`] `E`.
+#[repr(transparent)]
+pub struct ExtractAsync<E: AsyncKeyExtractor>(pub E::Key);
+
+impl_key_wrapper!(ExtractAsync, AsyncKeyExtractor);
+
+impl<S, E: AsyncKeyExtractor> FromRequestParts<S> for ExtractAsync<E> {
+    type Rejection = E::Rejection;
+
+    fn from_request_parts(parts: &mut Parts, _: &S) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        let key = E::extract(parts);
+
+        async move { key.await.map(ExtractAsync) }
+    }
+}
+
+/// The [`KeyExtractor`] could not find a key in the request, returns 400.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MissingKey;
+
+impl IntoResponse for MissingKey {
+    fn into_response(self) -> Response {
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+impl<S, E: KeyExtractor> FromRequestParts<S> for Extract<E> {
+    type Rejection = MissingKey;
+
+    fn from_request_parts(parts: &mut Parts, _: &S) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(E::extract(parts).map(Extract).ok_or(MissingKey))
+    }
+}
+
+/// Key extractor using a single bucket shared by all clients, equivalent to using `()` as the key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalKeyExtractor;
+
+impl KeyExtractor for GlobalKeyExtractor {
+    type Key = ();
+
+    #[inline]
+    fn extract(_: &Parts) -> Option<()> {
+        Some(())
+    }
+}
+
+/// Key extractor using the client IP address, resolved in the same way as [`RealIp`](crate::real_ip::RealIp).
+///
+/// To share a bucket between all addresses in a subnet, use the [`MaskedIpKeyExtractor`] instead.
+#[cfg(feature = "real_ip")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerIpKeyExtractor;
+
+#[cfg(feature = "real_ip")]
+impl KeyExtractor for PeerIpKeyExtractor {
+    type Key = crate::real_ip::RealIp;
+
+    fn extract(parts: &Parts) -> Option<Self::Key> {
+        match parts.extensions.get::<crate::real_ip::RealIp>() {
+            Some(ip) => Some(*ip),
+            None => crate::real_ip::get_ip_from_parts(parts),
+        }
+    }
+}
+
+/// Key extractor using the client IP address with the host bits zeroed out, so that all addresses
+/// within the same subnet share a single bucket.
+///
+/// Keeps the first `V4_PREFIX` bits of IPv4 addresses and the first `V6_PREFIX` bits of IPv6 addresses,
+/// as by [`RealIpMask`](crate::real_ip::RealIpMask). Since a single IPv6 client is commonly assigned
+/// an entire `/64` or more, limiting by exact address allows it to evade the limit by rotating addresses.
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{key::{Extract, MaskedIpKeyExtractor}, RateLimitLayer};
+///
+/// // each IPv4 address alone, and each IPv6 /56
+/// type Subnet = Extract<MaskedIpKeyExtractor<32, 56>>;
+///
+/// let app = Router::<()>::new()
+///     .route("/", get(|| async { "Hello, World!" }))
+///     .route_layer(RateLimitLayer::<Subnet>::builder().default_handle_error());
+/// ```
+#[cfg(feature = "real_ip")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaskedIpKeyExtractor<const V4_PREFIX: u8 = 32, const V6_PREFIX: u8 = 64>;
+
+#[cfg(feature = "real_ip")]
+impl<const V4_PREFIX: u8, const V6_PREFIX: u8> KeyExtractor for MaskedIpKeyExtractor<V4_PREFIX, V6_PREFIX> {
+    type Key = crate::real_ip::RealIpMask<V4_PREFIX, V6_PREFIX>;
+
+    #[inline]
+    fn extract(parts: &Parts) -> Option<Self::Key> {
+        PeerIpKeyExtractor::extract(parts).map(Into::into)
+    }
+}
+
+/// Key extractor using the path of the matched route, such as `/users/{id}`.
+///
+/// The path is copied into a new `Arc<str>` for each request. Requests that did not match
+/// a route, such as those handled by a fallback, are rejected with [`MissingKey`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RouteKeyExtractor;
+
+impl KeyExtractor for RouteKeyExtractor {
+    type Key = Arc<str>;
+
+    fn extract(parts: &Parts) -> Option<Self::Key> {
+        parts.extensions.get::<MatchedPath>().map(|path| Arc::from(path.as_str()))
+    }
+}
+
+/// Name of the cookie used by a [`CookieKeyExtractor`].
+///
+/// Since key extractors are stateless, the name is given as an associated constant of a marker type.
+pub trait CookieName: Send + Sync + 'static {
+    /// The name of the cookie, which is matched case-sensitively.
+    const NAME: &'static str;
+}
+
+/// Key extractor using the value of the cookie named by `N`, such as an anonymous session ID,
+/// so that clients sharing an IP address behind a NAT are limited separately.
+///
+/// All `Cookie` headers are searched, and the first cookie with the name is used. Values wrapped
+/// in double quotes are unquoted, and percent-encoded values are decoded. Requests without the cookie
+/// are rejected with [`MissingKey`]; use the [`CookieOrIpKeyExtractor`] to fall back to the client IP instead.
+///
+/// Note that clients choose their own cookies, so this should only be used to split clients further
+/// than a limit they cannot evade, or with cookies that are verified elsewhere.
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{key::{CookieKeyExtractor, CookieName, Extract}, RateLimitLayer};
+///
+/// struct Sid;
+///
+/// impl CookieName for Sid {
+///     const NAME: &'static str = "sid";
+/// }
+///
+/// let app = Router::<()>::new()
+///     .route("/", get(|| async { "Hello, World!" }))
+///     .route_layer(RateLimitLayer::<Extract<CookieKeyExtractor<Sid>>>::builder().default_handle_error());
+/// ```
+pub struct CookieKeyExtractor<N: CookieName>(std::marker::PhantomData<N>);
+
+impl<N: CookieName> KeyExtractor for CookieKeyExtractor<N> {
+    type Key = Arc<str>;
+
+    fn extract(parts: &Parts) -> Option<Self::Key> {
+        let value = parts
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .find_map(|cookie| match cookie.trim().split_once('=') {
+                Some((name, value)) if name.trim_end() == N::NAME => Some(value.trim()),
+                _ => None,
+            })?;
+
+        let value = match value.as_bytes() {
+            [b'"', .., b'"'] => &value[1..value.len() - 1],
+            _ => value,
+        };
+
+        if value.is_empty() {
+            return None;
+        }
+
+        Some(match percent_decode(value) {
+            Some(decoded) => Arc::from(decoded),
+            None => Arc::from(value),
+        })
+    }
+}
+
+/// Decodes `%XX` escapes, returning `None` if there are none or the result is not valid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    if !s.contains('%') {
+        return None;
+    }
+
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+
+        match hex.filter(|_| bytes[i] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Key of the [`CookieOrIpKeyExtractor`], either the cookie value or the client IP address.
+#[cfg(feature = "real_ip")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CookieOrIp {
+    /// The value of the cookie, as by [`CookieKeyExtractor`].
+    Cookie(Arc<str>),
+
+    /// The client IP address of a request without the cookie, as by [`PeerIpKeyExtractor`].
+    Ip(crate::real_ip::RealIp),
+}
+
+/// Key extractor using the value of the cookie named by `N` as by [`CookieKeyExtractor`],
+/// or the client IP address as by [`PeerIpKeyExtractor`] for requests without the cookie.
+///
+/// Clients can avoid the IP limit by sending any cookie value, so prefer cookies that are verified elsewhere.
+#[cfg(feature = "real_ip")]
+pub struct CookieOrIpKeyExtractor<N: CookieName>(std::marker::PhantomData<N>);
+
+#[cfg(feature = "real_ip")]
+impl<N: CookieName> KeyExtractor for CookieOrIpKeyExtractor<N> {
+    type Key = CookieOrIp;
+
+    fn extract(parts: &Parts) -> Option<Self::Key> {
+        match CookieKeyExtractor::<N>::extract(parts) {
+            Some(value) => Some(CookieOrIp::Cookie(value)),
+            None => PeerIpKeyExtractor::extract(parts).map(CookieOrIp::Ip),
+        }
+    }
+}
+
+macro_rules! impl_tuple_extractor {
+    ($($e:ident),+) => {
+        impl<$($e: KeyExtractor),+> KeyExtractor for ($($e,)+) {
+            type Key = ($($e::Key,)+);
+
+            #[inline]
+            fn extract(parts: &Parts) -> Option<Self::Key> {
+                Some(($($e::extract(parts)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple_extractor!(A, B);
+impl_tuple_extractor!(A, B, C);
+impl_tuple_extractor!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use http::Request;
+
+    struct Sid;
+
+    impl CookieName for Sid {
+        const NAME: &'static str = "sid";
+    }
+
+    fn cookie(headers: &[&str]) -> Result<Arc<str>, MissingKey> {
+        let mut req = Request::builder();
+        for value in headers {
+            req = req.header(http::header::COOKIE, *value);
+        }
+        let (mut parts, ()) = req.body(()).unwrap().into_parts();
+
+        <Extract<CookieKeyExtractor<Sid>> as FromRequestParts<()>>::from_request_parts(&mut parts, &())
+            .now_or_never()
+            .expect("extraction is synchronous")
+            .map(Extract::into_inner)
+    }
+
+    #[test]
+    fn cookie_key() {
+        assert_eq!(cookie(&["sid=abc"]).as_deref(), Ok("abc"));
+
+        // among several, in one header or across them
+        assert_eq!(cookie(&["theme=dark; sid=abc; lang=en"]).as_deref(), Ok("abc"));
+        assert_eq!(cookie(&["theme=dark", "lang=en;sid = abc"]).as_deref(), Ok("abc"));
+        assert_eq!(cookie(&["sid=first; sid=second"]).as_deref(), Ok("first"));
+
+        assert_eq!(cookie(&[r#"sid="abc""#]).as_deref(), Ok("abc"));
+        assert_eq!(cookie(&["sid=a%20b%3D"]).as_deref(), Ok("a b="));
+        assert_eq!(cookie(&["sid=100%"]).as_deref(), Ok("100%"));
+    }
+
+    #[test]
+    fn missing_cookie() {
+        assert_eq!(cookie(&[]), Err(MissingKey));
+        assert_eq!(cookie(&["theme=dark; lang=en"]), Err(MissingKey));
+        assert_eq!(cookie(&["SID=abc; xsid=abc; sidx=abc"]), Err(MissingKey));
+        assert_eq!(cookie(&["sid="]), Err(MissingKey));
+        assert_eq!(cookie(&[r#"sid="""#]), Err(MissingKey));
+
+        assert_eq!(MissingKey.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Rate limit by the `x-api-key` header.
+    struct ApiKey;
+
+    impl KeyExtractor for ApiKey {
+        type Key = String;
+
+        fn extract(parts: &Parts) -> Option<String> {
+            Some(parts.headers.get("x-api-key")?.to_str().ok()?.to_owned())
+        }
+    }
+
+    /// Statuses of requests to a route limited to one request per hour per key, with the given API keys.
+    async fn statuses<E: KeyExtractor>(keys: &[Option<&str>]) -> Vec<StatusCode> {
+        use axum::{body::Body, routing::get, Router};
+        use tower::ServiceExt;
+
+        let layer = crate::RateLimitLayer::<Extract<E>>::builder()
+            .with_default_quota(crate::gcra::Quota::per_hour(1))
+            .default_handle_error();
+        let app = Router::new().route("/", get(|| async { "ok" })).route_layer(layer);
+
+        let mut statuses = Vec::new();
+        for key in keys {
+            let mut req = Request::get("/");
+            if let Some(key) = key {
+                req = req.header("x-api-key", *key);
+            }
+
+            let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+            statuses.push(res.status());
+        }
+
+        statuses
+    }
+
+    #[tokio::test]
+    async fn custom_extractor() {
+        const OK: StatusCode = StatusCode::OK;
+        const LIMITED: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+
+        let keys = [Some("a"), Some("b"), Some("a"), None, Some("b"), Some("c")];
+
+        // each key is limited separately, and requests without one are rejected
+        let expected = [OK, OK, LIMITED, StatusCode::BAD_REQUEST, LIMITED, OK];
+        assert_eq!(statuses::<ApiKey>(&keys).await, expected);
+
+        // while every request shares a single bucket
+        let expected = [OK, LIMITED, LIMITED, LIMITED, LIMITED, LIMITED];
+        assert_eq!(statuses::<GlobalKeyExtractor>(&keys).await, expected);
+
+        // and combining keys rejects if any of them is missing
+        let expected = [OK, OK, LIMITED, StatusCode::BAD_REQUEST, LIMITED, OK];
+        assert_eq!(statuses::<(ApiKey, GlobalKeyExtractor)>(&keys).await, expected);
+    }
+
+    /// Statuses of requests from the given client IPs to the given paths, limited to one request per hour per key.
+    #[cfg(feature = "real_ip")]
+    async fn from_ips<E: KeyExtractor>(requests: &[(&str, &str)]) -> Vec<StatusCode> {
+        use axum::{body::Body, routing::get, Router};
+        use tower::ServiceExt;
+
+        let layer = crate::RateLimitLayer::<Extract<E>>::builder()
+            .with_default_quota(crate::gcra::Quota::per_hour(1))
+            .default_handle_error();
+        let app = Router::new()
+            .route("/a", get(|| async { "a" }))
+            .route("/b/{id}", get(|| async { "b" }))
+            .route_layer(layer);
+
+        let mut statuses = Vec::new();
+        for (ip, path) in requests {
+            let req = Request::get(*path).header("x-forwarded-for", *ip).body(Body::empty()).unwrap();
+            statuses.push(app.clone().oneshot(req).await.unwrap().status());
+        }
+
+        statuses
+    }
+
+    #[cfg(feature = "real_ip")]
+    #[tokio::test]
+    async fn composite_key() {
+        const OK: StatusCode = StatusCode::OK;
+        const LIMITED: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+
+        let requests = [
+            ("203.0.113.7", "/a"),
+            ("203.0.113.7", "/b/1"),
+            ("203.0.113.7", "/a"),
+            // the matched route is the key, not the path
+            ("203.0.113.7", "/b/2"),
+            ("198.51.100.1", "/a"),
+            ("198.51.100.1", "/b/1"),
+        ];
+
+        let statuses = from_ips::<(PeerIpKeyExtractor, RouteKeyExtractor)>(&requests).await;
+        assert_eq!(statuses, [OK, OK, LIMITED, LIMITED, OK, OK]);
+    }
+
+    #[cfg(feature = "real_ip")]
+    #[tokio::test]
+    async fn masked_ip_key() {
+        const OK: StatusCode = StatusCode::OK;
+        const LIMITED: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+
+        let requests = [
+            ("2001:db8:0:1::1", "/a"),
+            ("2001:db8:0:1:ffff::2", "/a"),
+            ("2001:db8:0:2::1", "/a"),
+            ("203.0.113.7", "/a"),
+            ("203.0.113.8", "/a"),
+        ];
+
+        // addresses within the same /64 share a bucket, while each IPv4 address has its own
+        assert_eq!(
+            from_ips::<MaskedIpKeyExtractor>(&requests).await,
+            [OK, LIMITED, OK, OK, OK]
+        );
+
+        // unlike exact addresses
+        assert_eq!(from_ips::<PeerIpKeyExtractor>(&requests).await, [OK; 5]);
+
+        // and the prefix is configurable for each family
+        let statuses = from_ips::<MaskedIpKeyExtractor<24, 48>>(&requests).await;
+        assert_eq!(statuses, [OK, LIMITED, LIMITED, OK, LIMITED]);
+    }
+
+    /// Rate limit by account, looked up from the `x-session` header.
+    struct Account;
+
+    impl AsyncKeyExtractor for Account {
+        type Key = u64;
+        type Rejection = StatusCode;
+
+        async fn extract(parts: &mut Parts) -> Result<u64, StatusCode> {
+            let session = parts.headers.get("x-session").map(|v| v.as_bytes().to_owned());
+
+            // as if looking it up elsewhere
+            tokio::task::yield_now().await;
+
+            match session.as_deref() {
+                Some(b"alice-laptop" | b"alice-phone") => Ok(1),
+                Some(b"bob") => Ok(2),
+                _ => Err(StatusCode::UNAUTHORIZED),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn async_extractor() {
+        use axum::{body::Body, routing::get, Router};
+        use tower::ServiceExt;
+
+        let layer = crate::RateLimitLayer::<ExtractAsync<Account>>::builder()
+            .with_default_quota(crate::gcra::Quota::per_hour(1))
+            .default_handle_error();
+        let app = Router::new().route("/", get(|| async { "ok" })).route_layer(layer);
+
+        let sessions = [
+            Some("alice-laptop"),
+            Some("bob"),
+            Some("alice-phone"),
+            Some("mallory"),
+            None,
+        ];
+
+        let mut statuses = Vec::new();
+        for session in sessions {
+            let mut req = Request::get("/");
+            if let Some(session) = session {
+                req = req.header("x-session", session);
+            }
+
+            let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+            statuses.push(res.status().as_u16());
+        }
+
+        // both of alice's sessions share a bucket,
+        // and requests without an account are rejected as returned by the extractor
+        assert_eq!(statuses, [200, 200, 429, 401, 401]);
+    }
+}
@@ -4,21 +4,24 @@
 
 use std::{
     any::TypeId,
-    borrow::Cow,
+    borrow::{Borrow, Cow},
     collections::HashMap,
     convert::Infallible,
     future::{Future, Ready},
     hash::{BuildHasher, Hash},
     ops::Deref,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     task::{ready, Context, Poll},
     time::{Duration, Instant},
 };
 
 use axum::{
     error_handling::HandleErrorLayer,
-    extract::{FromRequestParts, MatchedPath as AxumMatchedPath, Request},
+    extract::{FromRef, FromRequestParts, MatchedPath as AxumMatchedPath, Request},
     response::{IntoResponse, Response},
 };
 use http::{request::Parts, Extensions, Method};
@@ -44,14 +47,32 @@ pub trait Key: Hash + Eq + Send + Sync + 'static {}
 impl<K> Key for K where K: Hash + Eq + Send + Sync + 'static {}
 
 pub mod gcra;
-pub use gcra::RateLimitError;
+pub use gcra::{BatchError, NotUntil, RateLimitError};
+
+pub mod clock;
+pub use clock::Clock;
+
+pub mod key;
+pub use key::{AsyncKeyExtractor, Extract, ExtractAsync, KeyExtractor};
+
+pub mod quota;
+pub use quota::{CostResolver, QuotaResolver};
+
+pub mod store;
+
+pub mod chain;
+
+pub mod concurrency;
+
+pub mod global;
 
 /// Interval for garbage collection of the rate limiter, which can be either
 /// a number of requests or a time duration.
 ///
 /// The default is 8192 requests.
 ///
-/// Time durations require the `tokio` cargo feature to be enabled.
+/// Time durations require the `tokio` cargo feature to be enabled. On other runtimes,
+/// garbage collection can be driven manually with [`RateLimitLayer::maintain`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GCInterval {
     /// Run garbage collection after a number of requests.
@@ -69,6 +90,27 @@ pub enum GCInterval {
     Time(Duration),
 }
 
+/// How the rate limiter handles requests that exceed their quota, as set with
+/// [`RateLimitLayerBuilder::with_throttle_mode`].
+///
+/// The default is [`ThrottleMode::Reject`].
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleMode {
+    /// Reject the request immediately with [`Error::RateLimit`].
+    #[default]
+    Reject,
+
+    /// Hold the request until it would be allowed, smoothing bursts of traffic instead of shedding them,
+    /// and only reject it if that would take longer than `max_wait`.
+    ///
+    /// The wait is measured and slept with the configured [`Clock`].
+    Delay {
+        /// Longest time to hold a request before rejecting it instead.
+        max_wait: Duration,
+    },
+}
+
 impl Default for GCInterval {
     fn default() -> Self {
         GCInterval::Requests(8192)
@@ -151,8 +193,10 @@ decl_route_methods! {
     connect => CONNECT
 }
 
+/// The full key used to identify rate limiter entries within a [`Store`](store::Store),
+/// consisting of the matched route and the user-provided [`Key`].
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
-struct RouteWithKey<T> {
+pub struct RouteWithKey<T> {
     path: MatchedPath,
     method: Method,
     key: T,
@@ -160,12 +204,83 @@ struct RouteWithKey<T> {
 
 impl<T> RouteWithKey<T> {
     #[inline]
+    #[allow(unknown_lints, mismatched_lifetime_syntaxes)]
     fn as_route(&self) -> Route {
         Route {
             path: Cow::Borrowed(&*self.path),
             method: Cow::Borrowed(&self.method),
         }
     }
+
+    /// Get the user-provided key.
+    #[inline(always)]
+    pub fn key(&self) -> &T {
+        &self.key
+    }
+
+    /// Get the matched path of the route, or an empty string
+    /// if using the [global fallback](RateLimitLayerBuilder::with_global_fallback).
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Get the method of the route.
+    #[inline]
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+}
+
+// Serialized as a `{ path, method, key }` struct. Deserialized paths compare equal to the same
+// matched path of a request, so restored entries apply to the same routes.
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use std::sync::Arc;
+
+    use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{MatchedPath, Method, RouteWithKey};
+
+    #[derive(Serialize)]
+    struct Ref<'a, T> {
+        path: &'a str,
+        method: &'a str,
+        key: &'a T,
+    }
+
+    #[derive(Deserialize)]
+    struct Owned<T> {
+        path: String,
+        method: String,
+        key: T,
+    }
+
+    impl<T: Serialize> Serialize for RouteWithKey<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            Ref {
+                path: &self.path,
+                method: self.method.as_str(),
+                key: &self.key,
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de, T: Deserialize<'de>> Deserialize<'de> for RouteWithKey<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let Owned { path, method, key } = Owned::deserialize(deserializer)?;
+
+            Ok(RouteWithKey {
+                path: match path.is_empty() {
+                    true => MatchedPath::Fallback,
+                    false => MatchedPath::Owned(Arc::from(path)),
+                },
+                method: Method::from_bytes(method.as_bytes()).map_err(D::Error::custom)?,
+                key,
+            })
+        }
+    }
 }
 
 /// Hashmap of quotas for rate limiting, mapping a path as passed to [`Router`](axum::Router) to a [`gcra::Quota`].
@@ -175,6 +290,10 @@ type Quotas = HashMap<Route<'static>, gcra::Quota, RandomState>;
 enum MatchedPath {
     Fallback,
     Axum(AxumMatchedPath),
+
+    /// Deserialized path, equal to the same [`MatchedPath::Axum`] path.
+    #[cfg(feature = "serde")]
+    Owned(Arc<str>),
 }
 
 impl Deref for MatchedPath {
@@ -185,6 +304,9 @@ impl Deref for MatchedPath {
         match self {
             MatchedPath::Fallback => "",
             MatchedPath::Axum(path) => path.as_str(),
+
+            #[cfg(feature = "serde")]
+            MatchedPath::Owned(path) => path,
         }
     }
 }
@@ -207,14 +329,19 @@ impl Hash for MatchedPath {
     }
 }
 
-/// Rate limiter [`Service`] for axum.
+/// Rate limiter [`Service`] for axum, or any other tower stack over [`http`] requests.
 ///
 /// This struct is not meant to be used directly, but rather through the [`RateLimitLayerBuilder`].
+/// See [`RateLimitLayer`] for use outside of axum.
 ///
 /// Note: The limiter is shared across all clones of the layer and service.
-pub struct RateLimitService<I, K: Key = (), H: BuildHasher = RandomState> {
+pub struct RateLimitService<I, K: Key = (), H: BuildHasher = RandomState, S = store::MemoryStore<K, H>> {
     inner: I,
-    layer: RateLimitLayer<K, H>,
+    layer: RateLimitLayer<K, H, S>,
+
+    /// Timer until the global limiter is no longer saturated, if using backpressure.
+    #[cfg(feature = "tokio")]
+    backpressure: Option<Pin<Box<tokio::time::Sleep>>>,
 }
 
 #[cfg(feature = "tokio")]
@@ -223,21 +350,155 @@ struct BuilderDropNotify {
     notify: Arc<tokio::sync::Notify>,
 }
 
+/// Exemption predicate for requests or keys.
+type Predicate<T> = dyn Fn(&T) -> bool + Send + Sync;
+
+/// Formatter for keys in tracing events.
+#[cfg(feature = "tracing")]
+type KeyFormatter<T> = dyn Fn(&T) -> String + Send + Sync;
+
+/// Longest wait for denied requests and how to clone the key to retry them, in [`ThrottleMode::Delay`].
+#[cfg(feature = "tokio")]
+type Delay<K> = (Duration, fn(&RouteWithKey<K>) -> RouteWithKey<K>);
+
+/// Denial counts of each key, see [`RateLimitLayerBuilder::with_denial_tracking`].
+struct Denials<K> {
+    window: Duration,
+    escalation: Option<(u64, Duration)>,
+    clone: fn(&K) -> K,
+    recorded: AtomicU64,
+    counts: scc::HashMap<K, Denied, RandomState>,
+}
+
+#[derive(Clone, Copy)]
+struct Denied {
+    count: u64,
+    last: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl<K: Eq + Hash> Denials<K> {
+    fn count<Q>(&self, key: &Q, now: Instant) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let count = |_: &K, denied: &Denied| match now.saturating_duration_since(denied.last) > self.window {
+            true => 0,
+            false => denied.count,
+        };
+
+        self.counts.read(key, count).unwrap_or(0)
+    }
+
+    /// Returns the remaining time the key is blocked for, if escalated.
+    fn blocked(&self, key: &K, now: Instant) -> Option<RateLimitError> {
+        let until = self.counts.read(key, |_, denied| denied.blocked_until)??;
+        let wait = until.saturating_duration_since(now).as_nanos() as u64;
+
+        std::num::NonZeroU64::new(wait).map(RateLimitError)
+    }
+
+    async fn record(&self, key: K, now: Instant) {
+        // as the stores, prune keys that have complied for a full window every so often
+        #[allow(clippy::manual_is_multiple_of)] // `is_multiple_of` would require Rust 1.87
+        if 0 == self.recorded.fetch_add(1, Ordering::Relaxed) % 8192 {
+            self.prune(now).await;
+        }
+
+        let mut entry = self.counts.entry_async(key).await.or_insert(Denied {
+            count: 0,
+            last: now,
+            blocked_until: None,
+        });
+
+        let denied = entry.get_mut();
+
+        if now.saturating_duration_since(denied.last) > self.window {
+            denied.count = 0;
+        }
+
+        denied.count += 1;
+        denied.last = now;
+
+        if let Some((after, block)) = self.escalation {
+            // blocked requests count as denials, but don't extend the block
+            #[allow(clippy::unnecessary_map_or)] // `is_none_or` would require Rust 1.82
+            if denied.count >= after && denied.blocked_until.map_or(true, |until| until <= now) {
+                // only a platform with a very narrow `Instant` could overflow, in which case don't block
+                denied.blocked_until = now.checked_add(block);
+            }
+        }
+    }
+
+    async fn prune(&self, now: Instant) {
+        let window = self.window;
+
+        let keep = |_: &K, denied: &mut Denied| {
+            now.saturating_duration_since(denied.last) <= window || denied.blocked_until.is_some_and(|u| u > now)
+        };
+
+        self.counts.retain_async(keep).await;
+    }
+}
+
 /// Builder for the rate limiter layer.
 ///
 /// This struct is used to configure the rate limiter before building it.
-pub struct RateLimitLayerBuilder<K = (), H: BuildHasher = RandomState> {
+///
+/// The store `S` defaults to the in-memory [`MemoryStore`](store::MemoryStore), but any
+/// [`Store`](store::Store) can be used with [`RateLimitLayerBuilder::from_store`],
+/// or shared between layers with [`RateLimitLayerBuilder::from_shared_store`].
+pub struct RateLimitLayerBuilder<K = (), H: BuildHasher = RandomState, S = store::MemoryStore<K, H>> {
+    store: Option<Arc<S>>,
     quotas: Quotas,
     default_quota: gcra::Quota,
-    set_ext: Option<Box<dyn SetExtension<K, H>>>,
+    resolver: Option<Box<dyn QuotaResolver>>,
+    key_quotas: scc::HashMap<K, gcra::Quota, RandomState>,
+    cost: Option<Box<dyn CostResolver>>,
+    set_ext: Option<Box<dyn SetExtension<K, H, S>>>,
+    set_key: Option<fn(&mut http::Extensions, &K)>,
+    on_decision: Option<Box<dyn OnDecision<K>>>,
+    #[cfg(feature = "tracing")]
+    trace_key: Option<Box<KeyFormatter<K>>>,
+    exempt: Option<Box<Predicate<Parts>>>,
+    exempt_key: Option<Box<Predicate<K>>>,
+    #[cfg(feature = "real_ip")]
+    exempt_cidrs: Vec<real_ip::Cidr>,
+    #[cfg(feature = "real_ip")]
+    client_ip: Option<fn(real_ip::RealIp) -> std::net::IpAddr>,
+    clock: Arc<dyn Clock>,
     global_fallback: bool,
     gc_interval: GCInterval,
+    headers: bool,
+    header_style: gcra::HeaderStyle,
+    rejection_body: gcra::RejectionBody,
+    status: bool,
+    fail_open: bool,
+    dry_run: bool,
+    enabled: AtomicBool,
+    headers_when_disabled: bool,
+    retry_after: gcra::RetryAfter,
+    jitter: f64,
+    jitter_source: Option<Box<dyn Fn() -> f64 + Send + Sync>>,
+
+    #[cfg(feature = "tokio")]
+    delay: Option<Delay<K>>,
+
+    denials: Option<Denials<K>>,
+
+    #[cfg(feature = "tokio")]
+    backpressure: bool,
+
+    /// Earliest time the last rate limited request would have been allowed, for backpressure.
+    #[cfg(feature = "tokio")]
+    saturated: std::sync::Mutex<Option<Instant>>,
 
     #[cfg(feature = "tokio")]
     shutdown: BuilderDropNotify,
 }
 
-impl<K, H: BuildHasher> Drop for RateLimitLayerBuilder<K, H> {
+impl<K, H: BuildHasher, S> Drop for RateLimitLayerBuilder<K, H, S> {
     fn drop(&mut self) {
         #[cfg(feature = "tokio")]
         self.shutdown.notify.notify_waiters();
@@ -249,35 +510,105 @@ impl<K, H: BuildHasher> Drop for RateLimitLayerBuilder<K, H> {
 /// This struct is not meant to be used directly, but rather through the [`RateLimitLayerBuilder`].
 ///
 /// Note: The limiter is shared across all clones of the layer and service.
-pub struct RateLimitLayer<K: Key = (), H: BuildHasher = RandomState> {
-    builder: Arc<RateLimitLayerBuilder<K, H>>,
-    limiter: Arc<gcra::RateLimiter<RouteWithKey<K>, H>>,
+///
+/// # Outside of axum
+///
+/// The service accepts any [`http::Request`] body and inner service returning an [`http::Response`],
+/// so it can also be used in plain tower or hyper stacks. Keys are still extracted with [`FromRequestParts`],
+/// which only needs the request [`Parts`], and routes are treated as a single fallback route,
+/// since there is no axum [`MatchedPath`](axum::extract::MatchedPath) unless inserted as an extension.
+///
+/// Without [`HandleErrorLayer`], denials are returned as the [`Error::RateLimit`] of the service,
+/// which can be converted into a response with [`IntoResponse`].
+///
+/// ```rust
+/// use std::convert::Infallible;
+/// use axum_gcra::{gcra::Quota, Error, RateLimitLayer};
+/// use http::{Request, Response};
+/// use tower::{service_fn, Service, ServiceBuilder, ServiceExt};
+///
+/// # #[tokio::main(flavor = "current_thread")] async fn main() {
+/// let mut service = ServiceBuilder::new()
+///     .layer(RateLimitLayer::<()>::builder().with_default_quota(Quota::per_second(1)).build())
+///     .service(service_fn(|req: Request<String>| async move {
+///         Ok::<_, Infallible>(Response::new(format!("Hello, {}!", req.into_body())))
+///     }));
+///
+/// let res = service.ready().await.unwrap().call(Request::new("World".to_owned())).await;
+/// assert_eq!(res.unwrap().body(), "Hello, World!");
+///
+/// let res = service.ready().await.unwrap().call(Request::new("again".to_owned())).await;
+/// assert!(matches!(res, Err(Error::RateLimit(_))));
+/// # }
+/// ```
+pub struct RateLimitLayer<K: Key = (), H: BuildHasher = RandomState, S = store::MemoryStore<K, H>> {
+    builder: Arc<RateLimitLayerBuilder<K, H, S>>,
+    limiter: Arc<S>,
 }
 
 /// Object-safe trait for setting an extension on a request.
 ///
 /// Used to insert the rate limiter into the request's extensions,
 /// without knowing the type of the key except when the handler is defined and not further.
-trait SetExtension<K: Key, H: BuildHasher>: Send + Sync + 'static {
-    fn set_extension(&self, req: &mut Extensions, key: &RouteWithKey<K>, layer: RateLimitLayer<K, H>);
+trait SetExtension<K: Key, H: BuildHasher, S>: Send + Sync + 'static {
+    fn set_extension(
+        &self,
+        req: &mut Extensions,
+        key: &RouteWithKey<K>,
+        quota: gcra::Quota,
+        layer: RateLimitLayer<K, H, S>,
+    );
 }
 
 struct DoSetExtension;
 
-impl<K: Key, H: BuildHasher> SetExtension<K, H> for DoSetExtension
+impl<K: Key, H: BuildHasher, S> SetExtension<K, H, S> for DoSetExtension
 where
     K: Clone,
     H: Send + Sync + 'static,
+    S: Send + Sync + 'static,
 {
-    fn set_extension(&self, req: &mut Extensions, key: &RouteWithKey<K>, layer: RateLimitLayer<K, H>) {
-        req.insert(extensions::RateLimiter::<K, H> {
+    fn set_extension(
+        &self,
+        req: &mut Extensions,
+        key: &RouteWithKey<K>,
+        quota: gcra::Quota,
+        layer: RateLimitLayer<K, H, S>,
+    ) {
+        req.insert(extensions::RateLimiter::<K, H, S> {
             key: key.clone(),
+            quota,
             layer,
         });
     }
 }
 
-impl<K: Key, H: BuildHasher> Clone for RateLimitLayer<K, H> {
+/// Object-safe trait for observing rate limiting decisions.
+///
+/// Like [`SetExtension`], this allows cloning the key without a `K: Clone` bound on the service.
+trait OnDecision<K: Key>: Send + Sync + 'static {
+    fn clone_key(&self, key: &RouteWithKey<K>) -> RouteWithKey<K>;
+
+    fn on_decision(&self, key: &RouteWithKey<K>, decision: Result<gcra::Capacity, RateLimitError>);
+}
+
+struct DecisionHook<F>(F);
+
+impl<K: Key, F> OnDecision<K> for DecisionHook<F>
+where
+    K: Clone,
+    F: Fn(&RouteWithKey<K>, Result<gcra::Capacity, RateLimitError>) + Send + Sync + 'static,
+{
+    fn clone_key(&self, key: &RouteWithKey<K>) -> RouteWithKey<K> {
+        key.clone()
+    }
+
+    fn on_decision(&self, key: &RouteWithKey<K>, decision: Result<gcra::Capacity, RateLimitError>) {
+        (self.0)(key, decision)
+    }
+}
+
+impl<K: Key, H: BuildHasher, S> Clone for RateLimitLayer<K, H, S> {
     fn clone(&self) -> Self {
         Self {
             limiter: self.limiter.clone(),
@@ -286,16 +617,19 @@ impl<K: Key, H: BuildHasher> Clone for RateLimitLayer<K, H> {
     }
 }
 
-impl<I: Clone, K: Key, H: BuildHasher> Clone for RateLimitService<I, K, H> {
+impl<I: Clone, K: Key, H: BuildHasher, S> Clone for RateLimitService<I, K, H, S> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
             layer: self.layer.clone(),
+
+            #[cfg(feature = "tokio")]
+            backpressure: None,
         }
     }
 }
 
-impl<K: Key, H: BuildHasher> RateLimitLayer<K, H> {
+impl<K: Key, H: BuildHasher + Default> RateLimitLayer<K, H> {
     /// Begin building a new rate limiter layer starting with the default configuration.
     #[must_use]
     pub fn builder() -> RateLimitLayerBuilder<K, H> {
@@ -303,60 +637,259 @@ impl<K: Key, H: BuildHasher> RateLimitLayer<K, H> {
     }
 }
 
-impl<K: Key, H: BuildHasher> RateLimitLayerBuilder<K, H> {
+impl<K, H> RateLimitLayer<K, H>
+where
+    K: Key + FromRequestParts<()>,
+    H: BuildHasher + Default + Send + Sync + 'static,
+    store::MemoryStore<K, H>: store::Store<RouteWithKey<K>>,
+{
+    /// Construct a new rate limiter layer with the given default quota and otherwise the default
+    /// configuration, as by `RateLimitLayer::builder().with_default_quota(quota).build()`.
+    ///
+    /// Use [`RateLimitLayer::builder`] for any further configuration.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::convert::Infallible;
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer};
+    /// use http::{Request, Response};
+    /// use tower::{service_fn, Layer, Service, ServiceExt};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let quota = Quota::per_second(1).with_burst(2);
+    ///
+    /// let direct = RateLimitLayer::<()>::new(quota);
+    /// let built = RateLimitLayer::builder().with_default_quota(quota).build();
+    ///
+    /// for layer in [direct, built] {
+    ///     let mut service = layer.layer(service_fn(|_: Request<()>| async {
+    ///         Ok::<_, Infallible>(Response::new(()))
+    ///     }));
+    ///
+    ///     for allowed in [true, true, false] {
+    ///         let res = service.ready().await.unwrap().call(Request::new(())).await;
+    ///         assert_eq!(res.is_ok(), allowed);
+    ///     }
+    /// }
+    /// # }
+    /// ```
     #[must_use]
-    pub fn new() -> Self {
-        RateLimitLayerBuilder {
-            quotas: Default::default(),
-            default_quota: Default::default(),
-            set_ext: None,
-            global_fallback: false,
-            gc_interval: GCInterval::default(),
-
-            #[cfg(feature = "tokio")]
-            shutdown: BuilderDropNotify::default(),
-        }
+    pub fn new(quota: gcra::Quota) -> Self {
+        RateLimitLayerBuilder::new().with_default_quota(quota).build()
     }
+}
 
-    /// Insert a route entry into the quota table for the rate limiter.
-    pub fn add_route(&mut self, route: impl Into<Route<'static>>, quota: gcra::Quota) {
-        self.add_routes(Some((route.into(), quota)));
+impl<K: Key, S> RateLimitLayer<K, RandomState, S> {
+    /// Begin building a new rate limiter layer starting with the default configuration,
+    /// using the given [`Store`](store::Store) for rate limiter state.
+    ///
+    /// The key type must be given explicitly, e.g. `RateLimitLayer::<MyKey, _, _>::builder_with_store(store)`.
+    /// To use a custom hasher with the memory store, see [`RateLimitLayerBuilder::from_store`].
+    #[must_use]
+    pub fn builder_with_store(store: S) -> RateLimitLayerBuilder<K, RandomState, S> {
+        RateLimitLayerBuilder::from_store(store)
     }
 
-    /// Insert a route entry into the quota table for the rate limiter.
+    /// Begin building a new rate limiter layer starting with the default configuration,
+    /// using a [`Store`](store::Store) shared with other layers.
+    ///
+    /// See [`RateLimitLayerBuilder::from_shared_store`] for more information.
     #[must_use]
-    pub fn with_route(mut self, route: impl Into<Route<'static>>, quota: gcra::Quota) -> Self {
-        self.add_route(route.into(), quota);
-        self
+    pub fn builder_with_shared_store(store: Arc<S>) -> RateLimitLayerBuilder<K, RandomState, S> {
+        RateLimitLayerBuilder::from_shared_store(store)
     }
 
-    /// Insert many route entries into the quota table for the rate limiter.
-    pub fn add_routes(&mut self, quotas: impl IntoIterator<Item = (impl Into<Route<'static>>, gcra::Quota)>) {
-        self.quotas.extend(quotas.into_iter().map(|(route, quota)| (route.into(), quota)));
+    /// Begin building a new rate limiter layer starting with the default configuration,
+    /// using a [`Store`](store::Store) shared through the application state.
+    ///
+    /// See [`RateLimitLayerBuilder::from_state`] for more information.
+    #[must_use]
+    pub fn builder_from_state<St>(state: &St) -> RateLimitLayerBuilder<K, RandomState, S>
+    where
+        Arc<S>: FromRef<St>,
+    {
+        RateLimitLayerBuilder::from_state(state)
     }
+}
 
-    /// Insert many route entries into the quota table for the rate limiter.
+impl<K: Key, H: BuildHasher, S> RateLimitLayer<K, H, S> {
+    /// Get a handle to the underlying [`Store`](store::Store) of the rate limiter,
+    /// which can be cheaply cloned and used to build other layers with
+    /// [`RateLimitLayer::builder_with_shared_store`], or to manage entries directly.
+    #[inline]
     #[must_use]
-    pub fn with_routes(
-        mut self,
-        quotas: impl IntoIterator<Item = (impl Into<Route<'static>>, gcra::Quota)>,
-    ) -> Self {
-        self.add_routes(quotas);
-        self
+    pub fn store(&self) -> &Arc<S> {
+        &self.limiter
     }
 
-    /// Fallback quota for rate limiting if no specific quota is found for the path.
+    /// Run garbage collection on the store now, removing entries that have fully recovered
+    /// as of the configured [`Clock`], as by [`Store::clean`](store::Store::clean).
+    ///
+    /// Timed garbage collection with [`GCInterval::Time`] requires the `tokio` feature, so on other
+    /// runtimes this can be called periodically from a task instead, optionally disabling
+    /// request-based collection with a GC interval of `u64::MAX`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use axum_gcra::RateLimitLayer;
+    ///
+    /// # async fn sleep(_: Duration) {}
+    /// let layer = RateLimitLayer::<()>::builder().with_gc_interval(u64::MAX).build();
+    ///
+    /// // spawn on any runtime, such as with `smol::spawn` or `async_std::task::spawn`
+    /// let gc = layer.clone();
+    /// let task = async move {
+    ///     loop {
+    ///         sleep(Duration::from_secs(60)).await;
+    ///         gc.maintain().await;
+    ///     }
+    /// };
+    /// ```
+    pub async fn maintain(&self)
+    where
+        S: store::Store<RouteWithKey<K>>,
+    {
+        let now = self.now();
+
+        store::Store::clean(&*self.limiter, now).await;
+
+        if let Some(ref denials) = self.builder.denials {
+            denials.prune(now).await;
+        }
+    }
+
+    /// Set a custom quota for the given key, used for all of its subsequent requests through this layer
+    /// and any of its clones, such as to grant a higher limit to a paying customer.
+    /// Returns the previous custom quota of the key, if any.
+    ///
+    /// A custom quota takes precedence over the [resolver](RateLimitLayerBuilder::with_quota_resolver),
+    /// the [route quotas](RateLimitLayerBuilder::with_route) and the default quota, and applies to every
+    /// route of the layer. The key keeps its rate limit state, so requests already made count
+    /// towards the new quota.
+    ///
+    /// Custom quotas are kept by the layer rather than the store, so they are not garbage collected
+    /// and persist until [removed](RateLimitLayer::remove_quota).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// use axum_gcra::{gcra::Quota, real_ip::RealIp, RateLimitLayer};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let layer = RateLimitLayer::<RealIp>::builder().with_default_quota(Quota::per_second(1)).build();
+    ///
+    /// let partner = RealIp(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+    /// let premium = Quota::per_second(100).with_burst(100);
+    ///
+    /// assert!(layer.set_quota(partner, premium).await.is_none());
+    /// assert!(layer.quota_for(&partner).is_some());
+    ///
+    /// assert!(layer.remove_quota(&partner).await.is_some());
+    /// assert!(layer.quota_for(&partner).is_none());
+    /// # }
+    /// ```
+    pub async fn set_quota(&self, key: K, quota: gcra::Quota) -> Option<gcra::Quota> {
+        self.builder.key_quotas.upsert_async(key, quota).await
+    }
+
+    /// Remove the custom quota of the given key, set with [`RateLimitLayer::set_quota`],
+    /// returning it if there was one. Subsequent requests of the key use the usual quotas.
+    pub async fn remove_quota<Q>(&self, key: &Q) -> Option<gcra::Quota>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.builder.key_quotas.remove_async(key).await.map(|(_, quota)| quota)
+    }
+
+    /// Returns the custom quota of the given key, set with [`RateLimitLayer::set_quota`], if any.
+    pub fn quota_for<Q>(&self, key: &Q) -> Option<gcra::Quota>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.builder.key_quotas.read(key, |_, quota| *quota)
+    }
+
+    /// Returns the number of times the given key has been denied since it last went a full window without
+    /// denials, as configured with [`RateLimitLayerBuilder::with_denial_tracking`], or `0` if not tracked.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer};
+    ///
+    /// let layer = RateLimitLayer::<()>::builder()
+    ///     .with_default_quota(Quota::per_second(1))
+    ///     .with_denial_tracking(Duration::from_secs(60))
+    ///     .build();
+    ///
+    /// assert_eq!(layer.denied_count(&()), 0);
+    /// ```
+    pub fn denied_count<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.builder.denials {
+            Some(ref denials) => denials.count(key, self.now()),
+            None => 0,
+        }
+    }
+
+    /// Enable or disable rate limiting at runtime for this layer and all of its clones, such as a kill switch
+    /// during an incident. The change applies to the very next request.
+    ///
+    /// While disabled, every request is passed to the inner service, as configured by
+    /// [`RateLimitLayerBuilder::with_headers_when_disabled`]. Rate limit state is kept,
+    /// so re-enabling the layer picks up where it left off.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::convert::Infallible;
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer};
+    /// use http::{Request, Response};
+    /// use tower::{service_fn, Layer, Service, ServiceExt};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let layer = RateLimitLayer::<()>::new(Quota::per_hour(1));
+    /// let mut service = layer.layer(service_fn(|_: Request<()>| async {
+    ///     Ok::<_, Infallible>(Response::new(()))
+    /// }));
+    ///
+    /// for (enabled, allowed) in [(true, true), (true, false), (false, true), (false, true), (true, false)] {
+    ///     layer.set_enabled(enabled);
+    ///     assert_eq!(layer.is_enabled(), enabled);
+    ///
+    ///     let res = service.ready().await.unwrap().call(Request::new(())).await;
+    ///     assert_eq!(res.is_ok(), allowed);
+    /// }
+    /// # }
+    /// ```
+    pub fn set_enabled(&self, enabled: bool) {
+        self.builder.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the layer is enforcing rate limits, see [`RateLimitLayer::set_enabled`].
     #[must_use]
-    pub fn with_default_quota(mut self, default_quota: gcra::Quota) -> Self {
-        self.default_quota = default_quota;
-        self
+    pub fn is_enabled(&self) -> bool {
+        self.builder.enabled.load(Ordering::Relaxed)
     }
+}
 
-    /// Set whether to use a global fallback shared rate-limiter for all paths not explicitly defined.
+impl<K: Key, H: BuildHasher + Default> RateLimitLayerBuilder<K, H> {
     #[must_use]
-    pub fn with_global_fallback(mut self, global_fallback: bool) -> Self {
-        self.global_fallback = global_fallback;
-        self
+    pub fn new() -> Self {
+        Self::from_store(gcra::RateLimiter::new(
+            GCInterval::default().to_requests(),
+            H::default(),
+        ))
     }
 
     /// Set the interval for which garbage collection for the rate limiter will occur.
@@ -369,446 +902,3213 @@ impl<K: Key, H: BuildHasher> RateLimitLayerBuilder<K, H> {
     /// and a background task will be spawned to clean the rate limiter at the
     /// given time interval. Cleanup is asynchronous and will not block the request
     /// in this case.
+    ///
+    /// If the store is [shared](RateLimitLayerBuilder::from_shared_store), the request-based
+    /// interval cannot be changed and should be configured when constructing the store instead.
     #[must_use]
     pub fn with_gc_interval(mut self, gc_interval: impl Into<GCInterval>) -> Self {
         self.gc_interval = gc_interval.into();
+
+        if let Some(store) = self.store.as_mut().and_then(Arc::get_mut) {
+            store.set_gc_interval(self.gc_interval.to_requests());
+        }
+
         self
     }
 
-    /// Set whether to insert the [`RateLimiter`](extensions::RateLimiter) extension into the request
-    /// to allow for manual rate limiting control downstream.
+    /// Set the maximum number of entries in the rate limiter table, evicting
+    /// expired and then the oldest idle entries when a new key would exceed it.
     ///
-    /// # Example
+    /// This bounds memory usage for keys with unbounded cardinality, such as IP addresses,
+    /// at the cost of resetting the rate limit for evicted keys.
+    /// See [`gcra::RateLimiter::with_max_entries`] for more information.
     ///
-    /// ```rust,no_run
-    /// # use std::time::Duration;
-    /// use axum::{extract::Extension, routing::get, Router};
-    /// use axum_gcra::{RateLimitLayer, extensions::RateLimiter};
+    /// The default is unlimited.
     ///
-    /// // Note this must be identical to the key used in the rate limiter layer
-    /// type Key = ();
+    /// If the store is [shared](RateLimitLayerBuilder::from_shared_store), this has no effect,
+    /// and should be configured with [`gcra::RateLimiter::with_max_entries`] instead.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        if let Some(store) = self.store.as_mut().and_then(Arc::get_mut) {
+            store.set_max_entries(max_entries);
+        }
+
+        self
+    }
+
+    /// Set how much capacity keys start with when first seen, such as to make new keys
+    /// earn their burst over time instead of receiving it all at once.
+    /// See [`gcra::ColdStart`] for more information.
     ///
-    /// let app = Router::<()>::new()
-    ///     // access the rate limiter for this request
-    ///     .route("/", get(|rl: Extension<RateLimiter<Key>>| async move {
-    ///         rl.penalize(Duration::from_secs(50)).await;
-    ///     }))
-    ///     .route_layer(RateLimitLayer::<Key>::builder().with_extension(true).default_handle_error());
+    /// The default is [`ColdStart::Full`](gcra::ColdStart::Full).
+    ///
+    /// If the store is [shared](RateLimitLayerBuilder::from_shared_store), this has no effect,
+    /// and should be configured with [`gcra::RateLimiter::with_cold_start`] instead.
     #[must_use]
-    pub fn with_extension(mut self, extend: bool) -> Self
+    pub fn with_cold_start(mut self, cold_start: gcra::ColdStart) -> Self {
+        if let Some(store) = self.store.as_mut().and_then(Arc::get_mut) {
+            store.set_cold_start(cold_start);
+        }
+
+        self
+    }
+}
+
+impl<K: Key, H: BuildHasher, S> RateLimitLayerBuilder<K, H, S> {
+    /// Begin building a new rate limiter layer starting with the default configuration,
+    /// using the given [`Store`](store::Store) for rate limiter state.
+    #[must_use]
+    pub fn from_store(store: S) -> Self {
+        Self::from_shared_store(Arc::new(store))
+    }
+
+    /// Begin building a new rate limiter layer starting with the default configuration,
+    /// using a [`Store`](store::Store) shared with other layers or used directly elsewhere.
+    ///
+    /// Layers sharing a store will throttle against the same entries,
+    /// but entries are still keyed by route and method as well as the key, so the same key on
+    /// different routes is limited independently unless both routes use the
+    /// [global fallback](RateLimitLayerBuilder::with_global_fallback) without an explicit quota.
+    /// Each layer uses its own quotas, so layers sharing entries should usually use the same quotas.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::sync::Arc;
+    ///
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{gcra::{Quota, RateLimiter}, store::MemoryStore, RateLimitLayer};
+    ///
+    /// let store: Arc<MemoryStore<()>> = Arc::new(RateLimiter::new(8192, Default::default()));
+    ///
+    /// let api = Router::<()>::new().route("/api", get(|| async { "API" })).route_layer(
+    ///     RateLimitLayer::<()>::builder_with_shared_store(store.clone())
+    ///         .with_default_quota(Quota::per_second(5))
+    ///         .with_global_fallback(true)
+    ///         .default_handle_error(),
+    /// );
+    ///
+    /// let ws = Router::<()>::new().route("/ws", get(|| async { "WebSocket" })).route_layer(
+    ///     RateLimitLayer::<()>::builder_with_shared_store(store)
+    ///         .with_default_quota(Quota::per_second(5))
+    ///         .with_global_fallback(true)
+    ///         .default_handle_error(),
+    /// );
+    ///
+    /// let app = api.merge(ws);
+    /// ```
+    #[must_use]
+    pub fn from_shared_store(store: Arc<S>) -> Self {
+        RateLimitLayerBuilder {
+            store: Some(store),
+            quotas: Default::default(),
+            default_quota: Default::default(),
+            resolver: None,
+            key_quotas: Default::default(),
+            cost: None,
+            set_ext: None,
+            set_key: None,
+            on_decision: None,
+            #[cfg(feature = "tracing")]
+            trace_key: None,
+            exempt: None,
+            exempt_key: None,
+            #[cfg(feature = "real_ip")]
+            exempt_cidrs: Vec::new(),
+            #[cfg(feature = "real_ip")]
+            client_ip: None,
+            clock: Arc::new(clock::MonotonicClock),
+            global_fallback: false,
+            gc_interval: GCInterval::default(),
+            headers: false,
+            header_style: gcra::HeaderStyle::Legacy,
+            rejection_body: gcra::RejectionBody::Text,
+            status: false,
+            fail_open: false,
+            dry_run: false,
+            enabled: AtomicBool::new(true),
+            headers_when_disabled: false,
+            retry_after: gcra::RetryAfter::Ceil,
+            jitter: 0.0,
+            jitter_source: None,
+
+            #[cfg(feature = "tokio")]
+            delay: None,
+            denials: None,
+            #[cfg(feature = "tokio")]
+            backpressure: false,
+            #[cfg(feature = "tokio")]
+            saturated: std::sync::Mutex::new(None),
+
+            #[cfg(feature = "tokio")]
+            shutdown: BuilderDropNotify::default(),
+        }
+    }
+
+    /// Begin building a new rate limiter layer starting with the default configuration,
+    /// using a [`Store`](store::Store) taken from the application state with [`FromRef`],
+    /// as by [`RateLimitLayerBuilder::from_shared_store`].
+    ///
+    /// This lets handlers share the same store with the layer through axum's [`State`](axum::extract::State)
+    /// extractor, such as to [reset](gcra::RateLimiter::reset) or inspect keys, without any extensions.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::sync::Arc;
+    ///
+    /// use axum::{extract::{FromRef, State}, routing::get, Router};
+    /// use axum_gcra::{gcra::Quota, real_ip::RealIp, store::MemoryStore, RateLimitLayer};
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct AppState {
+    ///     limiter: Arc<MemoryStore<RealIp>>,
+    /// }
+    ///
+    /// impl FromRef<AppState> for Arc<MemoryStore<RealIp>> {
+    ///     fn from_ref(state: &AppState) -> Self {
+    ///         state.limiter.clone()
+    ///     }
+    /// }
+    ///
+    /// async fn tracked(State(limiter): State<Arc<MemoryStore<RealIp>>>) -> String {
+    ///     format!("{} clients tracked", limiter.len())
+    /// }
+    ///
+    /// let state = AppState::default();
+    ///
+    /// let app: Router = Router::new()
+    ///     .route("/", get(|| async { "Hello, World!" }))
+    ///     .route_layer(
+    ///         RateLimitLayer::<RealIp>::builder_from_state(&state)
+    ///             .with_default_quota(Quota::per_second(5))
+    ///             .default_handle_error(),
+    ///     )
+    ///     .route("/admin/tracked", get(tracked))
+    ///     .with_state(state);
+    /// ```
+    #[must_use]
+    pub fn from_state<St>(state: &St) -> Self
     where
-        K: Clone,
-        H: Send + Sync + 'static,
+        Arc<S>: FromRef<St>,
     {
-        self.set_ext = match extend {
-            true => Some(Box::new(DoSetExtension) as Box<dyn SetExtension<K, H>>),
-            false => None,
-        };
+        Self::from_shared_store(FromRef::from_ref(state))
+    }
+
+    /// Insert a route entry into the quota table for the rate limiter.
+    pub fn add_route(&mut self, route: impl Into<Route<'static>>, quota: gcra::Quota) {
+        self.add_routes(Some((route.into(), quota)));
+    }
+
+    /// Insert a route entry into the quota table for the rate limiter.
+    #[must_use]
+    pub fn with_route(mut self, route: impl Into<Route<'static>>, quota: gcra::Quota) -> Self {
+        self.add_route(route.into(), quota);
         self
     }
-}
 
-impl Default for RateLimitLayerBuilder<()> {
-    fn default() -> Self {
-        RateLimitLayerBuilder::new()
+    /// Insert many route entries into the quota table for the rate limiter.
+    pub fn add_routes(&mut self, quotas: impl IntoIterator<Item = (impl Into<Route<'static>>, gcra::Quota)>) {
+        self.quotas.extend(quotas.into_iter().map(|(route, quota)| (route.into(), quota)));
     }
-}
 
-/// Error wrapper for rate limiting errors or inner service errors.
-#[derive(Debug)]
-pub enum Error<Inner, Rejection> {
-    /// Inner service error.
+    /// Insert many route entries into the quota table for the rate limiter.
+    #[must_use]
+    pub fn with_routes(
+        mut self,
+        quotas: impl IntoIterator<Item = (impl Into<Route<'static>>, gcra::Quota)>,
+    ) -> Self {
+        self.add_routes(quotas);
+        self
+    }
+
+    /// Fallback quota for rate limiting if no specific quota is found for the path.
+    #[must_use]
+    pub fn with_default_quota(mut self, default_quota: gcra::Quota) -> Self {
+        self.default_quota = default_quota;
+        self
+    }
+
+    /// Set a [`QuotaResolver`] to select the quota for each request, such as from a header or extension.
     ///
-    /// For most axum services, this will be a [`Infallible`].
-    Inner(Inner),
+    /// The resolver takes precedence over the route table, which is only used if it returns `None`,
+    /// but not over [custom quotas](RateLimitLayer::set_quota) of individual keys.
+    ///
+    /// Note that the resolved quota does not change which bucket the request is counted against.
+    /// Buckets are still per-route and per-key, so the same key on two routes is limited independently,
+    /// unless both routes fall under the [global fallback](RateLimitLayerBuilder::with_global_fallback).
+    /// Paths with a resolved quota are not considered part of the global fallback.
+    /// To share a bucket across routes with different quotas, include the shared part in the key instead.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::{get, post}, Router};
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer};
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/login", post(|| async { "Logged in" }))
+    ///     .route("/search", get(|| async { "Results" }))
+    ///     .route_layer(
+    ///         RateLimitLayer::<()>::builder()
+    ///             .with_quota_resolver(|parts: &http::request::Parts| match parts.uri.path() {
+    ///                 "/login" => Some(Quota::per_minute(5)),
+    ///                 "/search" => Some(Quota::per_minute(100)),
+    ///                 _ => None,
+    ///             })
+    ///             .default_handle_error(),
+    ///     );
+    /// ```
+    #[must_use]
+    pub fn with_quota_resolver(mut self, resolver: impl QuotaResolver) -> Self {
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
 
-    /// Rate limiting error.
+    /// Set a [`CostResolver`] to select how many cells each request costs, such as for expensive endpoints.
     ///
-    /// This error is returned when the rate limiter has blocked the request,
-    /// and will be passed to the [error handler](RateLimitLayerBuilder::handle_error).
-    RateLimit(RateLimitError),
+    /// Without a resolver, every request costs a single cell.
+    /// Requests costing more than the burst size of their quota will always be rejected.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer};
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/export", get(|| async { "Exported" }))
+    ///     .route("/ping", get(|| async { "Pong" }))
+    ///     .route_layer(
+    ///         RateLimitLayer::<()>::builder()
+    ///             .with_default_quota(Quota::per_minute(20).with_burst(20))
+    ///             .with_global_fallback(true)
+    ///             .with_cost_resolver(|parts: &http::request::Parts| match parts.uri.path() {
+    ///                 "/export" => 10,
+    ///                 _ => 1,
+    ///             })
+    ///             .default_handle_error(),
+    ///     );
+    /// ```
+    #[must_use]
+    pub fn with_cost_resolver(mut self, cost: impl CostResolver) -> Self {
+        self.cost = Some(Box::new(cost));
+        self
+    }
 
-    /// Key extraction rejection.
-    KeyRejection(Rejection),
-}
+    /// Set the [`Clock`] used to get the time of each request, instead of [`Instant::now`].
+    ///
+    /// This is mainly useful for testing with a [`FakeClock`](clock::FakeClock).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::Router;
+    /// use axum_gcra::{clock::FakeClock, RateLimitLayer};
+    ///
+    /// let clock = FakeClock::new();
+    ///
+    /// let app = Router::<()>::new().route_layer(
+    ///     RateLimitLayer::<()>::builder().with_clock(clock.clone()).default_handle_error(),
+    /// );
+    ///
+    /// // ... make requests, then advance the clock to replenish the quota
+    /// clock.advance(std::time::Duration::from_secs(1));
+    /// ```
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
 
-impl<Inner, Rejection> IntoResponse for Error<Inner, Rejection>
-where
-    Inner: IntoResponse,
-    Rejection: IntoResponse,
-{
-    fn into_response(self) -> Response {
-        match self {
-            Error::RateLimit(e) => e.into_response(),
-            Error::KeyRejection(e) => e.into_response(),
-            Error::Inner(e) => e.into_response(),
-        }
+    /// Set whether to use a global fallback shared rate-limiter for all paths not explicitly defined.
+    #[must_use]
+    pub fn with_global_fallback(mut self, global_fallback: bool) -> Self {
+        self.global_fallback = global_fallback;
+        self
     }
-}
 
-use futures_util::{future::BoxFuture, TryFuture};
+    /// Set whether to insert rate limit headers into successful responses,
+    /// so clients can throttle themselves before being rate limited.
+    ///
+    /// When enabled, `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (in seconds)
+    /// are inserted, along with `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+    /// (as a Unix timestamp). The limit is the burst size of the quota, and the reset is the time
+    /// until the full burst capacity is restored.
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_rate_limit_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
 
-pin_project_lite::pin_project! {
-    #[doc(hidden)]
-    #[project = RateLimitedResponseProj]
-    pub enum RateLimitedResponse<B, I: Service<Request<B>>, K: FromRequestParts<()>> {
-        RateLimiting {
-            #[pin] f: BoxFuture<'static, Result<Parts, Error<I::Error, K::Rejection>>>,
+    /// Set which headers describe the rate limit, both in successful responses with
+    /// [`with_rate_limit_headers`](RateLimitLayerBuilder::with_rate_limit_headers) and in denials,
+    /// such as to emit the structured `RateLimit` and `RateLimit-Policy` headers of the IETF draft.
+    /// See [`HeaderStyle`](gcra::HeaderStyle) for more information.
+    ///
+    /// The default is [`HeaderStyle::Legacy`](gcra::HeaderStyle::Legacy).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{gcra::{HeaderStyle, Quota}, RateLimitLayer};
+    ///
+    /// // RateLimit: limit=100, remaining=99, reset=1
+    /// // RateLimit-Policy: 100;w=60
+    /// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+    ///     RateLimitLayer::<()>::builder()
+    ///         .with_default_quota(Quota::per_minute(100).with_burst(100))
+    ///         .with_rate_limit_headers(true)
+    ///         .with_header_style(HeaderStyle::Structured)
+    ///         .default_handle_error(),
+    /// );
+    /// ```
+    #[must_use]
+    pub fn with_header_style(mut self, style: gcra::HeaderStyle) -> Self {
+        self.header_style = style;
+        self
+    }
 
-            inner: I, // storing `I` separately helps avoid an `I: Sync` bound
-            body: Option<B>, // similar story, helps avoid `B: Send + 'static` bound
-        },
+    /// Set the body of denial responses, such as to respond with an RFC 9457 `application/problem+json`
+    /// document without a custom [rejection handler](RateLimitLayerBuilder::on_rejected).
+    /// See [`RejectionBody`](gcra::RejectionBody) for more information.
+    ///
+    /// The default is [`RejectionBody::Text`](gcra::RejectionBody::Text).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{gcra::{Quota, RejectionBody}, RateLimitLayer};
+    ///
+    /// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+    ///     RateLimitLayer::<()>::builder()
+    ///         .with_default_quota(Quota::per_second(10))
+    ///         .with_rejection_body(RejectionBody::ProblemJson)
+    ///         .default_handle_error(),
+    /// );
+    /// ```
+    #[must_use]
+    pub fn with_rejection_body(mut self, body: gcra::RejectionBody) -> Self {
+        self.rejection_body = body;
+        self
+    }
+
+    /// Set whether to insert the [`RateLimitStatus`](extensions::RateLimitStatus) extension into allowed requests,
+    /// so handlers can extract the remaining capacity for the key after the request was counted.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{RateLimitLayer, extensions::RateLimitStatus};
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/", get(|status: RateLimitStatus| async move {
+    ///         format!("{} requests remaining", status.remaining)
+    ///     }))
+    ///     .route_layer(RateLimitLayer::<()>::builder().with_status_extension(true).default_handle_error());
+    /// ```
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_status_extension(mut self, status: bool) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Set whether to insert the [`RateLimitKey`](extensions::RateLimitKey) extension into requests,
+    /// so handlers and later middleware can read the extracted key without extracting it again.
+    ///
+    /// The key is inserted as soon as it is extracted, so it is also present for requests
+    /// [exempt by key](RateLimitLayerBuilder::with_key_exemption) or passed through due to a store failure.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{RateLimitLayer, extensions::RateLimitKey, real_ip::RealIp};
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/", get(|RateLimitKey(ip): RateLimitKey<RealIp>| async move {
+    ///         format!("limited as {ip}")
+    ///     }))
+    ///     .route_layer(RateLimitLayer::<RealIp>::builder().with_key_extension(true).default_handle_error());
+    /// ```
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_key_extension(mut self, extension: bool) -> Self
+    where
+        K: Clone,
+    {
+        fn set_key<K: Key + Clone>(extensions: &mut http::Extensions, key: &K) {
+            extensions.insert(extensions::RateLimitKey(key.clone()));
+        }
 
-        Inner { #[pin] f: I::Future },
+        self.set_key = extension.then_some(set_key::<K>);
+        self
     }
-}
 
-impl<B, I, K> Future for RateLimitedResponse<B, I, K>
-where
-    I: Service<Request<B>, Future: TryFuture<Ok = I::Response, Error = I::Error>>,
-    K: FromRequestParts<()>,
-{
-    type Output = Result<I::Response, Error<I::Error, K::Rejection>>;
+    /// Set whether requests that exceed their quota are rejected immediately, or held until they would
+    /// be allowed, up to a maximum wait. See [`ThrottleMode`] for more information.
+    ///
+    /// While delayed, a request holds its connection and any resources of outer layers, so the maximum wait
+    /// should be kept short, and is best suited to internal services with well-behaved clients. Each delayed
+    /// request retries with a clone of its key, so is delayed again if other requests took its place meanwhile.
+    /// Requests are not delayed in [dry run](RateLimitLayerBuilder::with_dry_run) mode.
+    ///
+    /// The default is [`ThrottleMode::Reject`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer, ThrottleMode};
+    ///
+    /// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+    ///     RateLimitLayer::<()>::builder()
+    ///         .with_default_quota(Quota::per_second(100))
+    ///         .with_throttle_mode(ThrottleMode::Delay { max_wait: Duration::from_millis(250) })
+    ///         .default_handle_error(),
+    /// );
+    /// ```
+    #[cfg(feature = "tokio")]
+    #[must_use]
+    pub fn with_throttle_mode(mut self, mode: ThrottleMode) -> Self
+    where
+        K: Clone,
+    {
+        self.delay = match mode {
+            ThrottleMode::Reject => None,
+            ThrottleMode::Delay { max_wait } => Some((max_wait, RouteWithKey::clone)),
+        };
+        self
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        loop {
-            match self.as_mut().project() {
-                RateLimitedResponseProj::RateLimiting { inner, body, f } => match ready!(f.try_poll(cx)) {
-                    Ok(req) => {
-                        let req = Request::from_parts(req, body.take().expect("body is Some"));
-                        let f = inner.call(req);
-                        self.set(RateLimitedResponse::Inner { f })
-                    }
-                    Err(e) => return Poll::Ready(Err(e)),
-                },
-                RateLimitedResponseProj::Inner { f } => match ready!(f.try_poll(cx)) {
-                    Ok(ok) => return Poll::Ready(Ok(ok)),
-                    Err(e) => return Poll::Ready(Err(Error::Inner(e))),
-                },
+    /// Track the number of times each key is denied, available from [`RateLimitLayer::denied_count`]
+    /// and the [`DeniedCount`](extensions::DeniedCount) extension, such as to respond differently
+    /// to repeat offenders.
+    ///
+    /// A denial counts towards the previous ones if it comes within `window` of the last, so the count
+    /// resets once a key goes a full `window` without being denied. Counts are kept by the layer rather
+    /// than the store, and are pruned along with [garbage collection](RateLimitLayer::maintain).
+    ///
+    /// Tracking is disabled by default.
+    #[must_use]
+    pub fn with_denial_tracking(mut self, window: Duration) -> Self
+    where
+        K: Clone,
+    {
+        match self.denials {
+            Some(ref mut denials) => denials.window = window,
+            None => {
+                self.denials = Some(Denials {
+                    window,
+                    escalation: None,
+                    clone: K::clone,
+                    recorded: AtomicU64::new(1),
+                    counts: Default::default(),
+                })
+            }
+        }
+
+        self
+    }
+
+    /// Block keys for `block` once they have been denied `after` times, as counted by
+    /// [denial tracking](RateLimitLayerBuilder::with_denial_tracking), enabling it with a window
+    /// of one minute if not already enabled.
+    ///
+    /// Requests of a blocked key are denied without consulting the [`Store`](store::Store), and still count
+    /// as denials. Until the key goes a full window without being denied, each further denial after the block
+    /// blocks it again, so repeat offenders are held off for longer than the quota alone would allow.
+    ///
+    /// Escalation is disabled by default, and never blocks requests
+    /// in [dry run](RateLimitLayerBuilder::with_dry_run). Blocks are capped at the roughly 584 years
+    /// representable in nanoseconds, as with [`Quota`](gcra::Quota).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{gcra::Quota, real_ip::RealIp, RateLimitLayer};
+    ///
+    /// // block clients for 10 minutes after 20 denials, each within a minute of the last
+    /// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+    ///     RateLimitLayer::<RealIp>::builder()
+    ///         .with_default_quota(Quota::per_second(10))
+    ///         .with_escalation(20, Duration::from_secs(600))
+    ///         .default_handle_error(),
+    /// );
+    /// ```
+    #[must_use]
+    pub fn with_escalation(mut self, after: u64, block: Duration) -> Self
+    where
+        K: Clone,
+    {
+        if self.denials.is_none() {
+            self = self.with_denial_tracking(Duration::from_secs(60));
+        }
+
+        if let Some(ref mut denials) = self.denials {
+            denials.escalation = Some((after.max(1), block.min(Duration::from_nanos(u64::MAX))));
+        }
+
+        self
+    }
+
+    /// Set whether to allow requests through when the [`Store`](store::Store) fails, such as when
+    /// a remote store is unreachable.
+    ///
+    /// When `false` (fail-closed), store failures are returned as [`Error::Store`],
+    /// which responds with `503 Service Unavailable` by default, distinct from the `429 Too Many Requests`
+    /// of an actual rate limit. When `true` (fail-open), the request is passed to the inner service as if
+    /// it were allowed, without rate limit headers or the [`RateLimiter`](extensions::RateLimiter) extension.
+    ///
+    /// The default is `false`. The in-memory store never fails, so this only affects other stores.
+    #[must_use]
+    pub fn with_fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    /// Set whether to only observe rate limiting decisions without enforcing them, such as to tune
+    /// a new quota against real traffic before enabling it.
+    ///
+    /// In dry run mode, every request is passed to the inner service. Requests are still counted
+    /// against the store exactly as when enforcing, and the
+    /// [decision hook](RateLimitLayerBuilder::with_on_decision) and metrics still report each denial,
+    /// but denied requests are let through without the [`RateLimiter`](extensions::RateLimiter) extension.
+    /// If [enabled](RateLimitLayerBuilder::with_rate_limit_headers), their rate limit headers report
+    /// no remaining requests, resetting once the request would have been allowed. As with enforcement,
+    /// denied requests do not consume any capacity, so the observed decisions match what enforcement
+    /// would have done.
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Set whether the layer starts out enforcing rate limits, as later changed at runtime
+    /// with [`RateLimitLayer::set_enabled`].
+    ///
+    /// The default is `true`.
+    #[must_use]
+    pub fn with_enabled(self, enabled: bool) -> Self {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Set whether requests are still counted while the layer is [disabled](RateLimitLayer::set_enabled),
+    /// as in [dry run](RateLimitLayerBuilder::with_dry_run) mode, so requests keep their rate limit
+    /// headers and clients can see how close they would be to the limit.
+    ///
+    /// When `false`, a disabled layer passes requests straight through without consulting the store.
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_headers_when_disabled(mut self, headers: bool) -> Self {
+        self.headers_when_disabled = headers;
+        self
+    }
+
+    /// Set how the wait is rounded in the `Retry-After` and `RateLimit-Reset` headers of rate limited requests.
+    ///
+    /// The default is [`RetryAfter::Ceil`](gcra::RetryAfter::Ceil).
+    /// Use [`RetryAfter::ExactMs`](gcra::RetryAfter::ExactMs) to also insert `X-RateLimit-Reset-After`
+    /// with the wait in milliseconds, so clients that understand it need not wait for the next whole second.
+    #[must_use]
+    pub fn with_retry_after(mut self, rounding: gcra::RetryAfter) -> Self {
+        self.retry_after = rounding;
+        self
+    }
+
+    /// Add up to `fraction` of random jitter to the wait time of rate limited requests, so that
+    /// clients throttled at the same instant do not all retry in lockstep.
+    ///
+    /// The jitter only ever lengthens the wait, so `Retry-After` and `X-RateLimit-Reset` are never earlier
+    /// than the true time the next request would be allowed. For example, `0.1` adds between 0% and 10%
+    /// to each wait. The fraction is clamped between `0.0` and `1.0`.
+    ///
+    /// The default is `0.0`, which disables jitter.
+    #[must_use]
+    pub fn with_retry_jitter(mut self, fraction: f64) -> Self {
+        // NaN is treated as zero
+        self.jitter = if fraction > 0.0 { fraction.min(1.0) } else { 0.0 };
+        self
+    }
+
+    /// Set the source of random numbers for the [retry jitter](RateLimitLayerBuilder::with_retry_jitter),
+    /// which must return values between `0.0` and `1.0`. This is mostly useful for deterministic tests.
+    ///
+    /// By default, a fast non-cryptographic generator is used.
+    #[must_use]
+    pub fn with_jitter_source<F>(mut self, source: F) -> Self
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.jitter_source = Some(Box::new(source));
+        self
+    }
+
+    /// Set whether to insert the [`RateLimiter`](extensions::RateLimiter) extension into the request
+    /// to allow for manual rate limiting control downstream.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use std::time::Duration;
+    /// use axum::{extract::Extension, routing::get, Router};
+    /// use axum_gcra::{RateLimitLayer, extensions::RateLimiter};
+    ///
+    /// // Note this must be identical to the key used in the rate limiter layer
+    /// type Key = ();
+    ///
+    /// let app = Router::<()>::new()
+    ///     // access the rate limiter for this request
+    ///     .route("/", get(|rl: Extension<RateLimiter<Key>>| async move {
+    ///         rl.penalize(Duration::from_secs(50)).await;
+    ///     }))
+    ///     .route_layer(RateLimitLayer::<Key>::builder().with_extension(true).default_handle_error());
+    #[must_use]
+    pub fn with_extension(mut self, extend: bool) -> Self
+    where
+        K: Clone,
+        H: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        self.set_ext = match extend {
+            true => Some(Box::new(DoSetExtension) as Box<dyn SetExtension<K, H, S>>),
+            false => None,
+        };
+        self
+    }
+
+    /// Set a callback to observe every rate limiting decision, such as to record metrics.
+    ///
+    /// The callback receives the full key of the request, and either the remaining capacity
+    /// if the request was allowed, or the [`RateLimitError`] if it was denied. It is called exactly once
+    /// for each request that reaches the store, but not for key rejections or store failures.
+    ///
+    /// The callback is called on the request path, so should be cheap, and the key is cloned for each request.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// use axum::Router;
+    /// use axum_gcra::RateLimitLayer;
+    ///
+    /// static ALLOWED: AtomicU64 = AtomicU64::new(0);
+    /// static DENIED: AtomicU64 = AtomicU64::new(0);
+    ///
+    /// let app = Router::<()>::new().route_layer(
+    ///     RateLimitLayer::<()>::builder()
+    ///         .with_on_decision(|key, decision| {
+    ///             println!("{} {}: {:?}", key.method(), key.path(), decision);
+    ///
+    ///             match decision {
+    ///                 Ok(_) => ALLOWED.fetch_add(1, Ordering::Relaxed),
+    ///                 Err(_) => DENIED.fetch_add(1, Ordering::Relaxed),
+    ///             };
+    ///         })
+    ///         .default_handle_error(),
+    /// );
+    /// ```
+    #[must_use]
+    pub fn with_on_decision<F>(mut self, cb: F) -> Self
+    where
+        K: Clone,
+        F: Fn(&RouteWithKey<K>, Result<gcra::Capacity, RateLimitError>) + Send + Sync + 'static,
+    {
+        self.on_decision = Some(Box::new(DecisionHook(cb)));
+        self
+    }
+
+    /// Set how the key is formatted in the [`tracing`](https://docs.rs/tracing) event for each decision,
+    /// such as to redact or hash personally identifiable information like addresses or user ids.
+    ///
+    /// Without this, the `key` field is omitted from the events. The callback is only called
+    /// when the events are enabled for the `axum_gcra` target at `DEBUG` level or more verbose.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum_gcra::{real_ip::RealIpPrivacyMask, RateLimitLayer};
+    ///
+    /// // log only the network part of IPv6 addresses
+    /// let layer = RateLimitLayer::<RealIpPrivacyMask>::builder().with_trace_key(|ip| ip.to_string());
+    /// ```
+    #[cfg(feature = "tracing")]
+    #[must_use]
+    pub fn with_trace_key<F>(mut self, format: F) -> Self
+    where
+        F: Fn(&K) -> String + Send + Sync + 'static,
+    {
+        self.trace_key = Some(Box::new(format));
+        self
+    }
+
+    /// Set a predicate to exempt requests from rate limiting entirely, such as health checks
+    /// or metrics scraping, replacing any previous predicate.
+    ///
+    /// Exempt requests are passed straight to the inner service before the key is extracted,
+    /// so they never count against any bucket, emit rate limit headers, or receive the
+    /// [`RateLimiter`](extensions::RateLimiter) extension.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::RateLimitLayer;
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/healthz", get(|| async { "OK" }))
+    ///     .route_layer(
+    ///         RateLimitLayer::<()>::builder()
+    ///             .with_exemption(|parts| parts.uri.path() == "/healthz")
+    ///             .default_handle_error(),
+    ///     );
+    /// ```
+    #[must_use]
+    pub fn with_exemption<F>(mut self, exempt: F) -> Self
+    where
+        F: Fn(&Parts) -> bool + Send + Sync + 'static,
+    {
+        self.exempt = Some(Box::new(exempt));
+        self
+    }
+
+    /// Set a predicate to exempt keys from rate limiting entirely, such as an allowlisted admin address,
+    /// replacing any previous predicate.
+    ///
+    /// The predicate is checked after the key is extracted, and exempt requests are treated
+    /// as for [`with_exemption`](RateLimitLayerBuilder::with_exemption).
+    #[must_use]
+    pub fn with_key_exemption<F>(mut self, exempt: F) -> Self
+    where
+        F: Fn(&K) -> bool + Send + Sync + 'static,
+    {
+        self.exempt_key = Some(Box::new(exempt));
+        self
+    }
+
+    /// Set the address ranges exempt from rate limiting entirely, such as office networks
+    /// or monitoring subnets, replacing any previous ranges.
+    ///
+    /// The client address is resolved as by the [`RealIp`] extractor, using the configuration of
+    /// any [`RealIpLayer`](real_ip::RealIpLayer) in front of this layer, regardless of the key type.
+    /// Requests without a resolvable address are not exempt. Exempt requests are treated as for
+    /// [`with_exemption`](RateLimitLayerBuilder::with_exemption), which is checked first.
+    ///
+    /// Make sure the address cannot be spoofed by clients, such as by only trusting headers set
+    /// by your own proxies, or anyone can bypass the rate limit.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{routing::get, Router};
+    /// use axum_gcra::{real_ip::{Cidr, RealIp}, RateLimitLayer};
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/", get(|| async { "Hello, World!" }))
+    ///     .route_layer(
+    ///         RateLimitLayer::<RealIp>::builder()
+    ///             .with_cidr_exemption(["203.0.113.0/24".parse::<Cidr>().unwrap()])
+    ///             .default_handle_error(),
+    ///     );
+    /// ```
+    #[cfg(feature = "real_ip")]
+    #[must_use]
+    pub fn with_cidr_exemption(mut self, cidrs: impl IntoIterator<Item = real_ip::Cidr>) -> Self {
+        self.exempt_cidrs = cidrs.into_iter().collect();
+        self
+    }
+
+    /// Set whether to echo the client address in an `x-ratelimit-client` header of rejections,
+    /// so users can report exactly which address was throttled when contacting support.
+    ///
+    /// The address is resolved as by the [`RealIp`] extractor, reusing the address
+    /// already resolved by any [`RealIpLayer`](real_ip::RealIpLayer) in front of this layer, regardless of
+    /// the key type. The header is omitted if no address could be resolved.
+    ///
+    /// This reveals the address seen by the server, such as that of a proxy the client did not intend to expose,
+    /// to anyone able to read the response. Use [`with_masked_client_ip_header`] to echo only the network part.
+    /// The default is `false`.
+    ///
+    /// [`with_masked_client_ip_header`]: RateLimitLayerBuilder::with_masked_client_ip_header
+    #[cfg(feature = "real_ip")]
+    #[must_use]
+    pub fn with_client_ip_header(mut self, enable: bool) -> Self {
+        self.client_ip = enable.then_some(|ip: real_ip::RealIp| ip.0);
+        self
+    }
+
+    /// Echo the client address in an `x-ratelimit-client` header of rejections, as by
+    /// [`with_client_ip_header`](RateLimitLayerBuilder::with_client_ip_header), but masked as by
+    /// [`RealIpMask<V4_PREFIX, V6_PREFIX>`](real_ip::RealIpMask), such as to match a masked key.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum_gcra::{real_ip::RealIpPrivacyMask, RateLimitLayer};
+    ///
+    /// // echo only the /64 of IPv6 clients, as used for the key
+    /// let builder = RateLimitLayer::<RealIpPrivacyMask>::builder().with_masked_client_ip_header::<32, 64>();
+    /// ```
+    #[cfg(feature = "real_ip")]
+    #[must_use]
+    pub fn with_masked_client_ip_header<const V4_PREFIX: u8, const V6_PREFIX: u8>(mut self) -> Self {
+        fn mask<const V4_PREFIX: u8, const V6_PREFIX: u8>(ip: real_ip::RealIp) -> std::net::IpAddr {
+            real_ip::RealIpMask::<V4_PREFIX, V6_PREFIX>::from(ip).0 .0
+        }
+
+        self.client_ip = Some(mask::<V4_PREFIX, V6_PREFIX>);
+        self
+    }
+}
+
+impl<H: BuildHasher, S> RateLimitLayerBuilder<(), H, S> {
+    /// Set whether to signal backpressure from [`Service::poll_ready`] while the limiter is saturated,
+    /// so that upstream layers such as [`tower::load_shed`] can shed or queue requests before they
+    /// reach the limiter.
+    ///
+    /// After a request is rate limited, the service reports [`Poll::Pending`] until the time that
+    /// request would have been allowed, then becomes ready again. This is only available for
+    /// global (keyless) limiters, since a saturated limit for one key says nothing about the next request.
+    ///
+    /// # Caveats
+    ///
+    /// - The request that exceeds the limit is still rejected as usual, only later requests see backpressure.
+    /// - Saturation is shared by every route and method behind the layer, so per-route quotas
+    ///   will hold back all routes while any one of them is saturated.
+    /// - Without an upstream layer that sheds or buffers, requests simply wait for the limiter,
+    ///   which turns rate limiting into throttling.
+    /// - The wait is measured with the configured [`Clock`], but the timer uses tokio's clock.
+    ///
+    /// The default is `false`.
+    ///
+    /// [`tower::load_shed`]: https://docs.rs/tower/0.5/tower/load_shed/index.html
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{error_handling::HandleErrorLayer, http::StatusCode, Router};
+    /// use axum_gcra::{gcra::Quota, RateLimitLayer};
+    /// use tower::ServiceBuilder;
+    ///
+    /// let app = Router::<()>::new().layer(
+    ///     ServiceBuilder::new()
+    ///         .layer(HandleErrorLayer::new(|_| async { StatusCode::SERVICE_UNAVAILABLE }))
+    ///         .load_shed()
+    ///         .layer(
+    ///             RateLimitLayer::<()>::builder()
+    ///                 .with_default_quota(Quota::per_second(100))
+    ///                 .with_global_fallback(true)
+    ///                 .with_global_backpressure(true)
+    ///                 .default_handle_error(),
+    ///         ),
+    /// );
+    /// ```
+    #[cfg(feature = "tokio")]
+    #[must_use]
+    pub fn with_global_backpressure(mut self, backpressure: bool) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+}
+
+impl Default for RateLimitLayerBuilder<()> {
+    fn default() -> Self {
+        RateLimitLayerBuilder::new()
+    }
+}
+
+/// Error wrapper for rate limiting errors or inner service errors.
+#[derive(Debug)]
+pub enum Error<Inner, Rejection> {
+    /// Inner service error.
+    ///
+    /// For most axum services, this will be a [`Infallible`].
+    Inner(Inner),
+
+    /// Rate limiting error.
+    ///
+    /// This error is returned when the rate limiter has blocked the request,
+    /// and will be passed to the [error handler](RateLimitLayerBuilder::handle_error),
+    /// along with the quota that was in effect.
+    RateLimit(NotUntil),
+
+    /// Rate limiter [`Store`](store::Store) error, such as a lost connection to a remote store.
+    ///
+    /// Responds with `503 Service Unavailable` by default. Not returned if the layer
+    /// is configured to [fail open](RateLimitLayerBuilder::with_fail_open).
+    Store(tower::BoxError),
+
+    /// Key extraction rejection.
+    KeyRejection(Rejection),
+}
+
+impl<Inner, Rejection> IntoResponse for Error<Inner, Rejection>
+where
+    Inner: IntoResponse,
+    Rejection: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        match self {
+            Error::RateLimit(e) => e.into_response(),
+            Error::KeyRejection(e) => e.into_response(),
+            Error::Store(_) => http::StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            Error::Inner(e) => e.into_response(),
+        }
+    }
+}
+
+use futures_util::{future::BoxFuture, TryFuture};
+
+/// Remaining capacity of an allowed request, with the quota and style to describe it in the response headers.
+type Allowed = (gcra::Capacity, gcra::Quota, gcra::HeaderStyle);
+
+pin_project_lite::pin_project! {
+    #[doc(hidden)]
+    #[project = RateLimitedResponseProj]
+    pub enum RateLimitedResponse<B, I: Service<Request<B>>, K: FromRequestParts<()>> {
+        RateLimiting {
+            #[pin] f: BoxFuture<'static, Result<(Parts, Option<Allowed>), Error<I::Error, K::Rejection>>>,
+
+            inner: I, // storing `I` separately helps avoid an `I: Sync` bound
+            body: Option<B>, // similar story, helps avoid `B: Send + 'static` bound
+        },
+
+        Inner { #[pin] f: I::Future, allowed: Option<Allowed> },
+    }
+}
+
+impl<B, I, K, ResB> Future for RateLimitedResponse<B, I, K>
+where
+    I: Service<Request<B>, Response = Response<ResB>, Future: TryFuture<Ok = I::Response, Error = I::Error>>,
+    K: FromRequestParts<()>,
+{
+    type Output = Result<I::Response, Error<I::Error, K::Rejection>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.as_mut().project() {
+                RateLimitedResponseProj::RateLimiting { inner, body, f } => match ready!(f.try_poll(cx)) {
+                    Ok((req, allowed)) => {
+                        let req = Request::from_parts(req, body.take().expect("body is Some"));
+                        let f = inner.call(req);
+                        self.set(RateLimitedResponse::Inner { f, allowed })
+                    }
+                    Err(e) => return Poll::Ready(Err(e)),
+                },
+                RateLimitedResponseProj::Inner { f, allowed } => match ready!(f.try_poll(cx)) {
+                    Ok(mut res) => {
+                        if let Some((capacity, quota, style)) = *allowed {
+                            capacity.insert_headers_with(res.headers_mut(), quota, style);
+                        }
+
+                        return Poll::Ready(Ok(res));
+                    }
+                    Err(e) => return Poll::Ready(Err(Error::Inner(e))),
+                },
+            }
+        }
+    }
+}
+
+impl<K: Key, H: BuildHasher, S> RateLimitLayer<K, H, S> {
+    /// Get the current time from the configured [`Clock`].
+    #[inline]
+    fn now(&self) -> Instant {
+        self.builder.clock.now()
+    }
+
+    /// Lengthen the wait time by the configured retry jitter, if any.
+    fn jitter(&self, error: RateLimitError) -> RateLimitError {
+        if self.builder.jitter == 0.0 {
+            return error;
+        }
+
+        let r = match self.builder.jitter_source {
+            Some(ref source) => source(),
+            None => random_unit(),
+        };
+
+        let extra = error.0.get() as f64 * self.builder.jitter * r.clamp(0.0, 1.0);
+
+        RateLimitError(error.0.saturating_add(extra as u64))
+    }
+
+    /// Resolve the quota for the request, switching the key to the global fallback if needed.
+    fn resolve_quota(&self, parts: &Parts, key: &mut RouteWithKey<K>) -> gcra::Quota {
+        if let Some(quota) = self.builder.key_quotas.read(&key.key, |_, quota| *quota) {
+            return quota;
+        }
+
+        if let Some(quota) = self.builder.resolver.as_ref().and_then(|r| r.resolve(parts)) {
+            return quota;
+        }
+
+        match self.builder.quotas.get(&key.as_route()).copied() {
+            Some(quota) => quota,
+            None => {
+                if self.builder.global_fallback {
+                    key.path = MatchedPath::Fallback;
+                }
+
+                self.builder.default_quota
+            }
+        }
+    }
+}
+
+async fn get_user_key<K>(parts: &mut Parts) -> Result<K, K::Rejection>
+where
+    K: Key + FromRequestParts<()>,
+{
+    use core::mem::{size_of, transmute_copy};
+
+    #[inline(always)]
+    fn same_ty<A: 'static, B: 'static>() -> bool {
+        let b = TypeId::of::<B>();
+
+        // check same type or 1-tuple of the same layout
+        TypeId::of::<A>() == b || (TypeId::of::<(A,)>() == b && size_of::<A>() == size_of::<B>())
+    }
+
+    // poor man's specialization
+
+    if same_ty::<K, ()>() {
+        return Ok(unsafe { transmute_copy::<_, K>(&()) });
+    }
+
+    #[cfg(feature = "real_ip")]
+    if same_ty::<K, real_ip::RealIp>() {
+        #[rustfmt::skip]
+        let ip = parts.extensions.get::<real_ip::RealIp>().copied()
+            .or_else(|| real_ip::get_ip_from_parts(parts));
+
+        if let Some(ip) = ip {
+            return Ok(unsafe { transmute_copy::<_, K>(&ip) });
+        }
+    }
+
+    #[cfg(feature = "real_ip")]
+    if same_ty::<K, real_ip::RealIpPrivacyMask>() {
+        #[rustfmt::skip]
+        let ip = parts.extensions.get::<real_ip::RealIp>().copied()
+            .or_else(|| real_ip::get_ip_from_parts(parts));
+
+        if let Some(ip) = ip {
+            return Ok(unsafe { transmute_copy::<_, K>(&real_ip::RealIpPrivacyMask::from(ip)) });
+        }
+    }
+
+    match K::from_request_parts(parts, &()).await {
+        Ok(key) => Ok(key),
+        Err(rejection) => Err(rejection),
+    }
+}
+
+impl<I, K, B, H, S, ResB> Service<Request<B>> for RateLimitService<I, K, H, S>
+where
+    I: Service<Request<B>, Response = Response<ResB>, Future: TryFuture<Ok = I::Response, Error = I::Error>>
+        + Clone
+        + Send
+        + 'static,
+    K: Key + FromRequestParts<()>,
+    H: BuildHasher + Send + Sync + 'static,
+    S: store::Store<RouteWithKey<K>>,
+{
+    type Response = I::Response;
+    type Error = Error<I::Error, K::Rejection>;
+    type Future = RateLimitedResponse<B, I, K>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        #[cfg(feature = "tokio")]
+        if self.layer.builder.backpressure {
+            ready!(self.poll_backpressure(cx));
+        }
+
+        match self.inner.poll_ready(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::Inner(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // try to get the current time as close as possible to the request
+        let now = self.layer.now();
+
+        let (mut parts, body) = req.into_parts();
+
+        let layer = self.layer.clone();
+
+        RateLimitedResponse::RateLimiting {
+            inner: self.inner.clone(),
+            body: Some(body), // once told me
+
+            f: Box::pin(async move {
+                let allowed = layer.limit(&mut parts, now).await?.filter(|_| layer.builder.headers);
+
+                Ok((
+                    parts,
+                    allowed.map(|(capacity, quota)| (capacity, quota, layer.builder.header_style)),
+                ))
+            }),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<I, K: Key, H: BuildHasher, S> RateLimitService<I, K, H, S> {
+    /// Wait until the limiter is no longer saturated, re-arming the timer if it was saturated again meanwhile.
+    fn poll_backpressure(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(ref mut sleep) = self.backpressure {
+                ready!(sleep.as_mut().poll(cx));
+                self.backpressure = None;
+            }
+
+            let saturated = *self.layer.builder.saturated.lock().unwrap_or_else(|e| e.into_inner());
+            let now = self.layer.now();
+
+            match saturated {
+                Some(until) if until > now => {
+                    self.backpressure = Some(Box::pin(tokio::time::sleep(until - now)));
+                }
+                _ => return Poll::Ready(()),
+            }
+        }
+    }
+}
+
+impl<K, H, S> RateLimitLayer<K, H, S>
+where
+    K: Key + FromRequestParts<()>,
+    H: BuildHasher + Send + Sync + 'static,
+    S: store::Store<RouteWithKey<K>>,
+{
+    /// Apply the rate limit to the request, returning the remaining capacity and quota if it was counted,
+    /// or `None` if it was exempt, the store failed open, or it was denied in dry run mode.
+    pub(crate) async fn limit<E>(
+        &self,
+        parts: &mut Parts,
+        #[allow(unused_mut)] mut now: Instant,
+    ) -> Result<Option<(gcra::Capacity, gcra::Quota)>, Error<E, K::Rejection>> {
+        // before anything else, so even exempt requests are known to have passed through the layer
+        parts.extensions.insert(extensions::LayerApplied);
+
+        // a disabled layer either skips everything or observes as in dry run
+        let enabled = self.builder.enabled.load(Ordering::Relaxed);
+        let dry_run = self.builder.dry_run || !enabled;
+
+        if !enabled && !self.builder.headers_when_disabled {
+            return Ok(None);
+        }
+
+        if self.builder.exempt.as_ref().is_some_and(|exempt| exempt(parts)) {
+            return Ok(None);
+        }
+
+        #[cfg(feature = "real_ip")]
+        if !self.builder.exempt_cidrs.is_empty() {
+            let cidrs = &self.builder.exempt_cidrs;
+
+            if real_ip::RealIp::lookup(parts).is_some_and(|ip| ip.in_any_cidr(cidrs)) {
+                return Ok(None);
+            }
+        }
+
+        let path = match parts.extensions.get::<AxumMatchedPath>() {
+            Some(path) => MatchedPath::Axum(path.clone()),
+            None => MatchedPath::Fallback,
+        };
+
+        let mut key = RouteWithKey {
+            key: get_user_key(parts).await.map_err(Error::KeyRejection)?,
+            path,
+            method: parts.method.clone(),
+        };
+
+        if let Some(set_key) = self.builder.set_key {
+            set_key(&mut parts.extensions, &key.key);
+        }
+
+        if self.builder.exempt_key.as_ref().is_some_and(|exempt| exempt(&key.key)) {
+            return Ok(None);
+        }
+
+        let quota = self.resolve_quota(parts, &mut key);
+        let cost = self.builder.cost.as_ref().map_or(1, |c| c.cost(parts));
+        let observed = self.builder.on_decision.as_ref().map(|hook| (hook, hook.clone_key(&key)));
+
+        #[cfg(feature = "tracing")]
+        let traced = tracing::enabled!(target: "axum_gcra", tracing::Level::DEBUG).then(|| {
+            let name = self.builder.trace_key.as_ref().map(|format| format(&key.key));
+            (name, key.path.clone(), key.method.clone())
+        });
+
+        #[cfg(feature = "tokio")]
+        let delay = match self.builder.delay {
+            Some((max_wait, clone)) if !dry_run => Some((max_wait, clone, clone(&key))),
+            _ => None,
+        };
+
+        let denials = self.builder.denials.as_ref().map(|denials| (denials, (denials.clone)(&key.key)));
+
+        let blocked = match denials {
+            Some((denials, ref key)) if !dry_run => denials.blocked(key, now),
+            _ => None,
+        };
+
+        #[allow(unused_mut)]
+        let mut res = match blocked {
+            Some(e) => Ok(Err(e)),
+            None => self.req(parts, key, quota, cost, now).await,
+        };
+
+        #[cfg(feature = "tokio")]
+        if let Some((max_wait, clone, key)) = delay {
+            // an unrepresentable deadline waits for as long as it takes
+            let deadline = now.checked_add(max_wait);
+            let within = |now: Instant, wait: Duration| match (deadline, now.checked_add(wait)) {
+                (Some(deadline), Some(at)) => at <= deadline,
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+
+            loop {
+                let wait = match res {
+                    Ok(Err(e)) if within(now, e.as_duration()) => e.as_duration(),
+                    _ => break,
+                };
+
+                self.builder.clock.sleep(wait).await;
+
+                now = self.now();
+                res = self.req(parts, clone(&key), quota, cost, now).await;
+            }
+        }
+
+        if let Some((denials, key)) = denials {
+            match res {
+                Ok(Err(_)) => denials.record(key, now).await,
+                Ok(Ok(_)) => {
+                    let count = denials.count(&key, now);
+                    parts.extensions.insert(extensions::DeniedCount(count));
+                }
+                Err(_) => {}
+            }
+        }
+
+        if let (Some((hook, key)), Ok(decision)) = (observed, &res) {
+            hook.on_decision(&key, *decision);
+        }
+
+        #[cfg(feature = "metrics")]
+        record_metrics(&res);
+
+        #[cfg(feature = "tracing")]
+        if let Some((name, path, method)) = traced {
+            trace_decision(name.as_deref(), &path, &method, &res, dry_run);
+        }
+
+        match res {
+            Ok(Ok(capacity)) => {
+                if self.builder.status {
+                    parts.extensions.insert(extensions::RateLimitStatus(capacity));
+                }
+
+                Ok(Some((capacity, quota)))
+            }
+            // let through with the headers enforcement would have sent, but no `RateLimiter` extension
+            Ok(Err(e)) if dry_run => Ok(Some((
+                gcra::Capacity {
+                    limit: quota.burst(),
+                    remaining: 0,
+                    reset: e.as_duration(),
+                },
+                quota,
+            ))),
+            Ok(Err(e)) => {
+                let e = NotUntil::new(self.jitter(e), quota, now)
+                    .with_retry_after(self.builder.retry_after)
+                    .with_header_style(self.builder.header_style)
+                    .with_body(self.builder.rejection_body);
+
+                #[cfg(feature = "real_ip")]
+                let e = match self.builder.client_ip {
+                    Some(mask) => e.with_client_ip(real_ip::RealIp::lookup(parts).map(mask)),
+                    None => e,
+                };
+
+                #[cfg(feature = "tokio")]
+                if self.builder.backpressure {
+                    let mut saturated = self.builder.saturated.lock().unwrap_or_else(|e| e.into_inner());
+                    *saturated = Ord::max(*saturated, Some(e.earliest_possible()));
+                }
+
+                Err(Error::RateLimit(e))
+            }
+            Err(_) if self.builder.fail_open => Ok(None),
+            Err(e) => Err(Error::Store(e)),
+        }
+    }
+
+    /// Perform the request against the store, setting the extension if it was allowed.
+    ///
+    /// Store errors are boxed right away, so the result can be held across a delay.
+    async fn req(
+        &self,
+        parts: &mut Parts,
+        key: RouteWithKey<K>,
+        quota: gcra::Quota,
+        cost: u64,
+        now: Instant,
+    ) -> Result<Result<gcra::Capacity, RateLimitError>, tower::BoxError> {
+        store::Store::req(&*self.limiter, key, quota, cost, now, |key| {
+            if let Some(ref set_ext) = self.builder.set_ext {
+                // set_extension will clone the key internally
+                set_ext.set_extension(&mut parts.extensions, key, quota, self.clone());
+            }
+        })
+        .await
+        .map_err(Into::into)
+    }
+}
+
+/// Uniformly distributed random number in `[0, 1)`, for the retry jitter.
+fn random_unit() -> f64 {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    };
+
+    static STATE: OnceLock<foldhash::quality::RandomState> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let state = STATE.get_or_init(Default::default);
+    let bits = state.hash_one(COUNTER.fetch_add(1, Ordering::Relaxed));
+
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(feature = "metrics")]
+fn record_metrics<E>(res: &Result<Result<gcra::Capacity, RateLimitError>, E>) {
+    let outcome = match res {
+        Ok(Ok(_)) => "allowed",
+        Ok(Err(_)) => "throttled",
+        Err(_) => "error",
+    };
+
+    metrics::counter!("axum_gcra.requests_total", "outcome" => outcome).increment(1);
+
+    if outcome == "throttled" {
+        metrics::counter!("axum_gcra.throttled_total").increment(1);
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn trace_decision<E>(
+    key: Option<&str>,
+    path: &str,
+    method: &Method,
+    res: &Result<Result<gcra::Capacity, RateLimitError>, E>,
+    dry_run: bool,
+) {
+    match res {
+        Ok(Ok(capacity)) => {
+            let remaining = capacity.remaining;
+            tracing::trace!(target: "axum_gcra", key, path, %method, remaining, "request allowed");
+        }
+        Ok(Err(e)) => {
+            let retry_after_ms = e.as_duration().as_millis() as u64;
+            tracing::debug!(target: "axum_gcra", key, path, %method, retry_after_ms, dry_run, "request throttled");
+        }
+        Err(_) => tracing::warn!(target: "axum_gcra", key, path, %method, "rate limit store failed"),
+    }
+}
+
+impl<K, I, H, S> Layer<I> for RateLimitLayer<K, H, S>
+where
+    K: Key,
+    H: BuildHasher,
+{
+    type Service = RateLimitService<I, K, H, S>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        RateLimitService {
+            inner,
+            layer: self.clone(),
+
+            #[cfg(feature = "tokio")]
+            backpressure: None,
+        }
+    }
+}
+
+use tower::layer::util::Stack;
+
+impl<K, H: BuildHasher, S> RateLimitLayerBuilder<K, H, S>
+where
+    K: Key + FromRequestParts<()>,
+    H: Send + Sync + 'static,
+    S: store::Store<RouteWithKey<K>>,
+{
+    /// Build the [`RateLimitLayer`].
+    ///
+    /// This will create a new rate limiter and, if the `tokio` feature is
+    /// enabled and the [GC interval](RateLimitLayerBuilder::with_gc_interval)
+    /// is a time [`Duration`], spawn a background task for garbage collection.
+    ///
+    /// By itself, `RateLimitLayer` cannot be directly inserted into an [`axum::Router`],
+    /// as it requires a [`HandleErrorLayer`] to handle rate limiting errors.
+    /// Use [`RateLimitLayerBuilder::handle_error`] or [`RateLimitLayerBuilder::default_handle_error`] to create a stack
+    /// with the rate limiter layer and the error-handler layer combined.
+    #[must_use]
+    pub fn build(mut self) -> RateLimitLayer<K, H, S> {
+        let limiter = self.store.take().expect("store is Some until built");
+
+        #[cfg(feature = "tokio")]
+        if let GCInterval::Time(d) = self.gc_interval {
+            let limiter = limiter.clone();
+            let signal = self.shutdown.clone();
+            let clock = self.clock.clone();
+
+            _ = tokio::task::spawn(async move {
+                let mut interval = tokio::time::interval(d);
+                loop {
+                    tokio::select! { biased;
+                        _ = signal.notify.notified() => break,
+                        _ = interval.tick() => {},
+                    }
+
+                    store::Store::clean(&*limiter, clock.now()).await;
+
+                    // also close task if no more references to the limiter
+                    if Arc::strong_count(&limiter) == 1 {
+                        break;
+                    }
+                }
+            });
+        }
+
+        RateLimitLayer {
+            limiter,
+            builder: Arc::new(self),
+        }
+    }
+
+    /// Create a new rate limiter layer with the provided error-handler callback.
+    ///
+    /// Returns a [`Stack`]-ed layer with the rate limiter layer and the error-handler layer combined
+    /// that can be directly inserted into an [`axum::Router`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{Router, http::StatusCode};
+    /// use axum_gcra::RateLimitLayer;
+    ///
+    /// let builder = RateLimitLayer::<()>::builder();
+    ///
+    /// let app = Router::<()>::new().route_layer(
+    ///    builder.handle_error(|e| async move {
+    ///       StatusCode::TOO_MANY_REQUESTS
+    ///    }));
+    /// ```
+    #[must_use]
+    pub fn handle_error<F, R>(self, cb: F) -> Stack<RateLimitLayer<K, H, S>, HandleErrorLayer<F, ()>>
+    where
+        F: Fn(Error<Infallible, K::Rejection>) -> R + Clone,
+    {
+        Stack::new(self.build(), HandleErrorLayer::new(cb))
+    }
+
+    /// Create a new rate limiter layer with the default error-handler callback that simply returns the error
+    /// as a [`Response`].
+    ///
+    /// Returns a [`Stack`]-ed layer with the rate limiter layer and the error-handler layer combined
+    /// that can be directly inserted into an [`axum::Router`].
+    #[allow(clippy::type_complexity)]
+    #[must_use]
+    pub fn default_handle_error(
+        self,
+    ) -> Stack<
+        RateLimitLayer<K, H, S>,
+        HandleErrorLayer<impl Fn(Error<Infallible, K::Rejection>) -> Ready<Response> + Clone, ()>,
+    >
+    where
+        K::Rejection: IntoResponse,
+    {
+        self.handle_error(|e| core::future::ready(e.into_response()))
+    }
+
+    /// Create a new rate limiter layer with a custom response for rate limited requests,
+    /// with other errors handled as by [`RateLimitLayerBuilder::default_handle_error`].
+    ///
+    /// The standard rate limit headers, such as `Retry-After`, are inserted into the returned response
+    /// as by [`NotUntil::insert_headers`]. The status code is left as returned by the callback.
+    /// As with the default response, `Cache-Control: no-store` is also inserted, unless the callback
+    /// sets its own `Cache-Control` header.
+    ///
+    /// Returns a [`Stack`]-ed layer with the rate limiter layer and the error-handler layer combined
+    /// that can be directly inserted into an [`axum::Router`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use axum::{Router, http::StatusCode, response::IntoResponse};
+    /// use axum_gcra::RateLimitLayer;
+    ///
+    /// let app = Router::<()>::new().route_layer(
+    ///     RateLimitLayer::<()>::builder().on_rejected(|e| {
+    ///         let ms = e.as_duration().as_millis();
+    ///         let body = format!(r#"{{"code":"rate_limited","retry_after_ms":{ms}}}"#);
+    ///
+    ///         (StatusCode::TOO_MANY_REQUESTS, [("content-type", "application/json")], body).into_response()
+    ///     }),
+    /// );
+    /// ```
+    #[allow(clippy::type_complexity)]
+    #[must_use]
+    pub fn on_rejected<F>(
+        self,
+        cb: F,
+    ) -> Stack<
+        RateLimitLayer<K, H, S>,
+        HandleErrorLayer<impl Fn(Error<Infallible, K::Rejection>) -> Ready<Response> + Clone, ()>,
+    >
+    where
+        F: Fn(NotUntil) -> Response + Clone,
+        K::Rejection: IntoResponse,
+    {
+        self.handle_error(move |e| {
+            core::future::ready(match e {
+                Error::RateLimit(e) => {
+                    let mut res = cb(e);
+                    e.insert_headers(res.headers_mut());
+                    gcra::insert_no_store(res.headers_mut());
+                    res
+                }
+                e => e.into_response(),
+            })
+        })
+    }
+}
+
+/// Defines the [`RateLimiter`](extensions::RateLimiter) extension for the request's extensions,
+/// extractable with [`Extension<RateLimiter<Key>>`](axum::extract::Extension).
+pub mod extensions {
+    use super::*;
+
+    use axum::extract::OptionalFromRequestParts;
+    use http::StatusCode;
+
+    /// Extractor for the remaining capacity of the rate limited key, as of the current request.
+    ///
+    /// This is inserted by the layer when enabled with
+    /// [`with_status_extension`](RateLimitLayerBuilder::with_status_extension), and is the result
+    /// of the request that was already counted, so extracting it never consumes any capacity
+    /// or affects later requests. To check the rate limit again later, use [`RateLimiter::check`].
+    ///
+    /// Extraction is rejected with `500 Internal Server Error` if the extension is missing,
+    /// such as when the layer is not enabled for the route. Extract `Option<RateLimitStatus>` to handle this.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(transparent)]
+    pub struct RateLimitStatus(pub gcra::Capacity);
+
+    impl Deref for RateLimitStatus {
+        type Target = gcra::Capacity;
+
+        #[inline]
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl<S> FromRequestParts<S> for RateLimitStatus {
+        type Rejection = (StatusCode, &'static str);
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+            std::future::ready(parts.extensions.get::<RateLimitStatus>().copied().ok_or((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "rate limit status extension is missing",
+            )))
+        }
+    }
+
+    impl<S> OptionalFromRequestParts<S> for RateLimitStatus {
+        type Rejection = Infallible;
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+            std::future::ready(Ok(parts.extensions.get::<RateLimitStatus>().copied()))
+        }
+    }
+
+    /// Extractor for the number of times the key of the request has been denied, as of the current request.
+    ///
+    /// This is inserted into allowed requests by the layer when enabled with
+    /// [`with_denial_tracking`](RateLimitLayerBuilder::with_denial_tracking), and is the same as
+    /// [`RateLimitLayer::denied_count`] at the time of the request.
+    ///
+    /// Extraction is rejected with `500 Internal Server Error` if the extension is missing,
+    /// such as when the layer is not enabled for the route. Extract `Option<DeniedCount>` to handle this.
+    ///
+    /// ```rust,no_run
+    /// use axum_gcra::extensions::DeniedCount;
+    ///
+    /// async fn handler(DeniedCount(denied): DeniedCount) -> &'static str {
+    ///     if denied > 10 { "Please slow down." } else { "Hello, World!" }
+    /// }
+    /// ```
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(transparent)]
+    pub struct DeniedCount(pub u64);
+
+    impl<S> FromRequestParts<S> for DeniedCount {
+        type Rejection = (StatusCode, &'static str);
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+            std::future::ready(
+                parts
+                    .extensions
+                    .get::<DeniedCount>()
+                    .copied()
+                    .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "denied count extension is missing")),
+            )
+        }
+    }
+
+    impl<S> OptionalFromRequestParts<S> for DeniedCount {
+        type Rejection = Infallible;
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+            std::future::ready(Ok(parts.extensions.get::<DeniedCount>().copied()))
+        }
+    }
+
+    /// Marker inserted into every request that passes through a rate limit layer, for [`RequireRateLimit`].
+    ///
+    /// Inserted by [`RateLimitLayer`], [`ChainLayer`](crate::chain::ChainLayer),
+    /// [`GlobalRateLimitLayer`](crate::global::GlobalRateLimitLayer) and
+    /// [`ConcurrencyLimitLayer`](crate::concurrency::ConcurrencyLimitLayer).
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) struct LayerApplied;
+
+    /// Extractor requiring the request to have passed through a rate limit layer, rejecting it
+    /// with the `STATUS` code otherwise, `429 Too Many Requests` by default.
+    ///
+    /// This fails closed if a route was accidentally left without the layer, such as one added after
+    /// [`route_layer`](axum::Router::route_layer), rather than serving it without any limit. Requests that
+    /// were [exempt](RateLimitLayerBuilder::with_exemption), or passed through a
+    /// [disabled](RateLimitLayer::set_enabled) layer or [chain](crate::chain::ChainLayer), still count
+    /// as having passed through the layer, as do requests through a
+    /// [`GlobalRateLimitLayer`](crate::global::GlobalRateLimitLayer) or
+    /// [`ConcurrencyLimitLayer`](crate::concurrency::ConcurrencyLimitLayer). Other middleware, such as
+    /// tower's own rate limiting, doesn't satisfy it. An invalid `STATUS` code is rejected with
+    /// `500 Internal Server Error`.
+    ///
+    /// ```rust
+    /// use axum::{body::Body, http::{Request, StatusCode}, routing::get, Router};
+    /// use axum_gcra::{extensions::RequireRateLimit, gcra::Quota, RateLimitLayer};
+    /// use tower::ServiceExt;
+    ///
+    /// async fn handler(_: RequireRateLimit) -> &'static str {
+    ///     "Hello, World!"
+    /// }
+    ///
+    /// async fn strict(_: RequireRateLimit<503>) -> &'static str {
+    ///     "Hello, World!"
+    /// }
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let limit = RateLimitLayer::<()>::builder().with_default_quota(Quota::per_second(10));
+    ///
+    /// let app = Router::new()
+    ///     .route("/", get(handler))
+    ///     .route_layer(limit.default_handle_error())
+    ///     // oops, added after the layer
+    ///     .route("/forgotten", get(handler))
+    ///     .route("/strict", get(strict));
+    ///
+    /// for (uri, status) in [("/", StatusCode::OK), ("/forgotten", StatusCode::TOO_MANY_REQUESTS)] {
+    ///     let res = app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+    ///     assert_eq!(res.status(), status);
+    /// }
+    ///
+    /// let res = app.oneshot(Request::get("/strict").body(Body::empty()).unwrap()).await.unwrap();
+    /// assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    /// # }
+    /// ```
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct RequireRateLimit<const STATUS: u16 = 429>;
+
+    impl<S, const STATUS: u16> FromRequestParts<S> for RequireRateLimit<STATUS> {
+        type Rejection = (StatusCode, &'static str);
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+            std::future::ready(match parts.extensions.get::<LayerApplied>() {
+                Some(_) => Ok(RequireRateLimit),
+                None => Err((
+                    StatusCode::from_u16(STATUS).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                    "rate limit layer is missing",
+                )),
+            })
+        }
+    }
+
+    /// Extractor for the key the request was rate limited by, without the route.
+    ///
+    /// This is inserted by the layer when enabled with
+    /// [`with_key_extension`](RateLimitLayerBuilder::with_key_extension), where `K` must be the exact key type
+    /// of the layer. Extraction is rejected with `500 Internal Server Error` if the extension is missing,
+    /// such as when the layer is not enabled for the route. Extract `Option<RateLimitKey<K>>` to handle this.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    #[repr(transparent)]
+    pub struct RateLimitKey<K>(pub K);
+
+    impl<K> Deref for RateLimitKey<K> {
+        type Target = K;
+
+        #[inline]
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl<K, S> FromRequestParts<S> for RateLimitKey<K>
+    where
+        K: Key + Clone,
+    {
+        type Rejection = (StatusCode, &'static str);
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+            std::future::ready(
+                parts
+                    .extensions
+                    .get::<RateLimitKey<K>>()
+                    .cloned()
+                    .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "rate limit key extension is missing")),
+            )
+        }
+    }
+
+    impl<K, S> OptionalFromRequestParts<S> for RateLimitKey<K>
+    where
+        K: Key + Clone,
+    {
+        type Rejection = Infallible;
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &S,
+        ) -> impl Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+            std::future::ready(Ok(parts.extensions.get::<RateLimitKey<K>>().cloned()))
+        }
+    }
+
+    /// [`Request`] extension to access the internal rate limiter used during that request,
+    /// such as to apply a penalty or reset the rate limit.
+    ///
+    /// This is inserted by the layer when enabled with [`with_extension`](RateLimitLayerBuilder::with_extension),
+    /// and can be extracted directly or through [`Extension`](axum::Extension). Extraction is rejected with
+    /// `500 Internal Server Error` if the extension is missing. Extract `Option<RateLimiter>` to handle this.
+    ///
+    /// Note that the `K: Key`, `H: BuildHasher` and `S: Store` types must be the
+    /// exact same as those given to the [`RateLimitLayerBuilder`]/[`RateLimitLayer`].
+    ///
+    /// # WebSockets
+    ///
+    /// A WebSocket upgrade is an ordinary `GET` request to the layer, so the handshake is counted once
+    /// like any other request, and the upgraded connection is never throttled by the layer itself.
+    /// To also limit individual messages, move the limiter into the upgraded task and call
+    /// [`RateLimiter::req`] for each message, which counts against the same entry and quota as the handshake.
+    ///
+    /// ```rust,no_run
+    /// use axum::{extract::ws::{Message, WebSocketUpgrade}, routing::get, Router};
+    /// use axum_gcra::{extensions::RateLimiter, gcra::Quota, real_ip::RealIp, RateLimitLayer};
+    ///
+    /// let app = Router::<()>::new()
+    ///     .route("/ws", get(|ws: WebSocketUpgrade, limiter: RateLimiter<RealIp>| async move {
+    ///         ws.on_upgrade(|mut socket| async move {
+    ///             while let Some(Ok(msg)) = socket.recv().await {
+    ///                 if let Err(e) = limiter.req().await {
+    ///                     let _ = socket.send(Message::Text(e.to_string().into())).await;
+    ///                     continue; // drop the message
+    ///                 }
+    ///
+    ///                 // ... handle the message
+    ///             }
+    ///         })
+    ///     }))
+    ///     .route_layer(
+    ///         RateLimitLayer::<RealIp>::builder()
+    ///             .with_default_quota(Quota::per_second(10).with_burst(20))
+    ///             .with_extension(true)
+    ///             .default_handle_error(),
+    ///     );
+    /// ```
+    pub struct RateLimiter<K: Key = (), H: BuildHasher = RandomState, S = store::MemoryStore<K, H>> {
+        pub(crate) key: RouteWithKey<K>,
+        pub(crate) quota: gcra::Quota,
+        pub(crate) layer: RateLimitLayer<K, H, S>,
+    }
+
+    impl<K: Key, H: BuildHasher, S> Clone for RateLimiter<K, H, S>
+    where
+        K: Clone,
+    {
+        fn clone(&self) -> Self {
+            Self {
+                key: self.key.clone(),
+                quota: self.quota,
+                layer: self.layer.clone(),
+            }
+        }
+    }
+
+    impl<K, H, S, T> FromRequestParts<T> for RateLimiter<K, H, S>
+    where
+        K: Key + Clone,
+        H: BuildHasher + Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        type Rejection = (StatusCode, &'static str);
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &T,
+        ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+            std::future::ready(
+                parts
+                    .extensions
+                    .get::<RateLimiter<K, H, S>>()
+                    .cloned()
+                    .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "rate limiter extension is missing")),
+            )
+        }
+    }
+
+    impl<K, H, S, T> OptionalFromRequestParts<T> for RateLimiter<K, H, S>
+    where
+        K: Key + Clone,
+        H: BuildHasher + Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        type Rejection = Infallible;
+
+        fn from_request_parts(
+            parts: &mut Parts,
+            _: &T,
+        ) -> impl Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+            std::future::ready(Ok(parts.extensions.get::<RateLimiter<K, H, S>>().cloned()))
+        }
+    }
+
+    impl<K: Key, H: BuildHasher, S> RateLimiter<K, H, S> {
+        /// Get the key used to identify the rate limiter entry.
+        #[inline(always)]
+        pub fn key(&self) -> &K {
+            &self.key.key
+        }
+
+        /// Get the path of the route that was rate limited.
+        pub fn path(&self) -> &str {
+            &self.key.path
+        }
+
+        /// Get the method of the route that was rate limited.
+        pub fn method(&self) -> &Method {
+            &self.key.method
+        }
+
+        /// Get the quota that was applied to the request.
+        pub fn quota(&self) -> gcra::Quota {
+            self.quota
+        }
+
+        /// Get the full key used to identify the rate limiter entry within the store.
+        #[inline(always)]
+        pub fn route_key(&self) -> &RouteWithKey<K> {
+            &self.key
+        }
+
+        /// Get the underlying [`Store`](store::Store) of the rate limiter,
+        /// such as to manage entries with [`RateLimiter::route_key`].
+        #[inline(always)]
+        pub fn store(&self) -> &S {
+            &self.layer.limiter
+        }
+    }
+
+    impl<K: Key, H: BuildHasher> RateLimiter<K, H> {
+        /// See [`gcra::RateLimiter::penalize`] for more information.
+        pub async fn penalize(&self, penalty: Duration) -> bool {
+            self.layer.limiter.penalize(&self.key, penalty).await
+        }
+
+        /// See [`gcra::RateLimiter::penalize_sync`] for more information.
+        pub fn penalize_sync(&self, penalty: Duration) -> bool {
+            self.layer.limiter.penalize_sync(&self.key, penalty)
+        }
+
+        /// Counts another request against the same entry and quota, such as for each message
+        /// of a WebSocket connection upgraded from this request, returning the remaining capacity on success.
+        ///
+        /// Unlike [`RateLimiter::check`], this records the request, so it consumes capacity just as
+        /// a request through the layer would. Use [`RateLimiter::check_batch`] for requests costing more than one.
+        pub async fn req(&self) -> Result<gcra::Capacity, NotUntil>
+        where
+            K: Clone,
+        {
+            let now = self.layer.now();
+
+            let res = self.layer.limiter.req_peek_key(self.key.clone(), self.quota, 1, now, |_| {}).await;
+
+            res.map_err(|e| NotUntil::new(e, self.quota, now).with_retry_after(self.layer.builder.retry_after))
+        }
+
+        /// Checks whether another request would be allowed with the same quota, without recording it.
+        ///
+        /// See [`gcra::RateLimiter::check`] for more information.
+        pub async fn check(&self) -> Result<gcra::Capacity, RateLimitError> {
+            self.layer.limiter.check(&self.key, self.quota, self.layer.now()).await
+        }
+
+        /// See [`gcra::RateLimiter::check_sync`] for more information.
+        pub fn check_sync(&self) -> Result<gcra::Capacity, RateLimitError> {
+            self.layer.limiter.check_sync(&self.key, self.quota, self.layer.now())
+        }
+
+        /// Charges `n` more requests with the same quota at once, consuming all of them or none.
+        ///
+        /// See [`gcra::RateLimiter::check_batch`] for more information.
+        pub async fn check_batch(&self, n: u64) -> Result<gcra::Capacity, gcra::BatchError>
+        where
+            K: Clone,
+        {
+            self.layer.limiter.check_batch(self.key.clone(), self.quota, n, self.layer.now()).await
+        }
+
+        /// Returns when the key regains its next cell and its full burst under the quota of the request.
+        ///
+        /// See [`gcra::RateLimiter::reset_at`] for more information.
+        pub async fn reset_at(&self) -> gcra::ResetAt {
+            self.layer.limiter.reset_at(&self.key, self.quota, self.layer.now()).await
+        }
+
+        /// See [`gcra::RateLimiter::reset_at_sync`] for more information.
+        pub fn reset_at_sync(&self) -> gcra::ResetAt {
+            self.layer.limiter.reset_at_sync(&self.key, self.quota, self.layer.now())
+        }
+
+        /// See [`gcra::RateLimiter::reset`] for more information.
+        pub async fn reset(&self) -> bool {
+            self.layer.limiter.reset(&self.key).await
+        }
+
+        /// See [`gcra::RateLimiter::reset_sync`] for more information.
+        pub fn reset_sync(&self) -> bool {
+            self.layer.limiter.reset_sync(&self.key)
+        }
+
+        /// See [`gcra::RateLimiter::clean`] for more information.
+        pub async fn clean(&self, before: Instant) {
+            self.layer.limiter.clean(before).await;
+        }
+
+        /// See [`gcra::RateLimiter::clean_sync`] for more information.
+        pub fn clean_sync(&self, before: Instant) {
+            self.layer.limiter.clean_sync(before);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+
+    use http::{HeaderMap, StatusCode};
+    use tower::{service_fn, ServiceExt};
+
+    /// Store that always fails, as if unreachable.
+    struct FailingStore;
+
+    impl<K: Send + Sync + 'static> store::Store<K> for FailingStore {
+        type Error = std::io::Error;
+
+        async fn req<F>(
+            &self,
+            _: K,
+            _: gcra::Quota,
+            _: u64,
+            _: Instant,
+            _: F,
+        ) -> Result<Result<gcra::Capacity, gcra::RateLimitError>, Self::Error>
+        where
+            F: FnOnce(&K) + Send,
+        {
+            Err(std::io::Error::other("store is unreachable"))
+        }
+    }
+
+    fn failing(fail_open: bool) -> RateLimitLayer<(), RandomState, FailingStore> {
+        RateLimitLayer::<(), _, _>::builder_with_store(FailingStore)
+            .with_rate_limit_headers(true)
+            .with_fail_open(fail_open)
+            .build()
+    }
+
+    async fn call<S>(
+        layer: RateLimitLayer<(), RandomState, S>,
+    ) -> Result<http::Response<()>, Error<Infallible, Infallible>>
+    where
+        S: store::Store<RouteWithKey<()>>,
+    {
+        let inner = service_fn(|_: Request<()>| async { Ok::<_, Infallible>(http::Response::new(())) });
+        layer.layer(inner).oneshot(Request::new(())).await
+    }
+
+    /// Status codes of `GET` requests to each path in turn.
+    async fn statuses(app: axum::Router, paths: &[&str]) -> Vec<u16> {
+        let mut statuses = Vec::new();
+
+        for path in paths {
+            let req = Request::get(*path).body(axum::body::Body::empty()).unwrap();
+            statuses.push(app.clone().oneshot(req).await.unwrap().status().as_u16());
+        }
+
+        statuses
+    }
+
+    #[tokio::test]
+    async fn store_failure_fails_closed() {
+        let Err(e) = call(failing(false)).await else {
+            panic!("request should fail when the store does");
+        };
+
+        assert!(matches!(e, Error::Store(_)));
+        assert_eq!(e.into_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn store_failure_fails_open() {
+        for _ in 0..3 {
+            let res = call(failing(true)).await.unwrap();
+
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get("ratelimit-remaining").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn require_rate_limit() {
+        use axum::{body::Body, routing::get, Router};
+
+        async fn handler(_: extensions::RequireRateLimit) -> &'static str {
+            "ok"
+        }
+
+        let quota = gcra::Quota::per_second(10);
+        let router = || Router::new().route("/", get(handler));
+        let limited = RateLimitLayer::<()>::builder().with_default_quota(quota).default_handle_error();
+
+        let apps = [
+            (router(), StatusCode::TOO_MANY_REQUESTS),
+            (router().route_layer(limited), StatusCode::OK),
+            (router().layer(global::GlobalRateLimitLayer::new(quota)), StatusCode::OK),
+            (
+                router().layer(concurrency::ConcurrencyLimitLayer::<()>::new(1)),
+                StatusCode::OK,
+            ),
+        ];
+
+        for (app, status) in apps {
+            let res = app.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(res.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_lets_denied_requests_through() {
+        let denied = Arc::new(AtomicU64::new(0));
+        let reached = Arc::new(AtomicU64::new(0));
+
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per_hour(1).with_burst(2))
+            .with_rate_limit_headers(true)
+            .with_dry_run(true)
+            .with_on_decision({
+                let denied = denied.clone();
+                move |_, decision| {
+                    denied.fetch_add(u64::from(decision.is_err()), Ordering::Relaxed);
+                }
+            })
+            .build();
+
+        let inner = service_fn({
+            let reached = reached.clone();
+            move |_: Request<()>| {
+                reached.fetch_add(1, Ordering::Relaxed);
+                async { Ok::<_, Infallible>(http::Response::new(())) }
+            }
+        });
+        let service = layer.layer(inner);
+
+        for remaining in ["1", "0", "0", "0"] {
+            let res = service.clone().oneshot(Request::new(())).await.unwrap();
+
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["x-ratelimit-limit"], "2");
+            assert_eq!(res.headers()["x-ratelimit-remaining"], remaining);
+        }
+
+        let res = service.oneshot(Request::new(())).await.unwrap();
+        assert!(res.headers()["ratelimit-reset"].to_str().unwrap().parse::<u64>().unwrap() > 3000);
+
+        assert_eq!(reached.load(Ordering::Relaxed), 5);
+        assert_eq!(denied.load(Ordering::Relaxed), 3);
+    }
+
+    /// Headers of the response denying the second request under a quota of one request per 10 seconds.
+    async fn denied_headers<F>(configure: F) -> HeaderMap
+    where
+        F: FnOnce(RateLimitLayerBuilder<()>) -> RateLimitLayerBuilder<()>,
+    {
+        let builder = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per(Duration::from_secs(10), 1))
+            .with_clock(clock::FakeClock::new());
+
+        let layer = configure(builder).build();
+
+        assert!(call(layer.clone()).await.is_ok());
+
+        match call(layer).await {
+            Err(e @ Error::RateLimit(_)) => e.into_response().headers().clone(),
+            _ => panic!("second request should be rate limited"),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_jitter_within_bounds() {
+        let header = |headers: &HeaderMap, name| headers[name].to_str().unwrap().parse::<u64>().unwrap();
+
+        let wait = {
+            let headers = denied_headers(|b| b.with_retry_after(gcra::RetryAfter::ExactMs)).await;
+            header(&headers, "x-ratelimit-reset-after")
+        };
+
+        assert!(wait > 9_000 && wait <= 10_000, "{wait}");
+
+        let cases = [
+            (0.5, 0.0, 1.0),
+            (0.5, 0.5, 1.25),
+            (0.5, 1.0, 1.5),
+            // out of range values are clamped
+            (0.5, -1.0, 1.0),
+            (0.5, 7.0, 1.5),
+            (0.5, f64::NAN, 1.0),
+            (5.0, 1.0, 2.0),
+            (-1.0, 1.0, 1.0),
+        ];
+
+        for (fraction, r, factor) in cases {
+            let headers = denied_headers(|b| {
+                b.with_retry_after(gcra::RetryAfter::ExactMs)
+                    .with_retry_jitter(fraction)
+                    .with_jitter_source(move || r)
+            })
+            .await;
+
+            let jittered = header(&headers, "x-ratelimit-reset-after");
+            let expected = (wait as f64 * factor) as u64;
+
+            assert!(
+                jittered.abs_diff(expected) <= 1,
+                "{fraction} {r}: {jittered} != {expected}"
+            );
+            assert!(jittered >= wait && jittered as f64 <= wait as f64 * (1.0 + fraction.clamp(0.0, 1.0)) + 1.0);
+            assert!(header(&headers, "retry-after") * 1000 >= jittered);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_after_matches_wait() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let clock = clock::FakeClock::new();
+
+        let layers = [
+            gcra::RetryAfter::Ceil,
+            gcra::RetryAfter::Round,
+            gcra::RetryAfter::ExactMs,
+        ]
+        .map(|rounding| {
+            let layer = RateLimitLayer::<()>::builder()
+                .with_default_quota(gcra::Quota::per(Duration::from_secs(10), 1))
+                .with_retry_after(rounding)
+                .with_clock(clock.clone())
+                .build();
+
+            (rounding, layer)
+        });
+
+        for (_, layer) in &layers {
+            assert!(call(layer.clone()).await.is_ok());
+        }
+
+        // leaves a wait of 1.4 seconds
+        clock.advance(Duration::from_millis(8600));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        for (rounding, layer) in layers {
+            let Err(e @ Error::RateLimit(_)) = call(layer).await else {
+                panic!("second request should be rate limited");
+            };
+
+            let res = e.into_response();
+            let header = |name| res.headers()[name].to_str().unwrap().parse::<u64>().unwrap();
+
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                header("retry-after"),
+                if rounding == gcra::RetryAfter::Round { 1 } else { 2 }
+            );
+
+            let reset_at = header("x-ratelimit-reset");
+            assert!(reset_at > now && reset_at <= now + 3, "{reset_at} {now}");
+
+            if rounding == gcra::RetryAfter::ExactMs {
+                let ms = header("x-ratelimit-reset-after");
+                assert!(ms > 1390 && ms <= 1410, "{ms}");
+            } else {
+                assert!(!res.headers().contains_key("x-ratelimit-reset-after"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn allowed_responses_count_down() {
+        let clock = clock::FakeClock::new();
+
+        let layer = |headers| {
+            RateLimitLayer::<()>::builder()
+                .with_default_quota(gcra::Quota::per_second(1).with_burst(3))
+                .with_rate_limit_headers(headers)
+                .with_clock(clock.clone())
+                .build()
+        };
+
+        let res = call(layer(false)).await.unwrap();
+        assert!(!res.headers().contains_key("x-ratelimit-limit"));
+
+        let layer = layer(true);
+
+        // the limiter starts at the real time it was built, shortly after the clock, so move past it
+        clock.advance(Duration::from_secs(1));
+
+        for _ in 0..2 {
+            for (remaining, reset) in [("2", "1"), ("1", "2"), ("0", "3")] {
+                let res = call(layer.clone()).await.unwrap();
+
+                assert_eq!(res.headers()["x-ratelimit-limit"], "3");
+                assert_eq!(res.headers()["x-ratelimit-remaining"], remaining);
+                assert_eq!(res.headers()["ratelimit-reset"], reset);
+                assert!(res.headers().contains_key("x-ratelimit-reset"));
+            }
+
+            assert!(matches!(call(layer.clone()).await, Err(Error::RateLimit(_))));
+
+            // one request is restored each second, until the full burst is after three
+            clock.advance(Duration::from_millis(1001));
+            let res = call(layer.clone()).await.unwrap();
+            assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+
+            clock.advance(Duration::from_secs(3));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejections_are_not_cached() {
+        use axum::{body::Body, routing::get, Router};
+
+        let builder = || RateLimitLayer::<()>::builder().with_default_quota(gcra::Quota::per_hour(1));
+        let router = || Router::new().route("/", get(|| async { "ok" }));
+
+        let plain = |_| StatusCode::TOO_MANY_REQUESTS.into_response();
+        let cached = |_| (StatusCode::TOO_MANY_REQUESTS, [("cache-control", "max-age=5")]).into_response();
+
+        let apps = [
+            (router().route_layer(builder().default_handle_error()), "no-store"),
+            (router().route_layer(builder().on_rejected(plain)), "no-store"),
+            // unless the custom response sets its own
+            (router().route_layer(builder().on_rejected(cached)), "max-age=5"),
+        ];
+
+        for (app, cache_control) in apps {
+            let res = app.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(!res.headers().contains_key("cache-control"));
+
+            let res = app.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(res.headers()["cache-control"], cache_control);
+            assert!(res.headers().contains_key("retry-after"));
+        }
+    }
+
+    #[tokio::test]
+    async fn burst_then_sustained_rate() {
+        let clock = clock::FakeClock::new();
+
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per_second(10).with_burst(20))
+            .with_clock(clock.clone())
+            .build();
+
+        let allowed = |n| {
+            let layer = layer.clone();
+            async move {
+                for i in 0..n {
+                    assert!(call(layer.clone()).await.is_ok(), "request {i} of {n}");
+                }
+                assert!(matches!(call(layer).await, Err(Error::RateLimit(_))));
+            }
+        };
+
+        allowed(20).await;
+
+        // then refilled at 10 per second
+        clock.advance(Duration::from_millis(101));
+        allowed(1).await;
+
+        clock.advance(Duration::from_millis(1001));
+        allowed(10).await;
+    }
+
+    #[tokio::test]
+    async fn resolved_quotas_per_route() {
+        use axum::{routing::get, Router};
+
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per_hour(1))
+            .with_quota_resolver(|parts: &http::request::Parts| match parts.uri.path() {
+                "/login" => Some(gcra::Quota::per_hour(2).with_burst(2)),
+                "/search" => Some(gcra::Quota::per_hour(4).with_burst(4)),
+                _ => None,
+            })
+            .default_handle_error();
+
+        let app = ["/login", "/search", "/other"]
+            .into_iter()
+            .fold(Router::new(), |app, path| app.route(path, get(|| async { "ok" })))
+            .route_layer(layer);
+
+        // each route is limited by its own resolved quota, or the default
+        let paths = [
+            "/login", "/search", "/other", "/login", "/other", "/login", "/search", "/search",
+        ];
+        assert_eq!(
+            statuses(app.clone(), &paths).await,
+            [200, 200, 200, 200, 429, 429, 200, 200]
+        );
+        assert_eq!(statuses(app, &["/search", "/search", "/login"]).await, [200, 429, 429]);
+    }
+
+    #[tokio::test]
+    async fn weighted_requests() {
+        use axum::{routing::get, Router};
+
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per_hour(5).with_burst(5))
+            .with_global_fallback(true)
+            .with_cost_resolver(|parts: &http::request::Parts| match parts.uri.path() {
+                "/export" => 5,
+                "/huge" => 6,
+                _ => 1,
+            })
+            .default_handle_error();
+
+        let app = ["/export", "/huge", "/ping"]
+            .into_iter()
+            .fold(Router::new(), |app, path| app.route(path, get(|| async { "ok" })))
+            .route_layer(layer);
+
+        // more than the burst is never allowed, without using any of it,
+        // while a request costing the whole burst exhausts it at once
+        assert_eq!(statuses(app, &["/huge", "/export", "/ping"]).await, [429, 200, 429]);
+
+        let limiter = gcra::RateLimiter::<u32>::default();
+        let (quota, now) = (gcra::Quota::per_hour(5).with_burst(5), Instant::now());
+
+        assert!(limiter.req_n_sync(1, quota, 3, now).is_ok());
+        assert!(limiter.req_n_sync(1, quota, 3, now).is_err());
+        assert!(limiter.req_n_sync(1, quota, 2, now).is_ok());
+        assert!(limiter.req_sync(1, quota, now).is_err());
+    }
+
+    #[tokio::test]
+    async fn shared_store_shares_buckets() {
+        use axum::{routing::get, Router};
+
+        let app = |api: Arc<store::MemoryStore<()>>, ws: Arc<store::MemoryStore<()>>| {
+            let layer = |store| {
+                RateLimitLayer::<()>::builder_with_shared_store(store)
+                    .with_default_quota(gcra::Quota::per_hour(2).with_burst(2))
+                    .with_global_fallback(true)
+                    .default_handle_error()
+            };
+
+            let api = Router::new().route("/api", get(|| async { "API" })).route_layer(layer(api));
+            let ws = Router::new().route("/ws", get(|| async { "WebSocket" })).route_layer(layer(ws));
+            api.merge(ws)
+        };
+
+        let paths = ["/api", "/ws", "/api", "/ws"];
+
+        let store = Arc::new(store::MemoryStore::default());
+        assert_eq!(
+            statuses(app(store.clone(), store.clone()), &paths).await,
+            [200, 200, 429, 429]
+        );
+        assert_eq!(store.len(), 1);
+
+        let separate = app(Default::default(), Default::default());
+        assert_eq!(statuses(separate, &paths).await, [200, 200, 200, 200]);
+    }
+
+    #[tokio::test]
+    async fn on_decision_once_per_request() {
+        use axum::{routing::get, Router};
+        use std::sync::Mutex;
+
+        type Decisions = Arc<Mutex<Vec<(String, Result<u64, Duration>)>>>;
+
+        let decisions = Decisions::default();
+
+        let record = |decisions: &Decisions| {
+            let decisions = decisions.clone();
+            move |key: &RouteWithKey<()>, decision: Result<gcra::Capacity, RateLimitError>| {
+                let decision = decision.map(|c| c.remaining).map_err(|e| e.as_duration());
+                decisions.lock().unwrap().push((key.path().to_owned(), decision));
+            }
+        };
+
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per(Duration::from_secs(10), 2).with_burst(2))
+            .with_clock(clock::FakeClock::new())
+            .with_on_decision(record(&decisions))
+            .default_handle_error();
+
+        let app = Router::new()
+            .route("/a", get(|| async { "a" }))
+            .route("/b", get(|| async { "b" }))
+            .route_layer(layer);
+
+        assert_eq!(statuses(app, &["/a", "/a", "/b", "/a"]).await, [200, 200, 200, 429]);
+
+        let decisions = std::mem::take(&mut *decisions.lock().unwrap());
+        let paths: Vec<_> = decisions.iter().map(|(path, _)| &**path).collect();
+        assert_eq!(paths, ["/a", "/a", "/b", "/a"]);
+
+        assert_eq!(
+            decisions[..3].iter().map(|(_, d)| *d).collect::<Vec<_>>(),
+            [Ok(1), Ok(0), Ok(1)]
+        );
+        let Err(wait) = decisions[3].1 else {
+            panic!("last request should be denied")
+        };
+        assert!(
+            wait > Duration::from_millis(4990) && wait <= Duration::from_secs(5),
+            "{wait:?}"
+        );
+
+        // not called when the store fails
+        let failures = Decisions::default();
+        let layer = RateLimitLayer::<(), _, _>::builder_with_store(FailingStore)
+            .with_on_decision(record(&failures))
+            .build();
+        assert!(call(layer).await.is_err());
+        assert!(failures.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn denial_snapshot() {
+        let clock = clock::FakeClock::new();
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per(Duration::from_secs(10), 1))
+            .with_clock(clock.clone())
+            .build();
+
+        assert!(call(layer.clone()).await.is_ok());
+        clock.advance(Duration::from_secs(4));
+
+        let Err(Error::RateLimit(denied)) = call(layer).await else {
+            panic!("second request should be rate limited");
+        };
+
+        assert_eq!(denied.quota().emission_interval(), Duration::from_secs(10));
+        assert_eq!(denied.quota().burst(), 1);
+
+        let wait = denied.as_duration();
+        assert!(
+            wait > Duration::from_millis(5999) && wait < Duration::from_millis(6001),
+            "{wait:?}"
+        );
+        assert_eq!(denied.earliest_possible() - denied.denied_at(), wait);
+        assert_eq!(denied.exceeded_by(), 1);
+        assert_eq!(RateLimitError::from(denied), denied.error());
+
+        // a wait longer than the emission interval is more than one cell over
+        let error = RateLimitError(std::num::NonZeroU64::new(25_000_000_000).unwrap());
+        let denied = NotUntil::new(error, denied.quota(), denied.denied_at());
+        assert_eq!(denied.exceeded_by(), 3);
+        assert_eq!(denied.earliest_possible() - denied.denied_at(), Duration::from_secs(25));
+    }
+
+    #[tokio::test]
+    async fn status_extension() {
+        use axum::{body::Body, routing::get, Router};
+        use extensions::RateLimitStatus;
+
+        async fn remaining(app: &Router) -> (u16, String) {
+            let res = app.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+            let status = res.status().as_u16();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        let app = |status| {
+            let layer = RateLimitLayer::<()>::builder()
+                .with_default_quota(gcra::Quota::per_hour(3).with_burst(3))
+                .with_status_extension(status)
+                .default_handle_error();
+
+            // reads the status repeatedly, which must not consume anything
+            Router::new()
+                .route(
+                    "/",
+                    get(|a: Option<RateLimitStatus>, b: Option<RateLimitStatus>| async move {
+                        assert_eq!(a, b);
+                        a.map_or("none".to_owned(), |status| status.remaining.to_string())
+                    }),
+                )
+                .route_layer(layer)
+        };
+
+        let (with, without) = (app(true), app(false));
+        for expected in ["2", "1", "0"] {
+            assert_eq!(remaining(&with).await, (200, expected.to_owned()));
+            assert_eq!(remaining(&without).await, (200, "none".to_owned()));
+        }
+
+        // so it is denied on the same request as without it
+        assert_eq!(remaining(&with).await.0, 429);
+        assert_eq!(remaining(&without).await.0, 429);
+
+        let (mut parts, ()) = Request::new(()).into_parts();
+        let missing = <RateLimitStatus as FromRequestParts<()>>::from_request_parts(&mut parts, &()).await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[cfg(feature = "real_ip")]
+    #[tokio::test]
+    async fn exemptions() {
+        use axum::{body::Body, routing::get, Router};
+        use real_ip::RealIp;
+        use std::net::{IpAddr, Ipv4Addr};
+
+        const ADMIN: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let layer = RateLimitLayer::<RealIp>::builder()
+            .with_default_quota(gcra::Quota::per_hour(1))
+            .with_rate_limit_headers(true)
+            .with_exemption(|parts| parts.uri.path() == "/healthz")
+            .with_key_exemption(|ip| ip.0 == ADMIN)
+            .default_handle_error();
+
+        let app =
+            Router::new().route("/", get(|| async {})).route("/healthz", get(|| async {})).route_layer(layer);
+
+        // status, and whether rate limit headers were sent
+        let send = |path: &'static str, client: &'static str| {
+            let req = Request::get(path).header("x-forwarded-for", client).body(Body::empty()).unwrap();
+            let res = app.clone().oneshot(req);
+
+            async move {
+                let res = res.await.unwrap();
+                (
+                    res.status().as_u16(),
+                    res.headers().contains_key("x-ratelimit-remaining"),
+                )
+            }
+        };
+
+        // never throttled, nor counted against the client
+        for _ in 0..100 {
+            assert_eq!(send("/healthz", "203.0.113.7").await, (200, false));
+            assert_eq!(send("/", "192.0.2.1").await, (200, false));
+        }
+
+        assert_eq!(send("/", "203.0.113.7").await, (200, true));
+        assert_eq!(send("/", "203.0.113.7").await.0, 429);
+        assert_eq!(send("/healthz", "203.0.113.7").await, (200, false));
+    }
+
+    #[cfg(feature = "real_ip")]
+    #[tokio::test]
+    async fn cidr_exemption() {
+        use axum::{body::Body, routing::get, Router};
+        use real_ip::{Cidr, RealIpLayer};
+
+        // a single limit shared by every client, so only the exemption tells them apart
+        let layer = || {
+            RateLimitLayer::<()>::builder()
+                .with_default_quota(gcra::Quota::per_hour(1))
+                .with_cidr_exemption(["203.0.113.0/24".parse::<Cidr>().unwrap()])
+                .default_handle_error()
+        };
+
+        let send = |app: &Router, headers: &[(&'static str, &'static str)]| {
+            let mut req = Request::get("/");
+            for &(name, value) in headers {
+                req = req.header(name, value);
+            }
+            let res = app.clone().oneshot(req.body(Body::empty()).unwrap());
+            async move { res.await.unwrap().status().as_u16() }
+        };
+
+        let app = Router::new().route("/", get(|| async {})).route_layer(layer());
+
+        for client in ["203.0.113.0", "203.0.113.7", "203.0.113.255"] {
+            for _ in 0..10 {
+                assert_eq!(send(&app, &[("x-forwarded-for", client)]).await, 200);
+            }
+        }
+
+        // just outside the range, and requests without an address, are limited as usual
+        assert_eq!(send(&app, &[("x-forwarded-for", "203.0.114.1")]).await, 200);
+        assert_eq!(send(&app, &[("x-forwarded-for", "203.0.112.255")]).await, 429);
+        assert_eq!(send(&app, &[]).await, 429);
+        assert_eq!(send(&app, &[("x-forwarded-for", "203.0.113.7")]).await, 200);
+
+        // the address is resolved as configured by the real IP layer in front, ignoring the spoofed header
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route_layer(layer())
+            .layer(RealIpLayer::new().only_header(http::HeaderName::from_static("cf-connecting-ip")));
+
+        let spoofed = [("x-forwarded-for", "203.0.113.7"), ("cf-connecting-ip", "198.51.100.1")];
+        assert_eq!(send(&app, &spoofed).await, 200);
+        assert_eq!(send(&app, &spoofed).await, 429);
+        assert_eq!(send(&app, &[("cf-connecting-ip", "203.0.113.9")]).await, 200);
+    }
+
+    #[tokio::test]
+    async fn store_from_state() {
+        use axum::{
+            body::Body,
+            extract::State,
+            routing::{get, post},
+            Router,
+        };
+
+        #[derive(Clone, Default)]
+        struct AppState {
+            limiter: Arc<store::MemoryStore<()>>,
+        }
+
+        impl FromRef<AppState> for Arc<store::MemoryStore<()>> {
+            fn from_ref(state: &AppState) -> Self {
+                state.limiter.clone()
+            }
+        }
+
+        type Limiter = State<Arc<store::MemoryStore<()>>>;
+
+        let state = AppState::default();
+
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route_layer(
+                RateLimitLayer::<()>::builder_from_state(&state)
+                    .with_default_quota(gcra::Quota::per_hour(1))
+                    .default_handle_error(),
+            )
+            .route(
+                "/tracked",
+                get(|State(limiter): Limiter| async move { limiter.len().to_string() }),
+            )
+            .route(
+                "/reset",
+                post(|State(limiter): Limiter| async move { limiter.reset_all().await }),
+            )
+            .with_state(state.clone());
+
+        let send = |method: Method, path: &str| {
+            let req = Request::builder().method(method).uri(path).body(Body::empty()).unwrap();
+            let res = app.clone().oneshot(req);
+
+            async move {
+                let res = res.await.unwrap();
+                let status = res.status().as_u16();
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(send(Method::GET, "/tracked").await, (200, "0".to_owned()));
+        assert_eq!(send(Method::GET, "/").await.0, 200);
+        assert_eq!(send(Method::GET, "/").await.0, 429);
+
+        // the handlers see the same store as the layer
+        assert_eq!(send(Method::GET, "/tracked").await, (200, "1".to_owned()));
+        assert_eq!(state.limiter.len(), 1);
+
+        assert_eq!(send(Method::POST, "/reset").await.0, 200);
+        assert_eq!(send(Method::GET, "/tracked").await, (200, "0".to_owned()));
+        assert_eq!(send(Method::GET, "/").await.0, 200);
+        assert_eq!(send(Method::GET, "/").await.0, 429);
+    }
+
+    #[cfg(feature = "real_ip")]
+    #[tokio::test]
+    async fn client_ip_header() {
+        use axum::{body::Body, routing::get, Router};
+
+        let app = |builder: RateLimitLayerBuilder| {
+            let layer = builder.with_default_quota(gcra::Quota::per_hour(1)).default_handle_error();
+            Router::new().route("/", get(|| async {})).route_layer(layer)
+        };
+
+        // the header on the allowed and the rejected response from a client
+        let echoed = |app: Router, client: &'static str| async move {
+            let mut headers = Vec::new();
+            for _ in 0..2 {
+                let req = Request::get("/").header("x-forwarded-for", client).body(Body::empty()).unwrap();
+                let res = app.clone().oneshot(req).await.unwrap();
+                headers.push(res.headers().get("x-ratelimit-client").map(|v| v.to_str().unwrap().to_owned()));
+            }
+            (headers[0].clone(), headers[1].clone())
+        };
+
+        const V6: &str = "2001:db8:1:2:3:4:5:6";
+        const V4: &str = "203.0.113.7";
+
+        let builder = RateLimitLayer::<()>::builder;
+
+        assert_eq!(echoed(app(builder()), V6).await, (None, None));
+        assert_eq!(
+            echoed(app(builder().with_client_ip_header(false)), V6).await,
+            (None, None)
+        );
+
+        let unmasked = || app(builder().with_client_ip_header(true));
+        assert_eq!(echoed(unmasked(), V6).await, (None, Some(V6.to_owned())));
+        assert_eq!(echoed(unmasked(), V4).await, (None, Some(V4.to_owned())));
+
+        let masked = || app(builder().with_masked_client_ip_header::<24, 64>());
+        assert_eq!(echoed(masked(), V6).await, (None, Some("2001:db8:1:2::".to_owned())));
+        assert_eq!(echoed(masked(), V4).await, (None, Some("203.0.113.0".to_owned())));
+
+        // the address already resolved by a real IP layer in front is reused, here its fallback for a bad header
+        let behind_proxy =
+            unmasked().layer(real_ip::RealIpLayer::new().with_fallback_ip(Some(V4.parse().unwrap())));
+        let req = || Request::get("/").header("x-forwarded-for", "garbage").body(Body::empty()).unwrap();
+        assert_eq!(
+            behind_proxy.clone().oneshot(req()).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let res = behind_proxy.oneshot(req()).await.unwrap();
+        assert_eq!(res.headers()["x-ratelimit-client"], V4);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn tracing_events() {
+        use std::{
+            collections::HashMap,
+            sync::{Arc, Mutex},
+        };
+        use tracing::{field::Field, span, Event, Level, Metadata};
+
+        type Events = Arc<Mutex<Vec<(Level, HashMap<&'static str, String>)>>>;
+
+        /// Subscriber capturing the level and fields of every event from this crate.
+        struct Capture(Events);
+
+        struct Fields<'a>(&'a mut HashMap<&'static str, String>);
+
+        impl tracing::field::Visit for Fields<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name(), value.to_owned());
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name(), format!("{value:?}"));
             }
         }
-    }
-}
 
-impl<K: Key, H: BuildHasher> RateLimitLayer<K, H> {
-    async fn req_peek_key<F>(
-        &self,
-        mut key: RouteWithKey<K>,
-        now: std::time::Instant,
-        peek: F,
-    ) -> Result<(), RateLimitError>
-    where
-        F: FnOnce(&RouteWithKey<K>),
-    {
-        let quota = match self.builder.quotas.get(&key.as_route()).copied() {
-            Some(quota) => quota,
-            None => {
-                if self.builder.global_fallback {
-                    key.path = MatchedPath::Fallback;
-                }
+        impl tracing::Subscriber for Capture {
+            fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+                metadata.target() == "axum_gcra"
+            }
 
-                self.builder.default_quota
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = HashMap::new();
+                event.record(&mut Fields(&mut fields));
+                self.0.lock().unwrap().push((*event.metadata().level(), fields));
             }
+
+            fn enter(&self, _: &span::Id) {}
+
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let events = Events::default();
+        let _guard = tracing::subscriber::set_default(Capture(events.clone()));
+
+        let inner = service_fn(|_: Request<()>| async { Ok::<_, Infallible>(http::Response::new(())) });
+        let builder = || RateLimitLayer::<()>::builder().with_default_quota(gcra::Quota::per_hour(1));
+
+        let service = builder().build().layer(inner);
+        assert!(service.clone().oneshot(Request::new(())).await.is_ok());
+        assert!(matches!(
+            service.oneshot(Request::new(())).await,
+            Err(Error::RateLimit(_))
+        ));
+
+        let captured = std::mem::take(&mut *events.lock().unwrap());
+        let [(allowed, allowed_fields), (denied, denied_fields)] = &captured[..] else {
+            panic!("expected two events, got {captured:?}");
         };
 
-        self.limiter.req_peek_key(key, quota, now, peek).await
-    }
-}
+        assert_eq!(*allowed, Level::TRACE);
+        assert_eq!(allowed_fields["message"], "request allowed");
+        assert_eq!(allowed_fields["remaining"], "0");
+        assert_eq!(allowed_fields["method"], "GET");
 
-async fn get_user_key<K>(parts: &mut Parts) -> Result<K, K::Rejection>
-where
-    K: Key + FromRequestParts<()>,
-{
-    use core::mem::{size_of, transmute_copy};
+        assert_eq!(*denied, Level::DEBUG);
+        assert_eq!(denied_fields["message"], "request throttled");
+        assert_eq!(denied_fields["dry_run"], "false");
+        let retry_after_ms: u64 = denied_fields["retry_after_ms"].parse().unwrap();
+        assert!(
+            retry_after_ms > 3_599_000 && retry_after_ms <= 3_600_000,
+            "{retry_after_ms}"
+        );
 
-    #[inline(always)]
-    fn same_ty<A: 'static, B: 'static>() -> bool {
-        let b = TypeId::of::<B>();
+        // keys are only logged once formatted, which may redact them
+        assert!(!allowed_fields.contains_key("key") && !denied_fields.contains_key("key"));
 
-        // check same type or 1-tuple of the same layout
-        TypeId::of::<A>() == b || (TypeId::of::<(A,)>() == b && size_of::<A>() == size_of::<B>())
+        let redacted = builder().with_trace_key(|()| "<redacted>".to_owned()).build().layer(inner);
+        assert!(redacted.clone().oneshot(Request::new(())).await.is_ok());
+        assert!(redacted.oneshot(Request::new(())).await.is_err());
+
+        let captured = std::mem::take(&mut *events.lock().unwrap());
+        assert_eq!(captured.len(), 2);
+        assert!(captured.iter().all(|(_, fields)| fields["key"] == "<redacted>"));
     }
 
-    // poor man's specialization
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn global_backpressure() {
+        use futures_util::FutureExt;
 
-    if same_ty::<K, ()>() {
-        return Ok(unsafe { transmute_copy::<_, K>(&()) });
-    }
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per(Duration::from_millis(100), 1))
+            .with_global_backpressure(true)
+            .build();
 
-    #[cfg(feature = "real_ip")]
-    if same_ty::<K, real_ip::RealIp>() {
-        #[rustfmt::skip]
-        let ip = parts.extensions.get::<real_ip::RealIp>().copied()
-            .or_else(|| real_ip::get_ip_from_parts(parts));
+        let inner = service_fn(|_: Request<()>| async { Ok::<_, Infallible>(http::Response::new(())) });
+        let mut service = layer.layer(inner);
 
-        if let Some(ip) = ip {
-            return Ok(unsafe { transmute_copy::<_, K>(&ip) });
-        }
-    }
+        assert!(service.ready().await.unwrap().call(Request::new(())).await.is_ok());
 
-    #[cfg(feature = "real_ip")]
-    if same_ty::<K, real_ip::RealIpPrivacyMask>() {
-        #[rustfmt::skip]
-        let ip = parts.extensions.get::<real_ip::RealIp>().copied()
-            .or_else(|| real_ip::get_ip_from_parts(parts));
+        // the request that saturates the limiter is rejected as usual
+        assert!(service.ready().now_or_never().is_some());
+        let res = service.call(Request::new(())).await;
+        assert!(matches!(res, Err(Error::RateLimit(_))));
 
-        if let Some(ip) = ip {
-            return Ok(unsafe { transmute_copy::<_, K>(&real_ip::RealIpPrivacyMask::from(ip)) });
-        }
-    }
+        // but the service is not ready again until it would be allowed
+        let saturated = Instant::now();
+        assert!(service.ready().now_or_never().is_none());
 
-    match K::from_request_parts(parts, &()).await {
-        Ok(key) => Ok(key),
-        Err(rejection) => Err(rejection),
+        service.ready().await.unwrap();
+        assert!(
+            saturated.elapsed() >= Duration::from_millis(50),
+            "{:?}",
+            saturated.elapsed()
+        );
+        assert!(service.call(Request::new(())).await.is_ok());
     }
-}
 
-impl<I, K, B, H> Service<Request<B>> for RateLimitService<I, K, H>
-where
-    I: Service<Request<B>, Future: TryFuture<Ok = I::Response, Error = I::Error>> + Clone + Send + 'static,
-    K: Key + FromRequestParts<()>,
-    H: BuildHasher + Send + Sync + 'static,
-{
-    type Response = I::Response;
-    type Error = Error<I::Error, K::Rejection>;
-    type Future = RateLimitedResponse<B, I, K>;
+    #[tokio::test]
+    async fn per_message_limiting() {
+        use axum::{body::Body, routing::get, Router};
+        use extensions::RateLimiter;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match self.inner.poll_ready(cx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::Inner(e))),
-            Poll::Pending => Poll::Pending,
-        }
-    }
+        // stands in for a WebSocket handler, checking four messages after the handshake
+        let app = Router::new()
+            .route(
+                "/ws",
+                get(|limiter: RateLimiter<()>| async move {
+                    let task = tokio::spawn(async move {
+                        let mut remaining = Vec::new();
+                        for _ in 0..4 {
+                            remaining.push(limiter.req().await.map(|capacity| capacity.remaining).ok());
+                        }
+                        remaining
+                    });
 
-    fn call(&mut self, req: Request<B>) -> Self::Future {
-        // try to get the current time as close as possible to the request
-        let now = Instant::now();
+                    format!("{:?}", task.await.unwrap())
+                }),
+            )
+            .route_layer(
+                RateLimitLayer::<()>::builder()
+                    .with_default_quota(gcra::Quota::per_hour(4).with_burst(4))
+                    .with_extension(true)
+                    .default_handle_error(),
+            );
 
-        let path = match req.extensions().get::<AxumMatchedPath>() {
-            Some(path) => MatchedPath::Axum(path.clone()),
-            None => MatchedPath::Fallback,
+        let handshake = || {
+            let req = Request::get("/ws")
+                .header(http::header::CONNECTION, "upgrade")
+                .header(http::header::UPGRADE, "websocket")
+                .body(Body::empty())
+                .unwrap();
+
+            let res = app.clone().oneshot(req);
+            async move {
+                let res = res.await.unwrap();
+                let status = res.status().as_u16();
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
         };
 
-        let (mut parts, body) = req.into_parts();
+        // messages count against the same entry as the handshake, which was counted once
+        assert_eq!(handshake().await, (200, "[Some(2), Some(1), Some(0), None]".to_owned()));
 
-        let layer = self.layer.clone();
+        // so the next handshake is throttled
+        assert_eq!(handshake().await.0, 429);
+    }
 
-        RateLimitedResponse::RateLimiting {
-            inner: self.inner.clone(),
-            body: Some(body), // once told me
+    #[tokio::test]
+    async fn manual_maintenance() {
+        let clock = clock::FakeClock::new();
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(gcra::Quota::per_second(1).with_burst(2))
+            .with_gc_interval(u64::MAX)
+            .with_clock(clock.clone())
+            .build();
 
-            f: Box::pin(async move {
-                let key = RouteWithKey {
-                    key: get_user_key(&mut parts).await.map_err(Error::KeyRejection)?,
-                    path,
-                    method: parts.method.clone(),
-                };
+        assert!(call(layer.clone()).await.is_ok());
+        assert_eq!(layer.store().len(), 1);
 
-                let res = layer.req_peek_key(key, now, |key| {
-                    if let Some(ref set_ext) = layer.builder.set_ext {
-                        // set_extension will clone the key internally
-                        set_ext.set_extension(&mut parts.extensions, key, layer.clone());
-                    }
-                });
+        // kept until it has fully recovered
+        clock.advance(Duration::from_millis(500));
+        layer.maintain().await;
+        assert_eq!(layer.store().len(), 1);
 
-                match res.await {
-                    Ok(()) => Ok(parts),
-                    Err(e) => Err(Error::RateLimit(e)),
-                }
-            }),
-        }
+        clock.advance(Duration::from_millis(1501));
+        layer.maintain().await;
+        assert!(layer.store().is_empty());
     }
-}
 
-impl<K, I, H> Layer<I> for RateLimitLayer<K, H>
-where
-    K: Key,
-    H: BuildHasher,
-{
-    type Service = RateLimitService<I, K, H>;
+    /// Custom key, as resolved by authentication from the `x-tenant` header.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Tenant {
+        id: u32,
+        name: String,
+    }
 
-    fn layer(&self, inner: I) -> Self::Service {
-        RateLimitService {
-            inner,
-            layer: self.clone(),
+    impl<S: Sync> FromRequestParts<S> for Tenant {
+        type Rejection = StatusCode;
+
+        async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+            let name = parts.headers.get("x-tenant").ok_or(StatusCode::UNAUTHORIZED)?;
+            let name = name.to_str().map_err(|_| StatusCode::UNAUTHORIZED)?.to_owned();
+
+            Ok(Tenant {
+                id: name.len() as u32,
+                name,
+            })
         }
     }
-}
 
-use tower::layer::util::Stack;
+    #[tokio::test]
+    async fn key_extension() {
+        use axum::{body::Body, routing::get, Router};
+        use extensions::RateLimitKey;
 
-impl<K, H: BuildHasher> RateLimitLayerBuilder<K, H>
-where
-    K: Key + FromRequestParts<()>,
-    H: Default + Send + Sync + 'static,
-{
-    /// Build the [`RateLimitLayer`].
-    ///
-    /// This will create a new rate limiter and, if the `tokio` feature is
-    /// enabled and the [GC interval](RateLimitLayerBuilder::with_gc_interval)
-    /// is a time [`Duration`], spawn a background task for garbage collection.
-    ///
-    /// By itself, `RateLimitLayer` cannot be directly inserted into an [`axum::Router`],
-    /// as it requires a [`HandleErrorLayer`] to handle rate limiting errors.
-    /// Use [`RateLimitLayerBuilder::handle_error`] or [`RateLimitLayerBuilder::default_handle_error`] to create a stack
-    /// with the rate limiter layer and the error-handler layer combined.
-    #[must_use]
-    pub fn build(self) -> RateLimitLayer<K, H> {
-        let limiter = Arc::new(gcra::RateLimiter::new(self.gc_interval.to_requests(), H::default()));
+        let app = |extension| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|key: Option<RateLimitKey<Tenant>>| async move {
+                        key.map_or("none".to_owned(), |RateLimitKey(tenant)| {
+                            format!("{}: {}", tenant.id, tenant.name)
+                        })
+                    }),
+                )
+                .route_layer(
+                    RateLimitLayer::<Tenant>::builder().with_key_extension(extension).default_handle_error(),
+                )
+        };
 
-        #[cfg(feature = "tokio")]
-        if let GCInterval::Time(d) = self.gc_interval {
-            let limiter = limiter.clone();
-            let signal = self.shutdown.clone();
+        let send = |app: Router, tenant: &'static str| async move {
+            let req = Request::get("/").header("x-tenant", tenant).body(Body::empty()).unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
 
-            _ = tokio::task::spawn(async move {
-                let mut interval = tokio::time::interval(d);
-                loop {
-                    tokio::select! { biased;
-                        _ = signal.notify.notified() => break,
-                        _ = interval.tick() => {},
-                    }
+        // the key the request was limited by is handed to the handler as extracted
+        assert_eq!(send(app(true), "acme").await, "4: acme");
+        assert_eq!(send(app(true), "initech").await, "7: initech");
+        assert_eq!(send(app(false), "acme").await, "none");
 
-                    limiter.clean(Instant::now()).await;
+        let (mut parts, ()) = Request::new(()).into_parts();
+        let missing = <RateLimitKey<Tenant> as FromRequestParts<()>>::from_request_parts(&mut parts, &()).await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
-                    // also close task if no more references to the limiter
-                    if Arc::strong_count(&limiter) == 1 {
-                        break;
-                    }
-                }
-            });
-        }
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn delay_until_allowed() {
+        let clock = clock::FakeClock::new();
 
-        RateLimitLayer {
-            limiter,
-            builder: Arc::new(self),
-        }
-    }
+        let delayed = |max_wait| {
+            RateLimitLayer::<()>::builder()
+                .with_default_quota(gcra::Quota::per_second(1))
+                .with_throttle_mode(ThrottleMode::Delay { max_wait })
+                .with_clock(clock.clone())
+                .build()
+        };
 
-    /// Create a new rate limiter layer with the provided error-handler callback.
-    ///
-    /// Returns a [`Stack`]-ed layer with the rate limiter layer and the error-handler layer combined
-    /// that can be directly inserted into an [`axum::Router`].
-    ///
-    /// # Example
-    ///
-    /// ```rust,no_run
-    /// use axum::{Router, http::StatusCode};
-    /// use axum_gcra::RateLimitLayer;
-    ///
-    /// let builder = RateLimitLayer::<()>::builder();
-    ///
-    /// let app = Router::<()>::new().route_layer(
-    ///    builder.handle_error(|e| async move {
-    ///       StatusCode::TOO_MANY_REQUESTS
-    ///    }));
-    /// ```
-    #[must_use]
-    pub fn handle_error<F, R>(self, cb: F) -> Stack<RateLimitLayer<K, H>, HandleErrorLayer<F, ()>>
-    where
-        F: Fn(Error<Infallible, K::Rejection>) -> R + Clone,
-    {
-        Stack::new(self.build(), HandleErrorLayer::new(cb))
-    }
+        let layer = delayed(Duration::from_secs(2));
+        assert!(call(layer.clone()).await.is_ok());
+        assert_eq!(clock.elapsed(), Duration::ZERO);
 
-    /// Create a new rate limiter layer with the default error-handler callback that simply returns the error
-    /// as a [`Response`].
-    ///
-    /// Returns a [`Stack`]-ed layer with the rate limiter layer and the error-handler layer combined
-    /// that can be directly inserted into an [`axum::Router`].
-    #[allow(clippy::type_complexity)]
-    #[must_use]
-    pub fn default_handle_error(
-        self,
-    ) -> Stack<
-        RateLimitLayer<K, H>,
-        HandleErrorLayer<impl Fn(Error<Infallible, K::Rejection>) -> Ready<Response> + Clone, ()>,
-    >
-    where
-        K::Rejection: IntoResponse,
-    {
-        self.handle_error(|e| core::future::ready(e.into_response()))
-    }
-}
+        // held for as long as the quota requires, then allowed
+        assert!(call(layer.clone()).await.is_ok());
+        let waited = clock.elapsed();
+        let about_a_second = |d: Duration| d > Duration::from_millis(990) && d < Duration::from_millis(1010);
+        assert!(about_a_second(waited), "{waited:?}");
 
-/// Defines the [`RateLimiter`](extensions::RateLimiter) extension for the request's extensions,
-/// extractable with [`Extension<RateLimiter<Key>>`](axum::extract::Extension).
-pub mod extensions {
-    use super::*;
+        assert!(call(layer).await.is_ok());
+        assert!(about_a_second(clock.elapsed() - waited));
 
-    /// [`Request`] extension to access the internal rate limiter used during that request,
-    /// such as to apply a penalty or reset the rate limit.
-    ///
-    /// Note that the `K: Key` and `H: BuildHasher` types must be the
-    /// exact same as those given to the [`RateLimitLayerBuilder`]/[`RateLimitLayer`].
-    pub struct RateLimiter<K: Key = (), H: BuildHasher = RandomState> {
-        pub(crate) key: RouteWithKey<K>,
-        pub(crate) layer: RateLimitLayer<K, H>,
+        // rejected immediately if the wait would exceed the maximum
+        let layer = delayed(Duration::from_millis(500));
+        let before = clock.elapsed();
+
+        assert!(call(layer.clone()).await.is_ok());
+        assert!(matches!(call(layer).await, Err(Error::RateLimit(_))));
+        assert_eq!(clock.elapsed(), before);
     }
 
-    impl<K: Key, H: BuildHasher> Clone for RateLimiter<K, H>
-    where
-        K: Clone,
-    {
-        fn clone(&self) -> Self {
-            Self {
-                key: self.key.clone(),
-                layer: self.layer.clone(),
-            }
+    /// Recorder that tracks counters by name and labels, as `name{key=value,...}`.
+    #[cfg(feature = "metrics")]
+    #[derive(Default)]
+    struct CounterRecorder(std::sync::Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+    #[cfg(feature = "metrics")]
+    impl CounterRecorder {
+        fn get(&self, key: &str) -> u64 {
+            let counters = self.0.lock().unwrap();
+            counters.get(key).map_or(0, |c| c.load(Ordering::Relaxed))
         }
     }
 
-    impl<K: Key, H: BuildHasher> RateLimiter<K, H> {
-        /// Get the key used to identify the rate limiter entry.
-        #[inline(always)]
-        pub fn key(&self) -> &K {
-            &self.key.key
-        }
+    #[cfg(feature = "metrics")]
+    impl metrics::Recorder for CounterRecorder {
+        fn describe_counter(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+        fn describe_gauge(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+        fn describe_histogram(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
 
-        /// Get the path of the route that was rate limited.
-        pub fn path(&self) -> &str {
-            &self.key.path
-        }
+        fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
+            let labels: Vec<_> = key.labels().map(|l| format!("{}={}", l.key(), l.value())).collect();
+            let name = format!("{}{{{}}}", key.name(), labels.join(","));
 
-        /// Get the method of the route that was rate limited.
-        pub fn method(&self) -> &Method {
-            &self.key.method
+            metrics::Counter::from_arc(self.0.lock().unwrap().entry(name).or_default().clone())
         }
 
-        /// Get the quota for the route that was rate limited.
-        pub fn quota(&self) -> gcra::Quota {
-            self.layer.builder.quotas.get(&self.key.as_route()).copied().expect("no quota found for route")
+        fn register_gauge(&self, _: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
+            metrics::Gauge::noop()
         }
 
-        /// See [`gcra::RateLimiter::penalize`] for more information.
-        pub async fn penalize(&self, penalty: Duration) -> bool {
-            self.layer.limiter.penalize(&self.key, penalty).await
+        fn register_histogram(&self, _: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Histogram {
+            metrics::Histogram::noop()
         }
+    }
 
-        /// See [`gcra::RateLimiter::penalize_sync`] for more information.
-        pub fn penalize_sync(&self, penalty: Duration) -> bool {
-            self.layer.limiter.penalize_sync(&self.key, penalty)
-        }
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_count_outcomes() {
+        let recorder = CounterRecorder::default();
 
-        /// See [`gcra::RateLimiter::reset`] for more information.
-        pub async fn reset(&self) -> bool {
-            self.layer.limiter.reset(&self.key).await
-        }
+        // the local recorder is thread-local, so drive the requests on this thread
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
 
-        /// See [`gcra::RateLimiter::reset_sync`] for more information.
-        pub fn reset_sync(&self) -> bool {
-            self.layer.limiter.reset_sync(&self.key)
-        }
+        metrics::with_local_recorder(&recorder, || {
+            rt.block_on(async {
+                let layer = RateLimitLayer::<()>::new(gcra::Quota::per_hour(1).with_burst(2));
 
-        /// See [`gcra::RateLimiter::clean`] for more information.
-        pub async fn clean(&self, before: Instant) {
-            self.layer.limiter.clean(before).await;
-        }
+                for allowed in [true, true, false, false, false] {
+                    assert_eq!(call(layer.clone()).await.is_ok(), allowed);
+                }
 
-        /// See [`gcra::RateLimiter::clean_sync`] for more information.
-        pub fn clean_sync(&self, before: Instant) {
-            self.layer.limiter.clean_sync(before);
-        }
+                for _ in 0..2 {
+                    assert!(call(failing(true)).await.is_ok());
+                }
+            })
+        });
+
+        assert_eq!(recorder.get("axum_gcra.requests_total{outcome=allowed}"), 2);
+        assert_eq!(recorder.get("axum_gcra.requests_total{outcome=throttled}"), 3);
+        assert_eq!(recorder.get("axum_gcra.requests_total{outcome=error}"), 2);
+        assert_eq!(recorder.get("axum_gcra.throttled_total{}"), 3);
     }
 }
@@ -0,0 +1,349 @@
+//! Per-request quota and cost resolution.
+//!
+//! By default, the [`RateLimitLayer`](crate::RateLimitLayer) selects quotas from the route table
+//! given to the [`RateLimitLayerBuilder`](crate::RateLimitLayerBuilder), falling back to the default quota.
+//! A [`QuotaResolver`] can instead select the quota from anything in the request.
+//!
+//! Likewise, every request costs a single cell by default, which a [`CostResolver`] can override.
+
+use std::{collections::HashMap, hash::Hash};
+
+use http::{request::Parts, Method};
+
+use crate::{gcra::Quota, RandomState};
+
+/// Resolves the quota to use for a request, before the rate limit is checked.
+///
+/// Returning `None` falls back to the route table and default quota.
+///
+/// This is implemented for any `Fn(&Parts) -> Option<Quota>` closure.
+pub trait QuotaResolver: Send + Sync + 'static {
+    /// Resolve the quota for the request.
+    fn resolve(&self, parts: &Parts) -> Option<Quota>;
+}
+
+impl<F> QuotaResolver for F
+where
+    F: Fn(&Parts) -> Option<Quota> + Send + Sync + 'static,
+{
+    #[inline]
+    fn resolve(&self, parts: &Parts) -> Option<Quota> {
+        self(parts)
+    }
+}
+
+/// A [`QuotaResolver`] selecting among a registered set of quota tiers,
+/// such as from a user's plan inserted into the request extensions by authentication middleware.
+///
+/// Requests for which the selector returns `None`, or an unregistered tier, fall back to the route table.
+///
+/// The tier only selects the quota, and is not part of the rate limiting key, so a user whose tier
+/// changes will continue to use the same bucket with the new quota applied.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use axum::Router;
+/// use axum_gcra::{gcra::Quota, quota::Tiered, RateLimitLayer};
+///
+/// #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+/// enum Plan {
+///     Free,
+///     Pro,
+///     Enterprise,
+/// }
+///
+/// let tiers = Tiered::new(|parts: &http::request::Parts| parts.extensions.get::<Plan>().copied())
+///     .with_tier(Plan::Free, Quota::per_minute(10))
+///     .with_tier(Plan::Pro, Quota::per_minute(100))
+///     .with_tier(Plan::Enterprise, Quota::per_minute(1000));
+///
+/// let app = Router::<()>::new().route_layer(
+///     RateLimitLayer::<()>::builder().with_quota_resolver(tiers).default_handle_error(),
+/// );
+/// ```
+pub struct Tiered<T, F> {
+    tiers: HashMap<T, Quota, RandomState>,
+    select: F,
+}
+
+impl<T, F> Tiered<T, F>
+where
+    T: Hash + Eq,
+    F: Fn(&Parts) -> Option<T>,
+{
+    /// Constructs a new set of tiers with the given selector, and no tiers registered.
+    #[must_use]
+    pub fn new(select: F) -> Self {
+        Tiered {
+            tiers: HashMap::default(),
+            select,
+        }
+    }
+
+    /// Register the quota for a tier, replacing any existing quota for it.
+    pub fn add_tier(&mut self, tier: T, quota: Quota) {
+        self.tiers.insert(tier, quota);
+    }
+
+    /// Register the quota for a tier, replacing any existing quota for it.
+    #[must_use]
+    pub fn with_tier(mut self, tier: T, quota: Quota) -> Self {
+        self.add_tier(tier, quota);
+        self
+    }
+
+    /// Get the quota registered for a tier.
+    pub fn tier(&self, tier: &T) -> Option<Quota> {
+        self.tiers.get(tier).copied()
+    }
+}
+
+impl<T, F> QuotaResolver for Tiered<T, F>
+where
+    T: Hash + Eq + Send + Sync + 'static,
+    F: Fn(&Parts) -> Option<T> + Send + Sync + 'static,
+{
+    fn resolve(&self, parts: &Parts) -> Option<Quota> {
+        self.tier(&(self.select)(parts)?)
+    }
+}
+
+/// A [`QuotaResolver`] selecting the quota by request method, such as to allow more reads than writes.
+///
+/// Requests with a method that has no registered quota use the resolver's default quota, if set,
+/// or otherwise fall back to the route table. Since the method is always part of the rate limiting key,
+/// each method uses its own bucket for the same client.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use axum::Router;
+/// use axum_gcra::{gcra::Quota, quota::MethodQuotaResolver, RateLimitLayer};
+/// use http::Method;
+///
+/// let methods = MethodQuotaResolver::new()
+///     .with_method(Method::GET, Quota::per_minute(100))
+///     .with_method(Method::POST, Quota::per_minute(10))
+///     .with_exempt(Method::OPTIONS);
+///
+/// let app = Router::<()>::new().route_layer(
+///     RateLimitLayer::<()>::builder().with_quota_resolver(methods).default_handle_error(),
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MethodQuotaResolver {
+    methods: HashMap<Method, Quota, RandomState>,
+    default: Option<Quota>,
+}
+
+impl MethodQuotaResolver {
+    /// Constructs a new resolver with no methods registered and no default quota.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the quota for a method, replacing any existing quota for it.
+    pub fn add_method(&mut self, method: Method, quota: Quota) {
+        self.methods.insert(method, quota);
+    }
+
+    /// Register the quota for a method, replacing any existing quota for it.
+    #[must_use]
+    pub fn with_method(mut self, method: Method, quota: Quota) -> Self {
+        self.add_method(method, quota);
+        self
+    }
+
+    /// Exempt a method from rate limiting, such as `OPTIONS` for CORS preflight requests,
+    /// by registering an [unlimited](Quota::unlimited) quota for it.
+    ///
+    /// Exempt requests are still counted in a bucket that is never exhausted. To skip the rate limiter
+    /// entirely, use [`with_exemption`](crate::RateLimitLayerBuilder::with_exemption) on the layer instead.
+    pub fn add_exempt(&mut self, method: Method) {
+        self.add_method(method, Quota::unlimited());
+    }
+
+    /// Exempt a method from rate limiting. See [`MethodQuotaResolver::add_exempt`].
+    #[must_use]
+    pub fn with_exempt(mut self, method: Method) -> Self {
+        self.add_exempt(method);
+        self
+    }
+
+    /// Set the quota for methods with no registered quota, instead of falling back to the route table.
+    #[must_use]
+    pub fn with_default(mut self, quota: Quota) -> Self {
+        self.default = Some(quota);
+        self
+    }
+
+    /// Get the quota registered for a method.
+    pub fn method(&self, method: &Method) -> Option<Quota> {
+        self.methods.get(method).copied()
+    }
+}
+
+impl QuotaResolver for MethodQuotaResolver {
+    fn resolve(&self, parts: &Parts) -> Option<Quota> {
+        self.method(&parts.method).or(self.default)
+    }
+}
+
+/// Resolves the number of cells a request costs, before the rate limit is checked.
+///
+/// See [`RateLimiter::req_n`](crate::gcra::RateLimiter::req_n) for how the cost is applied.
+///
+/// This is implemented for any `Fn(&Parts) -> u64` closure.
+pub trait CostResolver: Send + Sync + 'static {
+    /// Resolve the cost of the request.
+    fn cost(&self, parts: &Parts) -> u64;
+}
+
+impl<F> CostResolver for F
+where
+    F: Fn(&Parts) -> u64 + Send + Sync + 'static,
+{
+    #[inline]
+    fn cost(&self, parts: &Parts) -> u64 {
+        self(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, routing::any, Router};
+    use http::Request;
+    use tower::ServiceExt;
+
+    use crate::RateLimitLayer;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum Plan {
+        Free,
+        Pro,
+        Enterprise,
+    }
+
+    /// Route limited to one request per hour unless the resolver says otherwise,
+    /// with the [`Plan`] inserted from the `x-plan` header as authentication middleware would.
+    fn app(resolver: impl QuotaResolver) -> Router {
+        let layer = RateLimitLayer::<()>::builder()
+            .with_default_quota(Quota::per_hour(1))
+            .with_quota_resolver(resolver)
+            .default_handle_error();
+
+        let authenticate = axum::middleware::map_request(|mut req: Request<Body>| async move {
+            let plan = match req.headers().get("x-plan").map(|plan| plan.as_bytes()) {
+                Some(b"free") => Some(Plan::Free),
+                Some(b"pro") => Some(Plan::Pro),
+                Some(b"enterprise") => Some(Plan::Enterprise),
+                _ => None,
+            };
+
+            if let Some(plan) = plan {
+                req.extensions_mut().insert(plan);
+            }
+
+            req
+        });
+
+        Router::new().route("/", any(|| async {})).route_layer(layer).layer(authenticate)
+    }
+
+    async fn send(app: &Router, method: Method, plan: Option<&str>) -> u16 {
+        let mut req = Request::builder().method(method).uri("/");
+        if let Some(plan) = plan {
+            req = req.header("x-plan", plan);
+        }
+
+        let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        res.status().as_u16()
+    }
+
+    fn tiers() -> impl QuotaResolver {
+        Tiered::new(|parts: &Parts| parts.extensions.get::<Plan>().copied())
+            .with_tier(Plan::Free, Quota::per_hour(2).with_burst(2))
+            .with_tier(Plan::Pro, Quota::per_hour(3).with_burst(3))
+            .with_tier(Plan::Enterprise, Quota::per_hour(4).with_burst(4))
+    }
+
+    #[tokio::test]
+    async fn tiered_limits() {
+        // unregistered or missing tiers fall back to the default quota
+        let plans = [
+            (Some("free"), 2),
+            (Some("pro"), 3),
+            (Some("enterprise"), 4),
+            (Some("other"), 1),
+            (None, 1),
+        ];
+
+        for (plan, allowed) in plans {
+            let app = app(tiers());
+
+            let mut statuses = Vec::new();
+            for _ in 0..5 {
+                statuses.push(send(&app, Method::GET, plan).await);
+            }
+
+            let expected: Vec<u16> = (0..5).map(|i| if i < allowed { 200 } else { 429 }).collect();
+            assert_eq!(statuses, expected, "{plan:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tier_change_keeps_bucket() {
+        let upgraded = app(tiers());
+
+        for status in [200, 200, 429] {
+            assert_eq!(send(&upgraded, Method::GET, Some("free")).await, status);
+        }
+
+        // upgrading doesn't grant a fresh burst, as the tier is not part of the key
+        assert_eq!(send(&upgraded, Method::GET, Some("pro")).await, 429);
+
+        // and after downgrading, the requests already made count against the new quota
+        let downgraded = app(tiers());
+        assert_eq!(send(&downgraded, Method::GET, Some("pro")).await, 200);
+        assert_eq!(send(&downgraded, Method::GET, Some("free")).await, 200);
+        assert_eq!(send(&downgraded, Method::GET, Some("free")).await, 429);
+    }
+
+    #[tokio::test]
+    async fn method_quotas() {
+        let methods = app(MethodQuotaResolver::new()
+            .with_method(Method::GET, Quota::per_hour(3).with_burst(3))
+            .with_method(Method::POST, Quota::per_hour(2).with_burst(2))
+            .with_exempt(Method::OPTIONS));
+
+        // each method is throttled by its own quota, independently of the others
+        for _ in 0..2 {
+            assert_eq!(send(&methods, Method::POST, None).await, 200);
+        }
+        assert_eq!(send(&methods, Method::POST, None).await, 429);
+
+        for _ in 0..3 {
+            assert_eq!(send(&methods, Method::GET, None).await, 200);
+        }
+        assert_eq!(send(&methods, Method::GET, None).await, 429);
+
+        // preflight requests are never throttled
+        for _ in 0..100 {
+            assert_eq!(send(&methods, Method::OPTIONS, None).await, 200);
+        }
+
+        // and any other method falls back to the default of the route
+        assert_eq!(send(&methods, Method::PUT, None).await, 200);
+        assert_eq!(send(&methods, Method::PUT, None).await, 429);
+
+        // unless the resolver has its own
+        let defaulted = app(MethodQuotaResolver::new().with_default(Quota::per_hour(2).with_burst(2)));
+        for status in [200, 200, 429] {
+            assert_eq!(send(&defaulted, Method::PUT, None).await, status);
+        }
+    }
+}
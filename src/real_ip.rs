@@ -1,45 +1,129 @@
 use std::{
+    convert::Infallible,
     fmt::{self, Debug, Display},
     future,
-    hash::Hash,
-    net::{IpAddr, SocketAddr},
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::Deref,
     str::FromStr,
+    sync::{Arc, OnceLock},
     task::{Context, Poll},
 };
 
-use axum::{extract::FromRequestParts, response::IntoResponse};
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    response::{IntoResponse, Response},
+};
 use http::{header::HeaderName, request::Parts, HeaderValue, Request, StatusCode};
 use tower::{Layer, Service};
 
+mod cidr;
+mod filter;
+mod forwarded;
+#[cfg(feature = "signed_ip")]
+mod signed;
+
+pub use cidr::{Cidr, CidrParseError};
+pub use filter::{IpFilterLayer, IpFilterService};
+#[cfg(feature = "signed_ip")]
+pub use signed::sign_client_ip;
+
 /// Wrapper around [`std::net::IpAddr`] that can be extracted from the request parts.
 ///
 /// This extractor tries to resolve the client's IP address from common proxy/load balancer headers,
-/// falling back to the underlying socket (if `ConnectInfo` is enabled).
+/// falling back to the underlying socket (with the `connect_info` feature), then to the
+/// [fallback address](RealIpLayer::with_fallback_ip) if configured. A PROXY protocol extension,
+/// if [configured](RealIpLayer::proxy_protocol_extension), takes precedence over all headers.
+///
+/// If no IP address can be determined, extraction is rejected with [`IpAddrRejection`](struct@IpAddrRejection).
+/// Extract `Option<RealIp>` instead to handle a missing IP address manually.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct RealIp(pub IpAddr);
 
+/// Like [`RealIp`], but with the host bits of the address zeroed out, keeping only
+/// the first `V4_PREFIX` bits of IPv4 addresses and the first `V6_PREFIX` bits of IPv6 addresses.
+///
+/// For example, `RealIpMask<24, 48>` will map `192.0.2.123` to `192.0.2.0`
+/// and `2001:db8:1234:5678::1` to `2001:db8:1234::`.
+///
+/// Prefix lengths greater than the address size (32 for IPv4, 128 for IPv6) fail to compile.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct RealIpMask<const V4_PREFIX: u8, const V6_PREFIX: u8>(pub RealIp);
+
 /// Like [`RealIp`], but with the last 64 bits of IPv6 addresses zeroed out.
+///
+/// IPv4 addresses are left untouched. This masks addresses the same as `RealIpMask<32, 64>`,
+/// and converts to and from it with [`From`].
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct RealIpPrivacyMask(pub RealIp);
 
-impl From<RealIp> for RealIpPrivacyMask {
+/// Like [`RealIp`], but keeping only the first 24 bits of IPv4 addresses and the first 56 bits of IPv6 addresses,
+/// so every client within a typical ISP allocation shares a single rate limit.
+///
+/// Limiting by exact address lets a client with a whole IPv6 `/64`, or often a `/56` from residential ISPs,
+/// rotate through fresh addresses to bypass the limit. Coarser keys close that gap, but unrelated clients
+/// behind the same carrier-grade NAT or in the same `/24` will also throttle each other, so quotas should be
+/// more generous than for a single address. Use [`RealIpMask`] directly for other prefix lengths.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Instant;
+/// use axum_gcra::{gcra::{Quota, RateLimiter}, real_ip::{RealIp, RealIpNetworkMask}};
+///
+/// let limiter = RateLimiter::<RealIpNetworkMask>::new(8192, Default::default());
+/// let key = |ip: &str| RealIpNetworkMask::from(RealIp(ip.parse().unwrap()));
+/// let (quota, now) = (Quota::per_hour(1), Instant::now());
+///
+/// assert!(limiter.req_sync(key("192.0.2.1"), quota, now).is_ok());
+/// assert!(limiter.req_sync(key("192.0.2.200"), quota, now).is_err()); // same /24
+/// assert!(limiter.req_sync(key("192.0.3.1"), quota, now).is_ok());
+///
+/// assert!(limiter.req_sync(key("2001:db8:0:aa::1"), quota, now).is_ok());
+/// assert!(limiter.req_sync(key("2001:db8:0:ff::2"), quota, now).is_err()); // same /56
+/// assert!(limiter.req_sync(key("2001:db8:0:100::1"), quota, now).is_ok());
+/// ```
+pub type RealIpNetworkMask = RealIpMask<24, 56>;
+
+impl<const V4_PREFIX: u8, const V6_PREFIX: u8> From<RealIp> for RealIpMask<V4_PREFIX, V6_PREFIX> {
     #[inline]
     fn from(ip: RealIp) -> Self {
-        match ip.0 {
-            IpAddr::V4(v4) => RealIpPrivacyMask(RealIp(IpAddr::V4(v4))),
-            IpAddr::V6(v6) => {
-                let mut segments = v6.segments();
-                // zero out lower 4 segments = last 64 bits
-                segments[4] = 0;
-                segments[5] = 0;
-                segments[6] = 0;
-                segments[7] = 0;
-                RealIpPrivacyMask(RealIp(IpAddr::V6(std::net::Ipv6Addr::from(segments))))
-            }
+        const {
+            assert!(V4_PREFIX <= 32, "IPv4 prefix must be at most 32 bits");
+            assert!(V6_PREFIX <= 128, "IPv6 prefix must be at most 128 bits");
         }
+
+        let prefix = match ip.0 {
+            IpAddr::V4(_) => V4_PREFIX,
+            IpAddr::V6(_) => V6_PREFIX,
+        };
+
+        // SAFETY: prefix lengths are checked above
+        RealIpMask(RealIp(unsafe { Cidr::new(ip.0, prefix).unwrap_unchecked() }.addr()))
+    }
+}
+
+impl From<RealIp> for RealIpPrivacyMask {
+    #[inline]
+    fn from(ip: RealIp) -> Self {
+        RealIpMask::<32, 64>::from(ip).into()
+    }
+}
+
+impl From<RealIpMask<32, 64>> for RealIpPrivacyMask {
+    #[inline]
+    fn from(RealIpMask(ip): RealIpMask<32, 64>) -> Self {
+        RealIpPrivacyMask(ip)
+    }
+}
+
+impl From<RealIpPrivacyMask> for RealIpMask<32, 64> {
+    #[inline]
+    fn from(RealIpPrivacyMask(ip): RealIpPrivacyMask) -> Self {
+        RealIpMask(ip)
     }
 }
 
@@ -53,6 +137,16 @@ impl Display for RealIp {
         Display::fmt(&self.0, f)
     }
 }
+impl<const V4_PREFIX: u8, const V6_PREFIX: u8> Debug for RealIpMask<V4_PREFIX, V6_PREFIX> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+impl<const V4_PREFIX: u8, const V6_PREFIX: u8> Display for RealIpMask<V4_PREFIX, V6_PREFIX> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
 impl Debug for RealIpPrivacyMask {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         Debug::fmt(&self.0, f)
@@ -70,6 +164,12 @@ impl Deref for RealIp {
         &self.0
     }
 }
+impl<const V4_PREFIX: u8, const V6_PREFIX: u8> Deref for RealIpMask<V4_PREFIX, V6_PREFIX> {
+    type Target = RealIp;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
 impl Deref for RealIpPrivacyMask {
     type Target = RealIp;
     fn deref(&self) -> &Self::Target {
@@ -77,13 +177,331 @@ impl Deref for RealIpPrivacyMask {
     }
 }
 
-/// IP Address not found, returns 400.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct IpAddrRejection;
+/// IP Address not found, returns 400 unless customized with [`RealIpLayer::with_rejection`].
+///
+/// The [reason](IpAddrRejection::reason) describes why no address could be determined, such as for logging.
+///
+/// For compatibility with code written when this was a unit struct, the [`IpAddrRejection`](const@IpAddrRejection)
+/// constant can still be used as a value or pattern, being the default rejection with no reason beyond
+/// [`RealIpError::NoHeaders`]:
+///
+/// ```rust
+/// use axum_gcra::real_ip::{IpAddrRejection, RealIpError};
+///
+/// let rejection = IpAddrRejection;
+///
+/// assert_eq!(rejection, IpAddrRejection::default());
+/// assert_eq!(*rejection.reason(), RealIpError::NoHeaders);
+/// assert!(matches!(rejection, IpAddrRejection));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IpAddrRejection {
+    reason: RealIpError,
+    response: Option<RejectionFn>,
+}
+
+/// The default [`IpAddrRejection`](struct@IpAddrRejection), as when it was a unit struct.
+#[allow(non_upper_case_globals)]
+pub const IpAddrRejection: IpAddrRejection = IpAddrRejection {
+    reason: RealIpError::NoHeaders,
+    response: None,
+};
+
+impl Default for IpAddrRejection {
+    fn default() -> Self {
+        IpAddrRejection
+    }
+}
+
+impl IpAddrRejection {
+    fn from_parts(parts: &Parts) -> Self {
+        let config = RealIpConfig::from_parts(parts);
+
+        IpAddrRejection {
+            reason: RealIpError::diagnose(parts, config),
+            response: config.rejection.clone(),
+        }
+    }
+
+    /// Returns the reason no address could be determined.
+    #[inline]
+    #[must_use]
+    pub fn reason(&self) -> &RealIpError {
+        &self.reason
+    }
+}
+
+impl fmt::Display for IpAddrRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.reason, f)
+    }
+}
+
+impl std::error::Error for IpAddrRejection {}
+
+/// Reason the client address could not be determined, as given by [`IpAddrRejection::reason`].
+///
+/// Some reasons only exist with optional features, and more may be added in the future,
+/// so matches must include a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RealIpError {
+    /// None of the configured headers were present, and the socket address
+    /// is not consulted without the `connect_info` feature.
+    NoHeaders,
+
+    /// A configured header was present, but did not yield a usable address, such as when it failed to parse
+    /// or held a private address with [`RealIpLayer::with_reject_private`].
+    ///
+    /// This is the first such header in order of precedence.
+    Unparseable {
+        /// The name of the header.
+        header: HeaderName,
+    },
+
+    /// None of the configured headers were present, and the [`ConnectInfo`](axum::extract::ConnectInfo)
+    /// extension was missing, such as when not serving with `into_make_service_with_connect_info`.
+    NoConnectInfo,
+
+    /// The [signed header](RealIpLayer::with_signed_header) was present, but its signature was invalid,
+    /// or it did not hold a valid address, so the value may have been tampered with.
+    #[cfg(feature = "signed_ip")]
+    InvalidSignature {
+        /// The name of the header.
+        header: HeaderName,
+    },
+
+    /// The header of highest precedence yielded a different address than the next header,
+    /// with [`HeaderConsistency::Reject`].
+    Inconsistent {
+        /// The name of the header of highest precedence.
+        header: HeaderName,
+    },
+}
+
+impl RealIpError {
+    /// Determines why resolution failed, only called on the slow path after it already has.
+    fn diagnose(parts: &Parts, config: &RealIpConfig) -> RealIpError {
+        #[cfg(feature = "signed_ip")]
+        if let Some(ref signed) = config.signed_header {
+            if parts.headers.contains_key(&signed.header) {
+                return RealIpError::InvalidSignature {
+                    header: signed.header.clone(),
+                };
+            }
+        }
+
+        // peer headers fall back to the socket, so only fail without it
+        #[cfg(feature = "connect_info")]
+        if !config.peer_headers.is_empty() {
+            return RealIpError::NoConnectInfo;
+        }
+
+        if config.consistency == HeaderConsistency::Reject {
+            let relaxed = RealIpConfig {
+                consistency: HeaderConsistency::PreferHighest,
+                ..config.clone()
+            };
+
+            if let Some((_, RealIpSource::Header(header))) = resolve_ip(parts, &relaxed) {
+                return RealIpError::Inconsistent { header };
+            }
+        }
+
+        let cf_ipv6 = config.prefer_cf_ipv6 && parts.headers.contains_key(CF_CONNECTING_IPV6);
+
+        for (header, _) in &config.headers {
+            // tried immediately before `cf-connecting-ip`, as in resolution
+            if cf_ipv6 && *header == CF_CONNECTING_IP {
+                return RealIpError::Unparseable {
+                    header: CF_CONNECTING_IPV6,
+                };
+            }
+
+            if parts.headers.contains_key(header) {
+                return RealIpError::Unparseable { header: header.clone() };
+            }
+        }
+
+        match cfg!(feature = "connect_info") {
+            true => RealIpError::NoConnectInfo,
+            false => RealIpError::NoHeaders,
+        }
+    }
+}
+
+impl fmt::Display for RealIpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RealIpError::NoHeaders => f.write_str("no client address headers present"),
+            RealIpError::Unparseable { header } => write!(f, "invalid client address in `{header}` header"),
+            RealIpError::NoConnectInfo => f.write_str("no client address headers or connection info present"),
+            #[cfg(feature = "signed_ip")]
+            RealIpError::InvalidSignature { header } => write!(f, "invalid signature in `{header}` header"),
+            RealIpError::Inconsistent { header } => {
+                write!(f, "client address in `{header}` header disagrees with other headers")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RealIpError {}
+
+impl From<IpAddrRejection> for RealIpError {
+    #[inline]
+    fn from(rejection: IpAddrRejection) -> Self {
+        rejection.reason
+    }
+}
 
 impl IntoResponse for IpAddrRejection {
-    fn into_response(self) -> axum::response::Response {
-        StatusCode::BAD_REQUEST.into_response()
+    fn into_response(self) -> Response {
+        match self.response {
+            Some(response) => (response.0)(),
+            None => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+}
+
+/// Callback used to build the response for an [`IpAddrRejection`](struct@IpAddrRejection).
+#[derive(Clone)]
+struct RejectionFn(Arc<dyn Fn() -> Response + Send + Sync>);
+
+impl Debug for RejectionFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RejectionFn")
+    }
+}
+
+// compared by identity, so rejections are equal if configured by the same layer
+
+impl PartialEq for RejectionFn {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for RejectionFn {}
+
+impl Hash for RejectionFn {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).cast::<()>().hash(state);
+    }
+}
+
+impl RealIp {
+    /// Returns `true` if the address is publicly routable.
+    ///
+    /// Returns `false` for unspecified, loopback, private (RFC 1918), shared (CGNAT, `100.64.0.0/10`),
+    /// link-local and reserved (`240.0.0.0/4`) IPv4 addresses, as well as unspecified, loopback,
+    /// unique local (`fc00::/7`) and link-local IPv6 addresses. IPv4-mapped IPv6 addresses are checked as IPv4.
+    ///
+    /// Note that Cloudflare's [pseudo IPv4](RealIp::is_cloudflare_pseudo_ipv4) addresses are reserved,
+    /// and therefore not global.
+    #[must_use]
+    pub fn is_global(&self) -> bool {
+        fn is_global_v4(ip: Ipv4Addr) -> bool {
+            let [a, b, ..] = ip.octets();
+
+            // 100.64.0.0/10
+            let shared = a == 100 && (b & 0b1100_0000) == 64;
+
+            // 240.0.0.0/4, including the broadcast address
+            let reserved = a >= 240;
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || shared
+                || reserved)
+        }
+
+        match self.0 {
+            IpAddr::V4(ip) => is_global_v4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => is_global_v4(ip),
+                None => {
+                    !(ip.is_unspecified()
+                        || ip.is_loopback()
+                        || ip.is_unique_local()
+                        || ip.is_unicast_link_local())
+                }
+            },
+        }
+    }
+
+    /// Returns `true` if the address is in the reserved `240.0.0.0/4` range used by
+    /// Cloudflare's Pseudo IPv4, which maps IPv6 clients to IPv4 addresses for legacy applications.
+    #[inline]
+    #[must_use]
+    pub const fn is_cloudflare_pseudo_ipv4(&self) -> bool {
+        match self.0 {
+            IpAddr::V4(ip) => ip.octets()[0] >= 240 && !ip.is_broadcast(),
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// Returns `true` if the address falls within the given range, as by [`Cidr::contains`].
+    ///
+    /// This is the same check used for [trusted proxies](RealIpLayer::with_trusted_proxies),
+    /// the [`IpFilterLayer`] and [CIDR exemptions](crate::RateLimitLayerBuilder::with_cidr_exemption),
+    /// so IPv4-mapped IPv6 addresses are matched against IPv4 ranges.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::net::IpAddr;
+    /// use axum_gcra::real_ip::{Cidr, RealIp};
+    ///
+    /// let ip = |s: &str| RealIp(s.parse::<IpAddr>().unwrap());
+    ///
+    /// let v4: Cidr = "192.0.2.0/24".parse().unwrap();
+    /// assert!(ip("192.0.2.0").in_cidr(&v4));
+    /// assert!(ip("192.0.2.255").in_cidr(&v4));
+    /// assert!(ip("::ffff:192.0.2.255").in_cidr(&v4));
+    /// assert!(!ip("192.0.3.0").in_cidr(&v4));
+    ///
+    /// let v6: Cidr = "2001:db8::/32".parse().unwrap();
+    /// assert!(ip("2001:db8::").in_cidr(&v6));
+    /// assert!(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff").in_cidr(&v6));
+    /// assert!(!ip("2001:db9::").in_cidr(&v6));
+    /// assert!(!ip("192.0.2.1").in_cidr(&v6));
+    /// ```
+    #[inline]
+    #[must_use]
+    pub fn in_cidr(&self, cidr: &Cidr) -> bool {
+        cidr.contains(self.0)
+    }
+
+    /// Returns `true` if the address falls within any of the given ranges, as by [`RealIp::in_cidr`].
+    #[must_use]
+    pub fn in_any_cidr<'a>(&self, cidrs: impl IntoIterator<Item = &'a Cidr>) -> bool {
+        cidrs.into_iter().any(|cidr| self.in_cidr(cidr))
+    }
+
+    /// Returns the address with IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) converted to IPv4.
+    ///
+    /// Other addresses are returned unchanged.
+    #[inline]
+    #[must_use]
+    pub const fn canonical(self) -> RealIp {
+        match self.0 {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => RealIp(IpAddr::V4(ip)),
+                None => self,
+            },
+            IpAddr::V4(_) => self,
+        }
+    }
+
+    /// Resolves the IP from the extension set by [`RealIpService`], or from the request parts directly.
+    #[inline]
+    pub(crate) fn lookup(parts: &Parts) -> Option<RealIp> {
+        match parts.extensions.get::<RealIp>() {
+            Some(ip) => Some(*ip),
+            None => get_ip_from_parts(parts),
+        }
     }
 }
 
@@ -94,10 +512,18 @@ impl<S> FromRequestParts<S> for RealIp {
         parts: &mut Parts,
         _: &S,
     ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
-        if let Some(ip) = parts.extensions.get::<RealIp>() {
-            return future::ready(Ok(*ip));
-        }
-        future::ready(get_ip_from_parts(parts).ok_or(IpAddrRejection))
+        future::ready(RealIp::lookup(parts).ok_or_else(|| IpAddrRejection::from_parts(parts)))
+    }
+}
+
+impl<S, const V4_PREFIX: u8, const V6_PREFIX: u8> FromRequestParts<S> for RealIpMask<V4_PREFIX, V6_PREFIX> {
+    type Rejection = IpAddrRejection;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(RealIp::lookup(parts).map(Into::into).ok_or_else(|| IpAddrRejection::from_parts(parts)))
     }
 }
 
@@ -108,93 +534,2318 @@ impl<S> FromRequestParts<S> for RealIpPrivacyMask {
         parts: &mut Parts,
         _: &S,
     ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
-        if let Some(ip) = parts.extensions.get::<RealIp>() {
-            return future::ready(Ok((*ip).into()));
-        }
-        future::ready(get_ip_from_parts(parts).map(Into::into).ok_or(IpAddrRejection))
+        future::ready(RealIp::lookup(parts).map(Into::into).ok_or_else(|| IpAddrRejection::from_parts(parts)))
     }
 }
 
-/// Service that adds the [`RealIp`] extension.
-#[derive(Debug, Clone, Copy)]
-pub struct RealIpService<I>(I);
+/// Allows extracting `Option<RealIp>`, which is `None` instead of rejecting when no IP could be determined.
+///
+/// When used as a rate limiting key, all requests without an IP will share a single bucket.
+impl<S> OptionalFromRequestParts<S> for RealIp {
+    type Rejection = Infallible;
 
-/// Layer that adds the [`RealIp`] extension.
-#[derive(Debug, Clone, Copy)]
-pub struct RealIpLayer;
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(RealIp::lookup(parts)))
+    }
+}
 
-impl<B, I> Service<Request<B>> for RealIpService<I>
-where
-    I: Service<Request<B>>,
+/// Allows extracting `Option<RealIpMask<..>>`, which is `None` instead of rejecting
+/// when no IP could be determined.
+///
+/// When used as a rate limiting key, all requests without an IP will share a single bucket.
+impl<S, const V4_PREFIX: u8, const V6_PREFIX: u8> OptionalFromRequestParts<S>
+    for RealIpMask<V4_PREFIX, V6_PREFIX>
 {
-    type Response = I::Response;
-    type Error = I::Error;
-    type Future = I::Future;
+    type Rejection = Infallible;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.0.poll_ready(cx)
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(RealIp::lookup(parts).map(Into::into)))
     }
+}
 
-    fn call(&mut self, req: Request<B>) -> Self::Future {
-        let (mut parts, body) = req.into_parts();
+/// Allows extracting `Option<RealIpPrivacyMask>`, which is `None` instead of rejecting
+/// when no IP could be determined.
+///
+/// When used as a rate limiting key, all requests without an IP will share a single bucket.
+impl<S> OptionalFromRequestParts<S> for RealIpPrivacyMask {
+    type Rejection = Infallible;
 
-        if let Some(ip) = get_ip_from_parts(&parts) {
-            parts.extensions.insert(ip);
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(RealIp::lookup(parts).map(Into::into)))
+    }
+}
+
+/// Where the [`RealIp`] of a request was found, for debugging header precedence or detecting spoofed headers.
+///
+/// Inserted as an extension by the [`RealIpLayer`] alongside the [`RealIp`], and can be extracted directly,
+/// rejecting with [`IpAddrRejection`](struct@IpAddrRejection) in the same way if no address could be determined.
+/// Extract `Option<RealIpSource>` instead to handle a missing address manually.
+///
+/// Some sources only exist with optional features, and more may be added in the future,
+/// so matches must include a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RealIpSource {
+    /// The address was read from a [PROXY protocol extension](RealIpLayer::proxy_protocol_extension).
+    ProxyProtocol,
+
+    /// The address was read from the given header.
+    Header(HeaderName),
+
+    /// The address was read from the given header, after verifying its
+    /// [signature](RealIpLayer::with_signed_header).
+    #[cfg(feature = "signed_ip")]
+    SignedHeader(HeaderName),
+
+    /// The address of the connected socket was used, as no configured header yielded an address.
+    Socket,
+
+    /// The [fallback address](RealIpLayer::with_fallback_ip) was used, as neither the headers
+    /// nor the socket yielded an address.
+    Fallback,
+
+    /// The address was already present as an extension, inserted by an outer layer,
+    /// and was [preserved](RealIpLayer::with_preserve_existing).
+    Extension,
+}
+
+impl Display for RealIpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RealIpSource::ProxyProtocol => f.write_str("PROXY protocol"),
+            RealIpSource::Header(header) => write!(f, "`{header}` header"),
+            #[cfg(feature = "signed_ip")]
+            RealIpSource::SignedHeader(header) => write!(f, "signed `{header}` header"),
+            RealIpSource::Socket => f.write_str("socket"),
+            RealIpSource::Fallback => f.write_str("fallback"),
+            RealIpSource::Extension => f.write_str("extension"),
         }
+    }
+}
 
-        self.0.call(Request::from_parts(parts, body))
+impl RealIpSource {
+    /// Resolves the source from the extension set by [`RealIpService`], or from the request parts directly.
+    #[inline]
+    fn lookup(parts: &Parts) -> Option<RealIpSource> {
+        match parts.extensions.get::<RealIpSource>() {
+            Some(source) => Some(source.clone()),
+            None => get_ip_and_source_from_parts(parts).map(|(_, source)| source),
+        }
     }
 }
 
-impl<I> Layer<I> for RealIpLayer {
-    type Service = RealIpService<I>;
+impl<S> FromRequestParts<S> for RealIpSource {
+    type Rejection = IpAddrRejection;
 
-    fn layer(&self, inner: I) -> Self::Service {
-        RealIpService(inner)
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(RealIpSource::lookup(parts).ok_or_else(|| IpAddrRejection::from_parts(parts)))
     }
 }
 
-pub(crate) fn get_ip_from_parts(parts: &Parts) -> Option<RealIp> {
-    fn parse_ip(val: &HeaderValue, allow_port: bool) -> Option<IpAddr> {
-        let s = val.to_str().ok()?.trim();
+impl<S> OptionalFromRequestParts<S> for RealIpSource {
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(RealIpSource::lookup(parts)))
+    }
+}
 
-        // Split on `,` for multi-hop headers (take the first entry)
-        let first = s.split(',').next()?.trim();
+/// Both the [`RealIp`] and [`RealIpPrivacyMask`] of the request, from a single resolution of the address,
+/// such as to rate limit by the full address while only logging the masked address.
+///
+/// Rejects with [`IpAddrRejection`](struct@IpAddrRejection) in the same way as [`RealIp`].
+/// Extract `Option<RealIpWithMask>` instead to handle a missing address manually.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RealIpWithMask {
+    /// The full address.
+    pub full: RealIp,
 
-        if allow_port {
-            // Handle `IP:port` (CloudFront, some proxies)
-            if let Ok(sock) = SocketAddr::from_str(first) {
-                return Some(sock.ip());
-            }
+    /// The address with the last 64 bits of IPv6 addresses zeroed out.
+    pub masked: RealIpPrivacyMask,
+}
+
+impl From<RealIp> for RealIpWithMask {
+    #[inline]
+    fn from(full: RealIp) -> Self {
+        RealIpWithMask {
+            full,
+            masked: full.into(),
         }
-        IpAddr::from_str(first).ok()
     }
+}
 
-    static HEADERS: [(HeaderName, bool); 10] = [
-        (HeaderName::from_static("cf-connecting-ip"), false),
-        (HeaderName::from_static("x-cluster-client-ip"), false),
-        (HeaderName::from_static("fly-client-ip"), false),
-        (HeaderName::from_static("fastly-client-ip"), false),
-        (HeaderName::from_static("cloudfront-viewer-address"), true), // IP:port
-        (HeaderName::from_static("x-real-ip"), false),
-        (HeaderName::from_static("x-forwarded-for"), false), // may contain list
-        (HeaderName::from_static("x-original-forwarded-for"), false),
-        (HeaderName::from_static("true-client-ip"), false),
-        (HeaderName::from_static("client-ip"), false),
-    ];
+impl<S> FromRequestParts<S> for RealIpWithMask {
+    type Rejection = IpAddrRejection;
 
-    for (header, allow_port) in &HEADERS {
-        if let Some(val) = parts.headers.get(header) {
-            if let Some(ip) = parse_ip(val, *allow_port) {
-                return Some(RealIp(ip));
-            }
-        }
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(RealIp::lookup(parts).map(Into::into).ok_or_else(|| IpAddrRejection::from_parts(parts)))
+    }
+}
+
+impl<S> OptionalFromRequestParts<S> for RealIpWithMask {
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(RealIp::lookup(parts).map(Into::into)))
     }
+}
+
+/// The address of the connected peer, from axum's [`ConnectInfo<SocketAddr>`](axum::extract::ConnectInfo),
+/// ignoring every forwarding header and any [`RealIpLayer`] configuration.
+///
+/// This is the inverse of [`RealIp`], for endpoints that must not trust any proxy headers, such as an admin
+/// panel bound to a private interface, where clients could otherwise spoof their address. The address is as
+/// reported by the socket, so use [`RealIp::canonical`] to convert IPv4-mapped addresses of dual-stack sockets.
+///
+/// If the `ConnectInfo` extension is missing, such as when not serving with `into_make_service_with_connect_info`,
+/// extraction is rejected with [`SocketIpRejection`]. Extract `Option<SocketIp>` instead to handle it manually.
+#[cfg(feature = "connect_info")]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct SocketIp(pub IpAddr);
 
-    #[cfg(feature = "tokio")]
-    if let Some(info) = parts.extensions.get::<axum::extract::ConnectInfo<SocketAddr>>() {
-        return Some(RealIp(info.ip()));
+#[cfg(feature = "connect_info")]
+impl SocketIp {
+    fn lookup(parts: &Parts) -> Option<SocketIp> {
+        let info = parts.extensions.get::<axum::extract::ConnectInfo<SocketAddr>>()?;
+
+        Some(SocketIp(info.ip()))
     }
+}
 
-    None
+#[cfg(feature = "connect_info")]
+impl Debug for SocketIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+#[cfg(feature = "connect_info")]
+impl Display for SocketIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+#[cfg(feature = "connect_info")]
+impl Deref for SocketIp {
+    type Target = IpAddr;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "connect_info")]
+impl From<SocketIp> for RealIp {
+    #[inline]
+    fn from(ip: SocketIp) -> Self {
+        RealIp(ip.0)
+    }
+}
+
+/// The [`ConnectInfo`](axum::extract::ConnectInfo) extension was missing when extracting a [`SocketIp`],
+/// returns `500 Internal Server Error` as it indicates the server was not configured to provide it.
+#[cfg(feature = "connect_info")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketIpRejection;
+
+#[cfg(feature = "connect_info")]
+impl fmt::Display for SocketIpRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no connection info present for the socket address")
+    }
+}
+
+#[cfg(feature = "connect_info")]
+impl std::error::Error for SocketIpRejection {}
+
+#[cfg(feature = "connect_info")]
+impl IntoResponse for SocketIpRejection {
+    fn into_response(self) -> Response {
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+}
+
+#[cfg(feature = "connect_info")]
+impl<S> FromRequestParts<S> for SocketIp {
+    type Rejection = SocketIpRejection;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(SocketIp::lookup(parts).ok_or(SocketIpRejection))
+    }
+}
+
+/// Allows extracting `Option<SocketIp>`, which is `None` instead of rejecting when
+/// the [`ConnectInfo`](axum::extract::ConnectInfo) extension is missing.
+#[cfg(feature = "connect_info")]
+impl<S> OptionalFromRequestParts<S> for SocketIp {
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(SocketIp::lookup(parts)))
+    }
+}
+
+/// Every address in the forwarding chain of the request, in order from the original client to the nearest proxy.
+///
+/// The chain is parsed from all [`Forwarded`](http::header::FORWARDED) headers if present,
+/// or all `X-Forwarded-For` headers otherwise. Ports and brackets around IPv6 addresses are stripped,
+/// and entries that are not valid addresses are skipped.
+///
+/// Unlike [`RealIp`], no trusted proxies are considered and this never rejects,
+/// being empty if there are no forwarding headers. Intended for logging and debugging,
+/// as any client can prepend arbitrary entries to the chain.
+///
+/// This is the only extractor to collect the chain into a [`Vec`]. [`RealIp`] walks the hops lazily,
+/// so resolving the client address doesn't allocate, however many hops there are.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ForwardedChain(pub Vec<IpAddr>);
+
+impl ForwardedChain {
+    fn from_parts(parts: &Parts) -> ForwardedChain {
+        let mut chain = Vec::new();
+
+        let forwarded = parts.headers.get_all(http::header::FORWARDED);
+
+        if forwarded.iter().next().is_some() {
+            for value in forwarded.iter().filter_map(|v| v.to_str().ok()) {
+                chain.extend(forwarded::forwarded_for(value).flatten());
+            }
+        } else {
+            let xff = parts.headers.get_all(HeaderName::from_static("x-forwarded-for"));
+
+            for value in xff.iter().filter_map(|v| v.to_str().ok()) {
+                chain.extend(value.split(',').filter_map(|hop| forwarded::parse_node(hop.trim())));
+            }
+        }
+
+        ForwardedChain(chain)
+    }
+}
+
+impl Deref for ForwardedChain {
+    type Target = [IpAddr];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<S> FromRequestParts<S> for ForwardedChain {
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(Ok(ForwardedChain::from_parts(parts)))
+    }
+}
+
+/// Returns the parameter from the first `Forwarded` element with it, or else the first entry of the
+/// `X-Forwarded-*` fallback header.
+fn forwarded_param(parts: &Parts, name: &str, fallback: &str) -> Option<String> {
+    let mut forwarded = parts.headers.get_all(http::header::FORWARDED).into_iter();
+
+    if let Some(value) = forwarded.find_map(|v| forwarded::first_param(v.to_str().ok()?, name)) {
+        return Some(value.to_owned());
+    }
+
+    let value = parts.headers.get(fallback)?.to_str().ok()?;
+
+    match value.split(',').next()?.trim() {
+        "" => None,
+        value => Some(value.to_owned()),
+    }
+}
+
+/// The original scheme of the request, such as `https`, as seen by the first proxy.
+///
+/// This is the `proto=` parameter of the first [`Forwarded`](http::header::FORWARDED) element with one,
+/// falling back to the first entry of the `X-Forwarded-Proto` header if no element has it. The scheme
+/// is lowercased, but not otherwise validated.
+///
+/// As with [`ForwardedChain`], no trusted proxies are considered, so this should only be used behind proxies
+/// that overwrite these headers, such as for URL generation. Extraction is rejected with [`ForwardedRejection`]
+/// if neither header is present. Extract `Option<ForwardedProto>` instead to handle this manually.
+///
+/// ```rust,no_run
+/// use axum_gcra::real_ip::{ForwardedHost, ForwardedProto};
+///
+/// async fn handler(ForwardedProto(proto): ForwardedProto, host: Option<ForwardedHost>) -> String {
+///     format!("{proto}://{}/", host.as_deref().map_or("localhost", |h| h.as_str()))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ForwardedProto(pub String);
+
+/// The original host of the request, such as `example.com` or `example.com:8080`, as seen by the first proxy.
+///
+/// This is the `host=` parameter of the first [`Forwarded`](http::header::FORWARDED) element with one,
+/// falling back to the first entry of the `X-Forwarded-Host` header if no element has it. The host is not
+/// validated, and may include a port.
+///
+/// See [`ForwardedProto`] for the caveats of trusting these headers. Extraction is rejected with
+/// [`ForwardedRejection`] if neither header is present. Extract `Option<ForwardedHost>` instead
+/// to handle this manually.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ForwardedHost(pub String);
+
+impl ForwardedProto {
+    fn from_parts(parts: &Parts) -> Option<ForwardedProto> {
+        forwarded_param(parts, "proto", "x-forwarded-proto").map(|mut proto| {
+            proto.make_ascii_lowercase();
+            ForwardedProto(proto)
+        })
+    }
+}
+
+impl ForwardedHost {
+    fn from_parts(parts: &Parts) -> Option<ForwardedHost> {
+        forwarded_param(parts, "host", "x-forwarded-host").map(ForwardedHost)
+    }
+}
+
+impl Deref for ForwardedProto {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Deref for ForwardedHost {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Neither a `Forwarded` parameter nor its `X-Forwarded-*` fallback was present when extracting
+/// a [`ForwardedProto`] or [`ForwardedHost`], returns `400 Bad Request`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardedRejection {
+    param: &'static str,
+}
+
+impl fmt::Display for ForwardedRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no forwarded `{}` present", self.param)
+    }
+}
+
+impl std::error::Error for ForwardedRejection {}
+
+impl IntoResponse for ForwardedRejection {
+    fn into_response(self) -> Response {
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+impl<S> FromRequestParts<S> for ForwardedProto {
+    type Rejection = ForwardedRejection;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(ForwardedProto::from_parts(parts).ok_or(ForwardedRejection { param: "proto" }))
+    }
+}
+
+impl<S> OptionalFromRequestParts<S> for ForwardedProto {
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(ForwardedProto::from_parts(parts)))
+    }
+}
+
+impl<S> FromRequestParts<S> for ForwardedHost {
+    type Rejection = ForwardedRejection;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(ForwardedHost::from_parts(parts).ok_or(ForwardedRejection { param: "host" }))
+    }
+}
+
+impl<S> OptionalFromRequestParts<S> for ForwardedHost {
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> impl std::future::Future<Output = Result<Option<Self>, Self::Rejection>> + Send {
+        future::ready(Ok(ForwardedHost::from_parts(parts)))
+    }
+}
+
+/// Extension types holding the client's original address, as provided by PROXY protocol acceptors.
+///
+/// See [`RealIpLayer::proxy_protocol_extension`].
+pub trait ProxyAddr: Send + Sync + 'static {
+    /// Returns the client's original source address.
+    fn client_ip(&self) -> IpAddr;
+}
+
+impl ProxyAddr for IpAddr {
+    #[inline]
+    fn client_ip(&self) -> IpAddr {
+        *self
+    }
+}
+
+impl ProxyAddr for SocketAddr {
+    #[inline]
+    fn client_ip(&self) -> IpAddr {
+        self.ip()
+    }
+}
+
+/// Strategy for selecting the client address from multi-hop headers such as `X-Forwarded-For` or `Forwarded`,
+/// as set by [`RealIpLayer::with_xff_mode`].
+///
+/// Each proxy appends the address it received the request from, so the rightmost entries
+/// are added by the proxies closest to the server, and the leftmost entries are whatever the
+/// client sent. Only entries added by your own proxies can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XffMode {
+    /// Use the leftmost entry.
+    ///
+    /// This is the original client address if every proxy along the way is well-behaved,
+    /// but any client can spoof it by sending their own header, so it should not be used
+    /// for rate limiting unless the outermost proxy discards client-provided headers.
+    Leftmost,
+
+    /// Use the rightmost entry that is not within the [trusted proxies](RealIpLayer::with_trusted_proxies),
+    /// or the leftmost entry if every hop is trusted. Without any trusted proxies, this is the rightmost entry.
+    Rightmost,
+
+    /// Skip the rightmost `n` entries, known to be added by your own proxies, and use the next entry,
+    /// or the leftmost entry if there are not enough hops. `RightmostTrusted(0)` is the rightmost entry.
+    ///
+    /// This suits deployments with a fixed number of proxies whose addresses are not known in advance.
+    RightmostTrusted(usize),
+}
+
+/// Action taken when the two highest-precedence headers present yield different addresses,
+/// as set by [`RealIpLayer::with_header_consistency`].
+///
+/// Proxies that all see the same client should agree, so a disagreement may indicate a misconfigured proxy,
+/// or a client spoofing a header that a proxy did not overwrite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum HeaderConsistency {
+    /// Use the highest-precedence header without comparing it to the others.
+    #[default]
+    PreferHighest,
+
+    /// Use the highest-precedence header, but log a warning with both headers and addresses
+    /// to the `axum_gcra` target.
+    ///
+    /// Without the `tracing` feature, this is the same as [`PreferHighest`](HeaderConsistency::PreferHighest).
+    Log,
+
+    /// Reject the request with [`RealIpError::Inconsistent`], without falling back to the
+    /// socket address.
+    Reject,
+}
+
+/// Configuration shared between [`RealIpLayer`], [`RealIpService`] and the extractors.
+#[derive(Debug, Clone)]
+pub(crate) struct RealIpConfig {
+    headers: Vec<(HeaderName, bool)>,
+    trusted_proxies: Vec<Cidr>,
+    reject_private: bool,
+    canonicalize: bool,
+    prefer_cf_ipv6: bool,
+    max_hops: usize,
+    xff_mode: Option<XffMode>,
+    consistency: HeaderConsistency,
+    proxy_protocol: Option<fn(&http::Extensions) -> Option<IpAddr>>,
+    #[cfg(feature = "signed_ip")]
+    signed_header: Option<signed::SignedHeader>,
+    #[cfg(feature = "connect_info")]
+    peer_headers: Vec<(Cidr, HeaderName, bool)>,
+    fallback: Option<IpAddr>,
+    preserve_existing: bool,
+    rejection: Option<RejectionFn>,
+}
+
+impl Default for RealIpConfig {
+    fn default() -> Self {
+        RealIpConfig {
+            headers: DEFAULT_HEADERS.to_vec(),
+            trusted_proxies: Vec::new(),
+            reject_private: false,
+            canonicalize: false,
+            prefer_cf_ipv6: false,
+            max_hops: 16,
+            xff_mode: None,
+            consistency: HeaderConsistency::PreferHighest,
+            proxy_protocol: None,
+            #[cfg(feature = "signed_ip")]
+            signed_header: None,
+            #[cfg(feature = "connect_info")]
+            peer_headers: Vec::new(),
+            fallback: None,
+            preserve_existing: false,
+            rejection: None,
+        }
+    }
+}
+
+const CF_CONNECTING_IP: HeaderName = HeaderName::from_static("cf-connecting-ip");
+const CF_CONNECTING_IPV6: HeaderName = HeaderName::from_static("cf-connecting-ipv6");
+const CF_PSEUDO_IPV4: HeaderName = HeaderName::from_static("cf-pseudo-ipv4");
+
+/// Default header precedence, as `(header, allow_port)` pairs.
+static DEFAULT_HEADERS: [(HeaderName, bool); 15] = [
+    (CF_CONNECTING_IP, false),
+    (CF_CONNECTING_IPV6, false),
+    (CF_PSEUDO_IPV4, false),
+    (HeaderName::from_static("x-cluster-client-ip"), false),
+    (HeaderName::from_static("fly-client-ip"), false),
+    (HeaderName::from_static("fastly-client-ip"), false),
+    (HeaderName::from_static("cloudfront-viewer-address"), true), // IP:port
+    (HeaderName::from_static("x-azure-clientip"), false),
+    (HeaderName::from_static("x-azure-socketip"), true), // may include port
+    (HeaderName::from_static("x-real-ip"), false),
+    (http::header::FORWARDED, false), // RFC 7239, may contain list
+    (HeaderName::from_static("x-forwarded-for"), false), // may contain list
+    (HeaderName::from_static("x-original-forwarded-for"), false),
+    (HeaderName::from_static("true-client-ip"), false),
+    (HeaderName::from_static("client-ip"), false),
+];
+
+impl RealIpConfig {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn xff_mode(&self) -> XffMode {
+        self.xff_mode.unwrap_or(match self.trusted_proxies.is_empty() {
+            true => XffMode::Leftmost,
+            false => XffMode::Rightmost,
+        })
+    }
+
+    /// Retrieves the configuration inserted by [`RealIpService`], or the default configuration.
+    fn from_parts(parts: &Parts) -> &RealIpConfig {
+        static DEFAULT: OnceLock<RealIpConfig> = OnceLock::new();
+
+        match parts.extensions.get::<SharedConfig>() {
+            Some(config) => &config.0,
+            None => DEFAULT.get_or_init(RealIpConfig::default),
+        }
+    }
+}
+
+/// Extension used to pass the layer configuration to the extractors.
+#[derive(Clone)]
+struct SharedConfig(Arc<RealIpConfig>);
+
+/// Service that adds the [`RealIp`] and [`RealIpSource`] extensions.
+#[derive(Debug, Clone)]
+pub struct RealIpService<I> {
+    inner: I,
+    config: Arc<RealIpConfig>,
+}
+
+/// Layer that adds the [`RealIp`] and [`RealIpSource`] extensions.
+///
+/// The same configuration is used by the [`RealIp`] and [`RealIpPrivacyMask`] extractors
+/// and the rate limiter for any request that has passed through this layer.
+#[derive(Debug, Clone, Default)]
+pub struct RealIpLayer {
+    config: Arc<RealIpConfig>,
+}
+
+impl RealIpLayer {
+    /// Constructs a new layer with the default configuration.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the trusted proxy ranges used when resolving multi-hop headers such as `X-Forwarded-For`.
+    ///
+    /// By default, the leftmost entry of the header is used, which any client can spoof by
+    /// prepending their own address. When trusted proxies are configured, the list is instead walked
+    /// from the rightmost entry inward, skipping addresses within the trusted ranges, and the first
+    /// untrusted hop is used as the client address. If every hop is trusted, the leftmost is used.
+    /// Either strategy can also be chosen explicitly with [`RealIpLayer::with_xff_mode`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use axum_gcra::real_ip::{Cidr, RealIpLayer};
+    ///
+    /// let layer = RealIpLayer::new().with_trusted_proxies([
+    ///     "10.0.0.0/8".parse::<Cidr>().unwrap(),
+    ///     "fd00::/8".parse::<Cidr>().unwrap(),
+    /// ]);
+    /// ```
+    #[must_use]
+    pub fn with_trusted_proxies(mut self, proxies: impl IntoIterator<Item = Cidr>) -> Self {
+        Arc::make_mut(&mut self.config).trusted_proxies = proxies.into_iter().collect();
+        self
+    }
+
+    /// Set the strategy for selecting the client address from multi-hop headers such as `X-Forwarded-For`.
+    ///
+    /// By default, [`XffMode::Leftmost`] is used unless [trusted proxies](RealIpLayer::with_trusted_proxies)
+    /// are configured, in which case [`XffMode::Rightmost`] is used. See [`XffMode`] for the security
+    /// implications of each strategy.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use axum_gcra::real_ip::{RealIpLayer, XffMode};
+    ///
+    /// // behind a CDN and a load balancer, each appending one hop
+    /// let layer = RealIpLayer::new().with_xff_mode(XffMode::RightmostTrusted(1));
+    /// ```
+    #[must_use]
+    pub fn with_xff_mode(mut self, mode: XffMode) -> Self {
+        Arc::make_mut(&mut self.config).xff_mode = Some(mode);
+        self
+    }
+
+    /// Set the action taken when the two highest-precedence headers present yield different addresses,
+    /// such as when both `cf-connecting-ip` and `x-real-ip` are present but differ.
+    ///
+    /// The address of the first header yielding one is compared to that of the next header yielding one,
+    /// ignoring the differences between IPv4 and IPv4-mapped IPv6 addresses. Cloudflare's `cf-connecting-ip`,
+    /// `cf-connecting-ipv6` and `cf-pseudo-ipv4` headers hold different forms of the same address,
+    /// so are never compared to each other. Only the configured [headers](RealIpLayer::with_headers)
+    /// are compared, not the [signed header](RealIpLayer::with_signed_header) or
+    /// [peer headers](RealIpLayer::with_peer_headers).
+    ///
+    /// The default is [`HeaderConsistency::PreferHighest`], which skips the comparison.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use axum_gcra::real_ip::{HeaderConsistency, RealIpLayer};
+    ///
+    /// let layer = RealIpLayer::new().with_header_consistency(HeaderConsistency::Reject);
+    /// ```
+    #[must_use]
+    pub fn with_header_consistency(mut self, consistency: HeaderConsistency) -> Self {
+        Arc::make_mut(&mut self.config).consistency = consistency;
+        self
+    }
+
+    /// Replace the list of headers consulted for the client address, in order of precedence.
+    ///
+    /// Each entry is a header name and whether the value may include a port (e.g. `IP:port`).
+    /// Headers are tried in order until one yields a valid address, after which the socket
+    /// address is used as a fallback (with the `connect_info` feature).
+    ///
+    /// The default list, in order, is:
+    /// `cf-connecting-ip`, `cf-connecting-ipv6`, `cf-pseudo-ipv4`, `x-cluster-client-ip`, `fly-client-ip`,
+    /// `fastly-client-ip`, `cloudfront-viewer-address` (with port), `x-azure-clientip`, `x-azure-socketip`
+    /// (with port), `x-real-ip`, `forwarded`, `x-forwarded-for`, `x-original-forwarded-for`, `true-client-ip`,
+    /// and `client-ip`.
+    ///
+    /// The standard [`Forwarded`](http::header::FORWARDED) header is parsed according to RFC 7239,
+    /// using the `for=` parameter of each element, and can be placed anywhere in the list.
+    /// Obfuscated identifiers such as `for=_hidden` are skipped for the leftmost entry, but stop the walk over
+    /// [trusted proxies](RealIpLayer::with_trusted_proxies), as they can't be trusted.
+    #[must_use]
+    pub fn with_headers(mut self, headers: impl IntoIterator<Item = (HeaderName, bool)>) -> Self {
+        Arc::make_mut(&mut self.config).headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Consult only the given header, followed by the socket address (with the `connect_info` feature),
+    /// ignoring every other header that a client could set to spoof its address.
+    ///
+    /// This suits deployments behind a single known proxy, such as Cloudflare with `cf-connecting-ip`,
+    /// where any other header may have been set by the client. It replaces the precedence list as by
+    /// [`RealIpLayer::with_headers`], and disables the
+    /// [`cf-connecting-ipv6` preference](RealIpLayer::with_cloudflare_ipv6), which would consult a second header.
+    /// A port is allowed if the header allows one by default, as with `cloudfront-viewer-address`.
+    ///
+    /// Make sure the proxy always overwrites the header, as a client bypassing the proxy can set it freely.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use axum_gcra::real_ip::RealIpLayer;
+    /// use http::HeaderName;
+    ///
+    /// let layer = RealIpLayer::new().only_header(HeaderName::from_static("cf-connecting-ip"));
+    /// ```
+    #[must_use]
+    pub fn only_header(mut self, header: HeaderName) -> Self {
+        let allow_port = DEFAULT_HEADERS.iter().any(|(h, allow_port)| *h == header && *allow_port);
+
+        let config = Arc::make_mut(&mut self.config);
+        config.headers = vec![(header, allow_port)];
+        config.prefer_cf_ipv6 = false;
+        self
+    }
+
+    /// Set whether to ignore non-global addresses found in headers, such as `X-Forwarded-For: 127.0.0.1`.
+    ///
+    /// When enabled, any header yielding an address that is not [global](RealIp::is_global)
+    /// is skipped as if it were missing, falling through to the next header or the socket address.
+    /// The socket address itself is always accepted.
+    ///
+    /// This prevents clients from spoofing internal addresses to bypass IP-based allowlists,
+    /// but should not be enabled if clients are expected to connect from a private network.
+    #[must_use]
+    pub fn with_reject_private(mut self, reject_private: bool) -> Self {
+        Arc::make_mut(&mut self.config).reject_private = reject_private;
+        self
+    }
+
+    /// Set whether to convert IPv4-mapped IPv6 addresses to IPv4, as by [`RealIp::canonical`].
+    ///
+    /// Clients connecting over a dual-stack socket may appear as `::ffff:192.0.2.1` rather than `192.0.2.1`,
+    /// which would otherwise be rate limited separately. This applies to both header and socket addresses.
+    #[must_use]
+    pub fn with_canonical_ips(mut self, canonicalize: bool) -> Self {
+        Arc::make_mut(&mut self.config).canonicalize = canonicalize;
+        self
+    }
+
+    /// Set the response returned when the [`RealIp`] extractors cannot determine an address,
+    /// instead of the default `400 Bad Request`.
+    ///
+    /// This applies to extractors used in handlers after this layer, but not to `Option<RealIp>`,
+    /// which never rejects. When used as a rate limiting key, the rejection is passed to the
+    /// rate limiter's [error handler](crate::RateLimitLayerBuilder::handle_error) as usual.
+    #[must_use]
+    pub fn with_rejection<F>(mut self, rejection: F) -> Self
+    where
+        F: Fn() -> Response + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).rejection = Some(RejectionFn(Arc::new(rejection)));
+        self
+    }
+
+    /// Set the maximum number of hops in a multi-hop header such as `X-Forwarded-For` or `Forwarded`.
+    ///
+    /// Only the rightmost `max_hops` hops of longer headers are parsed, as if the header began there,
+    /// which bounds the cost of parsing maliciously long headers. The hops added by your own proxies
+    /// are always the rightmost, so a client can't pad the header to escape the trusted proxy walk.
+    ///
+    /// The default is 16. A value of zero is treated as one.
+    #[must_use]
+    pub fn with_max_hops(mut self, max_hops: usize) -> Self {
+        Arc::make_mut(&mut self.config).max_hops = max_hops.max(1);
+        self
+    }
+
+    /// Set whether to prefer the original IPv6 address in `cf-connecting-ipv6` over `cf-connecting-ip`.
+    ///
+    /// With Cloudflare's Pseudo IPv4 set to overwrite headers, `cf-connecting-ip` contains a
+    /// [pseudo IPv4](RealIp::is_cloudflare_pseudo_ipv4) address for IPv6 clients, and the genuine address
+    /// is moved to `cf-connecting-ipv6`. When enabled, `cf-connecting-ipv6` is tried immediately
+    /// before `cf-connecting-ip`, wherever it is in the precedence list.
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_cloudflare_ipv6(mut self, prefer: bool) -> Self {
+        Arc::make_mut(&mut self.config).prefer_cf_ipv6 = prefer;
+        self
+    }
+
+    /// Set the address used when neither the headers nor the socket yield a client address,
+    /// instead of rejecting the request.
+    ///
+    /// This is the "local trusted" path for deployments where there is no meaningful peer address,
+    /// such as when serving over a Unix domain socket, where
+    /// [`ConnectInfo<SocketAddr>`](axum::extract::ConnectInfo) is never present. Every such request shares
+    /// the fallback address, and so a single rate limiting bucket when keyed by [`RealIp`], so this should
+    /// only be used when those callers are trusted, such as a local reverse proxy that always sets one of
+    /// the headers for external clients.
+    ///
+    /// The fallback is reported as [`RealIpSource::Fallback`].
+    /// The default is `None`, rejecting with [`IpAddrRejection`](struct@IpAddrRejection).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// use axum_gcra::real_ip::RealIpLayer;
+    ///
+    /// let layer = RealIpLayer::new().with_fallback_ip(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    /// ```
+    #[must_use]
+    pub fn with_fallback_ip(mut self, fallback: Option<IpAddr>) -> Self {
+        Arc::make_mut(&mut self.config).fallback = fallback;
+        self
+    }
+
+    /// Set whether to keep a [`RealIp`] extension already present on the request, such as one inserted
+    /// by a gateway or an outer layer, instead of resolving the address again and overwriting it.
+    ///
+    /// This matches the extractors, which already prefer an existing extension. A preserved address is
+    /// reported as [`RealIpSource::Extension`] unless the outer layer also inserted a [`RealIpSource`].
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_preserve_existing(mut self, preserve: bool) -> Self {
+        Arc::make_mut(&mut self.config).preserve_existing = preserve;
+        self
+    }
+
+    /// Read the client address from a header signed by a trusted edge with HMAC-SHA256, such as
+    /// `x-client-ip-signed: 203.0.113.9.<signature>`, before consulting any other headers.
+    ///
+    /// The value is the address followed by a `.` and the hex encoded HMAC-SHA256 of the address with
+    /// the shared secret, as produced by [`sign_client_ip`]. Since only the edge knows the secret, clients
+    /// cannot forge the header, giving header-based resolution the security of the
+    /// [PROXY protocol](RealIpLayer::proxy_protocol_extension) without changing the transport,
+    /// which still takes precedence.
+    ///
+    /// If the header is present but the signature is invalid, resolution fails with
+    /// [`RealIpError::InvalidSignature`] instead of falling back to other headers. If the header is missing,
+    /// the other headers are consulted as usual, so combine this with [`with_headers`](RealIpLayer::with_headers)
+    /// to trust only the signed header.
+    ///
+    /// Signatures are not bound to a time or request, so a signed value can be replayed by anyone who has seen
+    /// it, but only to claim that same address. Rotate the secret if it may have leaked.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use axum_gcra::real_ip::RealIpLayer;
+    /// use http::HeaderName;
+    ///
+    /// # let secret = std::env::var("CLIENT_IP_SECRET").unwrap_or_default();
+    /// let layer = RealIpLayer::new()
+    ///     .with_headers([]) // ignore unsigned headers entirely
+    ///     .with_signed_header(HeaderName::from_static("x-client-ip-signed"), secret);
+    /// ```
+    #[cfg(feature = "signed_ip")]
+    #[must_use]
+    pub fn with_signed_header(mut self, header: HeaderName, secret: impl AsRef<[u8]>) -> Self {
+        Arc::make_mut(&mut self.config).signed_header = Some(signed::SignedHeader::new(header, secret.as_ref()));
+        self
+    }
+
+    /// Replace the headers to trust depending on the address of the connected peer, as `(peer range, header)`
+    /// pairs, such as to trust `cf-connecting-ip` only on connections from Cloudflare's ranges.
+    ///
+    /// When any are set, the [precedence list](RealIpLayer::with_headers) is ignored entirely. Instead, each
+    /// pair whose range contains the peer address is tried in order, and the first header yielding an address
+    /// is used. Otherwise, including when the peer is in none of the ranges, the peer address itself is used.
+    /// Since the peer address comes from the connection rather than the request, clients connecting directly
+    /// can't spoof an address with any header, even one trusted from other peers.
+    ///
+    /// The peer address is read from [`ConnectInfo<SocketAddr>`](axum::extract::ConnectInfo), so the server
+    /// must be served with `into_make_service_with_connect_info`. Multi-hop headers are still resolved
+    /// as by [`RealIpLayer::with_xff_mode`], and a port is allowed as by [`RealIpLayer::only_header`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use axum_gcra::real_ip::RealIpLayer;
+    /// use http::HeaderName;
+    ///
+    /// let cf_connecting_ip = HeaderName::from_static("cf-connecting-ip");
+    /// let x_forwarded_for = HeaderName::from_static("x-forwarded-for");
+    ///
+    /// // a few of Cloudflare's published ranges, see https://www.cloudflare.com/ips/
+    /// let cloudflare = ["173.245.48.0/20", "103.21.244.0/22", "2400:cb00::/32"];
+    ///
+    /// let layer = RealIpLayer::new()
+    ///     .with_peer_headers(cloudflare.map(|cidr| (cidr.parse().unwrap(), cf_connecting_ip.clone())))
+    ///     // the internal load balancer
+    ///     .with_peer_header("10.0.0.0/8".parse().unwrap(), x_forwarded_for);
+    /// ```
+    #[cfg(feature = "connect_info")]
+    #[must_use]
+    pub fn with_peer_headers(mut self, headers: impl IntoIterator<Item = (Cidr, HeaderName)>) -> Self {
+        Arc::make_mut(&mut self.config).peer_headers.clear();
+
+        for (peer, header) in headers {
+            self.add_peer_header(peer, header);
+        }
+
+        self
+    }
+
+    /// Append a header to trust on connections from the given peer range.
+    /// See [`RealIpLayer::with_peer_headers`] for more information.
+    #[cfg(feature = "connect_info")]
+    pub fn add_peer_header(&mut self, peer: Cidr, header: HeaderName) {
+        let allow_port = DEFAULT_HEADERS.iter().any(|(h, allow_port)| *h == header && *allow_port);
+
+        Arc::make_mut(&mut self.config).peer_headers.push((peer, header, allow_port));
+    }
+
+    /// Append a header to trust on connections from the given peer range.
+    /// See [`RealIpLayer::with_peer_headers`] for more information.
+    #[cfg(feature = "connect_info")]
+    #[must_use]
+    pub fn with_peer_header(mut self, peer: Cidr, header: HeaderName) -> Self {
+        self.add_peer_header(peer, header);
+        self
+    }
+
+    /// Read the client address from a request extension of type `T` before consulting any headers.
+    ///
+    /// PROXY protocol acceptors, as used with HAProxy or AWS Network Load Balancers, typically insert
+    /// the original source address into the request extensions. Since that address comes from the
+    /// load balancer itself rather than the client, it cannot be spoofed like headers can, and is always
+    /// preferred when present. If the extension is missing, headers are consulted as usual.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::net::SocketAddr;
+    /// use axum_gcra::real_ip::RealIpLayer;
+    ///
+    /// // for acceptors that insert the source `SocketAddr` directly
+    /// let layer = RealIpLayer::new().proxy_protocol_extension::<SocketAddr>();
+    /// ```
+    #[must_use]
+    pub fn proxy_protocol_extension<T: ProxyAddr>(mut self) -> Self {
+        fn lookup<T: ProxyAddr>(extensions: &http::Extensions) -> Option<IpAddr> {
+            extensions.get::<T>().map(T::client_ip)
+        }
+
+        Arc::make_mut(&mut self.config).proxy_protocol = Some(lookup::<T>);
+        self
+    }
+
+    /// Append a header to the end of the precedence list, replacing any existing entry for it.
+    pub fn add_header(&mut self, header: HeaderName, allow_port: bool) {
+        self.remove_header(&header);
+        Arc::make_mut(&mut self.config).headers.push((header, allow_port));
+    }
+
+    /// Append a header to the end of the precedence list, replacing any existing entry for it.
+    #[must_use]
+    pub fn with_header(mut self, header: HeaderName, allow_port: bool) -> Self {
+        self.add_header(header, allow_port);
+        self
+    }
+
+    /// Remove a header from the precedence list, so it will never be consulted.
+    pub fn remove_header(&mut self, header: &HeaderName) {
+        Arc::make_mut(&mut self.config).headers.retain(|(h, _)| h != header);
+    }
+
+    /// Remove a header from the precedence list, so it will never be consulted.
+    #[must_use]
+    pub fn without_header(mut self, header: &HeaderName) -> Self {
+        self.remove_header(header);
+        self
+    }
+}
+
+impl<B, I> Service<Request<B>> for RealIpService<I>
+where
+    I: Service<Request<B>>,
+{
+    type Response = I::Response;
+    type Error = I::Error;
+    type Future = I::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let (mut parts, body) = req.into_parts();
+
+        parts.extensions.insert(SharedConfig(self.config.clone()));
+
+        if self.config.preserve_existing && parts.extensions.get::<RealIp>().is_some() {
+            if parts.extensions.get::<RealIpSource>().is_none() {
+                parts.extensions.insert(RealIpSource::Extension);
+            }
+        } else if let Some((ip, source)) = get_ip_and_source_from_parts(&parts) {
+            parts.extensions.insert(ip);
+            parts.extensions.insert(source);
+        }
+
+        self.inner.call(Request::from_parts(parts, body))
+    }
+}
+
+impl<I> Layer<I> for RealIpLayer {
+    type Service = RealIpService<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        RealIpService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+pub(crate) fn get_ip_from_parts(parts: &Parts) -> Option<RealIp> {
+    get_ip_and_source_from_parts(parts).map(|(ip, _)| ip)
+}
+
+fn get_ip_and_source_from_parts(parts: &Parts) -> Option<(RealIp, RealIpSource)> {
+    let config = RealIpConfig::from_parts(parts);
+    let (ip, source) = resolve_ip(parts, config)?;
+
+    Some((if config.canonicalize { ip.canonical() } else { ip }, source))
+}
+
+fn resolve_ip(parts: &Parts, config: &RealIpConfig) -> Option<(RealIp, RealIpSource)> {
+    fn parse_ip(s: &str, allow_port: bool) -> Option<IpAddr> {
+        let s = s.trim();
+
+        // some CDNs quote the value, as in `"203.0.113.9"`
+        let s = match s.as_bytes() {
+            [b'"', .., b'"'] | [b'\'', .., b'\''] => s[1..s.len() - 1].trim(),
+            _ => s,
+        };
+
+        if let Ok(ip) = IpAddr::from_str(s) {
+            return Some(ip);
+        }
+
+        // Handle `[IPv6]` and `[IPv6]:port` in any header, which are unambiguous
+        if let Some((ip, rest)) = s.strip_prefix('[').and_then(|s| s.split_once(']')) {
+            return match rest.strip_prefix(':') {
+                Some(port) if port.parse::<u16>().is_err() => None,
+                None if !rest.is_empty() => None,
+                _ => Ipv6Addr::from_str(ip).ok().map(IpAddr::V6),
+            };
+        }
+
+        if allow_port {
+            // Handle `IPv4:port` (CloudFront, some proxies)
+            if let Ok(sock) = SocketAddr::from_str(s) {
+                return Some(sock.ip());
+            }
+        }
+
+        None
+    }
+
+    fn select(mut hops: impl DoubleEndedIterator<Item = Option<IpAddr>>, config: &RealIpConfig) -> Option<IpAddr> {
+        let mode = config.xff_mode();
+
+        if mode == XffMode::Leftmost {
+            // take the first entry for multi-hop headers
+            return hops.next()?;
+        }
+
+        // walk from the nearest hop inward, skipping our own proxies
+        let mut leftmost = None;
+        for (i, ip) in hops.rev().enumerate() {
+            let ip = ip?;
+
+            let own = match mode {
+                XffMode::RightmostTrusted(n) => i < n,
+                _ => config.is_trusted(ip),
+            };
+
+            if !own {
+                return Some(ip);
+            }
+
+            leftmost = Some(ip);
+        }
+
+        leftmost
+    }
+
+    fn parse_header(
+        header: &HeaderName,
+        val: &HeaderValue,
+        allow_port: bool,
+        config: &RealIpConfig,
+    ) -> Option<IpAddr> {
+        let mut s = val.to_str().ok()?;
+
+        // truncate pathologically long chains from the client side, keeping the hops added by our own proxies,
+        // so padding the header can neither skip it nor push the trusted hops out of reach
+        if let Some((i, _)) = s.rmatch_indices(',').nth(config.max_hops - 1) {
+            s = &s[i + 1..];
+        }
+
+        // hops are parsed lazily as `select` walks them, so this never allocates
+
+        if *header == http::header::FORWARDED {
+            let hops = forwarded::forwarded_for(s);
+
+            // obfuscated nodes stop the walk over trusted proxies, but needn't be the leftmost entry
+            return match config.xff_mode() {
+                XffMode::Leftmost => select(hops.filter(Option::is_some), config),
+                _ => select(hops, config),
+            };
+        }
+
+        // Split on `,` for multi-hop headers, skipping empty entries left by misbehaving proxies
+        let hops = s.split(',').map(str::trim).filter(|hop| !hop.is_empty());
+
+        select(hops.map(|hop| parse_ip(hop, allow_port)), config)
+    }
+
+    fn try_header(parts: &Parts, header: &HeaderName, allow_port: bool, config: &RealIpConfig) -> Option<RealIp> {
+        let ip = parse_header(header, parts.headers.get(header)?, allow_port, config).map(RealIp)?;
+
+        if config.reject_private && !ip.is_global() {
+            return None;
+        }
+
+        Some(ip)
+    }
+
+    /// Applies the [`HeaderConsistency`] to the address from `header`, comparing it to the next of the `rest`.
+    fn check_consistency(
+        parts: &Parts,
+        config: &RealIpConfig,
+        ip: RealIp,
+        header: HeaderName,
+        rest: &[(HeaderName, bool)],
+    ) -> Option<(RealIp, RealIpSource)> {
+        let cloudflare = |h: &HeaderName| [CF_CONNECTING_IP, CF_CONNECTING_IPV6, CF_PSEUDO_IPV4].contains(h);
+
+        if config.consistency != HeaderConsistency::PreferHighest {
+            let next = rest
+                .iter()
+                .filter(|(other, _)| !(cloudflare(&header) && cloudflare(other)))
+                .find_map(|(other, allow_port)| Some((other, try_header(parts, other, *allow_port, config)?)));
+
+            match next {
+                Some((_, other_ip)) if other_ip.canonical() == ip.canonical() => {}
+                None => {}
+
+                Some((other, other_ip)) if config.consistency == HeaderConsistency::Log => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        target: "axum_gcra",
+                        %header, ip = %ip.0, %other, other_ip = %other_ip.0,
+                        "client address headers disagree"
+                    );
+
+                    #[cfg(not(feature = "tracing"))]
+                    let _ = (other, other_ip);
+                }
+
+                Some(_) => return None,
+            }
+        }
+
+        Some((ip, RealIpSource::Header(header)))
+    }
+
+    fn resolve(parts: &Parts, config: &RealIpConfig) -> Option<(RealIp, RealIpSource)> {
+        if let Some(ip) = config.proxy_protocol.and_then(|lookup| lookup(&parts.extensions)) {
+            return Some((RealIp(ip), RealIpSource::ProxyProtocol));
+        }
+
+        #[cfg(feature = "signed_ip")]
+        if let Some(ref signed) = config.signed_header {
+            if let Some(value) = parts.headers.get(&signed.header) {
+                // tampered values are rejected outright, rather than falling back to spoofable headers
+                let ip = signed.verify(value)?;
+                return Some((RealIp(ip), RealIpSource::SignedHeader(signed.header.clone())));
+            }
+        }
+
+        #[cfg(feature = "connect_info")]
+        if !config.peer_headers.is_empty() {
+            let Some(info) = parts.extensions.get::<axum::extract::ConnectInfo<SocketAddr>>() else {
+                return config.fallback.map(|ip| (RealIp(ip), RealIpSource::Fallback));
+            };
+
+            let peer = info.ip();
+
+            for (cidr, header, allow_port) in &config.peer_headers {
+                if cidr.contains(peer) {
+                    if let Some(ip) = try_header(parts, header, *allow_port, config) {
+                        return Some((ip, RealIpSource::Header(header.clone())));
+                    }
+                }
+            }
+
+            return Some((RealIp(peer), RealIpSource::Socket));
+        }
+
+        for (i, (header, allow_port)) in config.headers.iter().enumerate() {
+            if config.prefer_cf_ipv6 && *header == CF_CONNECTING_IP {
+                if let Some(ip) = try_header(parts, &CF_CONNECTING_IPV6, false, config) {
+                    return check_consistency(parts, config, ip, CF_CONNECTING_IPV6, &config.headers[i..]);
+                }
+            }
+
+            if let Some(ip) = try_header(parts, header, *allow_port, config) {
+                return check_consistency(parts, config, ip, header.clone(), &config.headers[i + 1..]);
+            }
+        }
+
+        #[cfg(feature = "connect_info")]
+        if let Some(info) = parts.extensions.get::<axum::extract::ConnectInfo<SocketAddr>>() {
+            return Some((RealIp(info.ip()), RealIpSource::Socket));
+        }
+
+        config.fallback.map(|ip| (RealIp(ip), RealIpSource::Fallback))
+    }
+
+    resolve(parts, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn parts(headers: &[(&'static str, &str)]) -> Parts {
+        let mut req = Request::builder();
+
+        for &(name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        req.body(()).unwrap().into_parts().0
+    }
+
+    fn resolve(layer: &RealIpLayer, headers: &[(&'static str, &str)]) -> Option<IpAddr> {
+        resolve_ip(&parts(headers), &layer.config).map(|(ip, _)| ip.0)
+    }
+
+    fn xff(layer: &RealIpLayer, value: &str) -> Option<IpAddr> {
+        resolve(layer, &[("x-forwarded-for", value)])
+    }
+
+    #[test]
+    fn leftmost_without_trusted_proxies() {
+        let layer = RealIpLayer::new();
+
+        assert_eq!(xff(&layer, "203.0.113.7"), Some(ip("203.0.113.7")));
+        assert_eq!(xff(&layer, "6.6.6.6, 203.0.113.7, 10.0.0.2"), Some(ip("6.6.6.6")));
+    }
+
+    #[test]
+    fn trusted_proxies_skip_spoofed_prefix() {
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8"), cidr("2001:db8::/32")]);
+
+        // the client prepended `6.6.6.6`, but the hop added by our own proxy is the real client
+        assert_eq!(xff(&layer, "6.6.6.6, 203.0.113.7, 10.0.0.2"), Some(ip("203.0.113.7")));
+        assert_eq!(
+            xff(&layer, "6.6.6.6, 203.0.113.7, 10.0.0.2, 10.0.0.1"),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            xff(&layer, "6.6.6.6, 203.0.113.7, 2001:db8::5"),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            xff(&layer, "6.6.6.6, 2001:db8:1::1, 203.0.113.7"),
+            Some(ip("203.0.113.7"))
+        );
+
+        // IPv4-mapped hops match IPv4 ranges
+        assert_eq!(
+            xff(&layer, "6.6.6.6, 203.0.113.7, ::ffff:10.0.0.2"),
+            Some(ip("203.0.113.7"))
+        );
+
+        // a spoofed address within the trusted ranges is also skipped
+        assert_eq!(xff(&layer, "10.6.6.6, 203.0.113.7, 10.0.0.2"), Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn trusted_proxies_all_trusted() {
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+
+        assert_eq!(xff(&layer, "10.0.0.3, 10.0.0.2, 10.0.0.1"), Some(ip("10.0.0.3")));
+        assert_eq!(xff(&layer, "10.0.0.1"), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn trusted_proxies_stop_at_invalid_hop() {
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+
+        // an unparseable hop can't be trusted, so nothing to its left can be either
+        assert_eq!(xff(&layer, "6.6.6.6, garbage, 10.0.0.2"), None);
+        assert_eq!(xff(&layer, "6.6.6.6, unknown"), None);
+
+        // and the walk falls back to the next header
+        let headers = [
+            ("x-forwarded-for", "6.6.6.6, garbage, 10.0.0.2"),
+            ("true-client-ip", "192.0.2.1"),
+        ];
+        assert_eq!(resolve(&layer, &headers), Some(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn xff_modes() {
+        const CHAIN: &str = "192.0.2.1, 192.0.2.2, 192.0.2.3, 192.0.2.4";
+
+        let mode = |mode| xff(&RealIpLayer::new().with_xff_mode(mode), CHAIN);
+
+        assert_eq!(mode(XffMode::Leftmost), Some(ip("192.0.2.1")));
+        assert_eq!(mode(XffMode::Rightmost), Some(ip("192.0.2.4")));
+
+        let trusted = RealIpLayer::new().with_xff_mode(XffMode::Rightmost);
+        let trusted = trusted.with_trusted_proxies([cidr("192.0.2.3/32"), cidr("192.0.2.4/32")]);
+        assert_eq!(xff(&trusted, CHAIN), Some(ip("192.0.2.2")));
+
+        let expected = [
+            "192.0.2.4",
+            "192.0.2.3",
+            "192.0.2.2",
+            "192.0.2.1",
+            "192.0.2.1",
+            "192.0.2.1",
+        ];
+        for (n, expected) in [0, 1, 2, 3, 4, 100].into_iter().zip(expected) {
+            assert_eq!(
+                mode(XffMode::RightmostTrusted(n)),
+                Some(ip(expected)),
+                "RightmostTrusted({n})"
+            );
+        }
+    }
+
+    fn forwarded(layer: &RealIpLayer, value: &str) -> Option<IpAddr> {
+        resolve(layer, &[("forwarded", value)])
+    }
+
+    #[test]
+    fn forwarded_multiple_elements() {
+        let value = r#"for=6.6.6.6, For="[2001:db8:cafe::17]:4711";proto=https, FOR=10.0.0.2"#;
+
+        assert_eq!(forwarded(&RealIpLayer::new(), value), Some(ip("6.6.6.6")));
+
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+        assert_eq!(forwarded(&layer, value), Some(ip("2001:db8:cafe::17")));
+    }
+
+    #[test]
+    fn forwarded_obfuscated_hop_stops_walk() {
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+
+        // the obfuscated hop is untrusted, so the client-controlled entry to its left must not be used
+        assert_eq!(forwarded(&layer, "for=6.6.6.6, for=_hidden"), None);
+        assert_eq!(forwarded(&layer, "for=6.6.6.6, for=unknown, for=10.0.0.2"), None);
+        assert_eq!(forwarded(&layer, "for=6.6.6.6, proto=https"), None);
+
+        // as with an unparseable `X-Forwarded-For` hop
+        assert_eq!(xff(&layer, "6.6.6.6, _hidden"), None);
+
+        // but the leftmost address is still found past obfuscated entries
+        let value = "for=_hidden, for=unknown, for=192.0.2.1";
+        assert_eq!(forwarded(&RealIpLayer::new(), value), Some(ip("192.0.2.1")));
+    }
+
+    /// `X-Forwarded-For` value with `n` hops, leftmost first.
+    fn chain(n: usize) -> String {
+        let hops: Vec<_> = (0..n).map(|i| format!("192.0.{}.{}", i / 256 % 256, i % 256)).collect();
+        hops.join(", ")
+    }
+
+    #[test]
+    fn max_hops_boundary() {
+        let layer = RealIpLayer::new().with_max_hops(4);
+
+        // 3 commas, so exactly `max_hops` hops
+        assert_eq!(xff(&layer, &chain(4)), Some(ip("192.0.0.0")));
+
+        // 4 commas is one hop too many, so the leftmost is dropped, even if some entries are empty
+        assert_eq!(xff(&layer, &chain(5)), Some(ip("192.0.0.1")));
+        assert_eq!(
+            xff(&layer, "192.0.2.1, , 192.0.2.2, 192.0.2.3, 192.0.2.4"),
+            Some(ip("192.0.2.2"))
+        );
+        let value = "for=192.0.2.1,for=192.0.2.2,for=192.0.2.3,for=192.0.2.4,for=192.0.2.5";
+        assert_eq!(forwarded(&layer, value), Some(ip("192.0.2.2")));
+
+        // the default is 16
+        assert_eq!(xff(&RealIpLayer::new(), &chain(16)), Some(ip("192.0.0.0")));
+        assert_eq!(xff(&RealIpLayer::new(), &chain(17)), Some(ip("192.0.0.1")));
+    }
+
+    #[test]
+    fn max_hops_long_header() {
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+
+        // the client padded the header, but the hops added by our own proxies are still found
+        let padded = format!("{}, 203.0.113.7, 10.0.0.2, 10.0.0.1", chain(10_000));
+        assert_eq!(xff(&layer, &padded), Some(ip("203.0.113.7")));
+
+        // and can't pick its own address through a lower-precedence header
+        let headers = [("x-forwarded-for", &*padded), ("true-client-ip", "6.6.6.6")];
+        assert_eq!(resolve(&layer, &headers), Some(ip("203.0.113.7")));
+
+        // without trusted proxies, the leftmost of the rightmost `max_hops` hops
+        assert_eq!(xff(&RealIpLayer::new(), &chain(10_000)), Some(ip("192.0.39.0")));
+        assert_eq!(
+            xff(&RealIpLayer::new().with_max_hops(usize::MAX), &chain(10_000)),
+            Some(ip("192.0.0.0"))
+        );
+    }
+
+    #[cfg(feature = "signed_ip")]
+    #[test]
+    fn signed_header() {
+        const SIGNED: HeaderName = HeaderName::from_static("x-client-ip-signed");
+
+        let layer = RealIpLayer::new().with_signed_header(SIGNED, "edge secret");
+        let signed = sign_client_ip(b"edge secret", ip("203.0.113.7"));
+
+        let valid = parts(&[("x-client-ip-signed", &signed), ("x-forwarded-for", "6.6.6.6")]);
+        let resolved = resolve_ip(&valid, &layer.config);
+        assert_eq!(
+            resolved,
+            Some((RealIp(ip("203.0.113.7")), RealIpSource::SignedHeader(SIGNED)))
+        );
+
+        // an invalid signature fails outright instead of falling back to other headers
+        let forged = sign_client_ip(b"guessed secret", ip("203.0.113.7"));
+        let forged = parts(&[("x-client-ip-signed", &forged), ("x-forwarded-for", "6.6.6.6")]);
+        assert_eq!(resolve_ip(&forged, &layer.config), None);
+        assert!(matches!(
+            RealIpError::diagnose(&forged, &layer.config),
+            RealIpError::InvalidSignature { header } if header == SIGNED
+        ));
+
+        // but a missing header falls back as usual
+        assert_eq!(resolve(&layer, &[("x-forwarded-for", "6.6.6.6")]), Some(ip("6.6.6.6")));
+    }
+
+    fn extract<T: FromRequestParts<()>>(parts: &mut Parts) -> Result<T, T::Rejection> {
+        let extracted = futures_util::FutureExt::now_or_never(T::from_request_parts(parts, &()));
+        extracted.expect("extraction is synchronous")
+    }
+
+    fn reason(layer: &RealIpLayer, headers: &[(&'static str, &str)]) -> RealIpError {
+        let mut parts = parts(headers);
+        parts.extensions.insert(SharedConfig(layer.config.clone()));
+
+        extract::<RealIp>(&mut parts).unwrap_err().reason().clone()
+    }
+
+    #[test]
+    fn rejection_reasons() {
+        let layer = RealIpLayer::new();
+
+        // the socket is consulted without any headers, but isn't available here
+        let missing = match cfg!(feature = "connect_info") {
+            true => RealIpError::NoConnectInfo,
+            false => RealIpError::NoHeaders,
+        };
+        assert_eq!(reason(&layer, &[]), missing);
+        assert_eq!(reason(&layer, &[("user-agent", "test")]), missing);
+
+        let header = HeaderName::from_static("x-forwarded-for");
+        assert_eq!(
+            reason(&layer, &[("x-forwarded-for", "garbage")]),
+            RealIpError::Unparseable { header }
+        );
+
+        // the first header present in order of precedence
+        let headers = [("x-forwarded-for", "garbage"), ("cf-connecting-ip", "nonsense")];
+        let header = HeaderName::from_static("cf-connecting-ip");
+        assert_eq!(reason(&layer, &headers), RealIpError::Unparseable { header });
+
+        // rejected with `400 Bad Request` by default
+        let rejection = IpAddrRejection::from_parts(&parts(&[("x-forwarded-for", "garbage")]));
+        assert_eq!(rejection.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn header_consistency() {
+        let layer = |consistency| {
+            let headers = ["x-real-ip", "client-ip"].map(|h| (HeaderName::from_static(h), false));
+            RealIpLayer::new().with_headers(headers).with_header_consistency(consistency)
+        };
+
+        let agree = [("x-real-ip", "192.0.2.1"), ("client-ip", "::ffff:192.0.2.1")];
+        let disagree = [("x-real-ip", "192.0.2.1"), ("client-ip", "198.51.100.1")];
+
+        for consistency in [
+            HeaderConsistency::PreferHighest,
+            HeaderConsistency::Log,
+            HeaderConsistency::Reject,
+        ] {
+            assert_eq!(
+                resolve(&layer(consistency), &agree),
+                Some(ip("192.0.2.1")),
+                "{consistency:?}"
+            );
+            assert_eq!(
+                resolve(&layer(consistency), &agree[..1]),
+                Some(ip("192.0.2.1")),
+                "{consistency:?}"
+            );
+        }
+
+        assert_eq!(
+            resolve(&layer(HeaderConsistency::PreferHighest), &disagree),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(
+            resolve(&layer(HeaderConsistency::Log), &disagree),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(resolve(&layer(HeaderConsistency::Reject), &disagree), None);
+
+        let header = HeaderName::from_static("x-real-ip");
+        assert_eq!(
+            reason(&layer(HeaderConsistency::Reject), &disagree),
+            RealIpError::Inconsistent { header }
+        );
+    }
+
+    fn mask<const V4: u8, const V6: u8>(s: &str) -> IpAddr {
+        RealIpMask::<V4, V6>::from(RealIp(ip(s))).0 .0
+    }
+
+    #[test]
+    fn masks() {
+        assert_eq!(mask::<24, 48>("192.0.2.123"), ip("192.0.2.0"));
+        assert_eq!(mask::<24, 48>("2001:db8:1234:5678:9abc::1"), ip("2001:db8:1234::"));
+        assert_eq!(mask::<16, 56>("198.51.100.17"), ip("198.51.0.0"));
+        assert_eq!(mask::<16, 56>("2001:db8:1234:56ff::1"), ip("2001:db8:1234:5600::"));
+
+        assert_eq!(mask::<32, 128>("192.0.2.123"), ip("192.0.2.123"));
+        assert_eq!(mask::<32, 128>("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(mask::<0, 0>("192.0.2.123"), ip("0.0.0.0"));
+        assert_eq!(mask::<0, 0>("2001:db8::1"), ip("::"));
+
+        assert_eq!(
+            RealIpNetworkMask::from(RealIp(ip("2001:db8:0:1ff::1"))).0 .0,
+            ip("2001:db8:0:100::")
+        );
+    }
+
+    #[test]
+    fn privacy_mask() {
+        let RealIpPrivacyMask(masked) = RealIpPrivacyMask::from(RealIp(ip("2001:db8:1:2:3:4:5:6")));
+        assert_eq!(masked, RealIp(ip("2001:db8:1:2::")));
+
+        let RealIpPrivacyMask(masked) = RealIpPrivacyMask::from(RealIp(ip("192.0.2.123")));
+        assert_eq!(masked, RealIp(ip("192.0.2.123")));
+
+        // the same as the const-generic mask, and converts to and from it
+        let full = RealIp(ip("2001:db8::1:2:3:4"));
+        let generic = RealIpMask::<32, 64>::from(full);
+
+        assert_eq!(RealIpPrivacyMask::from(generic), RealIpPrivacyMask::from(full));
+        assert_eq!(RealIpMask::<32, 64>::from(RealIpPrivacyMask::from(full)), generic);
+        assert_eq!(*RealIpPrivacyMask(full), full);
+    }
+
+    /// Request parts as if connected from `peer`.
+    #[cfg(feature = "connect_info")]
+    fn connected(peer: &str, headers: &[(&'static str, &str)]) -> Parts {
+        let mut parts = parts(headers);
+        parts.extensions.insert(axum::extract::ConnectInfo(SocketAddr::new(ip(peer), 4711)));
+        parts
+    }
+
+    #[cfg(feature = "connect_info")]
+    #[test]
+    fn socket_ip_ignores_headers() {
+        let headers = [
+            ("x-forwarded-for", "6.6.6.6"),
+            ("x-real-ip", "6.6.6.7"),
+            ("forwarded", "for=6.6.6.8"),
+        ];
+
+        // even if the layer would trust them
+        let layer = RealIpLayer::new().with_trusted_proxies([cidr("192.0.2.0/24")]);
+        let mut proxied = connected("192.0.2.1", &headers);
+        proxied.extensions.insert(SharedConfig(layer.config.clone()));
+
+        assert_eq!(extract::<SocketIp>(&mut proxied).unwrap(), SocketIp(ip("192.0.2.1")));
+        // unlike `RealIp`, which takes the header first in order of precedence
+        assert_eq!(extract::<RealIp>(&mut proxied).unwrap(), RealIp(ip("6.6.6.7")));
+
+        // rejected without the connection info, rather than falling back to the headers
+        let mut unconnected = parts(&headers);
+        assert_eq!(extract::<SocketIp>(&mut unconnected).unwrap_err(), SocketIpRejection);
+        assert_eq!(
+            SocketIpRejection.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(extract::<Option<SocketIp>>(&mut unconnected).unwrap(), None);
+    }
+
+    #[cfg(feature = "connect_info")]
+    #[test]
+    fn peer_headers() {
+        const CF_CONNECTING_IP: HeaderName = HeaderName::from_static("cf-connecting-ip");
+        const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+        let layer = RealIpLayer::new()
+            .with_peer_headers([(cidr("173.245.48.0/20"), CF_CONNECTING_IP)])
+            .with_peer_header(cidr("10.0.0.0/8"), X_FORWARDED_FOR);
+
+        let resolve = |peer, headers| resolve_ip(&connected(peer, headers), &layer.config);
+        let headers = [("cf-connecting-ip", "203.0.113.7"), ("x-forwarded-for", "198.51.100.1")];
+
+        // each header is honored only from its own peers
+        let cloudflare = Some((RealIp(ip("203.0.113.7")), RealIpSource::Header(CF_CONNECTING_IP)));
+        assert_eq!(resolve("173.245.48.5", &headers), cloudflare);
+        let internal = Some((RealIp(ip("198.51.100.1")), RealIpSource::Header(X_FORWARDED_FOR)));
+        assert_eq!(resolve("10.1.2.3", &headers), internal);
+
+        // any other peer is the client itself, whatever it sends
+        let direct = Some((RealIp(ip("6.6.6.6")), RealIpSource::Socket));
+        assert_eq!(resolve("6.6.6.6", &headers), direct);
+        assert_eq!(resolve("6.6.6.6", &[("x-real-ip", "203.0.113.7")]), direct);
+
+        // as is a trusted peer without its header
+        let peer = Some((RealIp(ip("173.245.48.5")), RealIpSource::Socket));
+        assert_eq!(resolve("173.245.48.5", &[("x-forwarded-for", "198.51.100.1")]), peer);
+
+        // and without the connection info, nothing can be trusted
+        assert_eq!(resolve_ip(&parts(&headers), &layer.config), None);
+        assert_eq!(reason(&layer, &headers), RealIpError::NoConnectInfo);
+    }
+
+    /// The client address and its source as inserted by the layer.
+    fn through_layer(layer: &RealIpLayer, parts: Parts) -> (Option<RealIp>, Option<RealIpSource>) {
+        use tower::ServiceExt;
+
+        let service = layer.layer(tower::service_fn(|req: Request<()>| async move {
+            let extensions = req.extensions();
+            Ok::<_, Infallible>((
+                extensions.get::<RealIp>().copied(),
+                extensions.get::<RealIpSource>().cloned(),
+            ))
+        }));
+
+        let res = futures_util::FutureExt::now_or_never(service.oneshot(Request::from_parts(parts, ())));
+        res.expect("resolution is synchronous").unwrap()
+    }
+
+    #[test]
+    fn sources() {
+        let layer = RealIpLayer::new();
+        let client = Some(RealIp(ip("203.0.113.7")));
+        let x_real_ip = RealIpSource::Header(HeaderName::from_static("x-real-ip"));
+
+        let only_x_real_ip = || parts(&[("x-real-ip", "203.0.113.7")]);
+        assert_eq!(
+            through_layer(&layer, only_x_real_ip()),
+            (client, Some(x_real_ip.clone()))
+        );
+        assert_eq!(extract::<RealIpSource>(&mut only_x_real_ip()), Ok(x_real_ip));
+
+        let fallback = RealIpLayer::new().with_fallback_ip(Some(ip("192.0.2.1")));
+        assert_eq!(through_layer(&fallback, parts(&[])).1, Some(RealIpSource::Fallback));
+
+        // neither is inserted without an address, and the extractor rejects as for `RealIp`
+        let strict = RealIpLayer::new().only_header(HeaderName::from_static("x-real-ip"));
+        assert_eq!(through_layer(&strict, parts(&[])), (None, None));
+        assert!(extract::<RealIpSource>(&mut parts(&[("x-forwarded-for", "garbage")])).is_err());
+
+        #[cfg(feature = "connect_info")]
+        {
+            assert_eq!(
+                through_layer(&layer, connected("192.0.2.1", &[])).1,
+                Some(RealIpSource::Socket)
+            );
+            assert_eq!(
+                extract::<RealIpSource>(&mut connected("192.0.2.1", &[])),
+                Ok(RealIpSource::Socket)
+            );
+        }
+
+        // an address inserted by an outer layer, if preserved
+        let outer = RealIp(ip("198.51.100.1"));
+        let mut existing = only_x_real_ip();
+        existing.extensions.insert(outer);
+        let preserving = layer.with_preserve_existing(true);
+        assert_eq!(
+            through_layer(&preserving, existing),
+            (Some(outer), Some(RealIpSource::Extension))
+        );
+    }
+
+    #[test]
+    fn header_precedence() {
+        const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+        const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+        const CF_CONNECTING_IP: HeaderName = HeaderName::from_static("cf-connecting-ip");
+
+        let headers = [
+            ("cf-connecting-ip", "192.0.2.1"),
+            ("x-real-ip", "192.0.2.2"),
+            ("x-forwarded-for", "192.0.2.3"),
+        ];
+
+        // the layer and the extractor agree on the configured order
+        let check = |layer: &RealIpLayer, expected: &str| {
+            let expected = Some(RealIp(ip(expected)));
+            assert_eq!(through_layer(layer, parts(&headers)).0, expected);
+
+            let mut unlayered = parts(&headers);
+            unlayered.extensions.insert(SharedConfig(layer.config.clone()));
+            assert_eq!(extract::<Option<RealIp>>(&mut unlayered).unwrap(), expected);
+        };
+
+        check(&RealIpLayer::new(), "192.0.2.1");
+        check(&RealIpLayer::new().without_header(&CF_CONNECTING_IP), "192.0.2.2");
+        check(
+            &RealIpLayer::new().with_headers([(X_FORWARDED_FOR, false), (X_REAL_IP, false)]),
+            "192.0.2.3",
+        );
+
+        // adding an existing header moves it to the end
+        let mut layer = RealIpLayer::new().with_headers([(X_REAL_IP, false), (X_FORWARDED_FOR, false)]);
+        layer.add_header(X_REAL_IP, false);
+        check(&layer, "192.0.2.3");
+
+        layer.remove_header(&X_FORWARDED_FOR);
+        check(&layer, "192.0.2.2");
+
+        // an empty list consults no headers at all
+        let empty = RealIpLayer::new().with_headers([]);
+        assert_eq!(through_layer(&empty, parts(&headers)).0, None);
+    }
+
+    #[tokio::test]
+    async fn optional_extraction() {
+        use axum::{body::Body, routing::get, Router};
+        use tower::ServiceExt;
+
+        let without_ip = || parts(&[("x-forwarded-for", "garbage")]);
+
+        assert!(extract::<RealIp>(&mut without_ip()).is_err());
+        assert_eq!(extract::<Option<RealIp>>(&mut without_ip()).unwrap(), None);
+        assert_eq!(extract::<Option<RealIpMask<24, 48>>>(&mut without_ip()).unwrap(), None);
+
+        let with_ip = &mut parts(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(
+            extract::<Option<RealIp>>(with_ip).unwrap(),
+            Some(RealIp(ip("192.0.2.1")))
+        );
+
+        // handlers can choose to accept requests without an address, while the strict extractor rejects them
+        let app = Router::new()
+            .route("/strict", get(|_: RealIp| async {}))
+            .route("/optional", get(|ip: Option<RealIp>| async move { format!("{ip:?}") }))
+            .layer(RealIpLayer::new().only_header(HeaderName::from_static("x-real-ip")));
+
+        for (path, status) in [("/strict", StatusCode::BAD_REQUEST), ("/optional", StatusCode::OK)] {
+            let res = app.clone().oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(res.status(), status);
+        }
+    }
+
+    #[test]
+    fn non_global_addresses() {
+        let non_global = [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "100.64.0.1",
+            "100.127.255.255",
+            "169.254.1.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ];
+
+        for addr in non_global {
+            assert!(!RealIp(ip(addr)).is_global(), "{addr}");
+        }
+
+        let global = [
+            "203.0.113.7",
+            "172.32.0.1",
+            "100.63.255.255",
+            "100.128.0.1",
+            "2001:db8::1",
+            "::ffff:203.0.113.7",
+        ];
+
+        for addr in global {
+            assert!(RealIp(ip(addr)).is_global(), "{addr}");
+        }
+
+        // rejected addresses fall through to the next header, and then the socket
+        let layer = RealIpLayer::new().with_reject_private(true);
+        let headers = [
+            ("x-real-ip", "127.0.0.1"),
+            ("x-forwarded-for", "10.0.0.1"),
+            ("true-client-ip", "203.0.113.7"),
+        ];
+        assert_eq!(resolve(&layer, &headers), Some(ip("203.0.113.7")));
+        assert_eq!(resolve(&RealIpLayer::new(), &headers), Some(ip("127.0.0.1")));
+        assert_eq!(resolve(&layer, &headers[..2]), None);
+
+        #[cfg(feature = "connect_info")]
+        {
+            let socket = resolve_ip(&connected("10.0.0.2", &headers[..2]), &layer.config);
+            assert_eq!(socket, Some((RealIp(ip("10.0.0.2")), RealIpSource::Socket)));
+        }
+    }
+
+    #[test]
+    fn canonical_ips_share_a_bucket() {
+        use crate::gcra::{Quota, RateLimiter};
+
+        let quota = Quota::per_hour(1);
+        let now = std::time::Instant::now();
+
+        let requests = |layer: &RealIpLayer| {
+            let limiter = RateLimiter::<RealIp>::default();
+
+            ["192.0.2.1", "::ffff:192.0.2.1"]
+                .map(|addr| through_layer(layer, parts(&[("x-real-ip", addr)])).0.unwrap())
+                .map(|ip| limiter.req_sync(ip, quota, now).is_ok())
+        };
+
+        // off by default, so each form has its own bucket
+        assert_eq!(requests(&RealIpLayer::new()), [true, true]);
+        assert_eq!(requests(&RealIpLayer::new().with_canonical_ips(true)), [true, false]);
+
+        assert_eq!(RealIp(ip("::ffff:192.0.2.1")).canonical(), RealIp(ip("192.0.2.1")));
+        assert_eq!(RealIp(ip("2001:db8::1")).canonical(), RealIp(ip("2001:db8::1")));
+        assert_eq!(RealIp(ip("::192.0.2.1")).canonical(), RealIp(ip("::192.0.2.1")));
+    }
+
+    #[test]
+    fn cloudflare_headers() {
+        // with Pseudo IPv4 overwriting `cf-connecting-ip`
+        let overwritten = [
+            ("cf-connecting-ip", "240.16.0.1"),
+            ("cf-connecting-ipv6", "2001:db8::1"),
+            ("cf-pseudo-ipv4", "240.16.0.1"),
+        ];
+
+        let source = |layer: &RealIpLayer| resolve_ip(&parts(&overwritten), &layer.config);
+        let header = |name, addr| Some((RealIp(ip(addr)), RealIpSource::Header(HeaderName::from_static(name))));
+
+        let genuine = header("cf-connecting-ipv6", "2001:db8::1");
+
+        assert_eq!(source(&RealIpLayer::new()), header("cf-connecting-ip", "240.16.0.1"));
+        assert_eq!(source(&RealIpLayer::new().with_cloudflare_ipv6(true)), genuine);
+
+        // the pseudo address is reserved, so rejecting non-global addresses skips it
+        assert_eq!(source(&RealIpLayer::new().with_reject_private(true)), genuine);
+
+        // with Pseudo IPv4 added as its own header, the genuine address comes first
+        let added = [("cf-connecting-ip", "2001:db8::1"), ("cf-pseudo-ipv4", "240.16.0.1")];
+        assert_eq!(resolve(&RealIpLayer::new(), &added), Some(ip("2001:db8::1")));
+        assert_eq!(resolve(&RealIpLayer::new(), &added[1..]), Some(ip("240.16.0.1")));
+
+        assert!(RealIp(ip("240.16.0.1")).is_cloudflare_pseudo_ipv4());
+        assert!(RealIp(ip("255.255.255.254")).is_cloudflare_pseudo_ipv4());
+        assert!(!RealIp(ip("255.255.255.255")).is_cloudflare_pseudo_ipv4());
+        assert!(!RealIp(ip("239.255.255.255")).is_cloudflare_pseudo_ipv4());
+        assert!(!RealIp(ip("2001:db8::1")).is_cloudflare_pseudo_ipv4());
+    }
+
+    #[test]
+    fn forwarded_chain() {
+        let chain = |headers: &[(&'static str, &str)]| extract::<ForwardedChain>(&mut parts(headers)).unwrap().0;
+        let ips = |addrs: &[&str]| addrs.iter().map(|addr| ip(addr)).collect::<Vec<_>>();
+
+        // in order, across every header, skipping anything that isn't an address
+        let xff = [
+            (
+                "x-forwarded-for",
+                " 203.0.113.7:4711 ,[2001:db8::1]:443,  garbage, 2001:db8::2",
+            ),
+            ("x-forwarded-for", "unknown,10.0.0.1"),
+        ];
+        assert_eq!(
+            chain(&xff),
+            ips(&["203.0.113.7", "2001:db8::1", "2001:db8::2", "10.0.0.1"])
+        );
+
+        // `Forwarded` takes precedence over `X-Forwarded-For` entirely
+        let forwarded = [
+            ("x-forwarded-for", "6.6.6.6"),
+            (
+                "forwarded",
+                r#"for=192.0.2.1;proto=https, for="[2001:db8:cafe::17]:4711""#,
+            ),
+            ("forwarded", "for=_hidden, for=10.0.0.2"),
+        ];
+        assert_eq!(chain(&forwarded), ips(&["192.0.2.1", "2001:db8:cafe::17", "10.0.0.2"]));
+
+        assert!(chain(&[]).is_empty());
+    }
+
+    #[test]
+    fn proxy_protocol_extension() {
+        let headers = [("x-forwarded-for", "6.6.6.6"), ("x-real-ip", "6.6.6.7")];
+        let layer = RealIpLayer::new().proxy_protocol_extension::<SocketAddr>();
+
+        // the extension wins over any headers
+        let mut proxied = parts(&headers);
+        proxied.extensions.insert(SocketAddr::new(ip("203.0.113.7"), 4711));
+        let client = (Some(RealIp(ip("203.0.113.7"))), Some(RealIpSource::ProxyProtocol));
+        assert_eq!(through_layer(&layer, proxied), client);
+
+        // which are consulted as usual without it
+        let x_real_ip = RealIpSource::Header(HeaderName::from_static("x-real-ip"));
+        let header = (Some(RealIp(ip("6.6.6.7"))), Some(x_real_ip));
+        assert_eq!(through_layer(&layer, parts(&headers)), header);
+
+        // and only the configured type is read
+        let mut other = parts(&headers);
+        other.extensions.insert(ip("203.0.113.7"));
+        assert_eq!(through_layer(&layer, other), header);
+
+        let mut direct = parts(&headers);
+        direct.extensions.insert(ip("2001:db8::1"));
+        let layer = RealIpLayer::new().proxy_protocol_extension::<IpAddr>();
+        assert_eq!(through_layer(&layer, direct).0, Some(RealIp(ip("2001:db8::1"))));
+    }
+
+    /// Runs with the `connect_info` feature alone, as well, so the fallback needs no particular runtime.
+    #[cfg(feature = "connect_info")]
+    #[test]
+    fn connect_info_fallback() {
+        let layer = RealIpLayer::new();
+        let socket = (Some(RealIp(ip("192.0.2.1"))), Some(RealIpSource::Socket));
+
+        assert_eq!(through_layer(&layer, connected("192.0.2.1", &[])), socket);
+        assert_eq!(
+            extract::<RealIp>(&mut connected("192.0.2.1", &[])),
+            Ok(RealIp(ip("192.0.2.1")))
+        );
+
+        // used only once no header yields an address
+        let headers = [("x-forwarded-for", "garbage")];
+        assert_eq!(through_layer(&layer, connected("192.0.2.1", &headers)), socket);
+        let headers = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(
+            through_layer(&layer, connected("192.0.2.1", &headers)).0,
+            Some(RealIp(ip("203.0.113.7")))
+        );
+
+        // and without the extension, there is nothing to fall back to
+        assert_eq!(through_layer(&layer, parts(&[])), (None, None));
+    }
+
+    #[test]
+    fn empty_xff_entries() {
+        let modes = [XffMode::Leftmost, XffMode::Rightmost, XffMode::RightmostTrusted(1)];
+
+        for mode in modes {
+            let layer = RealIpLayer::new().with_xff_mode(mode);
+
+            for value in [", 1.2.3.4", "1.2.3.4, ", "  ,  ,1.2.3.4", "1.2.3.4,,", " , 1.2.3.4 , "] {
+                assert_eq!(xff(&layer, value), Some(ip("1.2.3.4")), "{value:?} with {mode:?}");
+            }
+
+            assert_eq!(xff(&layer, " , , "), None, "{mode:?}");
+        }
+
+        // empty entries are not hops either
+        let layer = RealIpLayer::new().with_xff_mode(XffMode::RightmostTrusted(1));
+        assert_eq!(xff(&layer, "203.0.113.7, , 10.0.0.1, "), Some(ip("203.0.113.7")));
+
+        let trusted = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+        assert_eq!(xff(&trusted, ",203.0.113.7,, 10.0.0.1,"), Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn bracketed_ipv6() {
+        let layer = RealIpLayer::new();
+        let client = Some(ip("2001:db8::1"));
+
+        for header in ["x-forwarded-for", "x-real-ip", "true-client-ip"] {
+            for value in [
+                "[2001:db8::1]:443",
+                "[2001:db8::1]",
+                "2001:db8::1",
+                " [2001:db8::1]:443 ",
+            ] {
+                assert_eq!(resolve(&layer, &[(header, value)]), client, "{header}: {value}");
+            }
+
+            // while anything else around the brackets is still invalid
+            for value in [
+                "[2001:db8::1]:99999",
+                "[2001:db8::1]:",
+                "[2001:db8::1]x",
+                "[203.0.113.7]",
+            ] {
+                assert_eq!(resolve(&layer, &[(header, value)]), None, "{header}: {value}");
+            }
+        }
+
+        // including within a chain
+        assert_eq!(xff(&layer, "[2001:db8::1]:443, 10.0.0.1"), client);
+        let trusted = RealIpLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+        assert_eq!(xff(&trusted, "6.6.6.6, [2001:db8::1]:443, 10.0.0.1"), client);
+    }
+
+    #[test]
+    fn quoted_values() {
+        let layer = RealIpLayer::new();
+        let client = Some(ip("1.2.3.4"));
+
+        for header in ["x-real-ip", "x-forwarded-for"] {
+            for value in [r#""1.2.3.4""#, "'1.2.3.4'", " 1.2.3.4 ", r#" " 1.2.3.4 " "#] {
+                assert_eq!(resolve(&layer, &[(header, value)]), client, "{header}: {value}");
+            }
+
+            let quoted = resolve(&layer, &[(header, r#""[2001:db8::1]:443""#)]);
+            assert_eq!(quoted, Some(ip("2001:db8::1")), "{header}");
+
+            // only matching quotes around the whole value are stripped
+            for value in [r#""1.2.3.4"#, r#"1.2.3.4""#, r#""1.2.3.4'"#, r#""""1.2.3.4"""#] {
+                assert_eq!(resolve(&layer, &[(header, value)]), None, "{header}: {value}");
+            }
+        }
+
+        // and each hop of a chain is unquoted separately
+        assert_eq!(xff(&layer, r#"'1.2.3.4', "10.0.0.1""#), client);
+    }
+
+    #[test]
+    fn strict_single_header() {
+        const CF_CONNECTING_IP: HeaderName = HeaderName::from_static("cf-connecting-ip");
+
+        let layer = RealIpLayer::new().only_header(CF_CONNECTING_IP);
+        let spoofed = [
+            ("x-forwarded-for", "6.6.6.6"),
+            ("x-real-ip", "6.6.6.7"),
+            ("true-client-ip", "6.6.6.8"),
+        ];
+
+        // a spoofed header is ignored, whether or not the trusted one is present
+        let mut headers = spoofed.to_vec();
+        headers.push(("cf-connecting-ip", "203.0.113.7"));
+        assert_eq!(resolve(&layer, &headers), Some(ip("203.0.113.7")));
+        assert_eq!(resolve(&layer, &spoofed), None);
+
+        // where the usual precedence would fall through to the spoofed headers
+        assert_eq!(resolve(&RealIpLayer::new(), &spoofed), Some(ip("6.6.6.7")));
+
+        // falling back to the socket instead
+        #[cfg(feature = "connect_info")]
+        {
+            let direct = Some((RealIp(ip("192.0.2.1")), RealIpSource::Socket));
+            assert_eq!(resolve_ip(&connected("192.0.2.1", &spoofed), &layer.config), direct);
+        }
+    }
+
+    #[test]
+    fn fallback_ip() {
+        let local = ip("127.0.0.1");
+        let layer = RealIpLayer::new().with_fallback_ip(Some(local));
+
+        // used when neither the headers nor the socket yield an address
+        assert_eq!(
+            through_layer(&layer, parts(&[])),
+            (Some(RealIp(local)), Some(RealIpSource::Fallback))
+        );
+        let garbage = [("x-forwarded-for", "garbage")];
+        assert_eq!(through_layer(&layer, parts(&garbage)).0, Some(RealIp(local)));
+
+        let mut unix = parts(&[]);
+        unix.extensions.insert(SharedConfig(layer.config.clone()));
+        assert_eq!(extract::<RealIp>(&mut unix), Ok(RealIp(local)));
+
+        // but never over an address that was found
+        let header = [("x-real-ip", "203.0.113.7")];
+        assert_eq!(through_layer(&layer, parts(&header)).0, Some(RealIp(ip("203.0.113.7"))));
+
+        #[cfg(feature = "connect_info")]
+        {
+            let socket = (Some(RealIp(ip("192.0.2.1"))), Some(RealIpSource::Socket));
+            assert_eq!(through_layer(&layer, connected("192.0.2.1", &[])), socket);
+        }
+
+        // and without one, extraction is rejected
+        let mut unconfigured = parts(&[]);
+        assert!(extract::<RealIp>(&mut unconfigured).is_err());
+        assert_eq!(extract::<Option<RealIp>>(&mut unconfigured), Ok(None));
+    }
+
+    #[test]
+    fn azure_headers() {
+        let layer = RealIpLayer::new();
+        let source = |headers: &[(&'static str, &str)]| through_layer(&layer, parts(headers));
+        let header = |name| Some(RealIpSource::Header(HeaderName::from_static(name)));
+
+        let client = Some(RealIp(ip("203.0.113.7")));
+        assert_eq!(
+            source(&[("x-azure-clientip", "203.0.113.7")]),
+            (client, header("x-azure-clientip"))
+        );
+
+        // the socket address may include a port, for either family
+        for value in ["203.0.113.7", "203.0.113.7:4711"] {
+            assert_eq!(
+                source(&[("x-azure-socketip", value)]),
+                (client, header("x-azure-socketip"))
+            );
+        }
+        let v6 = source(&[("x-azure-socketip", "[2001:db8::1]:4711")]).0;
+        assert_eq!(v6, Some(RealIp(ip("2001:db8::1"))));
+
+        // while the client address may not
+        assert_eq!(source(&[("x-azure-clientip", "203.0.113.7:4711")]), (None, None));
+
+        // and the client address is preferred, ahead of the generic headers
+        let headers = [
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-azure-socketip", "192.0.2.1:4711"),
+            ("x-azure-clientip", "203.0.113.7"),
+        ];
+        assert_eq!(source(&headers), (client, header("x-azure-clientip")));
+        assert_eq!(source(&headers[..2]).0, Some(RealIp(ip("192.0.2.1"))));
+    }
+
+    #[test]
+    fn real_ip_with_mask() {
+        let mut req = parts(&[("x-forwarded-for", "2001:db8:1:2:3:4:5:6")]);
+        let both = extract::<RealIpWithMask>(&mut req).unwrap();
+        assert_eq!(both.full, RealIp(ip("2001:db8:1:2:3:4:5:6")));
+        assert_eq!(both.masked, RealIpPrivacyMask(RealIp(ip("2001:db8:1:2::"))));
+
+        let mut req = parts(&[("x-forwarded-for", "203.0.113.7")]);
+        let both = extract::<RealIpWithMask>(&mut req).unwrap();
+        assert_eq!((both.full.0, both.masked.0 .0), (ip("203.0.113.7"), ip("203.0.113.7")));
+
+        // an address already resolved by the layer is used for both, rather than the headers
+        let mut req = parts(&[("x-forwarded-for", "2001:db8:1:2:3:4:5:6")]);
+        req.extensions.insert(RealIp(ip("2001:db8:ffff:1::7")));
+        let both = extract::<RealIpWithMask>(&mut req).unwrap();
+        assert_eq!(both.full, RealIp(ip("2001:db8:ffff:1::7")));
+        assert_eq!(both.masked, RealIpPrivacyMask(RealIp(ip("2001:db8:ffff:1::"))));
+
+        let mut req = parts(&[]);
+        assert!(extract::<RealIpWithMask>(&mut req).is_err());
+
+        let optional = <RealIpWithMask as OptionalFromRequestParts<()>>::from_request_parts(&mut req, &());
+        assert_eq!(futures_util::FutureExt::now_or_never(optional), Some(Ok(None)));
+    }
+
+    #[test]
+    fn preserve_existing() {
+        let outer = RealIp(ip("198.51.100.1"));
+        let from_outer = || {
+            let mut req = parts(&[("x-real-ip", "203.0.113.7")]);
+            req.extensions.insert(outer);
+            req
+        };
+
+        let x_real_ip = Some(RealIpSource::Header(HeaderName::from_static("x-real-ip")));
+        let resolved = (Some(RealIp(ip("203.0.113.7"))), x_real_ip);
+
+        // overwritten by default
+        assert_eq!(through_layer(&RealIpLayer::new(), from_outer()), resolved);
+
+        let preserving = RealIpLayer::new().with_preserve_existing(true);
+        assert_eq!(
+            through_layer(&preserving, from_outer()),
+            (Some(outer), Some(RealIpSource::Extension))
+        );
+
+        // along with the source, if the outer layer inserted one
+        let mut req = from_outer();
+        req.extensions.insert(RealIpSource::Socket);
+        assert_eq!(
+            through_layer(&preserving, req),
+            (Some(outer), Some(RealIpSource::Socket))
+        );
+
+        // and resolved as usual when there is none
+        assert_eq!(
+            through_layer(&preserving, parts(&[("x-real-ip", "203.0.113.7")])),
+            resolved
+        );
+    }
+
+    #[test]
+    fn forwarded_proto_and_host() {
+        let proto = |headers: &[(&'static str, &str)]| extract::<ForwardedProto>(&mut parts(headers)).map(|p| p.0);
+        let host = |headers: &[(&'static str, &str)]| extract::<ForwardedHost>(&mut parts(headers)).map(|h| h.0);
+
+        // `Forwarded` takes precedence over the fallbacks
+        let both = [
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", "internal.example"),
+            ("forwarded", "for=192.0.2.60;proto=HTTPS;host=example.com"),
+        ];
+        assert_eq!(proto(&both), Ok("https".to_owned()));
+        assert_eq!(host(&both), Ok("example.com".to_owned()));
+
+        // using the first element with the parameter, in any `Forwarded` header
+        let headers = [
+            ("forwarded", "for=192.0.2.60"),
+            (
+                "forwarded",
+                r#"for=198.51.100.17;host="example.com:8080", proto=http;host=proxy.example"#,
+            ),
+            ("x-forwarded-proto", "https"),
+        ];
+        assert_eq!(host(&headers), Ok("example.com:8080".to_owned()));
+        assert_eq!(proto(&headers), Ok("http".to_owned()));
+
+        // falling back to the first entry of `X-Forwarded-*` for each parameter missing from `Forwarded`
+        let headers = [
+            ("forwarded", "for=192.0.2.60;proto=https"),
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", " example.org , proxy.example"),
+        ];
+        assert_eq!(proto(&headers), Ok("https".to_owned()));
+        assert_eq!(host(&headers), Ok("example.org".to_owned()));
+        assert_eq!(proto(&[("x-forwarded-proto", "HTTPS, http")]), Ok("https".to_owned()));
+
+        let rejected = ForwardedRejection { param: "host" };
+        assert_eq!(host(&[("forwarded", "for=192.0.2.60")]), Err(rejected));
+        assert_eq!(host(&[("x-forwarded-host", "")]), Err(rejected));
+        assert_eq!(rejected.into_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(proto(&[]), Err(ForwardedRejection { param: "proto" }));
+
+        let mut req = parts(&[]);
+        let optional = <ForwardedHost as OptionalFromRequestParts<()>>::from_request_parts(&mut req, &());
+        assert_eq!(futures_util::FutureExt::now_or_never(optional), Some(Ok(None)));
+    }
 }
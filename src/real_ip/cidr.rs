@@ -0,0 +1,196 @@
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// An IPv4 or IPv6 network range in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+///
+/// Host bits of the address are zeroed on construction, so `10.1.2.3/8` is equivalent to `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// Error returned when a [`Cidr`] could not be parsed or has an invalid prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CidrParseError;
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid CIDR range")
+    }
+}
+
+impl Error for CidrParseError {}
+
+#[inline]
+const fn mask_v4(prefix: u8) -> u32 {
+    match prefix {
+        0 => 0,
+        _ => u32::MAX << (32 - prefix as u32),
+    }
+}
+
+#[inline]
+const fn mask_v6(prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => u128::MAX << (128 - prefix as u32),
+    }
+}
+
+impl Cidr {
+    /// Constructs a new CIDR range from an address and prefix length,
+    /// returning `None` if the prefix is longer than the address family allows.
+    #[must_use]
+    pub const fn new(addr: IpAddr, prefix: u8) -> Option<Cidr> {
+        let addr = match addr {
+            IpAddr::V4(v4) if prefix <= 32 => IpAddr::V4(Ipv4Addr::from_bits(v4.to_bits() & mask_v4(prefix))),
+            IpAddr::V6(v6) if prefix <= 128 => IpAddr::V6(Ipv6Addr::from_bits(v6.to_bits() & mask_v6(prefix))),
+            _ => return None,
+        };
+
+        Some(Cidr { addr, prefix })
+    }
+
+    /// Constructs a CIDR range matching exactly one address.
+    #[must_use]
+    pub const fn single(addr: IpAddr) -> Cidr {
+        let prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        Cidr { addr, prefix }
+    }
+
+    /// Returns the network address of the range.
+    #[inline]
+    #[must_use]
+    pub const fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the prefix length of the range.
+    #[inline]
+    #[must_use]
+    pub const fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns `true` if the given address falls within this range.
+    ///
+    /// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are matched against IPv4 ranges.
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => ip.to_bits() & mask_v4(self.prefix) == net.to_bits(),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => ip.to_bits() & mask_v6(self.prefix) == net.to_bits(),
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    #[inline]
+    fn from(addr: IpAddr) -> Self {
+        Cidr::single(addr)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    /// Parses `addr/prefix`, or a bare address as a single-address range.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((addr, prefix)) = s.split_once('/') else {
+            return IpAddr::from_str(s).map(Cidr::single).map_err(|_| CidrParseError);
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|_| CidrParseError)?;
+        let prefix = u8::from_str(prefix).map_err(|_| CidrParseError)?;
+
+        Cidr::new(addr, prefix).ok_or(CidrParseError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.1.2.3/8"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("192.0.2.1"), Cidr::single(ip("192.0.2.1")));
+        assert_eq!(cidr("::1").prefix(), 128);
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/x", "nope", "nope/8"] {
+            assert_eq!(invalid.parse::<Cidr>(), Err(CidrParseError), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn contains_v4() {
+        let net = cidr("10.0.0.0/8");
+
+        assert!(net.contains(ip("10.0.0.0")));
+        assert!(net.contains(ip("10.255.255.255")));
+        assert!(!net.contains(ip("9.255.255.255")));
+        assert!(!net.contains(ip("11.0.0.0")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("192.0.2.1/32").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let net = cidr("2001:db8::/32");
+
+        assert!(net.contains(ip("2001:db8::")));
+        assert!(net.contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!net.contains(ip("2001:db9::")));
+        assert!(!net.contains(ip("2001:db7:ffff::")));
+
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn contains_mixed_families() {
+        // IPv4-mapped addresses match IPv4 ranges
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+
+        // but IPv4 addresses never match IPv6 ranges, even `::/0` or the mapped range itself
+        assert!(!cidr("::/0").contains(ip("10.1.2.3")));
+        assert!(!cidr("::ffff:0:0/96").contains(ip("10.1.2.3")));
+
+        // and non-mapped IPv6 addresses never match IPv4 ranges
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::10.1.2.3")));
+    }
+}
@@ -0,0 +1,225 @@
+//! IP allowlist/denylist filtering.
+
+use std::{
+    future,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::response::{IntoResponse, Response};
+use futures_util::future::Either;
+use http::{Request, StatusCode};
+use tower::{Layer, Service};
+
+use super::{Cidr, RealIp};
+
+#[derive(Debug, Clone, Default)]
+struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    default_deny: bool,
+}
+
+impl IpFilter {
+    fn is_allowed(&self, ip: Option<RealIp>) -> bool {
+        let Some(ip) = ip else {
+            return !self.default_deny;
+        };
+
+        if ip.in_any_cidr(&self.deny) {
+            return false;
+        }
+
+        if ip.in_any_cidr(&self.allow) {
+            return true;
+        }
+
+        !self.default_deny
+    }
+}
+
+/// Layer that rejects requests from denied IP addresses with `403 Forbidden`,
+/// before they reach the handler or any rate limiter after it.
+///
+/// The client address is resolved in the same way as the [`RealIp`] extractor,
+/// honoring the configuration of any [`RealIpLayer`](super::RealIpLayer) applied before this layer.
+///
+/// Addresses in the deny list are always rejected, and addresses in the allow list are accepted.
+/// Any other address, or a request without a resolvable address, is accepted unless
+/// the [default is to deny](IpFilterLayer::with_default_deny).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::real_ip::{Cidr, IpFilterLayer, RealIpLayer};
+///
+/// let admin = Router::<()>::new()
+///     .route("/admin", get(|| async { "Admin" }))
+///     .layer(IpFilterLayer::allow_list(["10.0.0.0/8".parse::<Cidr>().unwrap()]))
+///     .layer(RealIpLayer::new());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IpFilterLayer {
+    filter: Arc<IpFilter>,
+}
+
+/// Service that rejects requests from denied IP addresses, see [`IpFilterLayer`].
+#[derive(Debug, Clone)]
+pub struct IpFilterService<I> {
+    inner: I,
+    filter: Arc<IpFilter>,
+}
+
+impl IpFilterLayer {
+    /// Constructs a new layer that accepts all requests, until ranges are added.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Constructs a new layer that rejects the given ranges, and accepts all others.
+    #[must_use]
+    pub fn deny_list(deny: impl IntoIterator<Item = Cidr>) -> Self {
+        Self::new().with_denied(deny)
+    }
+
+    /// Constructs a new layer that accepts only the given ranges, and rejects all others,
+    /// including requests without a resolvable address.
+    #[must_use]
+    pub fn allow_list(allow: impl IntoIterator<Item = Cidr>) -> Self {
+        Self::new().with_allowed(allow).with_default_deny(true)
+    }
+
+    /// Add ranges to the allow list.
+    #[must_use]
+    pub fn with_allowed(mut self, allow: impl IntoIterator<Item = Cidr>) -> Self {
+        Arc::make_mut(&mut self.filter).allow.extend(allow);
+        self
+    }
+
+    /// Add ranges to the deny list, which takes precedence over the allow list.
+    #[must_use]
+    pub fn with_denied(mut self, deny: impl IntoIterator<Item = Cidr>) -> Self {
+        Arc::make_mut(&mut self.filter).deny.extend(deny);
+        self
+    }
+
+    /// Set whether to reject addresses in neither list, and requests without a resolvable address.
+    ///
+    /// The default is `false`.
+    #[must_use]
+    pub fn with_default_deny(mut self, default_deny: bool) -> Self {
+        Arc::make_mut(&mut self.filter).default_deny = default_deny;
+        self
+    }
+}
+
+impl<I> Layer<I> for IpFilterLayer {
+    type Service = IpFilterService<I>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        IpFilterService {
+            inner,
+            filter: self.filter.clone(),
+        }
+    }
+}
+
+impl<B, I> Service<Request<B>> for IpFilterService<I>
+where
+    I: Service<Request<B>, Response = Response>,
+{
+    type Response = Response;
+    type Error = I::Error;
+    type Future = Either<future::Ready<Result<Response, I::Error>>, I::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let (parts, body) = req.into_parts();
+
+        if !self.filter.is_allowed(RealIp::lookup(&parts)) {
+            return Either::Left(future::ready(Ok(StatusCode::FORBIDDEN.into_response())));
+        }
+
+        Either::Right(self.inner.call(Request::from_parts(parts, body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+
+    use futures_util::FutureExt;
+    use tower::{service_fn, ServiceExt};
+
+    fn cidrs(ranges: &[&str]) -> Vec<Cidr> {
+        ranges.iter().map(|range| range.parse().unwrap()).collect()
+    }
+
+    /// Status of a request through the layer from the given client address, if any.
+    fn status(layer: &IpFilterLayer, client: Option<&str>) -> u16 {
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(().into_response())
+        }));
+
+        let mut req = Request::builder();
+        if let Some(client) = client {
+            req = req.header("x-forwarded-for", client);
+        }
+
+        let res = service.oneshot(req.body(()).unwrap()).now_or_never().unwrap().unwrap();
+        res.status().as_u16()
+    }
+
+    fn statuses(layer: &IpFilterLayer, clients: &[Option<&str>]) -> Vec<u16> {
+        clients.iter().map(|client| status(layer, *client)).collect()
+    }
+
+    const CLIENTS: [Option<&str>; 6] = [
+        Some("10.1.2.3"),
+        Some("192.0.2.1"),
+        Some("2001:db8::1"),
+        Some("2001:db9::1"),
+        Some("::ffff:10.1.2.3"),
+        None,
+    ];
+
+    #[test]
+    fn empty_lists_pass_through() {
+        assert_eq!(statuses(&IpFilterLayer::new(), &CLIENTS), [200; 6]);
+        assert_eq!(statuses(&IpFilterLayer::deny_list([]), &CLIENTS), [200; 6]);
+
+        // but an empty allow list denies everything
+        assert_eq!(statuses(&IpFilterLayer::allow_list([]), &CLIENTS), [403; 6]);
+    }
+
+    #[test]
+    fn deny_list() {
+        let layer = IpFilterLayer::deny_list(cidrs(&["10.0.0.0/8", "2001:db8::/32"]));
+
+        assert_eq!(statuses(&layer, &CLIENTS), [403, 200, 403, 200, 403, 200]);
+    }
+
+    #[test]
+    fn allow_list() {
+        let layer = IpFilterLayer::allow_list(cidrs(&["10.0.0.0/8", "2001:db8::/32"]));
+
+        assert_eq!(statuses(&layer, &CLIENTS), [200, 403, 200, 403, 200, 403]);
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let layer = IpFilterLayer::allow_list(cidrs(&["10.0.0.0/8", "2001:db8::/32"]))
+            .with_denied(cidrs(&["10.1.0.0/16", "2001:db8::1/128"]));
+
+        assert_eq!(status(&layer, Some("10.2.0.1")), 200);
+        assert_eq!(status(&layer, Some("2001:db8::2")), 200);
+        assert_eq!(statuses(&layer, &CLIENTS), [403, 403, 403, 403, 403, 403]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Parsing of the RFC 7239 `Forwarded` header.
+
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// Returns the value of the given parameter within a single `forwarded-element`,
+/// e.g. `for=192.0.2.60;proto=http;by=203.0.113.43`.
+///
+/// Parameter names are case-insensitive, and quoted values are unquoted.
+pub(crate) fn param<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+
+        if !key.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+
+        let value = value.trim();
+        Some(value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value))
+    })
+}
+
+/// Returns the value of the first element of a `Forwarded` header value with the given parameter.
+pub(crate) fn first_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(',').find_map(|element| param(element, name))
+}
+
+/// Iterates over the `for=` addresses of a `Forwarded` header value in order, skipping empty elements.
+///
+/// Elements without a `for=` parameter or with obfuscated/unknown identifiers yield `None`, so that a walk
+/// over trusted proxies stops at them rather than skipping to a client-controlled element further left.
+///
+/// Note that elements are split on `,` without regard to quoting, as node identifiers cannot contain commas.
+pub(crate) fn forwarded_for(value: &str) -> impl DoubleEndedIterator<Item = Option<IpAddr>> + '_ {
+    value
+        .split(',')
+        .filter(|element| !element.trim().is_empty())
+        .map(|element| parse_node(param(element, "for")?))
+}
+
+/// Parses a node identifier, stripping the optional port.
+///
+/// Obfuscated identifiers (`_hidden`) and `unknown` are not addresses, and will return `None`.
+pub(crate) fn parse_node(node: &str) -> Option<IpAddr> {
+    // `[2001:db8::1]` or `[2001:db8::1]:41237`
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _port) = rest.split_once(']')?;
+        return Ipv6Addr::from_str(ip).ok().map(IpAddr::V6);
+    }
+
+    // `192.0.2.60:8080`, but not a bare (technically invalid) IPv6 address
+    let ip = match node.rsplit_once(':') {
+        Some((ip, _port)) if !ip.contains(':') => ip,
+        _ => node,
+    };
+
+    IpAddr::from_str(ip).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn param_names_are_case_insensitive() {
+        let element = "For=192.0.2.60;PROTO=https; by=203.0.113.43";
+
+        assert_eq!(param(element, "for"), Some("192.0.2.60"));
+        assert_eq!(param(element, "proto"), Some("https"));
+        assert_eq!(param(element, "BY"), Some("203.0.113.43"));
+        assert_eq!(param(element, "host"), None);
+    }
+
+    #[test]
+    fn param_values_are_unquoted() {
+        assert_eq!(
+            param(r#"for="[2001:db8:cafe::17]:4711""#, "for"),
+            Some("[2001:db8:cafe::17]:4711")
+        );
+        assert_eq!(param(r#"host="example.com""#, "host"), Some("example.com"));
+        assert_eq!(
+            first_param(r#"for=192.0.2.60, for=198.51.100.17;proto="http""#, "proto"),
+            Some("http")
+        );
+    }
+
+    #[test]
+    fn multiple_for_entries() {
+        let value = r#"for=192.0.2.43, FOR="[2001:db8:cafe::17]:4711", for=198.51.100.17:8080;by=203.0.113.60"#;
+        let hops: Vec<_> = forwarded_for(value).collect();
+
+        assert_eq!(hops, [ip("192.0.2.43"), ip("2001:db8:cafe::17"), ip("198.51.100.17")]);
+    }
+
+    #[test]
+    fn obfuscated_nodes_are_none() {
+        let value = "for=192.0.2.43, for=_hidden, for=unknown, proto=https, ,for=198.51.100.17";
+        let hops: Vec<_> = forwarded_for(value).collect();
+
+        assert_eq!(hops, [ip("192.0.2.43"), None, None, None, ip("198.51.100.17")]);
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.60"), ip("192.0.2.60"));
+        assert_eq!(parse_node("192.0.2.60:8080"), ip("192.0.2.60"));
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]:41237"), ip("2001:db8::1"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+    }
+}
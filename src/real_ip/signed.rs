@@ -0,0 +1,168 @@
+use std::{
+    fmt::{self, Write},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use hmac::{Hmac, Mac};
+use http::{header::HeaderName, HeaderValue};
+use sha2::Sha256;
+
+/// Header holding the client address signed by a trusted edge, as configured by
+/// [`RealIpLayer::with_signed_header`](super::RealIpLayer::with_signed_header).
+#[derive(Clone)]
+pub(crate) struct SignedHeader {
+    pub(crate) header: HeaderName,
+    mac: Hmac<Sha256>,
+}
+
+impl fmt::Debug for SignedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the secret
+        f.debug_struct("SignedHeader").field("header", &self.header).finish_non_exhaustive()
+    }
+}
+
+impl SignedHeader {
+    pub(crate) fn new(header: HeaderName, secret: &[u8]) -> Self {
+        SignedHeader {
+            header,
+            mac: Hmac::new_from_slice(secret).expect("HMAC accepts any key length"),
+        }
+    }
+
+    /// Returns the address in the header value if its signature is valid.
+    pub(crate) fn verify(&self, value: &HeaderValue) -> Option<IpAddr> {
+        // the signature is hex, so the last `.` always separates it, even from IPv4 addresses
+        let (ip, signature) = value.to_str().ok()?.trim().rsplit_once('.')?;
+        let signature = decode_hex(signature)?;
+
+        let mut mac = self.mac.clone();
+        mac.update(ip.as_bytes());
+
+        // constant-time comparison
+        mac.verify_slice(&signature).ok()?;
+
+        IpAddr::from_str(ip).ok()
+    }
+}
+
+fn decode_hex(s: &str) -> Option<[u8; 32]> {
+    fn nibble(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let s = s.as_bytes();
+
+    if s.len() != 64 {
+        return None;
+    }
+
+    let mut out = [0u8; 32];
+
+    for (byte, pair) in out.iter_mut().zip(s.chunks_exact(2)) {
+        *byte = (nibble(pair[0])? << 4) | nibble(pair[1])?;
+    }
+
+    Some(out)
+}
+
+/// Signs the client address for a header read by
+/// [`RealIpLayer::with_signed_header`](super::RealIpLayer::with_signed_header),
+/// returning the header value as `<ip>.<signature>`.
+///
+/// The signature is the lowercase hex encoding of the HMAC-SHA256 of the address, as formatted by
+/// [`Display`](std::fmt::Display), with the given secret. This is mostly useful for tests, or edges
+/// written in Rust; other edges can produce the same value, such as with `openssl dgst -sha256 -hmac <secret>`.
+pub fn sign_client_ip(secret: &[u8], ip: IpAddr) -> String {
+    let ip = ip.to_string();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(ip.as_bytes());
+
+    let mut value = ip;
+    value.push('.');
+
+    for byte in mac.finalize().into_bytes() {
+        _ = write!(value, "{byte:02x}");
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"edge secret";
+
+    fn verify(value: &str) -> Option<IpAddr> {
+        let header = SignedHeader::new(HeaderName::from_static("x-client-ip-signed"), SECRET);
+        header.verify(&HeaderValue::from_str(value).unwrap())
+    }
+
+    #[test]
+    fn valid_signature() {
+        for ip in ["203.0.113.7", "2001:db8::17"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let value = sign_client_ip(SECRET, ip);
+
+            assert_eq!(verify(&value), Some(ip));
+            let (signed, signature) = value.rsplit_once('.').unwrap();
+            assert_eq!(
+                verify(&format!("{signed}.{}", signature.to_ascii_uppercase())),
+                Some(ip)
+            );
+            assert_eq!(verify(&format!(" {value} ")), Some(ip));
+        }
+
+        // as with `openssl dgst -sha256 -hmac 'edge secret'`
+        let value = "203.0.113.7.3bee4f08419eec3684e19550847f027d0cd87d875dadcc2bb5102888d1ff1cc2";
+        assert_eq!(sign_client_ip(SECRET, "203.0.113.7".parse().unwrap()), value);
+
+        // keys of any length are accepted
+        for secret in [&b""[..], &[0xAB; 1000]] {
+            let value = sign_client_ip(secret, "203.0.113.7".parse().unwrap());
+            let header = SignedHeader::new(HeaderName::from_static("x-client-ip-signed"), secret);
+            assert!(header.verify(&HeaderValue::from_str(&value).unwrap()).is_some());
+        }
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let value = sign_client_ip(SECRET, "203.0.113.7".parse().unwrap());
+        let (_, signature) = value.rsplit_once('.').unwrap();
+
+        // another address with the same signature
+        assert_eq!(verify(&format!("203.0.113.8.{signature}")), None);
+
+        // a flipped bit in the signature
+        let mut flipped = value.clone().into_bytes();
+        let last = flipped.last_mut().unwrap();
+        *last = if *last == b'0' { b'1' } else { b'0' };
+        assert_eq!(verify(std::str::from_utf8(&flipped).unwrap()), None);
+
+        // signed with another secret
+        assert_eq!(
+            verify(&sign_client_ip(b"other secret", "203.0.113.7".parse().unwrap())),
+            None
+        );
+
+        // truncated or not hex
+        assert_eq!(verify(&value[..value.len() - 2]), None);
+        assert_eq!(verify(&format!("{}zz", &value[..value.len() - 2])), None);
+    }
+
+    #[test]
+    fn missing_signature() {
+        assert_eq!(verify("203.0.113.7"), None);
+        assert_eq!(verify("2001:db8::17"), None);
+        assert_eq!(verify("203.0.113.7."), None);
+        assert_eq!(verify(""), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Storage backends for rate limiter state.
+//!
+//! The [`Store`] trait decouples the rate limiting layer from where the GCRA state is kept,
+//! with the in-memory [`MemoryStore`] being the default. Other backends, such as
+//! [`RedisStore`] (requires the `redis` feature), can share state across multiple nodes.
+//!
+//! [`SlidingWindowStore`] is an in-memory alternative to GCRA, using a sliding window log
+//! with the same [`Quota`] and layer API, at the cost of more memory per key, while [`FixedWindowStore`]
+//! uses fixed window counters for the least memory per key, at the cost of bursts around window boundaries.
+//! [`PartitionedStore`] keeps a separate in-memory table for each partition of the keys, such as each tenant,
+//! and [`ShardedStore`] splits the in-memory table into shards by key hash to reduce contention under heavy load.
+//! [`MultiQuotaStore`] enforces several quotas on each key at once, such as both a burst and a sustained limit,
+//! and [`LruStore`] holds a fixed maximum number of keys, evicting the least recently used.
+
+use std::{future::Future, time::Instant};
+
+use crate::{
+    gcra::{self, Capacity, Quota, RateLimitError},
+    RandomState, RouteWithKey,
+};
+
+mod fixed_window;
+mod lru;
+mod multi_quota;
+mod partitioned;
+mod sharded;
+mod sliding_window;
+
+pub use self::fixed_window::FixedWindowStore;
+pub use self::lru::LruStore;
+pub use self::multi_quota::MultiQuotaStore;
+pub use self::partitioned::{PartitionKey, PartitionedStore};
+pub use self::sharded::ShardedStore;
+pub use self::sliding_window::SlidingWindowStore;
+
+/// The default in-memory store used by [`RateLimitLayer`](crate::RateLimitLayer).
+pub type MemoryStore<K, H = RandomState> = gcra::RateLimiter<RouteWithKey<K>, H>;
+
+/// Storage backend for rate limiter state, performing the GCRA check-and-update for each request.
+///
+/// Implementations are used by the [`RateLimitLayer`](crate::RateLimitLayer) with
+/// [`RouteWithKey<K>`] keys, and must be safe to use concurrently.
+pub trait Store<K>: Send + Sync + 'static {
+    /// Error type for failures of the store itself, not rate limiting.
+    ///
+    /// For stores that cannot fail, use [`Infallible`](std::convert::Infallible).
+    type Error: Into<tower::BoxError>;
+
+    /// Perform a request costing `cost` cells at the given time, returning an error if the request is too soon,
+    /// or the remaining capacity for the key on success.
+    ///
+    /// See [`gcra::RateLimiter::req_n`] for how the cost is applied.
+    ///
+    /// `peek` must be called with the key if, and only if, the request is allowed.
+    ///
+    /// The outer `Result` is for failures of the store itself.
+    fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> impl Future<Output = Result<Result<Capacity, RateLimitError>, Self::Error>> + Send
+    where
+        F: FnOnce(&K) + Send;
+
+    /// Cleans up any entries that have not been accessed since the given time.
+    ///
+    /// The default implementation does nothing, for stores that expire entries on their own.
+    fn clean(&self, before: Instant) -> impl Future<Output = ()> + Send {
+        _ = before;
+        async {}
+    }
+}
+
+impl<K, H> Store<K> for gcra::RateLimiter<K, H>
+where
+    K: Eq + std::hash::Hash + Send + Sync + 'static,
+    H: std::hash::BuildHasher + Send + Sync + 'static,
+{
+    type Error = std::convert::Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        Ok(self.req_peek_key(key, quota, cost, now, peek).await)
+    }
+
+    async fn clean(&self, before: Instant) {
+        gcra::RateLimiter::clean(self, before).await
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    convert::Infallible,
+    hash::{BuildHasher, Hash},
+    num::NonZeroU64,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use scc::hash_map::HashMap;
+
+use super::Store;
+use crate::{
+    gcra::{Capacity, Quota, RateLimitError},
+    RandomState,
+};
+
+/// An in-memory store using fixed window counters instead of GCRA.
+///
+/// Each [`Quota`] is interpreted as allowing [`burst`](Quota::burst) requests per window of
+/// `emission_interval * burst`, with windows aligned to the Unix epoch. For example,
+/// `Quota::per_hour(1000).with_burst(1000)` allows 1000 requests per calendar hour (in UTC),
+/// with the count resetting on the hour.
+///
+/// Only the window start and count are stored per key, making this the cheapest algorithm
+/// for very large numbers of keys, but clients may make up to twice the limit in quick succession
+/// around a window boundary. For smoother limiting, use GCRA or the
+/// [`SlidingWindowStore`](super::SlidingWindowStore).
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{gcra::Quota, store::FixedWindowStore, RateLimitLayer};
+///
+/// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+///     RateLimitLayer::<(), _, _>::builder_with_store(FixedWindowStore::new())
+///         .with_default_quota(Quota::per_hour(1000).with_burst(1000))
+///         .default_handle_error(),
+/// );
+/// ```
+pub struct FixedWindowStore<K, H: BuildHasher = RandomState> {
+    start: Instant,
+
+    /// Unix time of `start`, in nanoseconds
+    epoch: u64,
+
+    gc_interval: u64,
+    last_gc: AtomicU64,
+    windows: HashMap<K, Window, H>,
+}
+
+/// Counter for the current window of a single key.
+struct Window {
+    /// Unix time the window started, in nanoseconds
+    start: u64,
+
+    /// Window length, in nanoseconds
+    len: u64,
+
+    /// Cells used within the window
+    count: u64,
+}
+
+impl Window {
+    #[inline]
+    fn end(&self) -> u64 {
+        self.start.saturating_add(self.len)
+    }
+
+    fn req(&mut self, quota: Quota, cost: u64, now: u64) -> Result<Capacity, RateLimitError> {
+        let cost = cost.max(1);
+        let max = quota.burst();
+        let len = (quota.emission_interval().as_nanos() as u64).saturating_mul(max).max(1);
+
+        // never step back into a previous window if the clock goes backwards, which would reset the count
+        let now = now.max(self.start);
+        let start = now - now % len;
+
+        // roll over to the current window
+        if start != self.start || len != self.len {
+            *self = Window { start, len, count: 0 };
+        }
+
+        let reset = self.end() - now;
+        let count = self.count.saturating_add(cost);
+
+        if count > max {
+            // now < start + len, so reset is non-zero unless the window end saturated
+            return Err(RateLimitError(NonZeroU64::new(reset).unwrap_or(NonZeroU64::MIN)));
+        }
+
+        self.count = count;
+
+        Ok(Capacity {
+            limit: max,
+            remaining: max - count,
+            reset: Duration::from_nanos(reset),
+        })
+    }
+}
+
+impl<K: Eq + Hash> FixedWindowStore<K> {
+    /// Constructs a new fixed window store, garbage collecting every 8192 requests.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_hasher(8192, RandomState::default())
+    }
+}
+
+impl<K: Eq + Hash> Default for FixedWindowStore<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher> FixedWindowStore<K, H> {
+    /// Constructs a new fixed window store with the given hasher and garbage collection interval,
+    /// which is in number of requests, not time. Use `u64::MAX` to disable garbage collection.
+    pub fn with_hasher(gc_interval: u64, hasher: H) -> Self {
+        let epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+
+        FixedWindowStore {
+            start: Instant::now(),
+            epoch: epoch.as_nanos() as u64,
+            gc_interval,
+            last_gc: AtomicU64::new(1),
+            windows: HashMap::with_hasher(hasher),
+        }
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Returns `true` if the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Unix time of the given instant, in nanoseconds.
+    #[inline]
+    fn unix(&self, ts: Instant) -> u64 {
+        self.epoch + ts.saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    // `is_multiple_of` would require Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    fn should_gc(&self) -> bool {
+        self.gc_interval != u64::MAX && 0 == self.last_gc.fetch_add(1, Ordering::Relaxed) % self.gc_interval
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the current window is full,
+    /// or the remaining capacity for the key on success.
+    pub async fn req(&self, key: K, quota: Quota, cost: u64, now: Instant) -> Result<Capacity, RateLimitError> {
+        self.req_peek_key(key, quota, cost, now, |_| {}).await
+    }
+
+    async fn req_peek_key<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Capacity, RateLimitError>
+    where
+        F: FnOnce(&K),
+    {
+        let now = self.unix(now);
+
+        if self.should_gc() {
+            self.windows.retain_async(move |_, w| w.end() > now).await;
+        }
+
+        let mut entry = self.windows.entry_async(key).await.or_insert_with(|| Window {
+            start: 0,
+            len: 0,
+            count: 0,
+        });
+
+        let res = entry.get_mut().req(quota, cost, now);
+
+        if res.is_ok() {
+            peek(entry.key());
+        }
+
+        res
+    }
+
+    /// Resets the rate limit for the given key, returning `true` if the key was found.
+    pub async fn reset<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.windows.remove_async(key).await.is_some()
+    }
+
+    /// Resets the rate limit for every key.
+    pub async fn reset_all(&self) {
+        self.windows.clear_async().await;
+    }
+
+    /// Cleans up any keys whose window has ended at the given time.
+    pub async fn clean(&self, before: Instant) {
+        let before = self.unix(before);
+        self.windows.retain_async(move |_, w| w.end() > before).await;
+        self.last_gc.store(1, Ordering::Relaxed); // manual reset
+    }
+}
+
+impl<K, H> Store<K> for FixedWindowStore<K, H>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    H: BuildHasher + Send + Sync + 'static,
+{
+    type Error = Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        Ok(self.req_peek_key(key, quota, cost, now, peek).await)
+    }
+
+    async fn clean(&self, before: Instant) {
+        FixedWindowStore::clean(self, before).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn window() -> Window {
+        Window {
+            start: 0,
+            len: 0,
+            count: 0,
+        }
+    }
+
+    #[test]
+    fn resets_at_window_boundary() {
+        // 5 requests per 500ms window, aligned to the epoch
+        let quota = Quota::per_second(10).with_burst(5);
+        let mut w = window();
+        let now = 1_000 * SECOND + SECOND / 10;
+
+        for remaining in [4, 3, 2, 1, 0] {
+            let capacity = w.req(quota, 1, now).unwrap();
+            assert_eq!(
+                (capacity.remaining, capacity.reset),
+                (remaining, Duration::from_millis(400))
+            );
+        }
+
+        let e = w.req(quota, 1, now).unwrap_err();
+        assert_eq!(e.as_duration(), Duration::from_millis(400));
+
+        let end = 1_000 * SECOND + SECOND / 2;
+        let e = w.req(quota, 1, end - 1).unwrap_err();
+        assert_eq!(e.as_duration(), Duration::from_nanos(1));
+
+        // the count resets all at once on the boundary
+        let capacity = w.req(quota, 1, end).unwrap();
+        assert_eq!((capacity.remaining, capacity.reset), (4, Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn resets_when_quota_changes() {
+        let mut w = window();
+        let now = 1_000 * SECOND;
+
+        let quota = Quota::per_second(10).with_burst(5);
+        while w.req(quota, 1, now).is_ok() {}
+
+        // a different window length starts a new window
+        let quota = Quota::per_second(10).with_burst(10);
+        assert_eq!(w.req(quota, 1, now).unwrap().remaining, 9);
+
+        // the same window length keeps the count
+        let quota = Quota::per_second(20).with_burst(20);
+        assert_eq!(w.req(quota, 1, now).unwrap().remaining, 18);
+    }
+
+    #[test]
+    fn backwards_clock_keeps_count() {
+        let quota = Quota::per_second(10).with_burst(5);
+        let mut w = window();
+        let now = 1_000 * SECOND + SECOND / 2;
+
+        while w.req(quota, 1, now).is_ok() {}
+
+        // stepping back into a previous window neither resets the count nor lengthens the wait
+        for back in [1, SECOND / 10, SECOND, now] {
+            let e = w.req(quota, 1, now - back).unwrap_err();
+            assert_eq!(e.as_duration(), Duration::from_millis(500));
+        }
+
+        assert_eq!(w.req(quota, 1, now + SECOND / 2).unwrap().remaining, 4);
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    convert::Infallible,
+    hash::{BuildHasher, Hash},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use super::Store;
+use crate::{
+    gcra::{Capacity, Gcra, Quota, RateLimitError},
+    RandomState,
+};
+
+/// An in-memory GCRA store holding at most a fixed number of keys, evicting the least recently used key
+/// to make room for each new one.
+///
+/// Unlike [`RateLimiter::with_max_entries`](crate::gcra::RateLimiter::with_max_entries), which evicts in
+/// batches and relies on garbage collection, the number of keys never exceeds the limit, and each eviction
+/// takes constant time, giving a predictable memory bound for keys with unbounded cardinality, such as
+/// IP addresses. Every request, allowed or not, marks its key as most recently used.
+///
+/// # Security
+///
+/// Evicting a key resets its rate limit, so a throttled client can regain its full burst if enough other
+/// keys are seen in the meantime, such as by an attacker cycling through addresses. Set the limit well
+/// above the number of keys expected within the longest recovery time of the quotas in use.
+///
+/// # Performance
+///
+/// The table and recency list are behind a single lock, so every request is serialized,
+/// which may become a bottleneck at very high request rates. Keys are stored twice, so must be [`Clone`].
+///
+/// ```rust
+/// use std::time::Instant;
+/// use axum_gcra::{gcra::Quota, store::LruStore};
+///
+/// let store = LruStore::<&str>::new(2);
+/// let quota = Quota::per_second(1);
+/// let now = Instant::now();
+///
+/// for key in ["a", "b", "a", "c"] {
+///     _ = store.req(key, quota, 1, now);
+/// }
+///
+/// // "b" was the least recently used when "c" was inserted
+/// assert_eq!(store.len(), 2);
+/// assert!(!store.reset("b"));
+/// assert!(store.reset("a"));
+/// ```
+///
+/// Used with the layer:
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{gcra::Quota, real_ip::RealIp, store::LruStore, RateLimitLayer};
+///
+/// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+///     RateLimitLayer::<RealIp, _, _>::builder_with_store(LruStore::new(100_000))
+///         .with_default_quota(Quota::per_second(10))
+///         .default_handle_error(),
+/// );
+/// ```
+pub struct LruStore<K, H = RandomState> {
+    start: Instant,
+    max_keys: usize,
+    lru: Mutex<Lru<K, H>>,
+}
+
+/// Marks the end of the recency list.
+const NIL: usize = usize::MAX;
+
+struct Slot<K> {
+    key: K,
+    tat: u64,
+    prev: usize,
+    next: usize,
+}
+
+/// Table of keys indexing into slots, linked from most to least recently used.
+struct Lru<K, H> {
+    index: HashMap<K, usize, H>,
+    slots: Vec<Slot<K>>,
+    head: usize,
+    tail: usize,
+}
+
+impl<K: Eq + Hash, H: BuildHasher> Lru<K, H> {
+    fn unlink(&mut self, i: usize) {
+        let Slot { prev, next, .. } = self.slots[i];
+
+        match prev {
+            NIL => self.head = next,
+            prev => self.slots[prev].next = next,
+        }
+
+        match next {
+            NIL => self.tail = prev,
+            next => self.slots[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.slots[i].prev = NIL;
+        self.slots[i].next = self.head;
+
+        match self.head {
+            NIL => self.tail = i,
+            head => self.slots[head].prev = i,
+        }
+
+        self.head = i;
+    }
+
+    fn touch(&mut self, i: usize) {
+        if self.head != i {
+            self.unlink(i);
+            self.push_front(i);
+        }
+    }
+
+    /// Removes the slot, moving the last slot into its place.
+    fn remove(&mut self, i: usize) {
+        self.unlink(i);
+
+        let slot = self.slots.swap_remove(i);
+        self.index.remove(&slot.key);
+
+        if i < self.slots.len() {
+            // relink the slot moved from the end
+            let Slot { prev, next, .. } = self.slots[i];
+
+            match prev {
+                NIL => self.head = i,
+                prev => self.slots[prev].next = i,
+            }
+
+            match next {
+                NIL => self.tail = i,
+                next => self.slots[next].prev = i,
+            }
+
+            if let Some(index) = self.index.get_mut(&self.slots[i].key) {
+                *index = i;
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash> LruStore<K> {
+    /// Constructs a new store holding at most `max_keys` keys. A limit of zero is treated as one.
+    #[must_use]
+    pub fn new(max_keys: usize) -> Self {
+        Self::with_hasher(max_keys, RandomState::default())
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher> LruStore<K, H> {
+    /// Constructs a new store holding at most `max_keys` keys, with the given hasher.
+    /// A limit of zero is treated as one.
+    pub fn with_hasher(max_keys: usize, hasher: H) -> Self {
+        LruStore {
+            start: Instant::now(),
+            max_keys: max_keys.max(1),
+            lru: Mutex::new(Lru {
+                index: HashMap::with_hasher(hasher),
+                slots: Vec::new(),
+                head: NIL,
+                tail: NIL,
+            }),
+        }
+    }
+
+    /// Returns the maximum number of keys in the store.
+    #[inline]
+    pub fn max_keys(&self) -> usize {
+        self.max_keys
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        self.lock().slots.len()
+    }
+
+    /// Returns `true` if the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.lock().slots.is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru<K, H>> {
+        // the list is always consistent between operations, so a panic elsewhere can't corrupt it
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    fn relative(&self, ts: Instant) -> u64 {
+        ts.saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the request is too soon,
+    /// or the remaining capacity for the key on success.
+    ///
+    /// A new key is only inserted if its request is allowed, evicting the least recently used key if full.
+    pub fn req(&self, key: K, quota: Quota, cost: u64, now: Instant) -> Result<Capacity, RateLimitError>
+    where
+        K: Clone,
+    {
+        self.req_peek_key(key, quota, cost, now, |_| {})
+    }
+
+    fn req_peek_key<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Capacity, RateLimitError>
+    where
+        K: Clone,
+        F: FnOnce(&K),
+    {
+        let now = self.relative(now);
+        let mut lru = self.lock();
+
+        if let Some(&i) = lru.index.get(&key) {
+            lru.touch(i);
+
+            let slot = &mut lru.slots[i];
+            slot.tat = Gcra::decide(slot.tat, now, quota, cost)?;
+            peek(&slot.key);
+
+            return Ok(Capacity::new(slot.tat, now, quota));
+        }
+
+        // as for the first request of a single GCRA
+        let first = now.saturating_add(quota.emission_interval().as_nanos() as u64);
+        let tat = Gcra::decide(first, now, quota, cost)?;
+
+        if lru.slots.len() >= self.max_keys {
+            let tail = lru.tail;
+            lru.remove(tail);
+        }
+
+        let i = lru.slots.len();
+
+        lru.slots.push(Slot {
+            key: key.clone(),
+            tat,
+            prev: NIL,
+            next: NIL,
+        });
+        lru.index.insert(key, i);
+        lru.push_front(i);
+
+        peek(&lru.slots[i].key);
+
+        Ok(Capacity::new(tat, now, quota))
+    }
+
+    /// Resets the rate limit for the given key, returning `true` if the key was found.
+    pub fn reset<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut lru = self.lock();
+
+        match lru.index.get(key) {
+            Some(&i) => {
+                lru.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resets the rate limit for every key.
+    pub fn reset_all(&self) {
+        let mut lru = self.lock();
+
+        lru.index.clear();
+        lru.slots.clear();
+        lru.head = NIL;
+        lru.tail = NIL;
+    }
+
+    /// Cleans up any keys that have fully recovered at the given time.
+    pub fn clean(&self, before: Instant) {
+        let before = self.relative(before);
+        let mut lru = self.lock();
+
+        // from the end, so the slots moved into place have already been checked
+        for i in (0..lru.slots.len()).rev() {
+            if lru.slots[i].tat < before {
+                lru.remove(i);
+            }
+        }
+    }
+}
+
+impl<K, H> Store<K> for LruStore<K, H>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    H: BuildHasher + Send + Sync + 'static,
+{
+    type Error = Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        Ok(self.req_peek_key(key, quota, cost, now, peek))
+    }
+
+    async fn clean(&self, before: Instant) {
+        LruStore::clean(self, before)
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    borrow::Borrow,
+    convert::Infallible,
+    hash::{BuildHasher, Hash},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use scc::hash_map::HashMap;
+
+use super::Store;
+use crate::{
+    gcra::{Capacity, MultiQuota, Quota, RateLimitError},
+    RandomState,
+};
+
+/// An in-memory GCRA store enforcing several quotas on each key, such as a short burst limit
+/// and a long sustained limit, rejecting requests that exceed any of them.
+///
+/// Each key holds a GCRA state for the quota of the request, as set on the layer, followed by one for
+/// each quota of the [`MultiQuota`]. A request is only counted if every quota allows it, and is otherwise
+/// rejected with the longest wait among the exceeded quotas. The remaining capacity of allowed requests
+/// is that of the quota with the least remaining.
+///
+/// ```rust
+/// use std::time::{Duration, Instant};
+/// use axum_gcra::{gcra::{MultiQuota, Quota}, store::MultiQuotaStore};
+///
+/// # #[tokio::main(flavor = "current_thread")] async fn main() {
+/// // bursts of up to 10 requests per second, but at most 20 per hour
+/// let store = MultiQuotaStore::<&str>::new(MultiQuota::new().with_quota(Quota::per_hour(20).with_burst(20)));
+/// let burst = Quota::per_second(10).with_burst(10);
+/// let start = Instant::now();
+///
+/// for i in 0..11 {
+///     let res = store.req("client", burst, 1, start).await;
+///     assert_eq!(res.is_ok(), i < 10); // limited by the burst
+/// }
+///
+/// let later = start + Duration::from_secs(1);
+///
+/// for _ in 0..10 {
+///     assert!(store.req("client", burst, 1, later).await.is_ok());
+/// }
+///
+/// // the burst has recovered, but the hourly limit is exhausted
+/// let err = store.req("client", burst, 1, later + Duration::from_secs(1)).await.unwrap_err();
+/// assert!(err.as_duration() > Duration::from_secs(60));
+/// # }
+/// ```
+///
+/// Used with the layer, the quota of each route is the first of the quotas:
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{gcra::{MultiQuota, Quota}, real_ip::RealIp, store::MultiQuotaStore, RateLimitLayer};
+///
+/// let sustained = MultiQuota::new().with_quota(Quota::per_hour(1000).with_burst(1000));
+///
+/// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+///     RateLimitLayer::<RealIp, _, _>::builder_with_store(MultiQuotaStore::new(sustained))
+///         .with_default_quota(Quota::per_second(10).with_burst(10))
+///         .default_handle_error(),
+/// );
+/// ```
+pub struct MultiQuotaStore<K, H: BuildHasher = RandomState> {
+    start: Instant,
+    quotas: MultiQuota,
+    gc_interval: u64,
+    last_gc: AtomicU64,
+    limits: HashMap<K, Box<[u64]>, H>,
+}
+
+impl<K: Eq + Hash> MultiQuotaStore<K> {
+    /// Constructs a new store enforcing the given quotas in addition to the quota of each request,
+    /// garbage collecting every 8192 requests.
+    #[must_use]
+    pub fn new(quotas: MultiQuota) -> Self {
+        Self::with_hasher(quotas, 8192, RandomState::default())
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher> MultiQuotaStore<K, H> {
+    /// Constructs a new store with the given quotas, hasher and garbage collection interval,
+    /// which is in number of requests, not time. Use `u64::MAX` to disable garbage collection.
+    pub fn with_hasher(quotas: MultiQuota, gc_interval: u64, hasher: H) -> Self {
+        MultiQuotaStore {
+            start: Instant::now(),
+            quotas,
+            gc_interval,
+            last_gc: AtomicU64::new(1),
+            limits: HashMap::with_hasher(hasher),
+        }
+    }
+
+    /// Returns the additional quotas enforced by the store.
+    #[inline]
+    pub fn quotas(&self) -> &MultiQuota {
+        &self.quotas
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        self.limits.len()
+    }
+
+    /// Returns `true` if the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    #[inline]
+    fn relative(&self, ts: Instant) -> u64 {
+        ts.saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    // `is_multiple_of` would require Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    fn should_gc(&self) -> bool {
+        self.gc_interval != u64::MAX && 0 == self.last_gc.fetch_add(1, Ordering::Relaxed) % self.gc_interval
+    }
+
+    /// Perform a request costing `cost` cells against the given quota and every quota of the store,
+    /// returning an error if any of them would be exceeded, or the least remaining capacity on success.
+    pub async fn req(&self, key: K, quota: Quota, cost: u64, now: Instant) -> Result<Capacity, RateLimitError> {
+        self.req_peek_key(key, quota, cost, now, |_| {}).await
+    }
+
+    async fn req_peek_key<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Capacity, RateLimitError>
+    where
+        F: FnOnce(&K),
+    {
+        let now = self.relative(now);
+
+        if self.should_gc() {
+            self.limits.retain_async(move |_, tats| tats.iter().any(|&tat| tat >= now)).await;
+        }
+
+        // updating every quota at once needs exclusive access to the entry
+        let mut entry = self.limits.entry_async(key).await.or_insert_with(|| {
+            // as for the first request of a single GCRA
+            let first = |quota: Quota| now.saturating_add(quota.emission_interval().as_nanos() as u64);
+            self.quotas.with_primary(quota).map(first).collect()
+        });
+
+        let res = self.quotas.decide(quota, entry.get_mut(), cost, now);
+
+        if res.is_ok() {
+            peek(entry.key());
+        }
+
+        res
+    }
+
+    /// Resets the rate limit for the given key on every quota, returning `true` if the key was found.
+    pub async fn reset<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.limits.remove_async(key).await.is_some()
+    }
+
+    /// Resets the rate limit for every key.
+    pub async fn reset_all(&self) {
+        self.limits.clear_async().await;
+    }
+
+    /// Cleans up any keys that have recovered on every quota at the given time.
+    pub async fn clean(&self, before: Instant) {
+        let before = self.relative(before);
+        self.limits.retain_async(move |_, tats| tats.iter().any(|&tat| tat >= before)).await;
+        self.last_gc.store(1, Ordering::Relaxed); // manual reset
+    }
+}
+
+impl<K, H> Store<K> for MultiQuotaStore<K, H>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    H: BuildHasher + Send + Sync + 'static,
+{
+    type Error = Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        Ok(self.req_peek_key(key, quota, cost, now, peek).await)
+    }
+
+    async fn clean(&self, before: Instant) {
+        MultiQuotaStore::clean(self, before).await
+    }
+}
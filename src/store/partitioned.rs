@@ -0,0 +1,223 @@
+use std::{
+    convert::Infallible,
+    hash::{BuildHasher, Hash},
+    sync::Arc,
+    time::Instant,
+};
+
+use scc::hash_map::HashMap;
+
+use super::Store;
+use crate::{
+    gcra::{Capacity, Quota, RateLimitError, RateLimiter},
+    RandomState, RouteWithKey,
+};
+
+/// Keys that can be split into a partition, such as a tenant id, for a [`PartitionedStore`].
+///
+/// Implemented for `(P, K)` tuples, with the first element as the partition, and for
+/// [`RouteWithKey`]s of such keys, so a layer keyed by `(TenantId, RealIp)` partitions by tenant.
+pub trait PartitionKey {
+    /// The partition the key belongs to.
+    type Partition: Eq + Hash + Clone + Send + Sync + 'static;
+
+    /// Returns the partition the key belongs to.
+    fn partition(&self) -> &Self::Partition;
+}
+
+impl<P, K> PartitionKey for (P, K)
+where
+    P: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    type Partition = P;
+
+    #[inline]
+    fn partition(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<K: PartitionKey> PartitionKey for RouteWithKey<K> {
+    type Partition = K::Partition;
+
+    #[inline]
+    fn partition(&self) -> &Self::Partition {
+        self.key().partition()
+    }
+}
+
+/// An in-memory GCRA store with a separate table for each partition of the keys, such as for each tenant.
+///
+/// Each partition behaves as its own [`MemoryStore`](super::MemoryStore), so a partition with many keys
+/// does not slow down lookups in the others, can be limited in size with [`PartitionedStore::with_max_entries`],
+/// and can be cleared independently with [`PartitionedStore::reset_partition`].
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{gcra::Quota, real_ip::RealIp, store::PartitionedStore, RateLimitLayer};
+///
+/// # type TenantId = ();
+/// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+///     RateLimitLayer::<(TenantId, RealIp), _, _>::builder_with_store(PartitionedStore::new())
+///         .with_default_quota(Quota::per_second(10))
+///         .default_handle_error(),
+/// );
+/// ```
+pub struct PartitionedStore<K: PartitionKey + Eq + Hash, H: BuildHasher = RandomState> {
+    gc_interval: u64,
+    max_entries: usize,
+    hasher: H,
+    partitions: HashMap<K::Partition, Arc<RateLimiter<K, H>>, H>,
+}
+
+impl<K: PartitionKey + Eq + Hash> PartitionedStore<K> {
+    /// Constructs a new partitioned store, garbage collecting each partition every 8192 requests to it.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_hasher(8192, RandomState::default())
+    }
+}
+
+impl<K: PartitionKey + Eq + Hash> Default for PartitionedStore<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: PartitionKey + Eq + Hash, H: BuildHasher + Clone> PartitionedStore<K, H> {
+    /// Constructs a new partitioned store with the given hasher and garbage collection interval for each
+    /// partition, which is in number of requests, not time. Use `u64::MAX` to disable garbage collection.
+    pub fn with_hasher(gc_interval: u64, hasher: H) -> Self {
+        PartitionedStore {
+            gc_interval,
+            max_entries: usize::MAX,
+            partitions: HashMap::with_hasher(hasher.clone()),
+            hasher,
+        }
+    }
+
+    /// Sets the maximum number of entries in each partition, as by [`RateLimiter::with_max_entries`].
+    ///
+    /// This only applies to partitions created afterwards. The default is unlimited.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Returns the number of partitions in the store.
+    pub fn len(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Returns `true` if the store has no partitions.
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty()
+    }
+
+    /// Returns the table for the given partition, if it exists, such as to inspect or reset individual keys.
+    pub async fn partition(&self, partition: &K::Partition) -> Option<Arc<RateLimiter<K, H>>> {
+        self.partitions.read_async(partition, |_, limiter| limiter.clone()).await
+    }
+
+    /// Resets the rate limit for every key in the given partition, returning `true` if the partition was found.
+    ///
+    /// Other partitions are unaffected.
+    pub async fn reset_partition(&self, partition: &K::Partition) -> bool {
+        self.partitions.remove_async(partition).await.is_some()
+    }
+
+    async fn get_or_insert(&self, partition: &K::Partition) -> Arc<RateLimiter<K, H>> {
+        if let Some(limiter) = self.partition(partition).await {
+            return limiter;
+        }
+
+        let entry = self.partitions.entry_async(partition.clone()).await.or_insert_with(|| {
+            let mut limiter = RateLimiter::new(self.gc_interval, self.hasher.clone());
+            limiter.set_max_entries(self.max_entries);
+            Arc::new(limiter)
+        });
+
+        entry.get().clone()
+    }
+
+    /// Cleans up any entries that have not been accessed since the given time,
+    /// removing partitions left empty.
+    pub async fn clean(&self, before: Instant) {
+        let mut limiters = Vec::new();
+        self.partitions.scan_async(|_, limiter| limiters.push(limiter.clone())).await;
+
+        for limiter in limiters {
+            limiter.clean(before).await;
+        }
+
+        // partitions still held by a request in progress are kept, so its entry is not lost
+        self.partitions
+            .retain_async(|_, limiter| Arc::strong_count(limiter) > 1 || !limiter.is_empty())
+            .await;
+    }
+}
+
+impl<K, H> Store<K> for PartitionedStore<K, H>
+where
+    K: PartitionKey + Eq + Hash + Send + Sync + 'static,
+    H: BuildHasher + Clone + Send + Sync + 'static,
+{
+    type Error = Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        let limiter = self.get_or_insert(key.partition()).await;
+
+        Ok(limiter.req_peek_key(key, quota, cost, now, peek).await)
+    }
+
+    async fn clean(&self, before: Instant) {
+        PartitionedStore::clean(self, before).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether a request for `key` in `tenant` is allowed, limited to one request per hour.
+    async fn allowed(store: &PartitionedStore<(u32, u32)>, tenant: u32, key: u32) -> bool {
+        let res = store.req((tenant, key), Quota::per_hour(1), 1, Instant::now(), |_| {}).await;
+        matches!(res, Ok(Ok(_)))
+    }
+
+    #[tokio::test]
+    async fn reset_partition_leaves_others() {
+        let store = PartitionedStore::new();
+
+        for (tenant, key) in [(1, 1), (1, 2), (2, 1)] {
+            assert!(allowed(&store, tenant, key).await);
+            assert!(!allowed(&store, tenant, key).await);
+        }
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.partition(&1).await.unwrap().len(), 2);
+        assert_eq!(store.partition(&2).await.unwrap().len(), 1);
+
+        // every key of the reset tenant gets a fresh burst
+        assert!(store.reset_partition(&1).await);
+        assert!(!store.reset_partition(&3).await);
+        assert!(store.partition(&1).await.is_none());
+        assert!(allowed(&store, 1, 1).await);
+        assert!(allowed(&store, 1, 2).await);
+
+        // while the same key of another tenant is still throttled
+        assert!(!allowed(&store, 2, 1).await);
+        assert_eq!(store.partition(&2).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    num::NonZeroU64,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use ::redis::{aio::ConnectionManager, RedisError, Script};
+use sha2::{Digest, Sha256};
+
+use super::Store;
+use crate::gcra::{Capacity, Quota, RateLimitError};
+
+/// GCRA check-and-update, computing the time on the Redis server to avoid clock skew
+/// between application nodes. All times are in microseconds, as Lua numbers are doubles.
+///
+/// `KEYS[1]` is the key, `ARGV[1]` is the emission interval, `ARGV[2]` is the burst tolerance
+/// and `ARGV[3]` is the number of cells the request costs.
+///
+/// Returns `{0, wait}` if the request is too soon, or `{1, delay}` with the new delay until the theoretical
+/// arrival time if it was allowed. Keys expire once their theoretical arrival time has passed.
+const GCRA_SCRIPT: &str = r#"
+local t = tonumber(ARGV[1])
+local tau = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+
+local tat = tonumber(redis.call('GET', KEYS[1]))
+if not tat then
+    tat = now + t
+end
+
+local next = tat + (cost - 1) * t - tau
+if now < next then
+    return {0, next - now}
+end
+
+tat = math.max(now, tat) + cost * t
+redis.call('SET', KEYS[1], string.format('%d', tat), 'PX', math.max(1, math.ceil((tat - now) / 1000)))
+return {1, tat - now}
+"#;
+
+/// Adds a penalty to an existing key, returning `1` if the key was found.
+///
+/// `KEYS[1]` is the key and `ARGV[1]` is the penalty, in microseconds.
+const PENALIZE_SCRIPT: &str = r#"
+local tat = tonumber(redis.call('GET', KEYS[1]))
+if not tat then
+    return 0
+end
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+
+tat = tat + tonumber(ARGV[1])
+redis.call('SET', KEYS[1], string.format('%d', tat), 'PX', math.max(1, math.ceil((tat - now) / 1000)))
+return 1
+"#;
+
+fn gcra_script() -> &'static Script {
+    static SCRIPT: OnceLock<Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| Script::new(GCRA_SCRIPT))
+}
+
+fn penalize_script() -> &'static Script {
+    static SCRIPT: OnceLock<Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| Script::new(PENALIZE_SCRIPT))
+}
+
+/// A rate limiter backed by Redis, allowing the rate limit to be shared across multiple nodes.
+///
+/// The GCRA check-and-update is performed atomically by a Lua script on the Redis server,
+/// using the server's clock, so application nodes do not need synchronized clocks.
+///
+/// Keys are identified in Redis by the first 128 bits of the SHA-256 of the key's [`Hash`] implementation,
+/// prefixed with the configured [prefix](RedisStore::with_prefix). Integers are hashed in little-endian,
+/// with `usize` as 64 bits, so the same key maps to the same entry on every target and version of this crate,
+/// as long as its [`Hash`] implementation writes the same data. The implementations for standard types
+/// such as [`IpAddr`](std::net::IpAddr) and [`String`] are not formally guaranteed to, so use
+/// [`RedisStore::with_key_encoder`] to hash an explicit encoding of the key instead.
+///
+/// Entries expire on their own once their rate limit has fully replenished,
+/// so no garbage collection is required.
+///
+/// The script computes in whole microseconds, so emission intervals and burst tolerances are rounded up
+/// to the next microsecond, never allowing more than the quota, and [unlimited](Quota::unlimited) quotas
+/// are allowed without contacting Redis.
+///
+/// Requires the `redis` cargo feature.
+pub struct RedisStore<K> {
+    conn: ConnectionManager,
+    prefix: Cow<'static, str>,
+    encode: Option<fn(&K, &mut Vec<u8>)>,
+    _key: PhantomData<fn(&K)>,
+}
+
+impl<K> Clone for RedisStore<K> {
+    fn clone(&self) -> Self {
+        RedisStore {
+            conn: self.conn.clone(),
+            prefix: self.prefix.clone(),
+            encode: self.encode,
+            _key: PhantomData,
+        }
+    }
+}
+
+#[inline]
+fn as_micros(d: Duration) -> u64 {
+    d.as_micros() as u64
+}
+
+/// Emission interval and burst tolerance of the quota in microseconds, rounded up,
+/// or `None` for an unlimited quota.
+fn script_args(quota: Quota) -> Option<(u64, u64)> {
+    let t = quota.emission_interval().as_nanos() as u64;
+
+    if t == 0 {
+        return None;
+    }
+
+    Some((t.div_ceil(1000), t.saturating_mul(quota.burst()).div_ceil(1000)))
+}
+
+/// Feeds a [`Hash`] implementation into SHA-256, encoding integers independently of the target.
+struct StableHasher(Sha256);
+
+macro_rules! write_le {
+    ($($method:ident($ty:ty)),*) => {$(
+        #[inline]
+        fn $method(&mut self, n: $ty) {
+            self.0.update(n.to_le_bytes());
+        }
+    )*};
+}
+
+impl Hasher for StableHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    write_le!(
+        write_u8(u8),
+        write_u16(u16),
+        write_u32(u32),
+        write_u64(u64),
+        write_u128(u128),
+        write_i8(i8),
+        write_i16(i16),
+        write_i32(i32),
+        write_i64(i64),
+        write_i128(i128)
+    );
+
+    #[inline]
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    #[inline]
+    fn write_isize(&mut self, n: isize) {
+        self.write_i64(n as i64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.0.clone().finalize()[..8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// Returns the Redis key for the given key, as the prefix followed by 32 hex digits.
+fn redis_key<K: Hash>(prefix: &str, key: &K, encode: Option<fn(&K, &mut Vec<u8>)>) -> String {
+    let mut hasher = StableHasher(Sha256::new());
+
+    match encode {
+        Some(encode) => {
+            let mut bytes = Vec::new();
+            encode(key, &mut bytes);
+            hasher.write(&bytes);
+        }
+        None => key.hash(&mut hasher),
+    }
+
+    let mut out = String::with_capacity(prefix.len() + 32);
+    out.push_str(prefix);
+
+    for byte in &hasher.0.finalize()[..16] {
+        _ = write!(out, "{byte:02x}");
+    }
+
+    out
+}
+
+impl<K: Hash> RedisStore<K> {
+    /// Constructs a new Redis-backed rate limiter using the given connection,
+    /// with the default key prefix of `"axum_gcra:"`.
+    #[must_use]
+    pub fn new(conn: ConnectionManager) -> Self {
+        RedisStore {
+            conn,
+            prefix: Cow::Borrowed("axum_gcra:"),
+            encode: None,
+            _key: PhantomData,
+        }
+    }
+
+    /// Set the prefix prepended to all keys stored in Redis.
+    #[must_use]
+    pub fn with_prefix(mut self, prefix: impl Into<Cow<'static, str>>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set the encoding of keys hashed to identify them in Redis, instead of their [`Hash`] implementation,
+    /// such as to guarantee that nodes built with different versions of Rust share the same entries.
+    ///
+    /// The encoding should be unambiguous, so that distinct keys never encode to the same bytes.
+    ///
+    /// ```rust,no_run
+    /// use axum_gcra::{real_ip::RealIp, store::RedisStore};
+    /// # async fn store(conn: redis::aio::ConnectionManager) {
+    ///
+    /// let store = RedisStore::<RealIp>::new(conn).with_key_encoder(|ip, out| match ip.0 {
+    ///     std::net::IpAddr::V4(v4) => out.extend(v4.octets()),
+    ///     std::net::IpAddr::V6(v6) => out.extend(v6.octets()),
+    /// });
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_key_encoder(mut self, encode: fn(&K, &mut Vec<u8>)) -> Self {
+        self.encode = Some(encode);
+        self
+    }
+
+    fn redis_key(&self, key: &K) -> String {
+        redis_key(&self.prefix, key, self.encode)
+    }
+
+    /// Perform a request, returning an error if the request is too soon,
+    /// or the remaining capacity for the key on success.
+    ///
+    /// The outer `Result` is for errors communicating with Redis.
+    pub async fn req(&self, key: &K, quota: Quota) -> Result<Result<Capacity, RateLimitError>, RedisError> {
+        self.req_n(key, quota, 1).await
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the request is too soon,
+    /// or the remaining capacity for the key on success.
+    ///
+    /// See [`gcra::RateLimiter::req_n`](crate::gcra::RateLimiter::req_n) for more information.
+    pub async fn req_n(
+        &self,
+        key: &K,
+        quota: Quota,
+        cost: u64,
+    ) -> Result<Result<Capacity, RateLimitError>, RedisError> {
+        let Some((t, tau)) = script_args(quota) else {
+            return Ok(Ok(Capacity::new(0, 0, quota)));
+        };
+
+        let (allowed, delay): (u8, u64) = gcra_script()
+            .key(self.redis_key(key))
+            .arg(t)
+            .arg(tau)
+            .arg(cost.max(1))
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+
+        let delay = delay.saturating_mul(1000);
+
+        Ok(match NonZeroU64::new(delay) {
+            Some(wait) if allowed == 0 => Err(RateLimitError(wait)),
+            _ => Ok(Capacity::new(delay, 0, quota)),
+        })
+    }
+
+    /// Penalizes the given key by the given amount of time,
+    /// returning `true` if the key was found.
+    ///
+    /// See [`gcra::RateLimiter::penalize`](crate::gcra::RateLimiter::penalize) for more information.
+    pub async fn penalize(&self, key: &K, penalty: Duration) -> Result<bool, RedisError> {
+        let found: u8 = penalize_script()
+            .key(self.redis_key(key))
+            .arg(as_micros(penalty))
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+
+        Ok(found != 0)
+    }
+
+    /// Resets the rate limit for the given key, returning `true` if the key was found.
+    pub async fn reset(&self, key: &K) -> Result<bool, RedisError> {
+        let removed: u64 =
+            ::redis::cmd("DEL").arg(self.redis_key(key)).query_async(&mut self.conn.clone()).await?;
+
+        Ok(removed != 0)
+    }
+
+    /// Resets the rate limit for every key under the configured [prefix](RedisStore::with_prefix),
+    /// returning the number of keys removed.
+    ///
+    /// Keys are found with `SCAN`, so this does not block the server, but keys written while
+    /// the scan is in progress may or may not be removed.
+    pub async fn reset_all(&self) -> Result<u64, RedisError> {
+        // escape glob characters in the prefix, then match the 32 hex digits of the hash exactly
+        let mut pattern = String::with_capacity(self.prefix.len() + 32);
+        for c in self.prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push_str(&"?".repeat(32));
+
+        let mut conn = self.conn.clone();
+        let mut cursor = 0u64;
+        let mut removed = 0;
+
+        loop {
+            let (next, keys): (u64, Vec<String>) = ::redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut conn)
+                .await?;
+
+            if !keys.is_empty() {
+                let n: u64 = ::redis::cmd("UNLINK").arg(keys).query_async(&mut conn).await?;
+                removed += n;
+            }
+
+            if next == 0 {
+                return Ok(removed);
+            }
+
+            cursor = next;
+        }
+    }
+}
+
+impl<K> Store<K> for RedisStore<K>
+where
+    K: Hash + Send + Sync + 'static,
+{
+    type Error = RedisError;
+
+    /// Note that the time is ignored, using the Redis server's clock instead.
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        _now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        let res = RedisStore::req_n(self, &key, quota, cost).await?;
+
+        if res.is_ok() {
+            peek(&key);
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_keys() {
+        // pinned, so that a change to the hashing is a deliberate one,
+        // as the SHA-256 of `b"client\xff"` and of `1u32` and `2u64` in little-endian
+        assert_eq!(redis_key("p:", &"client", None), "p:55429f189f1b1383cc0e40a92b7c6a36");
+        assert_eq!(redis_key("", &(1u32, 2u64), None), "a7b5f185aee1c8e29b7e2c2bc39f2f83");
+
+        // `usize` is hashed as 64 bits on every target
+        assert_eq!(redis_key("", &7usize, None), redis_key("", &7u64, None));
+        assert_ne!(redis_key("", &7u32, None), redis_key("", &7u64, None));
+    }
+
+    #[test]
+    fn key_encoder() {
+        let encode: fn(&&str, &mut Vec<u8>) = |key, out| out.extend(key.to_ascii_lowercase().as_bytes());
+
+        // only the encoding is hashed
+        assert_eq!(
+            redis_key("", &"Client", Some(encode)),
+            redis_key("", &"client", Some(encode))
+        );
+        assert_ne!(redis_key("", &"Client", None), redis_key("", &"client", None));
+        assert_ne!(redis_key("", &"client", Some(encode)), redis_key("", &"client", None));
+    }
+
+    #[test]
+    fn script_args_round_up() {
+        assert_eq!(script_args(Quota::unlimited()), None);
+        assert_eq!(
+            script_args(Quota::per_second(10).with_burst(5)),
+            Some((100_000, 500_000))
+        );
+
+        // never more permissive than the quota
+        assert_eq!(
+            script_args(Quota::simple(Duration::from_nanos(1)).with_burst(3)),
+            Some((1, 1))
+        );
+        assert_eq!(
+            script_args(Quota::simple(Duration::from_nanos(1500)).with_burst(3)),
+            Some((2, 5))
+        );
+    }
+
+    /// Requires a Redis server at `REDIS_URL`, or `redis://127.0.0.1/` by default.
+    async fn store() -> RedisStore<String> {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+        let client = ::redis::Client::open(url).unwrap();
+
+        RedisStore::new(client.get_connection_manager().await.unwrap()).with_prefix("axum_gcra_test:")
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Redis server"]
+    async fn burst_then_deny() {
+        let store = store().await;
+        let key = format!("burst-{}", std::process::id());
+        let quota = Quota::per_hour(1).with_burst(3);
+
+        _ = store.reset(&key).await.unwrap();
+
+        for _ in 0..3 {
+            assert!(store.req(&key, quota).await.unwrap().is_ok());
+        }
+
+        let wait = store.req(&key, quota).await.unwrap().unwrap_err();
+        assert!(wait.as_duration() > Duration::from_secs(3590));
+
+        assert!(store.penalize(&key, Duration::from_secs(60)).await.unwrap());
+        assert!(store.reset(&key).await.unwrap());
+        assert!(store.req(&key, quota).await.unwrap().is_ok());
+        assert!(store.reset(&key).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Redis server"]
+    async fn unlimited_never_stored() {
+        let store = store().await;
+        let key = format!("unlimited-{}", std::process::id());
+
+        for _ in 0..100 {
+            assert!(store.req(&key, Quota::unlimited()).await.unwrap().is_ok());
+        }
+
+        assert!(!store.reset(&key).await.unwrap());
+    }
+}
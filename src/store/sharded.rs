@@ -0,0 +1,220 @@
+use std::{
+    borrow::Borrow,
+    convert::Infallible,
+    hash::{BuildHasher, Hash},
+    time::Instant,
+};
+
+use super::Store;
+use crate::{
+    gcra::{Capacity, Quota, RateLimitError, RateLimiter},
+    RandomState,
+};
+
+/// An in-memory GCRA store split into a fixed number of independent shards, selected by the hash of each key.
+///
+/// The [`MemoryStore`](super::MemoryStore) already locks individual buckets of its table, but garbage collection,
+/// eviction and resizing still operate on the whole table at once, stalling every request that arrives meanwhile.
+/// Each shard of a `ShardedStore` is its own table, so these pauses only affect the keys of a single shard, and a
+/// shard can only grow to a fraction of the total entries.
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{gcra::Quota, real_ip::RealIp, store::ShardedStore, RateLimitLayer};
+///
+/// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+///     RateLimitLayer::<RealIp, _, _>::builder_with_store(ShardedStore::with_shards(16))
+///         .with_default_quota(Quota::per_second(10))
+///         .default_handle_error(),
+/// );
+/// ```
+pub struct ShardedStore<K: Eq + Hash, H: BuildHasher = RandomState> {
+    // a separate hasher, so the keys of a shard don't share the bits its table uses for buckets
+    selector: RandomState,
+    shards: Box<[RateLimiter<K, H>]>,
+}
+
+impl<K: Eq + Hash> ShardedStore<K> {
+    /// Constructs a new sharded store, with four shards per available CPU, rounded up to a power of two.
+    ///
+    /// Each shard garbage collects every 8192 requests to it.
+    #[must_use]
+    pub fn new() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+        Self::with_shards((cpus * 4).next_power_of_two())
+    }
+
+    /// Constructs a new sharded store with the given number of shards, garbage collecting each shard
+    /// every 8192 requests to it. A shard count of zero is treated as one.
+    #[must_use]
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_hasher(shards, 8192, RandomState::default())
+    }
+}
+
+impl<K: Eq + Hash> Default for ShardedStore<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher + Clone> ShardedStore<K, H> {
+    /// Constructs a new sharded store with the given number of shards, hasher and garbage collection interval
+    /// for each shard, which is in number of requests, not time. Use `u64::MAX` to disable garbage collection.
+    ///
+    /// A shard count of zero is treated as one.
+    pub fn with_hasher(shards: usize, gc_interval: u64, hasher: H) -> Self {
+        ShardedStore {
+            selector: RandomState::default(),
+            shards: (0..shards.max(1)).map(|_| RateLimiter::new(gc_interval, hasher.clone())).collect(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher> ShardedStore<K, H> {
+    /// Sets the maximum number of entries in the store, as by [`RateLimiter::with_max_entries`].
+    ///
+    /// The limit is divided evenly between the shards, each evicting its own entries when full.
+    /// The default is unlimited.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        let per_shard = max_entries.div_ceil(self.shards.len());
+
+        for shard in &mut self.shards {
+            shard.set_max_entries(per_shard);
+        }
+
+        self
+    }
+
+    /// Returns the number of shards in the store.
+    #[inline]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard the given key belongs to, such as to inspect or reset individual keys.
+    pub fn shard<Q>(&self, key: &Q) -> &RateLimiter<K, H>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        // multiply-shift maps the hash onto the shards without the bias of a modulo
+        let index = (u128::from(self.selector.hash_one(key)) * self.shards.len() as u128) >> 64;
+
+        &self.shards[index as usize]
+    }
+
+    /// Returns the number of entries in every shard of the store.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(RateLimiter::len).sum()
+    }
+
+    /// Returns `true` if every shard of the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(RateLimiter::is_empty)
+    }
+
+    /// Resets the rate limit for the given key, as by [`RateLimiter::reset`].
+    pub async fn reset<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.shard(key).reset(key).await
+    }
+
+    /// Resets the rate limit for every key in every shard.
+    pub async fn reset_all(&self) {
+        for shard in &self.shards {
+            shard.reset_all().await;
+        }
+    }
+
+    /// Cleans up any entries that have not been accessed since the given time, one shard at a time.
+    pub async fn clean(&self, before: Instant) {
+        for shard in &self.shards {
+            shard.clean(before).await;
+        }
+    }
+}
+
+impl<K, H> Store<K> for ShardedStore<K, H>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    H: BuildHasher + Send + Sync + 'static,
+{
+    type Error = Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        Ok(self.shard(&key).req_peek_key(key, quota, cost, now, peek).await)
+    }
+
+    async fn clean(&self, before: Instant) {
+        ShardedStore::clean(self, before).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    async fn hammer(store: Arc<ShardedStore<u32>>, keys: u32, quota: Quota) -> Vec<u64> {
+        let now = Instant::now();
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for _ in 0..16 {
+            let store = store.clone();
+
+            tasks.spawn(async move {
+                let mut allowed = vec![0; keys as usize];
+                for _ in 0..20 {
+                    for key in 0..keys {
+                        if let Ok(Ok(_)) = store.req(key, quota, 1, now, |_| {}).await {
+                            allowed[key as usize] += 1;
+                        }
+                    }
+                }
+                allowed
+            });
+        }
+
+        let mut total = vec![0; keys as usize];
+        for allowed in tasks.join_all().await {
+            total.iter_mut().zip(allowed).for_each(|(total, allowed)| *total += allowed);
+        }
+        total
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn one_key_never_exceeds_burst() {
+        let quota = Quota::per_hour(1).with_burst(5);
+
+        for shards in [1, 16] {
+            let allowed = hammer(Arc::new(ShardedStore::with_shards(shards)), 1, quota).await;
+            assert_eq!(allowed, [5], "{shards} shards");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn overlapping_keys_never_exceed_burst() {
+        let store = Arc::new(ShardedStore::with_shards(8));
+        let allowed = hammer(store.clone(), 64, Quota::per_hour(1).with_burst(5)).await;
+
+        assert_eq!(allowed, [5; 64]);
+        assert_eq!(store.len(), 64);
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    hash::{BuildHasher, Hash},
+    num::NonZeroU64,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use scc::hash_map::HashMap;
+
+use super::Store;
+use crate::{
+    gcra::{Capacity, Quota, RateLimitError},
+    RandomState,
+};
+
+/// An in-memory store using a sliding window log instead of GCRA.
+///
+/// Each [`Quota`] is interpreted as allowing [`burst`](Quota::burst) requests within any window of
+/// `emission_interval * burst`, so `Quota::per_minute(100).with_burst(100)` allows at most 100 requests
+/// in any 60 second period. Unlike GCRA, requests are not spread evenly across the window, and capacity
+/// is regained all at once as each logged request falls out of the window, rather than gradually,
+/// so the [`reset`](Capacity::reset) of an allowed request is the time until the oldest one does.
+///
+/// # Memory usage
+///
+/// A timestamp is kept for every request within the window, so each key costs up to 16 bytes per
+/// request allowed in the window, plus overhead, while [`MemoryStore`](super::MemoryStore) uses a
+/// single 8 byte value per key regardless of the quota. Prefer GCRA for quotas with large bursts.
+///
+/// Expired timestamps are evicted when a key is accessed, and keys with no timestamps left
+/// in their window are removed by garbage collection.
+///
+/// ```rust,no_run
+/// use axum::{routing::get, Router};
+/// use axum_gcra::{gcra::Quota, store::SlidingWindowStore, RateLimitLayer};
+///
+/// let app = Router::<()>::new().route("/", get(|| async { "Hello, World!" })).route_layer(
+///     RateLimitLayer::<(), _, _>::builder_with_store(SlidingWindowStore::new())
+///         .with_default_quota(Quota::per_minute(100).with_burst(100))
+///         .default_handle_error(),
+/// );
+/// ```
+pub struct SlidingWindowStore<K, H: BuildHasher = RandomState> {
+    start: Instant,
+    gc_interval: u64,
+    last_gc: AtomicU64,
+    logs: HashMap<K, Log, H>,
+}
+
+/// Timestamps of allowed requests for a single key, oldest first.
+struct Log {
+    /// Window length of the last request, in nanoseconds
+    window: u64,
+
+    /// Pairs of `(time, cost)`, relative to the store's start
+    times: VecDeque<(u64, u64)>,
+
+    /// Sum of the costs in `times`
+    used: u64,
+}
+
+impl Log {
+    fn expires(&self) -> u64 {
+        self.times.back().map_or(0, |&(time, _)| time.saturating_add(self.window))
+    }
+
+    fn evict(&mut self, now: u64) {
+        while let Some(&(time, cost)) = self.times.front() {
+            if time.saturating_add(self.window) > now {
+                break;
+            }
+
+            self.times.pop_front();
+            self.used -= cost;
+        }
+    }
+
+    fn req(&mut self, quota: Quota, cost: u64, now: u64) -> Result<Capacity, RateLimitError> {
+        let cost = cost.max(1);
+        let max = quota.burst();
+
+        self.window = (quota.emission_interval().as_nanos() as u64).saturating_mul(max);
+
+        // keep the log in order if the clock goes backwards, so entries never expire early
+        let now = self.times.back().map_or(now, |&(last, _)| now.max(last));
+        self.evict(now);
+
+        let used = self.used.saturating_add(cost);
+
+        if used > max {
+            let excess = used - max;
+
+            // wait until enough of the oldest requests have left the window,
+            // or a full window if the request could never fit
+            let mut freed = 0;
+            let mut wait = self.window;
+
+            if cost <= max {
+                for &(time, c) in &self.times {
+                    freed += c;
+
+                    if freed >= excess {
+                        wait = time.saturating_add(self.window) - now;
+                        break;
+                    }
+                }
+            }
+
+            return Err(RateLimitError(NonZeroU64::new(wait).unwrap_or(NonZeroU64::MIN)));
+        }
+
+        self.times.push_back((now, cost));
+        self.used = used;
+
+        // capacity is next regained when the oldest request leaves the window
+        let oldest = self.times.front().map_or(now, |&(time, _)| time);
+
+        Ok(Capacity {
+            limit: max,
+            remaining: max - self.used,
+            reset: Duration::from_nanos(oldest.saturating_add(self.window) - now),
+        })
+    }
+}
+
+impl<K: Eq + Hash> SlidingWindowStore<K> {
+    /// Constructs a new sliding window store, garbage collecting every 8192 requests.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_hasher(8192, RandomState::default())
+    }
+}
+
+impl<K: Eq + Hash> Default for SlidingWindowStore<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, H: BuildHasher> SlidingWindowStore<K, H> {
+    /// Constructs a new sliding window store with the given hasher and garbage collection interval,
+    /// which is in number of requests, not time. Use `u64::MAX` to disable garbage collection.
+    pub fn with_hasher(gc_interval: u64, hasher: H) -> Self {
+        SlidingWindowStore {
+            start: Instant::now(),
+            gc_interval,
+            last_gc: AtomicU64::new(1),
+            logs: HashMap::with_hasher(hasher),
+        }
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        self.logs.len()
+    }
+
+    /// Returns `true` if the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.logs.is_empty()
+    }
+
+    #[inline]
+    fn relative(&self, ts: Instant) -> u64 {
+        ts.saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    // `is_multiple_of` would require Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    fn should_gc(&self) -> bool {
+        self.gc_interval != u64::MAX && 0 == self.last_gc.fetch_add(1, Ordering::Relaxed) % self.gc_interval
+    }
+
+    /// Perform a request costing `cost` cells, returning an error if the window is full,
+    /// or the remaining capacity for the key on success.
+    pub async fn req(&self, key: K, quota: Quota, cost: u64, now: Instant) -> Result<Capacity, RateLimitError> {
+        self.req_peek_key(key, quota, cost, now, |_| {}).await
+    }
+
+    async fn req_peek_key<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Capacity, RateLimitError>
+    where
+        F: FnOnce(&K),
+    {
+        let now = self.relative(now);
+
+        if self.should_gc() {
+            self.logs.retain_async(move |_, log| log.expires() > now).await;
+        }
+
+        let mut entry = self.logs.entry_async(key).await.or_insert_with(|| Log {
+            window: 0,
+            times: VecDeque::new(),
+            used: 0,
+        });
+
+        let res = entry.get_mut().req(quota, cost, now);
+
+        if res.is_ok() {
+            peek(entry.key());
+        }
+
+        res
+    }
+
+    /// Resets the rate limit for the given key, returning `true` if the key was found.
+    pub async fn reset<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.logs.remove_async(key).await.is_some()
+    }
+
+    /// Resets the rate limit for every key.
+    pub async fn reset_all(&self) {
+        self.logs.clear_async().await;
+    }
+
+    /// Cleans up any keys with no requests within their window at the given time.
+    pub async fn clean(&self, before: Instant) {
+        let before = self.relative(before);
+        self.logs.retain_async(move |_, log| log.expires() > before).await;
+        self.last_gc.store(1, Ordering::Relaxed); // manual reset
+    }
+}
+
+impl<K, H> Store<K> for SlidingWindowStore<K, H>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    H: BuildHasher + Send + Sync + 'static,
+{
+    type Error = Infallible;
+
+    async fn req<F>(
+        &self,
+        key: K,
+        quota: Quota,
+        cost: u64,
+        now: Instant,
+        peek: F,
+    ) -> Result<Result<Capacity, RateLimitError>, Self::Error>
+    where
+        F: FnOnce(&K) + Send,
+    {
+        Ok(self.req_peek_key(key, quota, cost, now, peek).await)
+    }
+
+    async fn clean(&self, before: Instant) {
+        SlidingWindowStore::clean(self, before).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::{Clock, FakeClock};
+
+    #[tokio::test]
+    async fn window_boundary() {
+        // 5 requests in any 500ms
+        let quota = Quota::per_second(10).with_burst(5);
+        let store = SlidingWindowStore::<u32>::new();
+        let clock = FakeClock::new();
+
+        for remaining in [4, 3, 2, 1, 0] {
+            let capacity = store.req(1, quota, 1, clock.now()).await.unwrap();
+            assert_eq!(
+                (capacity.remaining, capacity.reset),
+                (remaining, Duration::from_millis(500))
+            );
+        }
+
+        clock.advance(Duration::from_millis(100));
+        let e = store.req(1, quota, 1, clock.now()).await.unwrap_err();
+        assert_eq!(e.as_duration(), Duration::from_millis(400));
+
+        clock.advance(Duration::from_millis(400) - Duration::from_nanos(1));
+        let e = store.req(1, quota, 1, clock.now()).await.unwrap_err();
+        assert_eq!(e.as_duration(), Duration::from_nanos(1));
+
+        // every request left the window at once
+        clock.advance(Duration::from_nanos(1));
+        for remaining in [4, 3, 2, 1, 0] {
+            assert_eq!(store.req(1, quota, 1, clock.now()).await.unwrap().remaining, remaining);
+        }
+
+        // other keys are independent
+        assert_eq!(store.req(2, quota, 1, clock.now()).await.unwrap().remaining, 4);
+    }
+
+    #[tokio::test]
+    async fn reset_until_oldest_expires() {
+        let quota = Quota::per_second(10).with_burst(5);
+        let store = SlidingWindowStore::<u32>::new();
+        let clock = FakeClock::new();
+
+        store.req(1, quota, 2, clock.now()).await.unwrap();
+        clock.advance(Duration::from_millis(200));
+
+        let capacity = store.req(1, quota, 3, clock.now()).await.unwrap();
+        assert_eq!((capacity.remaining, capacity.reset), (0, Duration::from_millis(300)));
+
+        // only the first request has left the window, freeing enough for a cost of 2 but not 3
+        clock.advance(Duration::from_millis(300));
+        let e = store.req(1, quota, 3, clock.now()).await.unwrap_err();
+        assert_eq!(e.as_duration(), Duration::from_millis(200));
+
+        let capacity = store.req(1, quota, 2, clock.now()).await.unwrap();
+        assert_eq!((capacity.remaining, capacity.reset), (0, Duration::from_millis(200)));
+    }
+
+    #[tokio::test]
+    async fn backwards_clock_keeps_log() {
+        let quota = Quota::per_second(10).with_burst(5);
+        let store = SlidingWindowStore::<u32>::new();
+        let clock = FakeClock::new();
+
+        clock.advance(Duration::from_secs(1));
+        store.req(1, quota, 2, clock.now()).await.unwrap();
+        clock.advance(Duration::from_millis(200));
+        store.req(1, quota, 3, clock.now()).await.unwrap();
+
+        // stepping back, even before the store was created, neither frees capacity nor lengthens the wait
+        for back in [1, 300, 5_000] {
+            let e = store.req(1, quota, 1, clock.now() - Duration::from_millis(back)).await.unwrap_err();
+            assert_eq!(e.as_duration(), Duration::from_millis(300));
+        }
+
+        clock.advance(Duration::from_millis(300));
+        assert!(store.req(1, quota, 3, clock.now()).await.is_err());
+        assert_eq!(store.req(1, quota, 2, clock.now()).await.unwrap().remaining, 0);
+    }
+}
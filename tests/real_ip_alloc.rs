@@ -0,0 +1,110 @@
+//! Client address resolution walks the hops of multi-hop headers lazily, so shouldn't allocate at all,
+//! however long the headers are. Counted with a global allocator, so this is its own test binary.
+
+#![cfg(feature = "real_ip")]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    convert::Infallible,
+    net::IpAddr,
+};
+
+use axum::extract::OptionalFromRequestParts;
+use axum_gcra::real_ip::{Cidr, RealIp, RealIpLayer};
+use futures_util::FutureExt;
+use http::{Request, Response};
+use tower::{service_fn, Layer, ServiceExt};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Resolves the client address through the layer, returning it and the number of allocations made resolving it.
+fn resolve(layer: &RealIpLayer, header: &str, value: &str) -> (Option<IpAddr>, usize) {
+    let service = layer.layer(service_fn(|req: Request<()>| async move {
+        let (mut parts, ()) = req.into_parts();
+
+        // resolved again below, as the layer already inserted it
+        parts.extensions.remove::<RealIp>();
+
+        let before = ALLOCATIONS.with(Cell::get);
+        let ip = <RealIp as OptionalFromRequestParts<()>>::from_request_parts(&mut parts, &())
+            .now_or_never()
+            .expect("resolution is synchronous")
+            .unwrap();
+        let allocations = ALLOCATIONS.with(Cell::get) - before;
+
+        Ok::<_, Infallible>(Response::new((ip.map(|ip| ip.0), allocations)))
+    }));
+
+    let req = Request::builder().header(header, value).body(()).unwrap();
+
+    service.oneshot(req).now_or_never().unwrap().unwrap().into_body()
+}
+
+fn chain(n: usize) -> String {
+    let hops: Vec<_> = (0..n).map(|i| format!("10.0.{}.{}", i / 256 % 256, i % 256)).collect();
+    hops.join(", ")
+}
+
+#[test]
+fn x_forwarded_for_does_not_allocate() {
+    let trusted = RealIpLayer::new().with_trusted_proxies(["10.0.0.0/8".parse::<Cidr>().unwrap()]);
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+    assert_eq!(
+        resolve(&RealIpLayer::new(), "x-forwarded-for", "203.0.113.7"),
+        (Some(client), 0)
+    );
+
+    let value = format!("6.6.6.6, 203.0.113.7, {}", chain(3));
+    assert_eq!(resolve(&trusted, "x-forwarded-for", &value), (Some(client), 0));
+
+    // walking every hop within the limit
+    let value = format!("203.0.113.7, {}", chain(15));
+    assert_eq!(resolve(&trusted, "x-forwarded-for", &value), (Some(client), 0));
+
+    // and truncating a header with too many
+    let value = format!("{}, 203.0.113.7, 10.0.0.1", chain(10_000));
+    assert_eq!(resolve(&trusted, "x-forwarded-for", &value), (Some(client), 0));
+
+    let unlimited = trusted.with_max_hops(usize::MAX);
+    let value = format!("203.0.113.7, {}", chain(10_000));
+    assert_eq!(resolve(&unlimited, "x-forwarded-for", &value), (Some(client), 0));
+}
+
+#[test]
+fn forwarded_does_not_allocate() {
+    let trusted = RealIpLayer::new().with_trusted_proxies(["10.0.0.0/8".parse::<Cidr>().unwrap()]);
+    let value = r#"for=6.6.6.6, for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.2, for=10.0.0.1"#;
+
+    assert_eq!(
+        resolve(&trusted, "forwarded", value),
+        (Some("2001:db8:cafe::17".parse().unwrap()), 0)
+    );
+}
+
+#[test]
+fn allocations_are_counted() {
+    let before = ALLOCATIONS.with(Cell::get);
+    let v = std::hint::black_box(vec![0u8; 16]);
+    assert_eq!(ALLOCATIONS.with(Cell::get) - before, 1);
+    drop(v);
+}